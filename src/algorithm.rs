@@ -0,0 +1,521 @@
+// Rate limiting algorithms. Each one keeps a small amount of state per source
+// and decides, packet by packet, whether that source is over its `Limit`.
+//
+// Every packet has a cost: 1 when counting packets, its length when counting
+// bytes. The algorithms all read the same limit, "at most `amount` per
+// `window`", but differ in how strictly they enforce it:
+//
+// - `FixedWindow` sums costs in back-to-back windows. Cheap, but a source
+//   can send twice the limit across the edge between two windows.
+// - `SlidingLog` remembers packet timestamps and looks back exactly one
+//   window from every packet. Exact, but needs memory per packet.
+// - `SlidingWindowCounter` blends the previous and current fixed windows by
+//   how far we are into the current one. Close to exact at fixed-window cost.
+// - `TokenBucket` refills `amount` tokens per `window` and spends the cost of
+//   each packet, allowing bursts of up to `amount` after a quiet period.
+// - `LeakyBucket` fills by the cost of each packet and drains at `amount` per
+//   `window`, so it starts empty and smooths traffic instead of rewarding
+//   idle time with a burst allowance.
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// "At most `amount` (packets or bytes) per `window`."
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit {
+    pub amount: u64,
+    pub window: Duration,
+}
+
+impl Limit {
+    // Amount per second, for the bucket algorithms.
+    fn rate(&self) -> f64 {
+        self.amount as f64 / self.window.as_secs_f64()
+    }
+}
+
+/// How much of its limit a source has used after a packet, and whether that
+/// packet took it over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level {
+    pub used: u64,
+    pub exceeded: bool,
+}
+
+// The common interface every algorithm implements (Go: interface). The limit
+// is passed in instead of stored so per-source state stays small.
+pub trait Algorithm {
+    /// Fresh state for a source first seen at `now`.
+    fn new(limit: &Limit, now: Duration) -> Self
+    where
+        Self: Sized;
+
+    /// Account for one packet costing `cost` at `now`.
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level;
+
+    /// `true` once the state is indistinguishable from a fresh one, so the
+    /// limiter can forget the source.
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool;
+}
+
+fn level(used: u64, limit: &Limit) -> Level {
+    Level {
+        used,
+        exceeded: used > limit.amount,
+    }
+}
+
+pub struct FixedWindow {
+    start: Duration,
+    used: u64,
+}
+
+impl Algorithm for FixedWindow {
+    fn new(_limit: &Limit, now: Duration) -> FixedWindow {
+        FixedWindow {
+            start: now,
+            used: 0,
+        }
+    }
+
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        // `saturating_sub` clamps at zero if a file's timestamps go backwards.
+        if now.saturating_sub(self.start) >= limit.window {
+            self.start = now;
+            self.used = 0;
+        }
+        self.used = self.used.saturating_add(cost);
+        level(self.used, limit)
+    }
+
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        now.saturating_sub(self.start) >= limit.window
+    }
+}
+
+pub struct SlidingLog {
+    // Timestamp and cost of the packets in the last window, oldest first.
+    // Packets stop being logged once the total is over the limit: the exact
+    // total no longer changes the verdict, and a flood can't make the log
+    // grow without bound.
+    entries: VecDeque<(Duration, u64)>,
+    // Sum of the costs in `entries`.
+    used: u64,
+}
+
+impl Algorithm for SlidingLog {
+    fn new(_limit: &Limit, _now: Duration) -> SlidingLog {
+        SlidingLog {
+            entries: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        while let Some(&(oldest, oldest_cost)) = self.entries.front() {
+            if now.saturating_sub(oldest) < limit.window {
+                break;
+            }
+            self.entries.pop_front();
+            self.used -= oldest_cost;
+        }
+        if self.used <= limit.amount {
+            self.entries.push_back((now, cost));
+            self.used += cost;
+        }
+        level(self.used, limit)
+    }
+
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        // The newest entry is the last to age out.
+        self.entries
+            .back()
+            .is_none_or(|&(newest, _)| now.saturating_sub(newest) >= limit.window)
+    }
+}
+
+pub struct SlidingWindowCounter {
+    current_start: Duration,
+    current: u64,
+    previous: u64,
+}
+
+impl Algorithm for SlidingWindowCounter {
+    fn new(_limit: &Limit, now: Duration) -> SlidingWindowCounter {
+        SlidingWindowCounter {
+            current_start: now,
+            current: 0,
+            previous: 0,
+        }
+    }
+
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        let elapsed = now.saturating_sub(self.current_start);
+        if elapsed >= limit.window.saturating_mul(2) {
+            // Both windows are stale; start over.
+            self.current_start = now;
+            self.previous = 0;
+            self.current = 0;
+        } else if elapsed >= limit.window {
+            self.current_start += limit.window;
+            self.previous = self.current;
+            self.current = 0;
+        }
+        self.current = self.current.saturating_add(cost);
+
+        // The previous window counts for the fraction of it that still lies
+        // within one window of `now`.
+        let into_current = now.saturating_sub(self.current_start).as_secs_f64();
+        let weight = 1.0 - (into_current / limit.window.as_secs_f64()).min(1.0);
+        let estimate = (self.previous as f64 * weight) as u64 + self.current;
+        level(estimate, limit)
+    }
+
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        now.saturating_sub(self.current_start) >= limit.window.saturating_mul(2)
+    }
+}
+
+pub struct TokenBucket {
+    tokens: f64,
+    last: Duration,
+}
+
+impl Algorithm for TokenBucket {
+    fn new(limit: &Limit, now: Duration) -> TokenBucket {
+        // A new source starts with a full bucket.
+        TokenBucket {
+            tokens: limit.amount as f64,
+            last: now,
+        }
+    }
+
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        let capacity = limit.amount as f64;
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate()).min(capacity);
+        self.last = now;
+
+        // Report how many tokens are spent, i.e. the recent usage. A packet
+        // that can't be paid for counts on top of that without spending.
+        let used = capacity - self.tokens + cost as f64;
+        if self.tokens >= cost as f64 {
+            self.tokens -= cost as f64;
+            level(used.round() as u64, limit)
+        } else {
+            // Round up so the report is always over the limit.
+            Level {
+                used: used.ceil() as u64,
+                exceeded: true,
+            }
+        }
+    }
+
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.tokens + elapsed * limit.rate() >= limit.amount as f64
+    }
+}
+
+pub struct LeakyBucket {
+    level: f64,
+    last: Duration,
+}
+
+impl Algorithm for LeakyBucket {
+    fn new(_limit: &Limit, now: Duration) -> LeakyBucket {
+        LeakyBucket {
+            level: 0.0,
+            last: now,
+        }
+    }
+
+    fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.level = (self.level - elapsed * limit.rate()).max(0.0);
+        self.last = now;
+
+        // A packet that would overflow the bucket is over the limit and is
+        // not added, so the bucket drains as soon as the source slows down.
+        let filled = self.level + cost as f64;
+        if filled > limit.amount as f64 {
+            Level {
+                used: filled.ceil() as u64,
+                exceeded: true,
+            }
+        } else {
+            self.level = filled;
+            level(filled.round() as u64, limit)
+        }
+    }
+
+    fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        let elapsed = now.saturating_sub(self.last).as_secs_f64();
+        self.level - elapsed * limit.rate() <= 0.0
+    }
+}
+
+/// Which algorithm a `RateLimiter` uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlgorithmKind {
+    FixedWindow,
+    SlidingLog,
+    SlidingWindowCounter,
+    TokenBucket,
+    LeakyBucket,
+}
+
+impl AlgorithmKind {
+    pub const ALL: [AlgorithmKind; 5] = [
+        AlgorithmKind::FixedWindow,
+        AlgorithmKind::SlidingLog,
+        AlgorithmKind::SlidingWindowCounter,
+        AlgorithmKind::TokenBucket,
+        AlgorithmKind::LeakyBucket,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AlgorithmKind::FixedWindow => "fixed-window",
+            AlgorithmKind::SlidingLog => "sliding-log",
+            AlgorithmKind::SlidingWindowCounter => "sliding-window-counter",
+            AlgorithmKind::TokenBucket => "token-bucket",
+            AlgorithmKind::LeakyBucket => "leaky-bucket",
+        }
+    }
+}
+
+impl fmt::Display for AlgorithmKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for AlgorithmKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AlgorithmKind, String> {
+        AlgorithmKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| format!("unknown rate limiting algorithm '{}'", s))
+    }
+}
+
+// Per-source state for whichever algorithm was chosen. An enum rather than a
+// `Box<dyn Algorithm>` keeps every source's state inline in the map, with no
+// allocation per source (except for the sliding log's buffer).
+pub enum State {
+    FixedWindow(FixedWindow),
+    SlidingLog(SlidingLog),
+    SlidingWindowCounter(SlidingWindowCounter),
+    TokenBucket(TokenBucket),
+    LeakyBucket(LeakyBucket),
+}
+
+impl State {
+    pub fn new(kind: AlgorithmKind, limit: &Limit, now: Duration) -> State {
+        match kind {
+            AlgorithmKind::FixedWindow => State::FixedWindow(FixedWindow::new(limit, now)),
+            AlgorithmKind::SlidingLog => State::SlidingLog(SlidingLog::new(limit, now)),
+            AlgorithmKind::SlidingWindowCounter => {
+                State::SlidingWindowCounter(SlidingWindowCounter::new(limit, now))
+            }
+            AlgorithmKind::TokenBucket => State::TokenBucket(TokenBucket::new(limit, now)),
+            AlgorithmKind::LeakyBucket => State::LeakyBucket(LeakyBucket::new(limit, now)),
+        }
+    }
+
+    pub fn record(&mut self, limit: &Limit, now: Duration, cost: u64) -> Level {
+        match self {
+            State::FixedWindow(s) => s.record(limit, now, cost),
+            State::SlidingLog(s) => s.record(limit, now, cost),
+            State::SlidingWindowCounter(s) => s.record(limit, now, cost),
+            State::TokenBucket(s) => s.record(limit, now, cost),
+            State::LeakyBucket(s) => s.record(limit, now, cost),
+        }
+    }
+
+    pub fn is_idle(&self, limit: &Limit, now: Duration) -> bool {
+        match self {
+            State::FixedWindow(s) => s.is_idle(limit, now),
+            State::SlidingLog(s) => s.is_idle(limit, now),
+            State::SlidingWindowCounter(s) => s.is_idle(limit, now),
+            State::TokenBucket(s) => s.is_idle(limit, now),
+            State::LeakyBucket(s) => s.is_idle(limit, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(amount: u64, seconds: u64) -> Limit {
+        Limit {
+            amount,
+            window: Duration::from_secs(seconds),
+        }
+    }
+
+    fn at(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    // Record one packet per time in `times`, returning what was used after
+    // each and whether it was over.
+    fn run<A: Algorithm>(limit: &Limit, times: &[f64]) -> Vec<(u64, bool)> {
+        let mut state = A::new(limit, at(times[0]));
+        times
+            .iter()
+            .map(|&time| {
+                let level = state.record(limit, at(time), 1);
+                (level.used, level.exceeded)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed_window_resets_at_the_window_edge() {
+        let limit = limit(3, 10);
+        let levels = run::<FixedWindow>(&limit, &[0.0, 1.0, 2.0, 9.9, 10.0, 10.0]);
+        assert_eq!(
+            levels,
+            [
+                (1, false),
+                (2, false),
+                (3, false),
+                (4, true),
+                (1, false),
+                (2, false)
+            ]
+        );
+
+        let mut state = FixedWindow::new(&limit, at(0.0));
+        assert!(!state.is_idle(&limit, at(9.9)));
+        assert!(state.is_idle(&limit, at(10.0)));
+        // A window starts at its first packet, not on a fixed grid.
+        state.record(&limit, at(25.0), 1);
+        assert!(!state.is_idle(&limit, at(34.9)));
+    }
+
+    #[test]
+    fn sliding_log_stops_logging_once_over() {
+        let limit = limit(3, 10);
+        let mut state = SlidingLog::new(&limit, at(0.0));
+        for time in [0.0, 1.0, 2.0] {
+            assert!(!state.record(&limit, at(time), 1).exceeded);
+        }
+        // The packet that goes over is logged, the ones after it aren't.
+        for _ in 0..100 {
+            let level = state.record(&limit, at(3.0), 1);
+            assert_eq!((level.used, level.exceeded), (4, true));
+        }
+        assert_eq!(state.entries.len(), 4);
+
+        // Exactly one window after the first packet it no longer counts,
+        // but the three after it still take the total over.
+        assert_eq!(state.record(&limit, at(10.0), 1).used, 4);
+        // Only 10.0 and the now allowed 13.0 are left.
+        let level = state.record(&limit, at(13.0), 1);
+        assert_eq!((level.used, level.exceeded), (2, false));
+        assert!(!state.is_idle(&limit, at(22.9)));
+        assert!(state.is_idle(&limit, at(23.0)));
+    }
+
+    #[test]
+    fn sliding_window_counter_weights_the_previous_window() {
+        let limit = limit(10, 10);
+        let mut state = SlidingWindowCounter::new(&limit, at(0.0));
+        for _ in 0..10 {
+            assert!(!state.record(&limit, at(0.0), 1).exceeded);
+        }
+        // Half way into the next window, half of the previous one counts.
+        let level = state.record(&limit, at(15.0), 1);
+        assert_eq!((level.used, level.exceeded), (6, false));
+        for _ in 0..4 {
+            state.record(&limit, at(15.0), 1);
+        }
+        let level = state.record(&limit, at(15.0), 1);
+        assert_eq!((level.used, level.exceeded), (11, true));
+
+        // Two windows of silence start over.
+        assert!(!state.is_idle(&limit, at(29.9)));
+        assert!(state.is_idle(&limit, at(30.0)));
+        assert_eq!(state.record(&limit, at(30.0), 1).used, 1);
+    }
+
+    #[test]
+    fn sliding_window_counter_takes_a_huge_window() {
+        let limit = limit(2, u64::MAX);
+        assert_eq!(
+            run::<SlidingWindowCounter>(&limit, &[0.0, 1.0, 2.0]),
+            [(1, false), (2, false), (3, true)]
+        );
+        let state = SlidingWindowCounter::new(&limit, at(0.0));
+        assert!(!state.is_idle(&limit, at(1e9)));
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refills() {
+        // One token a second, up to four.
+        let limit = limit(4, 4);
+        let levels = run::<TokenBucket>(&limit, &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(
+            levels,
+            [
+                (1, false),
+                (2, false),
+                (3, false),
+                (4, false),
+                (5, true),
+                (4, false),
+                (5, true)
+            ]
+        );
+
+        let mut state = TokenBucket::new(&limit, at(0.0));
+        assert!(state.is_idle(&limit, at(0.0)));
+        state.record(&limit, at(0.0), 2);
+        assert!(!state.is_idle(&limit, at(1.9)));
+        assert!(state.is_idle(&limit, at(2.0)));
+        // A packet costing more than the whole bucket never fits.
+        let level = state.record(&limit, at(10.0), 5);
+        assert_eq!((level.used, level.exceeded), (5, true));
+    }
+
+    #[test]
+    fn leaky_bucket_starts_empty_and_drains() {
+        // Drains one a second, holds four.
+        let limit = limit(4, 4);
+        let levels = run::<LeakyBucket>(&limit, &[0.0, 0.0, 0.0, 0.0, 0.0, 2.0, 2.0, 2.0]);
+        assert_eq!(
+            levels,
+            [
+                (1, false),
+                (2, false),
+                (3, false),
+                (4, false),
+                (5, true),
+                (3, false),
+                (4, false),
+                (5, true)
+            ]
+        );
+
+        let mut state = LeakyBucket::new(&limit, at(0.0));
+        assert!(state.is_idle(&limit, at(0.0)));
+        state.record(&limit, at(0.0), 3);
+        assert!(!state.is_idle(&limit, at(2.9)));
+        assert!(state.is_idle(&limit, at(3.0)));
+    }
+
+    #[test]
+    fn parses_every_name() {
+        for kind in AlgorithmKind::ALL {
+            assert_eq!(kind.name().parse::<AlgorithmKind>(), Ok(kind));
+        }
+        assert!("bucket".parse::<AlgorithmKind>().is_err());
+    }
+}
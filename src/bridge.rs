@@ -0,0 +1,318 @@
+// A transparent bridge between two interfaces, for running inline instead
+// of beside the traffic. Every frame read on one side goes through the same
+// rate limiting as any captured frame, and is then sent out of the other
+// side, unless it went over a limit or its source is blocked or denied:
+//
+//   eth0 --> capture --> limiters --> forward --> eth1
+//   eth0 <-- forward <-- limiters <-- capture <-- eth1
+//
+// Each direction is its own capture thread (or several, with fanout), with
+// a `Forwarder` sending through a socket on the opposite interface. The
+// capture sockets ignore frames going out of their interface, so frames
+// forwarded to a side are never read back in and sent round again.
+#[cfg(target_os = "linux")]
+use pnet::datalink;
+use pnet::datalink::DataLinkSender;
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::capture::{ErrorClass, Frame};
+#[cfg(target_os = "linux")]
+use crate::socket::PacketSender;
+
+/// What one direction of a bridge did with its frames. Shared by every
+/// thread forwarding that way, like the `Metrics` it's registered with.
+#[derive(Debug, Default)]
+pub struct Direction {
+    from: String,
+    to: String,
+    forwarded: AtomicU64,
+    forwarded_bytes: AtomicU64,
+    dropped: AtomicU64,
+    dropped_bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Direction {
+    pub fn new(from: &str, to: &str) -> Direction {
+        Direction {
+            from: from.to_string(),
+            to: to.to_string(),
+            ..Direction::default()
+        }
+    }
+
+    pub fn from(&self) -> &str {
+        &self.from
+    }
+
+    pub fn to(&self) -> &str {
+        &self.to
+    }
+
+    /// The counts so far.
+    pub fn stats(&self) -> DirectionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DirectionStats {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            forwarded: load(&self.forwarded),
+            forwarded_bytes: load(&self.forwarded_bytes),
+            dropped: load(&self.dropped),
+            dropped_bytes: load(&self.dropped_bytes),
+            errors: load(&self.errors),
+        }
+    }
+}
+
+/// A copy of one direction's counts, for reports.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DirectionStats {
+    pub from: String,
+    pub to: String,
+    pub forwarded: u64,
+    pub forwarded_bytes: u64,
+    /// Frames held back by the rate limit, the lists or a block.
+    pub dropped: u64,
+    pub dropped_bytes: u64,
+    /// Frames that should have been forwarded, but couldn't be sent.
+    pub errors: u64,
+}
+
+// How often a side that's gone away is looked for again.
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sends the frames one side of a bridge lets through out of the other.
+pub struct Forwarder {
+    // The `tx` half of a channel on the other side.
+    sender: Box<dyn DataLinkSender>,
+    write_buffer_size: usize,
+    direction: Arc<Direction>,
+    // When to next try opening the other side again, after it went away.
+    reopen_at: Instant,
+}
+
+impl Forwarder {
+    /// Forward frames the way `direction` goes, opening a socket on its
+    /// `to` interface with a buffer of `write_buffer_size` bytes.
+    pub fn open(direction: Arc<Direction>, write_buffer_size: usize) -> io::Result<Forwarder> {
+        let sender = open_sender(direction.to(), write_buffer_size)?;
+        Ok(Forwarder {
+            sender,
+            write_buffer_size,
+            direction,
+            reopen_at: Instant::now(),
+        })
+    }
+
+    pub fn direction(&self) -> &Direction {
+        &self.direction
+    }
+
+    /// Send `frame` out of the other side.
+    ///
+    /// A frame cut short by the read buffer can't be sent whole, and
+    /// counts as an error like a failed send. If the other side went away
+    /// and came back as a new interface, its socket is opened again, at
+    /// most once a second; frames are lost until then.
+    pub fn forward(&mut self, frame: &Frame) -> io::Result<()> {
+        let result = match frame.data.len() < frame.len {
+            true => Err(io::Error::other("frame is longer than the read buffer")),
+            // `None` means the frame doesn't fit the write buffer.
+            false => self
+                .sender
+                .send_to(frame.data, None)
+                .unwrap_or_else(|| Err(io::Error::other("frame is longer than the write buffer"))),
+        };
+        let direction = &self.direction;
+        match result {
+            Ok(()) => {
+                direction.forwarded.fetch_add(1, Ordering::Relaxed);
+                direction
+                    .forwarded_bytes
+                    .fetch_add(frame.len as u64, Ordering::Relaxed);
+            }
+            Err(ref e) => {
+                direction.errors.fetch_add(1, Ordering::Relaxed);
+                if ErrorClass::of(e) == ErrorClass::InterfaceDown
+                    && Instant::now() >= self.reopen_at
+                {
+                    self.reopen_at = Instant::now() + REOPEN_INTERVAL;
+                    if let Ok(sender) = open_sender(direction.to(), self.write_buffer_size) {
+                        self.sender = sender;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Count `frame` as dropped rather than forwarded.
+    pub fn drop_frame(&self, frame: &Frame) {
+        self.direction.dropped.fetch_add(1, Ordering::Relaxed);
+        self.direction
+            .dropped_bytes
+            .fetch_add(frame.len as u64, Ordering::Relaxed);
+    }
+}
+
+// A send-only socket on the interface called `name`, looked up again each
+// time, since one that was deleted and created again has a new index.
+#[cfg(target_os = "linux")]
+fn open_sender(name: &str, write_buffer_size: usize) -> io::Result<Box<dyn DataLinkSender>> {
+    let interface = datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == name)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ENODEV))?;
+    Ok(Box::new(PacketSender::open(&interface, write_buffer_size)?))
+}
+
+// Elsewhere there's no way to keep the capture from reading back the frames
+// forwarded out of its interface, so `Config::check` rejects a bridge.
+#[cfg(not(target_os = "linux"))]
+fn open_sender(_name: &str, _write_buffer_size: usize) -> io::Result<Box<dyn DataLinkSender>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "bridging needs Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::datalink::NetworkInterface;
+    use std::sync::{Mutex, PoisonError};
+
+    // What the fake sender does with the next frame.
+    #[derive(Clone, Copy)]
+    enum Outcome {
+        Send,
+        TooLong,
+        Fail(i32),
+    }
+
+    // The frames the fake sent, and what it does with the next one.
+    struct Wire {
+        sent: Vec<Vec<u8>>,
+        outcome: Outcome,
+    }
+
+    // A `DataLinkSender` that keeps what it sends instead.
+    struct Fake(Arc<Mutex<Wire>>);
+
+    impl DataLinkSender for Fake {
+        fn build_and_send(
+            &mut self,
+            _num_packets: usize,
+            _packet_size: usize,
+            _func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<io::Result<()>> {
+            unimplemented!()
+        }
+
+        fn send_to(
+            &mut self,
+            packet: &[u8],
+            _dst: Option<NetworkInterface>,
+        ) -> Option<io::Result<()>> {
+            let mut wire = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            match wire.outcome {
+                Outcome::Send => {
+                    wire.sent.push(packet.to_vec());
+                    Some(Ok(()))
+                }
+                Outcome::TooLong => None,
+                Outcome::Fail(errno) => Some(Err(io::Error::from_raw_os_error(errno))),
+            }
+        }
+    }
+
+    // A forwarder to an interface that doesn't exist, sending through a
+    // fake, and the fake's side of it.
+    fn forwarder() -> (Forwarder, Arc<Mutex<Wire>>) {
+        let wire = Arc::new(Mutex::new(Wire {
+            sent: Vec::new(),
+            outcome: Outcome::Send,
+        }));
+        let forwarder = Forwarder {
+            sender: Box::new(Fake(Arc::clone(&wire))),
+            write_buffer_size: 4096,
+            direction: Arc::new(Direction::new("eth0", "packet_processor-none")),
+            reopen_at: Instant::now(),
+        };
+        (forwarder, wire)
+    }
+
+    fn frame(data: &[u8], len: usize) -> Frame<'_> {
+        Frame {
+            data,
+            len,
+            timestamp: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn counts_what_it_forwards_and_drops() {
+        let (mut forwarder, wire) = forwarder();
+        forwarder.forward(&frame(&[1; 60], 60)).unwrap();
+        forwarder.forward(&frame(&[2; 100], 100)).unwrap();
+        forwarder.drop_frame(&frame(&[3; 80], 80));
+
+        assert_eq!(wire.lock().unwrap().sent, [vec![1; 60], vec![2; 100]]);
+        let stats = forwarder.direction().stats();
+        assert_eq!(
+            (stats.from.as_str(), stats.to.as_str()),
+            ("eth0", "packet_processor-none")
+        );
+        assert_eq!((stats.forwarded, stats.forwarded_bytes), (2, 160));
+        assert_eq!((stats.dropped, stats.dropped_bytes), (1, 80));
+        assert_eq!(stats.errors, 0);
+    }
+
+    #[test]
+    fn does_not_send_truncated_frames() {
+        let (mut forwarder, wire) = forwarder();
+        // Only the first 60 bytes of a 1500-byte frame were read.
+        assert!(forwarder.forward(&frame(&[1; 60], 1500)).is_err());
+
+        assert!(wire.lock().unwrap().sent.is_empty());
+        let stats = forwarder.direction().stats();
+        assert_eq!((stats.forwarded, stats.errors), (0, 1));
+    }
+
+    #[test]
+    fn counts_frames_it_cannot_send_as_errors() {
+        let (mut forwarder, wire) = forwarder();
+        wire.lock().unwrap().outcome = Outcome::TooLong;
+        assert!(forwarder.forward(&frame(&[1; 60], 60)).is_err());
+        wire.lock().unwrap().outcome = Outcome::Fail(libc::ENOBUFS);
+        let e = forwarder.forward(&frame(&[1; 60], 60)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOBUFS));
+
+        let stats = forwarder.direction().stats();
+        assert_eq!((stats.forwarded, stats.errors), (0, 2));
+        // It goes on sending once the other side takes frames again.
+        wire.lock().unwrap().outcome = Outcome::Send;
+        forwarder.forward(&frame(&[1; 60], 60)).unwrap();
+        assert_eq!(wire.lock().unwrap().sent.len(), 1);
+        assert_eq!(forwarder.direction().stats().forwarded, 1);
+    }
+
+    #[test]
+    fn keeps_its_socket_while_the_other_side_is_missing() {
+        let (mut forwarder, wire) = forwarder();
+        wire.lock().unwrap().outcome = Outcome::Fail(libc::ENODEV);
+        assert!(forwarder.forward(&frame(&[1; 60], 60)).is_err());
+        // The interface can't be opened again, so it waits before trying.
+        assert!(forwarder.reopen_at > Instant::now());
+
+        wire.lock().unwrap().outcome = Outcome::Send;
+        forwarder.forward(&frame(&[1; 60], 60)).unwrap();
+        assert_eq!(wire.lock().unwrap().sent.len(), 1);
+        let stats = forwarder.direction().stats();
+        assert_eq!((stats.forwarded, stats.errors), (1, 1));
+    }
+}
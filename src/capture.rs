@@ -0,0 +1,507 @@
+use pnet::datalink::{self, DataLinkReceiver};
+#[cfg(target_os = "linux")]
+use pnet::datalink::{FanoutOption, FanoutType};
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::pcap::{LINKTYPE_ETHERNET, PcapReader};
+
+/// A single Ethernet frame and the moment it was captured.
+pub struct Frame<'a> {
+    pub data: &'a [u8],
+    /// Length of the frame on the wire. Larger than `data.len()` when a
+    /// capture file was recorded with a small snaplen.
+    pub len: usize,
+    /// Capture time as an offset from the Unix epoch: the recorded time for
+    /// a file, and `now()` for a live interface. All rate limiting windows
+    /// are measured on this clock, so replaying a file behaves the same as
+    /// watching the traffic live.
+    pub timestamp: Duration,
+}
+
+// Anything frames can be read from: a live interface or a capture file.
+// The `'_` in the return type says the frame borrows from the source and is
+// only valid until the next call, like a reused buffer in Go.
+// `Send` lets each source be handed to its own capture thread.
+pub trait PacketSource: Send {
+    /// Return the next frame, or `None` once the source is exhausted.
+    fn next_frame(&mut self) -> io::Result<Option<Frame<'_>>>;
+
+    /// What the kernel counted since the last call, for sources that can
+    /// tell. A method with a default body is one implementors may leave
+    /// out, unlike anything in a Go interface.
+    fn kernel_stats(&mut self) -> Option<KernelStats> {
+        None
+    }
+}
+
+/// Frames the kernel saw for a capture socket, and how many of them it had
+/// to drop because the capture thread didn't keep up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct KernelStats {
+    /// Frames that passed the filter, including the dropped ones.
+    pub packets: u64,
+    pub drops: u64,
+}
+
+/// Frames from a live interface, timestamped as they arrive.
+pub struct LiveCapture {
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl LiveCapture {
+    pub fn new(rx: Box<dyn DataLinkReceiver>) -> LiveCapture {
+        LiveCapture { rx }
+    }
+}
+
+impl PacketSource for LiveCapture {
+    fn next_frame(&mut self) -> io::Result<Option<Frame<'_>>> {
+        let data = self.rx.next()?;
+        Ok(Some(Frame {
+            data,
+            len: data.len(),
+            timestamp: now(),
+        }))
+    }
+}
+
+/// Which layer a live capture reads at.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelType {
+    /// Whole Ethernet frames.
+    #[default]
+    Layer2,
+    /// Only packets of one EtherType, with the Ethernet header already
+    /// stripped by the kernel. The MAC addresses and VLAN tags are lost.
+    Layer3(u16),
+}
+
+impl ChannelType {
+    pub fn datalink(self) -> datalink::ChannelType {
+        match self {
+            ChannelType::Layer2 => datalink::ChannelType::Layer2,
+            ChannelType::Layer3(ethertype) => datalink::ChannelType::Layer3(ethertype),
+        }
+    }
+}
+
+// Written as `layer2`, or `layer3:` and an EtherType: `ipv4`, `ipv6`, `arp`
+// or a number such as `0x88cc`.
+impl fmt::Display for ChannelType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelType::Layer2 => f.write_str("layer2"),
+            ChannelType::Layer3(0x0800) => f.write_str("layer3:ipv4"),
+            ChannelType::Layer3(0x86dd) => f.write_str("layer3:ipv6"),
+            ChannelType::Layer3(0x0806) => f.write_str("layer3:arp"),
+            ChannelType::Layer3(ethertype) => write!(f, "layer3:{:#06x}", ethertype),
+        }
+    }
+}
+
+impl FromStr for ChannelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChannelType, String> {
+        let ethertype = match s.split_once(':') {
+            None if s == "layer2" => return Ok(ChannelType::Layer2),
+            Some(("layer3", ethertype)) => ethertype,
+            _ => {
+                return Err(format!(
+                    "unknown channel type '{}', expected layer2 or layer3:ETHERTYPE",
+                    s
+                ));
+            }
+        };
+        let ethertype = match ethertype {
+            "ipv4" => 0x0800,
+            "ipv6" => 0x86dd,
+            "arp" => 0x0806,
+            _ => ethertype
+                .strip_prefix("0x")
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| {
+                    format!(
+                        "invalid EtherType '{}', expected ipv4, ipv6, arp or a hex number",
+                        ethertype
+                    )
+                })?,
+        };
+        Ok(ChannelType::Layer3(ethertype))
+    }
+}
+
+/// How the kernel spreads an interface's packets over the capture threads
+/// sharing it (`PACKET_FANOUT`, so Linux only).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FanoutMode {
+    /// By a hash of the addresses and ports, so every packet of a flow goes
+    /// to the same thread. IP fragments are put back together first, so
+    /// they follow their flow too.
+    #[default]
+    Hash,
+    /// Each thread in turn.
+    RoundRobin,
+    /// By the CPU the packet arrived on.
+    Cpu,
+    /// Fill one thread's queue before moving on to the next.
+    Rollover,
+    /// A random thread.
+    Random,
+    /// By the network card's receive queue.
+    QueueMapping,
+}
+
+impl FanoutMode {
+    pub const ALL: [FanoutMode; 6] = [
+        FanoutMode::Hash,
+        FanoutMode::RoundRobin,
+        FanoutMode::Cpu,
+        FanoutMode::Rollover,
+        FanoutMode::Random,
+        FanoutMode::QueueMapping,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FanoutMode::Hash => "hash",
+            FanoutMode::RoundRobin => "round-robin",
+            FanoutMode::Cpu => "cpu",
+            FanoutMode::Rollover => "rollover",
+            FanoutMode::Random => "random",
+            FanoutMode::QueueMapping => "queue-mapping",
+        }
+    }
+
+    /// The option that puts a socket in fanout group `group`. Sockets join
+    /// the same group by giving the same id on the same interface.
+    #[cfg(target_os = "linux")]
+    pub fn option(self, group: u16) -> FanoutOption {
+        let fanout_type = match self {
+            FanoutMode::Hash => FanoutType::HASH,
+            FanoutMode::RoundRobin => FanoutType::LB,
+            FanoutMode::Cpu => FanoutType::CPU,
+            FanoutMode::Rollover => FanoutType::ROLLOVER,
+            FanoutMode::Random => FanoutType::RND,
+            FanoutMode::QueueMapping => FanoutType::QM,
+        };
+        FanoutOption {
+            group_id: group,
+            fanout_type,
+            defrag: self == FanoutMode::Hash,
+            rollover: false,
+        }
+    }
+}
+
+impl fmt::Display for FanoutMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for FanoutMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FanoutMode, String> {
+        FanoutMode::ALL
+            .into_iter()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| format!("unknown fanout mode '{}'", s))
+    }
+}
+
+/// Frames from a layer 3 channel, given back an Ethernet header so the rest
+/// of the pipeline can treat them like any other frame. The header has zero
+/// MAC addresses and the channel's EtherType.
+pub struct Layer3Capture {
+    inner: Box<dyn PacketSource>,
+    // The frame being handed out: the made-up header, then the packet.
+    buf: Vec<u8>,
+}
+
+// Destination MAC, source MAC and EtherType.
+const ETHERNET_HEADER_LEN: usize = 14;
+
+impl Layer3Capture {
+    pub fn new(inner: Box<dyn PacketSource>, ethertype: u16) -> Layer3Capture {
+        let mut buf = vec![0; ETHERNET_HEADER_LEN];
+        buf[12..].copy_from_slice(&ethertype.to_be_bytes());
+        Layer3Capture { inner, buf }
+    }
+}
+
+impl PacketSource for Layer3Capture {
+    fn next_frame(&mut self) -> io::Result<Option<Frame<'_>>> {
+        let Some(frame) = self.inner.next_frame()? else {
+            return Ok(None);
+        };
+        // Keep the header, replace the packet after it.
+        self.buf.truncate(ETHERNET_HEADER_LEN);
+        self.buf.extend_from_slice(frame.data);
+        let (len, timestamp) = (frame.len + ETHERNET_HEADER_LEN, frame.timestamp);
+        Ok(Some(Frame {
+            data: &self.buf,
+            len,
+            timestamp,
+        }))
+    }
+
+    fn kernel_stats(&mut self) -> Option<KernelStats> {
+        self.inner.kernel_stats()
+    }
+}
+
+/// What a receive error means for the capture.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Nothing has arrived yet: the read timeout ran out, or a signal
+    /// interrupted the wait. Not really an error.
+    Timeout,
+    /// Something that may pass, such as the kernel running short of buffer
+    /// space. Worth trying again after a pause.
+    Transient,
+    /// The interface went down or away. Its channel has to be opened again
+    /// once it's back.
+    InterfaceDown,
+    /// Retrying won't help, e.g. a corrupt capture file.
+    Fatal,
+}
+
+impl ErrorClass {
+    pub fn of(e: &io::Error) -> ErrorClass {
+        if matches!(
+            e.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ) {
+            return ErrorClass::Timeout;
+        }
+        match e.raw_os_error() {
+            Some(libc::ENETDOWN | libc::ENODEV | libc::ENXIO) => ErrorClass::InterfaceDown,
+            // A bug on our side, or a socket that is gone for good.
+            Some(libc::EBADF | libc::EFAULT | libc::EINVAL | libc::ENOTSOCK) => ErrorClass::Fatal,
+            Some(_) => ErrorClass::Transient,
+            // Not from the kernel at all: a capture file that doesn't parse.
+            None => ErrorClass::Fatal,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Transient => "transient",
+            ErrorClass::InterfaceDown => "interface_down",
+            ErrorClass::Fatal => "fatal",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Exponential backoff between retries: each delay is twice the last, up to
+/// a ceiling, until `reset`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    first: Duration,
+    max: Duration,
+    next: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(first: Duration, max: Duration) -> Backoff {
+        Backoff {
+            first,
+            max,
+            next: first,
+            attempts: 0,
+        }
+    }
+
+    /// The delay before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        self.attempts += 1;
+        delay
+    }
+
+    /// Delays handed out since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Start again from the first delay, after an attempt worked.
+    pub fn reset(&mut self) {
+        self.next = self.first;
+        self.attempts = 0;
+    }
+}
+
+/// Frames replayed from a pcap or pcapng file, using the recorded timestamps.
+pub struct FileCapture {
+    reader: PcapReader<BufReader<File>>,
+}
+
+impl FileCapture {
+    pub fn open(path: &Path) -> io::Result<FileCapture> {
+        let file = File::open(path)?;
+        let reader = PcapReader::new(BufReader::new(file))?;
+        Ok(FileCapture { reader })
+    }
+}
+
+impl PacketSource for FileCapture {
+    fn next_frame(&mut self) -> io::Result<Option<Frame<'_>>> {
+        match self.reader.next_packet()? {
+            Some(record) if record.linktype != LINKTYPE_ETHERNET => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unsupported link type {} (only Ethernet is supported)",
+                    record.linktype
+                ),
+            )),
+            Some(record) => Ok(Some(Frame {
+                data: record.data,
+                len: record.original_len,
+                timestamp: record.timestamp,
+            })),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The current time on the same scale as `Frame::timestamp`. The wall clock
+/// is only read the first time; after that the time is counted on the
+/// monotonic clock, so setting the clock while capturing (by hand, or NTP
+/// stepping it) can't stretch or cut short a window or a block.
+pub fn now() -> Duration {
+    // `OnceLock` is Go's `sync.Once` together with the value it computed.
+    static START: OnceLock<(Instant, Duration)> = OnceLock::new();
+    let (started, wall) = START.get_or_init(|| {
+        // Only fails if the clock is set before 1970.
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (Instant::now(), wall)
+    });
+    *wall + started.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::{AlgorithmKind, Limit};
+    use crate::limiter::{Limits, RateLimiter, Verdict};
+    use crate::source::Source;
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    // A capture file in the temp directory, removed when dropped.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let name = format!("packet_processor-{}-{name}.pcap", std::process::id());
+            TempFile(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Write a little-endian pcap with batches of (seconds after `start`,
+    // count) 60-byte frames, cut to 20 bytes as if by a small snaplen.
+    fn write_file(file: &TempFile, start: Duration, batches: &[(u64, usize)]) {
+        let mut out = 0xa1b2_c3d4u32.to_le_bytes().to_vec();
+        out.extend(2u16.to_le_bytes());
+        out.extend(4u16.to_le_bytes());
+        for value in [0u32, 0, 65535, u32::from(LINKTYPE_ETHERNET)] {
+            out.extend(value.to_le_bytes());
+        }
+        for &(seconds, count) in batches {
+            let timestamp = start + Duration::from_secs(seconds);
+            for _ in 0..count {
+                let (secs, micros) = (timestamp.as_secs() as u32, timestamp.subsec_micros());
+                for value in [secs, micros, 20, 60] {
+                    out.extend(value.to_le_bytes());
+                }
+                out.extend([0; 20]);
+            }
+        }
+        fs::write(&file.0, out).unwrap();
+    }
+
+    #[test]
+    fn file_frames_carry_capture_time_and_wire_length() {
+        let file = TempFile::new("frames");
+        let start = Duration::new(1_000_000_000, 250_000_000);
+        write_file(&file, start, &[(0, 1), (7, 1)]);
+
+        let mut capture = FileCapture::open(&file.0).unwrap();
+        let frame = capture.next_frame().unwrap().unwrap();
+        assert_eq!(
+            (frame.timestamp, frame.data.len(), frame.len),
+            (start, 20, 60)
+        );
+        let frame = capture.next_frame().unwrap().unwrap();
+        assert_eq!(frame.timestamp, start + Duration::from_secs(7));
+        assert!(capture.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn windows_follow_capture_time() {
+        // Years before the test runs, so a wall-clock window would lump
+        // every packet together and the second batch would be exceeded.
+        let file = TempFile::new("windows");
+        let start = Duration::from_secs(1_000_000_000);
+        write_file(&file, start, &[(0, 100), (15, 100), (30, 101)]);
+
+        let limits = Limits {
+            packets: Some(Limit {
+                amount: 100,
+                window: Duration::from_secs(10),
+            }),
+            bytes: None,
+        };
+        let limiter = RateLimiter::with_shards(AlgorithmKind::FixedWindow, limits, 1);
+        let source = Source::Ipv4(Ipv4Addr::new(192, 0, 2, 1));
+
+        let mut capture = FileCapture::open(&file.0).unwrap();
+        let mut verdicts = Vec::new();
+        while let Some(frame) = capture.next_frame().unwrap() {
+            verdicts.push(limiter.record(source, frame.timestamp, frame.len));
+        }
+        let (last, rest) = verdicts.split_last().unwrap();
+        assert_eq!(rest.len(), 300);
+        assert!(rest.iter().all(|verdict| *verdict == Verdict::Allow));
+        assert!(matches!(last, Verdict::Exceeded { used: 101, .. }));
+    }
+
+    #[test]
+    fn live_time_starts_at_the_wall_clock_and_never_goes_back() {
+        let first = now();
+        let wall = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        assert!(wall.abs_diff(first) < Duration::from_secs(60));
+        let mut last = first;
+        for _ in 0..1000 {
+            let next = now();
+            assert!(next >= last);
+            last = next;
+        }
+    }
+}
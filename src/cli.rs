@@ -0,0 +1,400 @@
+// Command-line parsing lives here so `main` only has to deal with an already
+// validated `Cli` value.
+// `clap`'s derive API turns these structs into a parser, similar to how Go's
+// `flag` package binds flags to struct fields, but with subcommands built in.
+use clap::builder::RangedU64ValueParser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use packet_processor::config::{AggregateConfig, PolicyConfig, parse_rate};
+use packet_processor::{AlgorithmKind, ChannelType, EventTarget, Filter, Prefix};
+
+/// Watch network interfaces and flag sources that send too many packets.
+#[derive(Debug, Parser)]
+#[command(name = "packet_processor", version, about)]
+pub struct Cli {
+    /// Increase output verbosity (-v logs an event for every packet, like
+    /// `--packet-sample 1`).
+    // `ArgAction::Count` turns `-vvv` into the number 3.
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// An `enum` with data in each variant, one per subcommand. Go has no direct
+// equivalent; you'd typically switch on `os.Args[1]` by hand.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List the network interfaces that can be captured on.
+    ListInterfaces,
+    /// Capture packets on one or more interfaces, or replay a capture file,
+    /// and apply the rate limit.
+    // Boxed because `RunArgs` is much bigger than the other variants.
+    Run(Box<RunArgs>),
+}
+
+// With `--config`, everything comes from the file, so the flags that the
+// file would override are rejected rather than silently ignored.
+#[derive(Debug, Args)]
+#[command(group = clap::ArgGroup::new("settings").multiple(true).conflicts_with("config"))]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub input: Input,
+
+    /// Length of the rate limiting window, in seconds. For the bucket
+    /// algorithms, the time it takes to refill or drain a full bucket.
+    #[arg(short, long, value_name = "SECONDS", default_value_t = 10, group = "settings",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub window: u64,
+
+    /// Number of packets a source may send per window before it is limited.
+    /// Defaults to 100 unless `--pps` or `--bps` is given.
+    #[arg(
+        short,
+        long,
+        value_name = "PACKETS",
+        conflicts_with = "pps",
+        group = "settings"
+    )]
+    pub threshold: Option<u64>,
+
+    /// Packet limit as a rate in packets per second (accepts k/M/G suffixes).
+    #[arg(long, value_name = "RATE", value_parser = parse_rate, group = "settings")]
+    pub pps: Option<u64>,
+
+    /// Byte limit as a rate in bits per second (accepts k/M/G suffixes, e.g.
+    /// 100M). Can be combined with a packet limit.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate, group = "settings")]
+    pub bps: Option<u64>,
+
+    /// Also limit whole prefixes, written as IPV4LEN/IPV6LEN:PACKETS: e.g.
+    /// `24/64:1000` allows each IPv4 /24 and IPv6 /64 1000 packets per
+    /// window in total. Can be repeated for more levels.
+    #[arg(long, value_name = "LEVEL", value_parser = parse_aggregate, group = "settings")]
+    pub aggregate: Vec<AggregateConfig>,
+
+    /// Also limit packets grouped by other fields, written as
+    /// KEY:PACKETS[:MATCH[:RESPOND]]. KEY joins `source`, `destination`,
+    /// `source-port`, `destination-port` and `protocol` with `+` (or is
+    /// `5-tuple`); MATCH only counts some packets, e.g. `syn`,
+    /// `echo-request`, `arp-request` or `udp+dport=53`; RESPOND answers
+    /// packets over the limit with any of `tcp-reset`, `icmp-prohibited` and
+    /// `arp-correction`, joined with `,` (layer 2 channels only). For example
+    /// `destination+destination-port:1000`, `source:50:syn` or
+    /// `source:50::tcp-reset`. Can be repeated.
+    #[arg(long, value_name = "POLICY", value_parser = parse_policy, group = "settings")]
+    pub limit_by: Vec<PolicyConfig>,
+
+    /// Rate limiting algorithm.
+    #[arg(short, long, value_enum, default_value_t = Algorithm::FixedWindow, group = "settings")]
+    pub algorithm: Algorithm,
+
+    /// Only process frames matching a tcpdump-style filter, e.g. `not port
+    /// 22` or `tcp and dst net 10.0.0.0/8`. On an interface the kernel
+    /// drops everything else before it reaches us.
+    #[arg(short, long, value_name = "EXPR", group = "settings")]
+    pub filter: Option<Filter>,
+
+    /// Size of the buffer each frame is read into, in bytes. Longer frames
+    /// are cut short.
+    #[arg(long, value_name = "BYTES", default_value_t = 4096, group = "settings",
+          value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub read_buffer_size: usize,
+
+    /// Size of the buffer frames are sent from, in bytes.
+    #[arg(long, value_name = "BYTES", default_value_t = 4096, group = "settings",
+          value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub write_buffer_size: usize,
+
+    /// Stop waiting for a frame after this many milliseconds and check in
+    /// (e.g. for Ctrl-C) before waiting again. 250 by default.
+    #[arg(long, value_name = "MS", group = "settings", value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_timeout: Option<u64>,
+
+    /// Only receive frames addressed to this host.
+    #[arg(long, group = "settings", conflicts_with = "bridge")]
+    pub no_promiscuous: bool,
+
+    /// Read whole Ethernet frames (`layer2`), or only packets of one
+    /// EtherType with the Ethernet header stripped, e.g. `layer3:ipv4`,
+    /// `layer3:ipv6`, `layer3:arp` or `layer3:0x88cc`.
+    #[arg(long, value_name = "TYPE", default_value_t = ChannelType::Layer2, group = "settings",
+          conflicts_with_all = ["read", "bridge"])]
+    pub channel: ChannelType,
+
+    /// Capture threads per interface. With more than one, the kernel
+    /// spreads each interface's packets over them (Linux `PACKET_FANOUT`).
+    #[arg(long, value_name = "THREADS", default_value_t = 1, group = "settings", conflicts_with = "read",
+          value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub fanout: usize,
+
+    /// How `--fanout` picks a thread for each packet.
+    #[arg(long, value_enum, default_value_t = FanoutMode::Hash, group = "settings")]
+    pub fanout_mode: FanoutMode,
+
+    /// Fanout group id for the first interface; the others take the ids
+    /// after it. Defaults to one taken from the process id.
+    #[arg(long, value_name = "ID", group = "settings")]
+    pub fanout_group: Option<u16>,
+
+    /// Give every interface its own counters, so a source is only limited by
+    /// what it sends through each interface rather than by its total.
+    #[arg(long, group = "settings")]
+    pub per_interface_limits: bool,
+
+    /// Never limit sources in this address or prefix (e.g. 10.0.0.0/8, or a
+    /// MAC prefix like 02:00:5e:00:00:00/24). Can be repeated.
+    #[arg(long, value_name = "PREFIX", value_delimiter = ',', group = "settings")]
+    pub allow: Vec<Prefix>,
+
+    /// Always block sources in this address or prefix. Can be repeated; the
+    /// allowlist wins where the two overlap.
+    #[arg(long, value_name = "PREFIX", value_delimiter = ',', group = "settings")]
+    pub deny: Vec<Prefix>,
+
+    /// Read more allowlist prefixes from a file, one per line (`#` starts a
+    /// comment).
+    #[arg(long, value_name = "FILE", group = "settings")]
+    pub allow_file: Vec<PathBuf>,
+
+    /// Read more denylist prefixes from a file, one per line.
+    #[arg(long, value_name = "FILE", group = "settings")]
+    pub deny_file: Vec<PathBuf>,
+
+    /// How sources over the limit are blocked.
+    #[arg(short, long, value_enum, default_value_t = EnforcerKind::Log, group = "settings")]
+    pub enforcer: EnforcerKind,
+
+    /// How long a source stays blocked once it exceeds the limit, in seconds.
+    #[arg(
+        short,
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        group = "settings"
+    )]
+    pub block_duration: u64,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics (e.g. 127.0.0.1:9100).
+    #[arg(short, long, value_name = "ADDR", group = "settings")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Where to write JSON events: `-` for stdout, a file path, or
+    /// `unix:PATH` for a Unix socket.
+    #[arg(long, value_name = "TARGET", default_value = "-", group = "settings")]
+    pub events: EventTarget,
+
+    /// Show a live dashboard of the loudest sources instead of printing
+    /// events to stdout; they're shown at the bottom of it. Works with
+    /// `--config` too.
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Log a `packet` event for one in every N packets (0 turns it off).
+    #[arg(long, value_name = "N", group = "settings")]
+    pub packet_sample: Option<u64>,
+
+    /// Save the frames of sources that go over a limit to pcapng files in
+    /// this directory.
+    #[arg(long, value_name = "DIR", group = "settings")]
+    pub evidence_dir: Option<PathBuf>,
+
+    /// Recent frames each capture thread keeps, so an offender's frames from
+    /// just before it went over are saved too.
+    #[arg(long, value_name = "FRAMES", default_value_t = 100, group = "settings")]
+    pub evidence_pre_trigger: usize,
+
+    /// Frames saved per offender after it goes over.
+    #[arg(
+        long,
+        value_name = "FRAMES",
+        default_value_t = 1000,
+        group = "settings"
+    )]
+    pub evidence_post_trigger: u32,
+
+    /// Start a new evidence file once the current one reaches this size.
+    #[arg(long, value_name = "MB", default_value_t = 100, group = "settings",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    pub evidence_max_file_mb: u64,
+
+    /// Delete the oldest evidence files past this many (0 keeps them all).
+    #[arg(long, value_name = "FILES", default_value_t = 10, group = "settings")]
+    pub evidence_max_files: usize,
+}
+
+// Mirrors `packet_processor::AlgorithmKind`, with help text for `--help`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    /// Count packets in back-to-back windows.
+    FixedWindow,
+    /// Count exactly the packets in the last window (more memory).
+    SlidingLog,
+    /// Approximate a sliding window from the last two fixed windows.
+    SlidingWindowCounter,
+    /// Allow bursts up to the threshold, refilled at threshold per window.
+    TokenBucket,
+    /// Drain at threshold per window, without a burst allowance.
+    LeakyBucket,
+}
+
+impl From<Algorithm> for AlgorithmKind {
+    fn from(algorithm: Algorithm) -> AlgorithmKind {
+        match algorithm {
+            Algorithm::FixedWindow => AlgorithmKind::FixedWindow,
+            Algorithm::SlidingLog => AlgorithmKind::SlidingLog,
+            Algorithm::SlidingWindowCounter => AlgorithmKind::SlidingWindowCounter,
+            Algorithm::TokenBucket => AlgorithmKind::TokenBucket,
+            Algorithm::LeakyBucket => AlgorithmKind::LeakyBucket,
+        }
+    }
+}
+
+// Mirrors `packet_processor::FanoutMode`, with help text for `--help`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum FanoutMode {
+    /// By flow, so each connection stays on one thread.
+    Hash,
+    /// Each thread in turn.
+    RoundRobin,
+    /// By the CPU the packet arrived on.
+    Cpu,
+    /// Fill one thread's queue before moving on to the next.
+    Rollover,
+    /// A random thread.
+    Random,
+    /// By the network card's receive queue.
+    QueueMapping,
+}
+
+impl From<FanoutMode> for packet_processor::FanoutMode {
+    fn from(mode: FanoutMode) -> packet_processor::FanoutMode {
+        match mode {
+            FanoutMode::Hash => packet_processor::FanoutMode::Hash,
+            FanoutMode::RoundRobin => packet_processor::FanoutMode::RoundRobin,
+            FanoutMode::Cpu => packet_processor::FanoutMode::Cpu,
+            FanoutMode::Rollover => packet_processor::FanoutMode::Rollover,
+            FanoutMode::Random => packet_processor::FanoutMode::Random,
+            FanoutMode::QueueMapping => packet_processor::FanoutMode::QueueMapping,
+        }
+    }
+}
+
+// `ValueEnum` lets clap parse `--enforcer iptables` straight into a variant.
+// Mirrors `packet_processor::EnforcerKind`, with help text for `--help`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EnforcerKind {
+    /// Only report blocks, never touch the firewall.
+    Log,
+    /// Insert DROP rules with iptables/ip6tables.
+    Iptables,
+}
+
+impl From<EnforcerKind> for packet_processor::EnforcerKind {
+    fn from(kind: EnforcerKind) -> packet_processor::EnforcerKind {
+        match kind {
+            EnforcerKind::Log => packet_processor::EnforcerKind::Log,
+            EnforcerKind::Iptables => packet_processor::EnforcerKind::Iptables,
+        }
+    }
+}
+
+/// Exactly one of `--interface`, `--index`, `--all-interfaces`, `--bridge`,
+/// `--read` or `--config` has to be given.
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct Input {
+    /// Name of the interface to capture on (e.g. eth0, en0). Repeat it, or
+    /// separate names with commas, to capture on several interfaces at once.
+    // `value_delimiter` splits `-i eth0,eth1` into two values, and a `Vec`
+    // collects every occurrence of the flag.
+    #[arg(short, long, value_name = "NAME", value_delimiter = ',')]
+    pub interface: Vec<String>,
+
+    /// Index of the interface, as shown by `list-interfaces`. Can be given
+    /// more than once, like `--interface`.
+    #[arg(long, value_name = "INDEX", value_delimiter = ',')]
+    pub index: Vec<u32>,
+
+    /// Capture on every interface that is up and not a loopback device.
+    #[arg(long)]
+    pub all_interfaces: bool,
+
+    /// Forward frames between two interfaces, written as A,B, dropping
+    /// those that go over a limit or come from a blocked source.
+    #[arg(long, value_name = "A,B", value_delimiter = ',')]
+    pub bridge: Vec<String>,
+
+    /// Read packets from a pcap or pcapng file instead of a live interface.
+    #[arg(short, long, value_name = "FILE")]
+    pub read: Option<PathBuf>,
+
+    /// Take every setting from a TOML config file instead of flags. The file
+    /// is reloaded when it changes or on SIGHUP.
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+// Parse an aggregate level like `24/64:1000`.
+fn parse_aggregate(s: &str) -> Result<AggregateConfig, String> {
+    let invalid = || format!("invalid level '{}', expected IPV4LEN/IPV6LEN:PACKETS", s);
+    let (lengths, threshold) = s.split_once(':').ok_or_else(invalid)?;
+    let (ipv4, ipv6) = lengths.split_once('/').ok_or_else(invalid)?;
+    let ipv4_prefix = ipv4
+        .parse()
+        .ok()
+        .filter(|&len| len <= 32)
+        .ok_or_else(invalid)?;
+    let ipv6_prefix = ipv6
+        .parse()
+        .ok()
+        .filter(|&len| len <= 128)
+        .ok_or_else(invalid)?;
+    let threshold = threshold.parse().map_err(|_| invalid())?;
+    Ok(AggregateConfig {
+        ipv4_prefix,
+        ipv6_prefix,
+        window: None,
+        threshold: Some(threshold),
+        pps: None,
+        bps: None,
+    })
+}
+
+// Parse a policy like `source+destination-port:500`, `source:50:syn` or
+// `source:50:syn:tcp-reset,icmp-prohibited`. An empty MATCH counts every
+// packet, for responses without one.
+fn parse_policy(s: &str) -> Result<PolicyConfig, String> {
+    // `splitn(4, ..)` stops after the fourth part, like Go's `strings.SplitN`.
+    let mut parts = s.splitn(4, ':');
+    let (Some(key), Some(threshold)) = (parts.next(), parts.next()) else {
+        return Err(format!(
+            "invalid policy '{}', expected KEY:PACKETS[:MATCH[:RESPOND]]",
+            s
+        ));
+    };
+    let threshold = threshold
+        .parse()
+        .map_err(|_| format!("invalid packet count in '{}'", s))?;
+    let matches = parts.next().filter(|matches| !matches.is_empty());
+    let respond = match parts.next() {
+        Some(respond) => respond
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+    Ok(PolicyConfig {
+        name: None,
+        key: key.parse()?,
+        matches: matches.map(str::parse).transpose()?.unwrap_or_default(),
+        window: None,
+        threshold: Some(threshold),
+        pps: None,
+        bps: None,
+        respond,
+    })
+}
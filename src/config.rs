@@ -0,0 +1,1027 @@
+// The run configuration, loaded from a TOML file or built from command-line
+// flags. A file can be reloaded while running (see `watch`), which applies
+// the limits, block duration, lists (re-reading any list files) and sampling
+// to the running capture and keeps every source's counters where the new
+// limits allow it.
+//
+// A complete file looks like:
+//
+//   [capture]
+//   interfaces = ["eth0", "eth1"]    # or `all_interfaces = true`, `bridge = ["eth0", "eth1"]`
+//                                    # or `read = "x.pcap"`
+//   per_interface_limits = false
+//   filter = "not port 22"           # tcpdump-style, run in the kernel
+//   read_buffer_size = 65536         # bytes; longer frames are cut short
+//   read_timeout_ms = 500
+//   promiscuous = true
+//   channel = "layer2"               # or e.g. "layer3:ipv4"
+//   fanout = 4                       # capture threads per interface
+//   fanout_mode = "hash"
+//
+//   [limit]
+//   window = 10
+//   pps = "5k"                       # or `threshold = 100` packets per window
+//   bps = "100M"
+//   algorithm = "token-bucket"
+//
+//   [[limit.aggregate]]             # repeat for more levels
+//   ipv4_prefix = 24
+//   ipv6_prefix = 64
+//   threshold = 1000                 # or pps/bps; `window` defaults to the one above
+//
+//   [[policy]]                       # repeat for more policies
+//   name = "dns"
+//   key = "destination+destination-port"
+//   pps = "2k"                       # or threshold/bps, like an aggregate
+//
+//   [[policy]]
+//   name = "syn-flood"
+//   key = "source"
+//   match = "syn"                    # only count TCP SYNs without ACK
+//   pps = 50
+//   respond = ["tcp-reset"]          # or "icmp-prohibited", "arp-correction"
+//
+//   [block]
+//   enforcer = "iptables"
+//   duration = 60
+//
+//   [lists]
+//   allow = ["10.0.0.5", "192.168.0.0/16", "02:00:5e:00:00:00/24"]
+//   deny = ["192.0.2.0/24", "2001:db8::/32"]
+//   deny_files = ["/etc/packet_processor/deny.txt"]
+//
+//   [output]
+//   events = "/var/log/packet_processor.jsonl"
+//   metrics = "127.0.0.1:9100"
+//   packet_sample = 0
+//
+//   [evidence]
+//   dir = "/var/lib/packet_processor/evidence"
+//   pre_trigger = 100                # recent frames kept per capture thread
+//   post_trigger = 1000              # frames saved per offender after it trips
+//   max_file_mb = 100
+//   max_files = 10
+//
+// Every section and key is optional; missing ones take the same defaults as
+// the command-line flags.
+use pnet::datalink;
+use serde::Deserialize;
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::algorithm::{AlgorithmKind, Limit};
+use crate::capture::{ChannelType, FanoutMode};
+use crate::enforce::EnforcerKind;
+use crate::error::{Error, Result};
+use crate::events::EventTarget;
+use crate::filter::Filter;
+use crate::flow::{KeySpec, Match};
+use crate::hierarchy::{Grouping, LevelLimits};
+use crate::limiter::Limits;
+use crate::lists::Prefix;
+use crate::policy::Policy;
+use crate::respond::Response;
+
+// `deny_unknown_fields` turns a typo like `treshold` into an error instead of
+// silently ignoring it; `default` fills in whatever the file leaves out.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub capture: CaptureConfig,
+    pub limit: LimitConfig,
+    /// Limits keyed on more than the source, written as `[[policy]]`.
+    #[serde(rename = "policy")]
+    pub policies: Vec<PolicyConfig>,
+    pub block: BlockConfig,
+    pub lists: ListConfig,
+    pub output: OutputConfig,
+    pub evidence: EvidenceConfig,
+}
+
+/// What to capture from, and how. Exactly one of the three inputs has to be
+/// set; the channel settings only apply to live interfaces.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Interfaces by name.
+    pub interfaces: Vec<String>,
+    /// Interfaces by index, as shown by `list-interfaces`.
+    pub indexes: Vec<u32>,
+    /// Every interface that is up and not a loopback device.
+    pub all_interfaces: bool,
+    /// Two interfaces to forward frames between (see `bridge`).
+    pub bridge: Vec<String>,
+    /// A pcap or pcapng file to replay.
+    pub read: Option<PathBuf>,
+    /// Give every interface its own counters.
+    pub per_interface_limits: bool,
+    /// Only look at frames matching this filter (see `filter::Filter`).
+    #[serde(deserialize_with = "from_str")]
+    pub filter: Filter,
+    /// Size of the buffer each frame is read into, in bytes. Longer frames
+    /// are cut short.
+    pub read_buffer_size: usize,
+    /// Size of the buffer frames are sent from, in bytes.
+    pub write_buffer_size: usize,
+    /// How long to wait for a frame before checking in, in milliseconds.
+    /// 250 by default, so a quiet interface still notices it's time to stop.
+    pub read_timeout_ms: Option<u64>,
+    /// Also receive frames addressed to other hosts.
+    pub promiscuous: bool,
+    #[serde(deserialize_with = "from_str")]
+    pub channel: ChannelType,
+    /// Capture threads per interface. With more than one, the kernel
+    /// spreads the interface's packets over them (`PACKET_FANOUT`).
+    pub fanout: usize,
+    #[serde(deserialize_with = "from_str")]
+    pub fanout_mode: FanoutMode,
+    /// Fanout group id of the first interface; the others take the ids
+    /// after it. Ids are shared by every program on the host, so this picks
+    /// another if the default, taken from the process id, clashes.
+    pub fanout_group: Option<u16>,
+}
+
+// pnet's own defaults, apart from the fanout.
+impl Default for CaptureConfig {
+    fn default() -> CaptureConfig {
+        CaptureConfig {
+            interfaces: Vec::new(),
+            indexes: Vec::new(),
+            all_interfaces: false,
+            bridge: Vec::new(),
+            read: None,
+            per_interface_limits: false,
+            filter: Filter::default(),
+            read_buffer_size: 4096,
+            write_buffer_size: 4096,
+            read_timeout_ms: None,
+            promiscuous: true,
+            channel: ChannelType::Layer2,
+            fanout: 1,
+            fanout_mode: FanoutMode::Hash,
+            fanout_group: None,
+        }
+    }
+}
+
+// How long a read waits for a frame when `read_timeout_ms` isn't set.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(250);
+
+impl CaptureConfig {
+    /// The channel settings for the `i`th interface captured on. `i` only
+    /// picks the fanout group, so it goes unused where there's no fanout.
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    pub fn datalink(&self, i: usize) -> datalink::Config {
+        datalink::Config {
+            read_buffer_size: self.read_buffer_size,
+            write_buffer_size: self.write_buffer_size,
+            read_timeout: Some(
+                self.read_timeout_ms
+                    .map_or(DEFAULT_READ_TIMEOUT, Duration::from_millis),
+            ),
+            channel_type: self.channel.datalink(),
+            #[cfg(target_os = "linux")]
+            linux_fanout: (self.fanout > 1).then(|| {
+                // Each interface needs its own group: the kernel won't let
+                // one group span interfaces.
+                let first = self.fanout_group.unwrap_or(std::process::id() as u16);
+                self.fanout_mode.option(first.wrapping_add(i as u16))
+            }),
+            // A bridge has to see the frames for every host behind it.
+            promiscuous: self.promiscuous || !self.bridge.is_empty(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitConfig {
+    /// Window length in seconds.
+    pub window: u64,
+    /// Packets per window.
+    pub threshold: Option<u64>,
+    /// Packets per second.
+    #[serde(deserialize_with = "rate")]
+    pub pps: Option<u64>,
+    /// Bits per second.
+    #[serde(deserialize_with = "rate")]
+    pub bps: Option<u64>,
+    #[serde(deserialize_with = "from_str")]
+    pub algorithm: AlgorithmKind,
+    /// Limits on whole prefixes, on top of the per-address limits above.
+    pub aggregate: Vec<AggregateConfig>,
+}
+
+impl Default for LimitConfig {
+    fn default() -> LimitConfig {
+        LimitConfig {
+            window: 10,
+            threshold: None,
+            pps: None,
+            bps: None,
+            algorithm: AlgorithmKind::FixedWindow,
+            aggregate: Vec::new(),
+        }
+    }
+}
+
+impl LimitConfig {
+    /// The per-address limits. Without any limit settings, keep the
+    /// historical 100 packets per window.
+    pub fn limits(&self) -> Limits {
+        let packets = match (self.threshold, self.pps, self.bps) {
+            (None, None, None) => Some(100),
+            _ => self.threshold,
+        };
+        limits(self.window, packets, self.pps, self.bps)
+    }
+
+    /// Every level's limits: the per-address level first, then the
+    /// aggregates.
+    pub fn levels(&self) -> Vec<LevelLimits> {
+        let address = LevelLimits {
+            grouping: Grouping::ADDRESS,
+            limits: self.limits(),
+        };
+        let aggregates = self.aggregate.iter().map(|aggregate| LevelLimits {
+            grouping: aggregate.grouping(),
+            limits: limits(
+                aggregate.window.unwrap_or(self.window),
+                aggregate.threshold,
+                aggregate.pps,
+                aggregate.bps,
+            ),
+        });
+        // `once` + `chain` builds "this one, then all of those", like
+        // `append([]T{first}, rest...)` in Go.
+        iter::once(address).chain(aggregates).collect()
+    }
+}
+
+/// One prefix-level limit: sources are grouped by these prefix lengths, and
+/// each group as a whole is held to the limits.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateConfig {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Window length in seconds; the per-address window if not set.
+    #[serde(default)]
+    pub window: Option<u64>,
+    #[serde(default)]
+    pub threshold: Option<u64>,
+    #[serde(default, deserialize_with = "rate")]
+    pub pps: Option<u64>,
+    #[serde(default, deserialize_with = "rate")]
+    pub bps: Option<u64>,
+}
+
+impl AggregateConfig {
+    pub fn grouping(&self) -> Grouping {
+        Grouping {
+            ipv4: self.ipv4_prefix,
+            ipv6: self.ipv6_prefix,
+        }
+    }
+}
+
+/// A limit on packets grouped by a key other than the source address alone,
+/// e.g. on everything sent to one destination port.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Defaults to the key, e.g. `destination+destination-port`.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(deserialize_with = "from_str")]
+    pub key: KeySpec,
+    /// Only count packets like these, e.g. `syn` or `udp+dport=53`.
+    #[serde(default, rename = "match", deserialize_with = "from_str")]
+    pub matches: Match,
+    /// Window length in seconds; the per-address window if not set.
+    #[serde(default)]
+    pub window: Option<u64>,
+    #[serde(default)]
+    pub threshold: Option<u64>,
+    #[serde(default, deserialize_with = "rate")]
+    pub pps: Option<u64>,
+    #[serde(default, deserialize_with = "rate")]
+    pub bps: Option<u64>,
+    /// Answer packets over the limit, e.g. with `tcp-reset` (see
+    /// `respond::Response`). Only on a layer 2 channel.
+    #[serde(default, deserialize_with = "from_str_seq")]
+    pub respond: Vec<Response>,
+}
+
+impl PolicyConfig {
+    /// The name, or one made up from the key and match, e.g. `syn by
+    /// source`.
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None if self.matches.is_empty() => self.key.to_string(),
+            None => format!("{} by {}", self.matches, self.key),
+        }
+    }
+}
+
+// Turn limit settings into per-window amounts. Rates are per second, so they
+// are scaled up to the window length; bits become bytes, rounded up so a
+// rate below 8 bit/s isn't a limit of nothing.
+fn limits(window: u64, threshold: Option<u64>, pps: Option<u64>, bps: Option<u64>) -> Limits {
+    let packets = threshold.or(pps.map(|pps| pps.saturating_mul(window)));
+    let bytes = bps.map(|bps| bps.saturating_mul(window).div_ceil(8));
+    let window = Duration::from_secs(window);
+
+    // `Option::map` applies the closure only to `Some`, like an `if x != nil`.
+    Limits {
+        packets: packets.map(|amount| Limit { amount, window }),
+        bytes: bytes.map(|amount| Limit { amount, window }),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlockConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub enforcer: EnforcerKind,
+    /// How long a source stays blocked, in seconds.
+    pub duration: u64,
+}
+
+impl Default for BlockConfig {
+    fn default() -> BlockConfig {
+        BlockConfig {
+            enforcer: EnforcerKind::Log,
+            duration: 60,
+        }
+    }
+}
+
+/// Sources that are never limited, and sources that are always blocked, as
+/// addresses or prefixes (see `lists::Prefix`) and as files listing more.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListConfig {
+    #[serde(deserialize_with = "from_str_seq")]
+    pub allow: Vec<Prefix>,
+    #[serde(deserialize_with = "from_str_seq")]
+    pub deny: Vec<Prefix>,
+    pub allow_files: Vec<PathBuf>,
+    pub deny_files: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    #[serde(deserialize_with = "from_str")]
+    pub events: EventTarget,
+    pub metrics: Option<SocketAddr>,
+    /// Log one `packet` event per this many packets; 0 turns them off.
+    pub packet_sample: u64,
+}
+
+impl Default for OutputConfig {
+    fn default() -> OutputConfig {
+        OutputConfig {
+            events: EventTarget::Stdout,
+            metrics: None,
+            packet_sample: 0,
+        }
+    }
+}
+
+/// Saving the frames of sources that go over a limit (see `evidence`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvidenceConfig {
+    /// Directory to write pcapng files to. Nothing is saved without one.
+    pub dir: Option<PathBuf>,
+    /// Recent frames each capture thread keeps, so an offender's frames
+    /// from before it tripped a limit can be saved too.
+    pub pre_trigger: usize,
+    /// Frames saved per offender after it trips a limit.
+    pub post_trigger: u32,
+    /// Start a new file once the current one reaches this size, in
+    /// megabytes.
+    pub max_file_mb: u64,
+    /// Delete the oldest files past this many; 0 keeps them all.
+    pub max_files: usize,
+}
+
+impl Default for EvidenceConfig {
+    fn default() -> EvidenceConfig {
+        EvidenceConfig {
+            dir: None,
+            pre_trigger: 100,
+            post_trigger: 1000,
+            max_file_mb: 100,
+            max_files: 10,
+        }
+    }
+}
+
+impl Config {
+    /// Read and check a config file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let error = |message: String| Error::Config {
+            path: path.to_path_buf(),
+            message,
+        };
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        let config: Config = toml::from_str(&text).map_err(|e| error(e.to_string()))?;
+        config.check().map_err(error)?;
+        Ok(config)
+    }
+
+    /// Every policy, with its limits worked out. Policies share the
+    /// per-address window and algorithm unless they set their own window.
+    pub fn policies(&self) -> Vec<Policy> {
+        self.policies
+            .iter()
+            .map(|policy| Policy {
+                name: policy.name(),
+                key: policy.key,
+                matches: policy.matches.clone(),
+                limits: limits(
+                    policy.window.unwrap_or(self.limit.window),
+                    policy.threshold,
+                    policy.pps,
+                    policy.bps,
+                ),
+                responses: policy.respond.clone(),
+            })
+            .collect()
+    }
+
+    /// Check the rules the file format alone can't express.
+    pub fn check(&self) -> std::result::Result<(), String> {
+        let capture = &self.capture;
+        let inputs = [
+            !capture.interfaces.is_empty() || !capture.indexes.is_empty(),
+            capture.all_interfaces,
+            !capture.bridge.is_empty(),
+            capture.read.is_some(),
+        ];
+        match inputs.iter().filter(|&&set| set).count() {
+            0 => {
+                return Err(
+                    "no input: set `interfaces`, `all_interfaces`, `bridge` or `read`".to_string(),
+                );
+            }
+            1 => {}
+            _ => {
+                return Err(
+                    "`interfaces`, `all_interfaces`, `bridge` and `read` can't be combined"
+                        .to_string(),
+                );
+            }
+        }
+        if !capture.bridge.is_empty() {
+            if capture.bridge.len() != 2 || capture.bridge[0] == capture.bridge[1] {
+                return Err("`bridge` takes two different interfaces".to_string());
+            }
+            if capture.channel != ChannelType::Layer2 {
+                return Err("`bridge` only works with the layer 2 channel".to_string());
+            }
+        }
+        if capture.read.is_some() && (capture.fanout > 1 || capture.channel != ChannelType::Layer2)
+        {
+            return Err("`fanout` and `channel` only apply to interfaces, not `read`".to_string());
+        }
+        if capture.fanout == 0 {
+            return Err("`fanout` must be at least 1 thread".to_string());
+        }
+        // Without `PACKET_FANOUT` every thread would see every frame, and
+        // without `PACKET_IGNORE_OUTGOING` a bridge would read back what it
+        // forwards.
+        if !cfg!(target_os = "linux") && (capture.fanout > 1 || !capture.bridge.is_empty()) {
+            return Err("`fanout` and `bridge` need Linux".to_string());
+        }
+        if capture.read_buffer_size == 0 || capture.write_buffer_size == 0 {
+            return Err("buffer sizes must be at least 1 byte".to_string());
+        }
+        // A zero timeout would have capture threads spin on an idle
+        // interface.
+        if capture.read_timeout_ms == Some(0) {
+            return Err("`read_timeout_ms` must be at least 1".to_string());
+        }
+        if self.evidence.max_file_mb == 0 {
+            return Err("`max_file_mb` must be at least 1".to_string());
+        }
+        if self.limit.window == 0 {
+            return Err("`window` must be at least 1 second".to_string());
+        }
+        if self.limit.threshold.is_some() && self.limit.pps.is_some() {
+            return Err("`threshold` and `pps` can't both be set".to_string());
+        }
+        for aggregate in &self.limit.aggregate {
+            let name = format!(
+                "aggregate /{} /{}",
+                aggregate.ipv4_prefix, aggregate.ipv6_prefix
+            );
+            if aggregate.ipv4_prefix > 32 || aggregate.ipv6_prefix > 128 {
+                return Err(format!(
+                    "{}: prefix lengths are at most 32 for IPv4 and 128 for IPv6",
+                    name
+                ));
+            }
+            if aggregate.window == Some(0) {
+                return Err(format!("{}: `window` must be at least 1 second", name));
+            }
+            match (aggregate.threshold, aggregate.pps, aggregate.bps) {
+                (None, None, None) => {
+                    return Err(format!("{}: set `threshold`, `pps` or `bps`", name));
+                }
+                (Some(_), Some(_), _) => {
+                    return Err(format!("{}: `threshold` and `pps` can't both be set", name));
+                }
+                _ => {}
+            }
+        }
+        for (i, policy) in self.policies.iter().enumerate() {
+            let name = format!("policy '{}'", policy.name());
+            // Names tell policies apart in events and on reload.
+            if self.policies[..i]
+                .iter()
+                .any(|other| other.name() == policy.name())
+            {
+                return Err(format!(
+                    "{}: defined twice with the same key and match; give one a different `name`",
+                    name
+                ));
+            }
+            if policy.window == Some(0) {
+                return Err(format!("{}: `window` must be at least 1 second", name));
+            }
+            match (policy.threshold, policy.pps, policy.bps) {
+                (None, None, None) => {
+                    return Err(format!("{}: set `threshold`, `pps` or `bps`", name));
+                }
+                (Some(_), Some(_), _) => {
+                    return Err(format!("{}: `threshold` and `pps` can't both be set", name));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings that differ from `new` but can't be changed while
+    /// running, by name. Reloading keeps the old values for these.
+    pub fn needs_restart(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.capture != new.capture {
+            changed.push("capture");
+        }
+        // A changed limit is applied, but a new or removed level isn't.
+        let groupings = |config: &Config| -> Vec<Grouping> {
+            config
+                .limit
+                .aggregate
+                .iter()
+                .map(AggregateConfig::grouping)
+                .collect()
+        };
+        if groupings(self) != groupings(new) {
+            changed.push("limit.aggregate");
+        }
+        // Likewise for policies, which are matched up by name, key and match,
+        // and keep the responses they started with.
+        let policies = |config: &Config| -> Vec<(String, KeySpec, Match, Vec<Response>)> {
+            let policies = config.policies.iter();
+            policies
+                .map(|policy| {
+                    (
+                        policy.name(),
+                        policy.key,
+                        policy.matches.clone(),
+                        policy.respond.clone(),
+                    )
+                })
+                .collect()
+        };
+        if policies(self) != policies(new) {
+            changed.push("policy");
+        }
+        if self.block.enforcer != new.block.enforcer {
+            changed.push("block.enforcer");
+        }
+        if self.output.events != new.output.events {
+            changed.push("output.events");
+        }
+        if self.output.metrics != new.output.metrics {
+            changed.push("output.metrics");
+        }
+        if self.evidence != new.evidence {
+            changed.push("evidence");
+        }
+        changed
+    }
+
+    /// What's running after reloading `new`, where `ignored` is what
+    /// `needs_restart` found: the new reloadable settings, and the old values
+    /// of everything that needs a restart. The limiters keep the levels and
+    /// policies they started with, only changing their limits, so a new or
+    /// removed one is still ignored on the next reload.
+    pub fn reloaded(&self, new: Config, ignored: &[&str]) -> Config {
+        let aggregate = match ignored.contains(&"limit.aggregate") {
+            true => self.limit.aggregate.clone(),
+            false => new.limit.aggregate,
+        };
+        let policies = match ignored.contains(&"policy") {
+            true => self.policies.clone(),
+            false => new.policies,
+        };
+        Config {
+            capture: self.capture.clone(),
+            limit: LimitConfig {
+                aggregate,
+                ..new.limit
+            },
+            policies,
+            block: BlockConfig {
+                enforcer: self.block.enforcer,
+                ..new.block
+            },
+            output: OutputConfig {
+                packet_sample: new.output.packet_sample,
+                ..self.output.clone()
+            },
+            evidence: self.evidence.clone(),
+            ..new
+        }
+    }
+}
+
+/// Parse a rate like `500`, `10k`, `100M` or `1G` (SI prefixes, so 1k = 1000).
+pub fn parse_rate(s: &str) -> std::result::Result<u64, String> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k' | 'K') => (&s[..s.len() - 1], 1_000),
+        Some('m' | 'M') => (&s[..s.len() - 1], 1_000_000),
+        Some('g' | 'G') => (&s[..s.len() - 1], 1_000_000_000),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid rate '{}'", s))?;
+    value
+        .checked_mul(multiplier)
+        .filter(|&rate| rate > 0)
+        .ok_or_else(|| format!("rate '{}' must be between 1 and {}", s, u64::MAX))
+}
+
+// serde's `deserialize_with` hooks. Each is called with the raw value for one
+// field and has to produce the field's type.
+
+// Any type with a `FromStr`, written as a string.
+fn from_str<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
+}
+
+// A list of strings, each parsed with `FromStr`.
+fn from_str_seq<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+// A rate, either as a plain number (`pps = 5000`) or with a suffix
+// (`pps = "5k"`). A `Visitor` is serde's way of accepting more than one kind
+// of value for a field.
+fn rate<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<u64>, D::Error> {
+    struct RateVisitor;
+
+    impl Visitor<'_> for RateVisitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a rate such as 5000 or \"5k\"")
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> std::result::Result<u64, E> {
+            u64::try_from(value)
+                .ok()
+                .filter(|&rate| rate > 0)
+                .ok_or_else(|| E::custom(format!("rate {} must be positive", value)))
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> std::result::Result<u64, E> {
+            parse_rate(value).map_err(E::custom)
+        }
+    }
+
+    deserializer.deserialize_any(RateVisitor).map(Some)
+}
+
+/// Call `on_change` whenever `path` is modified or the process gets SIGHUP,
+/// from a background thread that checks once per `interval`.
+///
+/// The file's modification time is polled rather than watched with inotify,
+/// which also notices an editor replacing the file instead of writing to it.
+pub fn watch<F>(path: PathBuf, interval: Duration, mut on_change: F) -> io::Result<JoinHandle<()>>
+where
+    F: FnMut() + Send + 'static,
+{
+    // The signal handler only sets this flag; the real work happens on the
+    // thread below, where it is safe to allocate and take locks.
+    let hangup = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&hangup))?;
+
+    let modified = |path: &Path| -> Option<SystemTime> { fs::metadata(path).ok()?.modified().ok() };
+    let mut last = modified(&path);
+    Ok(thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+            let current = modified(&path);
+            // `swap` clears the flag and tells us whether it was set, in one
+            // step, so a SIGHUP arriving meanwhile isn't lost.
+            if hangup.swap(false, Ordering::Relaxed) || current != last {
+                last = current;
+                on_change();
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const BASE: &str = r#"
+        [capture]
+        interfaces = ["eth0"]
+
+        [limit]
+        window = 10
+        pps = "1k"
+
+        [[limit.aggregate]]
+        ipv4_prefix = 24
+        ipv6_prefix = 64
+        threshold = 5000
+
+        [[policy]]
+        name = "dns"
+        key = "destination+destination-port"
+        match = "udp+dport=53"
+        pps = 200
+
+        [block]
+        duration = 60
+
+        [output]
+        packet_sample = 10
+    "#;
+
+    fn parse(text: &str) -> Config {
+        let config: Config = toml::from_str(text).unwrap();
+        config.check().unwrap();
+        config
+    }
+
+    // `BASE` with the first `from` replaced by `to`.
+    fn changed(from: &str, to: &str) -> Config {
+        assert!(BASE.contains(from), "{}", from);
+        parse(&BASE.replacen(from, to, 1))
+    }
+
+    fn check_error(text: &str) -> String {
+        let config: Config = toml::from_str(text).unwrap();
+        config.check().unwrap_err()
+    }
+
+    #[test]
+    fn reloadable_changes_need_no_restart() {
+        let current = parse(BASE);
+        for new in [
+            changed("pps = \"1k\"", "pps = \"2k\""),
+            changed("threshold = 5000", "threshold = 9000"),
+            changed("pps = 200", "pps = 500"),
+            changed("duration = 60", "duration = 600"),
+            changed("packet_sample = 10", "packet_sample = 0"),
+            changed("[block]", "[lists]\ndeny = [\"192.0.2.0/24\"]\n[block]"),
+        ] {
+            assert_eq!(current.needs_restart(&new), Vec::<&str>::new());
+        }
+    }
+
+    #[test]
+    fn flags_what_needs_a_restart() {
+        let current = parse(BASE);
+        let cases = [
+            (changed("\"eth0\"", "\"eth1\""), "capture"),
+            (
+                changed("ipv4_prefix = 24", "ipv4_prefix = 16"),
+                "limit.aggregate",
+            ),
+            (changed("name = \"dns\"", "name = \"resolver\""), "policy"),
+            (changed("udp+dport=53", "udp+dport=5353"), "policy"),
+            (
+                changed("pps = 200", "pps = 200\nrespond = [\"icmp-prohibited\"]"),
+                "policy",
+            ),
+            (
+                changed("duration = 60", "duration = 60\nenforcer = \"iptables\""),
+                "block.enforcer",
+            ),
+            (
+                changed("[output]", "[output]\nevents = \"stderr\""),
+                "output.events",
+            ),
+            (
+                changed("[output]", "[output]\nmetrics = \"127.0.0.1:9100\""),
+                "output.metrics",
+            ),
+            (
+                changed("[block]", "[evidence]\nmax_files = 3\n[block]"),
+                "evidence",
+            ),
+        ];
+        for (new, flagged) in cases {
+            assert_eq!(current.needs_restart(&new), [flagged]);
+        }
+    }
+
+    #[test]
+    fn reloading_keeps_what_needs_a_restart() {
+        let current = parse(BASE);
+        let text = BASE
+            .replacen("\"eth0\"", "\"eth1\"", 1)
+            .replacen("pps = \"1k\"", "pps = \"2k\"", 1)
+            .replacen("ipv4_prefix = 24", "ipv4_prefix = 16", 1)
+            .replacen("name = \"dns\"", "name = \"resolver\"", 1)
+            .replacen(
+                "duration = 60",
+                "duration = 600\nenforcer = \"iptables\"",
+                1,
+            )
+            .replacen(
+                "packet_sample = 10",
+                "packet_sample = 0\nevents = \"stderr\"",
+                1,
+            );
+        let new = parse(&text);
+        let ignored = current.needs_restart(&new);
+        let running = current.reloaded(new.clone(), &ignored);
+
+        assert_eq!(running.capture, current.capture);
+        assert_eq!(running.limit.pps, Some(2000));
+        // The level and the policy weren't swapped, so the old ones stay,
+        // and the next reload still sees the change.
+        assert_eq!(running.limit.aggregate, current.limit.aggregate);
+        assert_eq!(running.policies, current.policies);
+        assert_eq!(running.block.duration, 600);
+        assert_eq!(running.block.enforcer, EnforcerKind::Log);
+        assert_eq!(running.output.events, EventTarget::Stdout);
+        assert_eq!(running.output.packet_sample, 0);
+        assert_eq!(running.needs_restart(&new), ignored);
+    }
+
+    #[test]
+    fn reloading_takes_changed_limits_on_kept_levels_and_policies() {
+        let current = parse(BASE);
+        let new = changed("threshold = 5000", "threshold = 9000");
+        let new = Config {
+            policies: changed("pps = 200", "pps = 500").policies,
+            ..new
+        };
+        let running = current.reloaded(new.clone(), &current.needs_restart(&new));
+        assert_eq!(running, new);
+    }
+
+    #[test]
+    fn checks_bounds() {
+        let cases = [
+            ("", "no input"),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\nread = \"x.pcap\"",
+                "can't be combined",
+            ),
+            (
+                "[capture]\nbridge = [\"eth0\", \"eth0\"]",
+                "two different interfaces",
+            ),
+            (
+                "[capture]\nread = \"x.pcap\"\nfanout = 2",
+                "only apply to interfaces",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\nfanout = 0",
+                "at least 1 thread",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\nread_buffer_size = 0",
+                "at least 1 byte",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\nread_timeout_ms = 0",
+                "`read_timeout_ms`",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[limit]\nwindow = 0",
+                "`window`",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[limit]\nthreshold = 5\npps = 5",
+                "can't both be set",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[[limit.aggregate]]\nipv4_prefix = 33\nipv6_prefix = 64\nthreshold = 5",
+                "at most 32",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[[limit.aggregate]]\nipv4_prefix = 24\nipv6_prefix = 64",
+                "set `threshold`, `pps` or `bps`",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[[policy]]\nkey = \"source\"\npps = 5\n[[policy]]\nkey = \"source\"\npps = 9",
+                "defined twice",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[[policy]]\nkey = \"source\"\nwindow = 0\npps = 5",
+                "`window`",
+            ),
+            (
+                "[capture]\ninterfaces = [\"eth0\"]\n[evidence]\nmax_file_mb = 0",
+                "`max_file_mb`",
+            ),
+        ];
+        for (text, error) in cases {
+            let message = check_error(text);
+            assert!(message.contains(error), "{:?}: {}", text, message);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_malformed_settings() {
+        let errors = [
+            "[limit]\ntreshold = 5",
+            "[limit]\npps = \"5x\"",
+            "[limit]\npps = 0",
+            "[limit]\nalgorithm = \"fastest\"",
+            "[[policy]]\nkey = \"source+colour\"\npps = 5",
+            "[lists]\ndeny = [\"10.0.0.0/33\"]",
+        ];
+        for text in errors {
+            assert!(toml::from_str::<Config>(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate("10k"), Ok(10_000));
+        assert_eq!(parse_rate("100M"), Ok(100_000_000));
+        assert_eq!(parse_rate("1g"), Ok(1_000_000_000));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("k").is_err());
+        assert!(parse_rate("20000000000G").is_err());
+    }
+
+    #[test]
+    fn watch_calls_back_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!(
+            "packet_processor-{}-watch.toml",
+            std::process::id()
+        ));
+        fs::write(&path, BASE).unwrap();
+        let (tx, rx) = mpsc::channel();
+        // The thread runs for the rest of the test process.
+        watch(path.clone(), Duration::from_millis(10), move || {
+            let _ = tx.send(());
+        })
+        .unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let called = rx.recv_timeout(Duration::from_secs(5));
+        let _ = fs::remove_file(&path);
+        assert!(called.is_ok());
+    }
+}
@@ -0,0 +1,691 @@
+// Packet dissection: one pass over a frame's headers, from Ethernet down to
+// the transport layer, into a small `Summary` that everything else reads.
+//
+//   Ethernet
+//     802.1Q / QinQ tags (up to two)
+//       ARP
+//       IPv4 (with options, and fragments)       \
+//       IPv6 (through its extension headers)      } TCP, UDP, ICMP, ICMPv6
+//
+// Each layer is parsed with pnet's zero-copy packet views, which borrow the
+// frame rather than copying it, and the summary only holds fixed-size fields,
+// so dissecting a frame never allocates. Anything truncated or not understood
+// simply ends the dissection at the layer before it.
+use pnet::datalink::MacAddr;
+use pnet::packet::Packet;
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet};
+use pnet::packet::ipv6::{ExtensionPacket, FragmentPacket, Ipv6Packet};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::vlan::VlanPacket;
+use serde::Serialize;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::events::display;
+use crate::flow::Flow;
+use crate::protocol::Protocol;
+use crate::source::Source;
+
+/// What one frame contains, outermost header first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    #[serde(serialize_with = "display")]
+    pub source_mac: MacAddr,
+    #[serde(serialize_with = "display")]
+    pub destination_mac: MacAddr,
+    #[serde(skip_serializing_if = "Vlans::is_empty")]
+    pub vlans: Vlans,
+    /// The EtherType after any VLAN tags, e.g. 0x0800 for IPv4.
+    pub ethertype: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+}
+
+/// The VLAN IDs a frame is tagged with, outermost first: one for 802.1Q,
+/// two for QinQ. A fixed array plus a length, so it stays `Copy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Vlans {
+    ids: [u16; Vlans::MAX],
+    len: u8,
+}
+
+impl Vlans {
+    /// Frames with more tags than this aren't dissected past the tags.
+    pub const MAX: usize = 2;
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.ids[..usize::from(self.len)]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The network layer.
+// `tag = "type"` writes the variant as a field, e.g. `{"type":"ipv4",...}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Network {
+    Ipv4 {
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
+        ttl: u8,
+        /// The IP protocol number of the payload.
+        protocol: u8,
+        /// Bytes of IP options after the fixed 20-byte header.
+        options: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fragment: Option<Fragment>,
+    },
+    Ipv6 {
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        hop_limit: u8,
+        /// The protocol after the last extension header.
+        protocol: u8,
+        /// How many extension headers were skipped to get there.
+        extension_headers: u8,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fragment: Option<Fragment>,
+    },
+    /// ARP for IPv4 over Ethernet, the only kind in practical use.
+    Arp {
+        operation: ArpOperation,
+        #[serde(serialize_with = "display")]
+        sender_mac: MacAddr,
+        sender_ip: Ipv4Addr,
+        target_ip: Ipv4Addr,
+    },
+}
+
+/// Where a fragment of a larger IP packet goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Fragment {
+    /// Offset of this fragment's data, in bytes. Only the first fragment
+    /// (offset 0) carries the transport header.
+    pub offset: u16,
+    /// Whether more fragments follow.
+    pub more: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArpOperation {
+    Request,
+    Reply,
+    Other(u16),
+}
+
+/// The transport layer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "protocol", rename_all = "snake_case")]
+pub enum Transport {
+    Tcp {
+        source_port: u16,
+        destination_port: u16,
+        flags: TcpFlags,
+    },
+    Udp {
+        source_port: u16,
+        destination_port: u16,
+    },
+    Icmp {
+        icmp_type: u8,
+        code: u8,
+    },
+    Icmpv6 {
+        icmp_type: u8,
+        code: u8,
+    },
+}
+
+/// The control bits of a TCP header, as a bit set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TcpFlags(pub u8);
+
+impl TcpFlags {
+    pub const FIN: TcpFlags = TcpFlags(0x01);
+    pub const SYN: TcpFlags = TcpFlags(0x02);
+    pub const RST: TcpFlags = TcpFlags(0x04);
+    pub const PSH: TcpFlags = TcpFlags(0x08);
+    pub const ACK: TcpFlags = TcpFlags(0x10);
+    pub const URG: TcpFlags = TcpFlags(0x20);
+    pub const ECE: TcpFlags = TcpFlags(0x40);
+    pub const CWR: TcpFlags = TcpFlags(0x80);
+
+    /// Every flag with its lower-case name, in bit order.
+    pub const NAMES: [(TcpFlags, &'static str); 8] = [
+        (TcpFlags::FIN, "fin"),
+        (TcpFlags::SYN, "syn"),
+        (TcpFlags::RST, "rst"),
+        (TcpFlags::PSH, "psh"),
+        (TcpFlags::ACK, "ack"),
+        (TcpFlags::URG, "urg"),
+        (TcpFlags::ECE, "ece"),
+        (TcpFlags::CWR, "cwr"),
+    ];
+
+    /// Whether every flag in `other` is set.
+    pub fn contains(self, other: TcpFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Summary {
+    pub fn of(ethernet: &EthernetPacket) -> Summary {
+        let mut summary = Summary {
+            source_mac: ethernet.get_source(),
+            destination_mac: ethernet.get_destination(),
+            vlans: Vlans::default(),
+            ethertype: ethernet.get_ethertype().0,
+            network: None,
+            transport: None,
+        };
+
+        // Peel off VLAN tags. 0x8100 is an 802.1Q tag; QinQ outer tags use
+        // 0x88a8, or 0x9100 on older gear.
+        let mut ethertype = ethernet.get_ethertype();
+        let mut payload = ethernet.payload();
+        while matches!(
+            ethertype,
+            EtherTypes::Vlan | EtherTypes::PBridge | EtherTypes::QinQ
+        ) {
+            let Some(vlan) = VlanPacket::new(payload) else {
+                return summary;
+            };
+            let vlans = &mut summary.vlans;
+            if usize::from(vlans.len) == Vlans::MAX {
+                return summary;
+            }
+            vlans.ids[usize::from(vlans.len)] = vlan.get_vlan_identifier();
+            vlans.len += 1;
+            ethertype = vlan.get_ethertype();
+            // Slice past the 4-byte tag ourselves: `vlan.payload()` would
+            // borrow from `vlan`, which doesn't outlive this iteration.
+            payload = &payload[VlanPacket::minimum_packet_size()..];
+        }
+        summary.ethertype = ethertype.0;
+
+        match ethertype {
+            EtherTypes::Arp => summary.network = arp(payload),
+            EtherTypes::Ipv4 => summary.ipv4(payload),
+            EtherTypes::Ipv6 => summary.ipv6(payload),
+            _ => {}
+        }
+        summary
+    }
+
+    fn ipv4(&mut self, payload: &[u8]) {
+        let Some(ipv4) = Ipv4Packet::new(payload) else {
+            return;
+        };
+        let offset = ipv4.get_fragment_offset() * 8;
+        let more = ipv4.get_flags() & Ipv4Flags::MoreFragments != 0;
+        let fragment = (offset != 0 || more).then_some(Fragment { offset, more });
+        let protocol = ipv4.get_next_level_protocol();
+        self.network = Some(Network::Ipv4 {
+            source: ipv4.get_source(),
+            destination: ipv4.get_destination(),
+            ttl: ipv4.get_ttl(),
+            protocol: protocol.0,
+            options: (usize::from(ipv4.get_header_length()) * 4).saturating_sub(20) as u8,
+            fragment,
+        });
+        self.transport = transport(protocol, ipv4.payload(), fragment);
+    }
+
+    fn ipv6(&mut self, payload: &[u8]) {
+        let Some(ipv6) = Ipv6Packet::new(payload) else {
+            return;
+        };
+        // Walk the chain of extension headers. Each names the header after
+        // it; the chain ends at the first one that isn't an extension.
+        let mut protocol = ipv6.get_next_header();
+        let mut rest = ipv6.payload();
+        let mut extension_headers = 0u8;
+        let mut fragment = None;
+        let mut truncated = false;
+        loop {
+            // `len` is the size of this extension header, `next` what follows.
+            let (next, len) = match protocol {
+                IpNextHeaderProtocols::Hopopt
+                | IpNextHeaderProtocols::Ipv6Route
+                | IpNextHeaderProtocols::Ipv6Opts => match ExtensionPacket::new(rest) {
+                    Some(ext) => (
+                        ext.get_next_header(),
+                        (usize::from(ext.get_hdr_ext_len()) + 1) * 8,
+                    ),
+                    None => (protocol, usize::MAX),
+                },
+                IpNextHeaderProtocols::Ipv6Frag => match FragmentPacket::new(rest) {
+                    Some(frag) => {
+                        fragment = Some(Fragment {
+                            offset: frag.get_fragment_offset(),
+                            more: !frag.is_last_fragment(),
+                        });
+                        (frag.get_next_header(), 8)
+                    }
+                    None => (protocol, usize::MAX),
+                },
+                // The authentication header counts its length in 4-byte
+                // units, unlike the others.
+                IpNextHeaderProtocols::Ah => match rest {
+                    [next, len, ..] => (IpNextHeaderProtocol(*next), (usize::from(*len) + 2) * 4),
+                    _ => (protocol, usize::MAX),
+                },
+                _ => break,
+            };
+            if len > rest.len() {
+                truncated = true;
+                break;
+            }
+            protocol = next;
+            rest = &rest[len..];
+            extension_headers = extension_headers.saturating_add(1);
+        }
+
+        self.network = Some(Network::Ipv6 {
+            source: ipv6.get_source(),
+            destination: ipv6.get_destination(),
+            hop_limit: ipv6.get_hop_limit(),
+            protocol: protocol.0,
+            extension_headers,
+            fragment,
+        });
+        if !truncated {
+            self.transport = transport(protocol, rest, fragment);
+        }
+    }
+
+    /// The address the frame is counted against: the IP source, or the
+    /// source MAC for anything that isn't IP.
+    pub fn source(&self) -> Source {
+        match self.network {
+            Some(Network::Ipv4 { source, .. }) => Source::Ipv4(source),
+            Some(Network::Ipv6 { source, .. }) => Source::Ipv6(source),
+            _ => Source::Mac(self.source_mac),
+        }
+    }
+
+    /// The IP destination, or the destination MAC for anything else.
+    pub fn destination(&self) -> Source {
+        match self.network {
+            Some(Network::Ipv4 { destination, .. }) => Source::Ipv4(destination),
+            Some(Network::Ipv6 { destination, .. }) => Source::Ipv6(destination),
+            _ => Source::Mac(self.destination_mac),
+        }
+    }
+
+    /// The IP protocol number, for IP packets.
+    pub fn ip_protocol(&self) -> Option<u8> {
+        match self.network {
+            Some(Network::Ipv4 { protocol, .. } | Network::Ipv6 { protocol, .. }) => Some(protocol),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self.network {
+            Some(Network::Arp { .. }) => Protocol::Arp,
+            // Classified by protocol number, so later fragments of a TCP
+            // packet still count as TCP.
+            Some(Network::Ipv4 { protocol, .. } | Network::Ipv6 { protocol, .. }) => {
+                Protocol::from_next_header(IpNextHeaderProtocol(protocol))
+            }
+            None if EtherType(self.ethertype) == EtherTypes::Arp => Protocol::Arp,
+            None => Protocol::Other,
+        }
+    }
+
+    /// The source and destination ports, for TCP and UDP.
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        match self.transport {
+            Some(Transport::Tcp {
+                source_port,
+                destination_port,
+                ..
+            })
+            | Some(Transport::Udp {
+                source_port,
+                destination_port,
+            }) => Some((source_port, destination_port)),
+            _ => None,
+        }
+    }
+
+    /// The fields policies key on.
+    pub fn flow(&self) -> Flow {
+        let (source_port, destination_port) = self.ports().unzip();
+        Flow {
+            source: self.source(),
+            destination: self.destination(),
+            protocol: self.ip_protocol(),
+            source_port,
+            destination_port,
+        }
+    }
+}
+
+fn arp(payload: &[u8]) -> Option<Network> {
+    let arp = ArpPacket::new(payload)?;
+    if arp.get_hardware_type() != ArpHardwareTypes::Ethernet
+        || arp.get_protocol_type() != EtherTypes::Ipv4
+    {
+        return None;
+    }
+    let operation = match arp.get_operation() {
+        ArpOperations::Request => ArpOperation::Request,
+        ArpOperations::Reply => ArpOperation::Reply,
+        other => ArpOperation::Other(other.0),
+    };
+    Some(Network::Arp {
+        operation,
+        sender_mac: arp.get_sender_hw_addr(),
+        sender_ip: arp.get_sender_proto_addr(),
+        target_ip: arp.get_target_proto_addr(),
+    })
+}
+
+// Parse the transport header, unless this is a fragment other than the
+// first, which carries only data.
+fn transport(
+    protocol: IpNextHeaderProtocol,
+    payload: &[u8],
+    fragment: Option<Fragment>,
+) -> Option<Transport> {
+    if fragment.is_some_and(|fragment| fragment.offset != 0) {
+        return None;
+    }
+    match protocol {
+        IpNextHeaderProtocols::Tcp => TcpPacket::new(payload).map(|tcp| Transport::Tcp {
+            source_port: tcp.get_source(),
+            destination_port: tcp.get_destination(),
+            flags: TcpFlags(tcp.get_flags()),
+        }),
+        IpNextHeaderProtocols::Udp => UdpPacket::new(payload).map(|udp| Transport::Udp {
+            source_port: udp.get_source(),
+            destination_port: udp.get_destination(),
+        }),
+        IpNextHeaderProtocols::Icmp => IcmpPacket::new(payload).map(|icmp| Transport::Icmp {
+            icmp_type: icmp.get_icmp_type().0,
+            code: icmp.get_icmp_code().0,
+        }),
+        IpNextHeaderProtocols::Icmpv6 => Icmpv6Packet::new(payload).map(|icmp| Transport::Icmpv6 {
+            icmp_type: icmp.get_icmpv6_type().0,
+            code: icmp.get_icmpv6_code().0,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const DESTINATION_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+    const SOURCE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DESTINATION_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    // An Ethernet frame: `ethertype`, then `payload`.
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = [DESTINATION_MAC, SOURCE_MAC].concat();
+        frame.extend(ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    // A VLAN tag with ID `id`, followed by `ethertype` and `payload`.
+    fn vlan(id: u16, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut tag = id.to_be_bytes().to_vec();
+        tag.extend(ethertype.to_be_bytes());
+        tag.extend(payload);
+        tag
+    }
+
+    // An IPv4 header from 192.0.2.1 to 192.0.2.2 with `options` and the
+    // flags and fragment offset field `fragment`, then `payload`.
+    fn ipv4(protocol: u8, options: &[u8], fragment: u16, payload: &[u8]) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let total = (header_len + payload.len()) as u16;
+        let mut packet = vec![0x40 | (header_len / 4) as u8, 0];
+        packet.extend(total.to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(fragment.to_be_bytes());
+        packet.extend([64, protocol, 0, 0, 192, 0, 2, 1, 192, 0, 2, 2]);
+        packet.extend(options);
+        packet.extend(payload);
+        packet
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend((payload.len() as u16).to_be_bytes());
+        packet.extend([next_header, 64]);
+        packet.extend(SOURCE_V6.octets());
+        packet.extend(DESTINATION_V6.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    // A 20-byte TCP header with `flags`.
+    fn tcp(source_port: u16, destination_port: u16, flags: TcpFlags) -> Vec<u8> {
+        let mut segment = source_port.to_be_bytes().to_vec();
+        segment.extend(destination_port.to_be_bytes());
+        segment.extend([0; 8]);
+        segment.extend([0x50, flags.0, 0xff, 0xff, 0, 0, 0, 0]);
+        segment
+    }
+
+    fn udp(source_port: u16, destination_port: u16) -> Vec<u8> {
+        let mut datagram = source_port.to_be_bytes().to_vec();
+        datagram.extend(destination_port.to_be_bytes());
+        datagram.extend([0, 8, 0, 0]);
+        datagram
+    }
+
+    fn dissect(frame: &[u8]) -> Summary {
+        Summary::of(&EthernetPacket::new(frame).unwrap())
+    }
+
+    fn udp_transport(source_port: u16, destination_port: u16) -> Option<Transport> {
+        Some(Transport::Udp {
+            source_port,
+            destination_port,
+        })
+    }
+
+    #[test]
+    fn reads_a_single_vlan_tag() {
+        let ip = ipv4(17, &[], 0, &udp(5353, 53));
+        let frame = ethernet(0x8100, &vlan(100, 0x0800, &ip));
+        let summary = dissect(&frame);
+        assert_eq!(summary.vlans.as_slice(), [100]);
+        assert_eq!(summary.ethertype, 0x0800);
+        assert_eq!(summary.transport, udp_transport(5353, 53));
+        assert_eq!(summary.source(), Source::Ipv4(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(summary.source_mac, MacAddr::from(SOURCE_MAC));
+    }
+
+    #[test]
+    fn reads_qinq_tags_outermost_first() {
+        let ip = ipv4(6, &[], 0, &tcp(40000, 443, TcpFlags::SYN));
+        // The priority bits above the 12-bit ID are ignored.
+        let inner = vlan(0xe000 | 20, 0x0800, &ip);
+        let frame = ethernet(0x88a8, &vlan(10, 0x8100, &inner));
+        let summary = dissect(&frame);
+        assert_eq!(summary.vlans.as_slice(), [10, 20]);
+        assert_eq!(summary.protocol(), Protocol::Tcp);
+        assert_eq!(summary.ports(), Some((40000, 443)));
+
+        // A third tag is more than is dissected.
+        let frame = ethernet(0x88a8, &vlan(10, 0x8100, &vlan(20, 0x8100, &inner)));
+        let summary = dissect(&frame);
+        assert_eq!(summary.vlans.as_slice(), [10, 20]);
+        assert_eq!(summary.network, None);
+    }
+
+    #[test]
+    fn skips_ipv4_options() {
+        // A record-route option padded to 8 bytes.
+        let options = [7, 7, 4, 0, 0, 0, 0, 1];
+        let frame = ethernet(0x0800, &ipv4(6, &options, 0, &tcp(1, 2, TcpFlags::ACK)));
+        let summary = dissect(&frame);
+        assert!(matches!(
+            summary.network,
+            Some(Network::Ipv4 {
+                options: 8,
+                ttl: 64,
+                fragment: None,
+                ..
+            })
+        ));
+        let expected = Transport::Tcp {
+            source_port: 1,
+            destination_port: 2,
+            flags: TcpFlags::ACK,
+        };
+        assert_eq!(summary.transport, Some(expected));
+    }
+
+    #[test]
+    fn reads_ports_only_from_the_first_ipv4_fragment() {
+        // More fragments, offset 0.
+        let frame = ethernet(0x0800, &ipv4(17, &[], 0x2000, &udp(1000, 2000)));
+        let summary = dissect(&frame);
+        let fragment = Fragment {
+            offset: 0,
+            more: true,
+        };
+        assert!(matches!(
+            summary.network,
+            Some(Network::Ipv4 { fragment: Some(f), .. }) if f == fragment
+        ));
+        assert_eq!(summary.transport, udp_transport(1000, 2000));
+
+        // The last fragment, 1480 bytes in. Its data only looks like a
+        // UDP header.
+        let frame = ethernet(0x0800, &ipv4(17, &[], 1480 / 8, &udp(1000, 2000)));
+        let summary = dissect(&frame);
+        let fragment = Fragment {
+            offset: 1480,
+            more: false,
+        };
+        assert!(matches!(
+            summary.network,
+            Some(Network::Ipv4 { fragment: Some(f), .. }) if f == fragment
+        ));
+        assert_eq!(summary.transport, None);
+        assert_eq!(summary.protocol(), Protocol::Udp);
+    }
+
+    #[test]
+    fn walks_ipv6_extension_headers() {
+        let mut chain = Vec::new();
+        // Hop-by-hop options, 8 bytes, then routing.
+        chain.extend([43, 0, 1, 4, 0, 0, 0, 0]);
+        // Routing, 16 bytes, then fragment.
+        chain.extend([44, 1]);
+        chain.extend([0; 14]);
+        // Fragment at offset 0 with more to come, then AH.
+        chain.extend([51, 0, 0, 1, 0, 0, 0, 7]);
+        // Authentication header, (4 + 2) * 4 = 24 bytes, then UDP.
+        chain.extend([17, 4]);
+        chain.extend([0; 22]);
+        chain.extend(udp(500, 4500));
+
+        let frame = ethernet(0x86dd, &ipv6(0, &chain));
+        let summary = dissect(&frame);
+        assert_eq!(
+            summary.network,
+            Some(Network::Ipv6 {
+                source: SOURCE_V6,
+                destination: DESTINATION_V6,
+                hop_limit: 64,
+                protocol: 17,
+                extension_headers: 4,
+                fragment: Some(Fragment {
+                    offset: 0,
+                    more: true
+                }),
+            })
+        );
+        assert_eq!(summary.transport, udp_transport(500, 4500));
+    }
+
+    #[test]
+    fn reads_ports_only_from_the_first_ipv6_fragment() {
+        // 1448 bytes in, the last fragment.
+        let mut chain = vec![6, 0];
+        chain.extend(1448u16.to_be_bytes());
+        chain.extend([0, 0, 0, 7]);
+        chain.extend(tcp(1, 2, TcpFlags::SYN));
+        let frame = ethernet(0x86dd, &ipv6(44, &chain));
+        let summary = dissect(&frame);
+        assert!(matches!(
+            summary.network,
+            Some(Network::Ipv6 {
+                protocol: 6,
+                extension_headers: 1,
+                fragment: Some(Fragment {
+                    offset: 1448,
+                    more: false
+                }),
+                ..
+            })
+        ));
+        assert_eq!(summary.transport, None);
+    }
+
+    #[test]
+    fn stops_at_truncated_headers() {
+        // A VLAN tag with nothing after it.
+        let summary = dissect(&ethernet(0x8100, &[0, 100]));
+        assert!(summary.vlans.is_empty());
+        assert_eq!((summary.ethertype, summary.network), (0x8100, None));
+        assert_eq!(summary.source(), Source::Mac(MacAddr::from(SOURCE_MAC)));
+
+        // Half an IPv4 header.
+        let ip = ipv4(6, &[], 0, &[]);
+        let summary = dissect(&ethernet(0x0800, &ip[..10]));
+        assert_eq!(
+            (summary.network, summary.protocol()),
+            (None, Protocol::Other)
+        );
+
+        // A whole IPv4 header but half a TCP one.
+        let ip = ipv4(6, &[], 0, &tcp(1, 2, TcpFlags::SYN)[..10]);
+        let summary = dissect(&ethernet(0x0800, &ip));
+        assert!(summary.network.is_some());
+        assert_eq!(
+            (summary.transport, summary.protocol()),
+            (None, Protocol::Tcp)
+        );
+
+        // An IPv6 routing header longer than the rest of the packet.
+        let mut chain = vec![17, 4];
+        chain.extend([0; 14]);
+        let summary = dissect(&ethernet(0x86dd, &ipv6(43, &chain)));
+        assert!(matches!(
+            summary.network,
+            Some(Network::Ipv6 {
+                protocol: 43,
+                extension_headers: 0,
+                ..
+            })
+        ));
+        assert_eq!(summary.transport, None);
+
+        // Half an ARP packet.
+        let summary = dissect(&ethernet(0x0806, &[0, 1, 8, 0, 6, 4]));
+        assert_eq!((summary.network, summary.protocol()), (None, Protocol::Arp));
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+use crate::lists::Prefix;
+use crate::source::Source;
+
+// An `Enforcer` is whatever actually stops traffic from a source. It's a trait
+// (Go: interface) so the capture loop doesn't care whether that means a
+// firewall rule or just a log line.
+// `Send` lets the enforcer be moved to another thread later on.
+// What gets blocked is a `Prefix`: usually a single address, but a whole
+// range when a prefix-level limit or a denylist range is hit.
+pub trait Enforcer: Send {
+    /// Start dropping traffic from `prefix`.
+    fn block(&mut self, prefix: &Prefix) -> io::Result<()>;
+    /// Stop dropping traffic from `prefix`.
+    fn unblock(&mut self, prefix: &Prefix) -> io::Result<()>;
+}
+
+/// The enforcers that can be chosen by name, from the command line or a
+/// config file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EnforcerKind {
+    Log,
+    Iptables,
+}
+
+impl EnforcerKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EnforcerKind::Log => "log",
+            EnforcerKind::Iptables => "iptables",
+        }
+    }
+
+    /// A new enforcer of this kind.
+    // `Box<dyn Enforcer>` is a heap-allocated value of "some type implementing
+    // `Enforcer`", the same as storing a Go interface value.
+    pub fn build(self) -> Box<dyn Enforcer> {
+        match self {
+            EnforcerKind::Log => Box::new(LogEnforcer),
+            EnforcerKind::Iptables => Box::new(IptablesEnforcer),
+        }
+    }
+}
+
+impl fmt::Display for EnforcerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for EnforcerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EnforcerKind, String> {
+        [EnforcerKind::Log, EnforcerKind::Iptables]
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| format!("unknown enforcer '{}'", s))
+    }
+}
+
+/// Leaves the firewall alone; blocks only show up in the event log and
+/// traffic keeps flowing. This is the default, so running the tool never
+/// changes the host's firewall unless asked to.
+pub struct LogEnforcer;
+
+impl Enforcer for LogEnforcer {
+    fn block(&mut self, _prefix: &Prefix) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn unblock(&mut self, _prefix: &Prefix) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Installs a DROP rule at the top of the INPUT chain for each blocked source
+/// or prefix, using `iptables` for IPv4 and MAC sources and `ip6tables` for
+/// IPv6. iptables can only match whole MAC addresses, not MAC prefixes.
+pub struct IptablesEnforcer;
+
+// Every rule we add carries this comment, so they are easy to find with
+// `iptables -S INPUT` and are never confused with the host's own rules.
+const RULE_COMMENT: &str = "packet_processor";
+
+impl IptablesEnforcer {
+    // Run `iptables <action> INPUT <match> -j DROP` for the prefix. `action`
+    // is `-I` to insert a rule or `-D` to delete the same rule again.
+    fn rule(action: &str, prefix: &Prefix) -> io::Result<()> {
+        // `-s` takes both `10.0.0.1` and `10.0.0.0/24`, which is exactly how
+        // a prefix displays.
+        let (program, matcher) = match prefix.network() {
+            Source::Ipv6(_) => ("ip6tables", vec!["-s".to_string(), prefix.to_string()]),
+            Source::Ipv4(_) => ("iptables", vec!["-s".to_string(), prefix.to_string()]),
+            Source::Mac(_) if !prefix.is_address() => {
+                return Err(io::Error::other(format!(
+                    "iptables can't match MAC prefix {}",
+                    prefix
+                )));
+            }
+            Source::Mac(mac) => (
+                "iptables",
+                vec![
+                    "-m".to_string(),
+                    "mac".to_string(),
+                    "--mac-source".to_string(),
+                    mac.to_string(),
+                ],
+            ),
+        };
+
+        // `Command` is like Go's `exec.Command`.
+        let status = Command::new(program)
+            .args([action, "INPUT"])
+            .args(&matcher)
+            .args(["-m", "comment", "--comment", RULE_COMMENT, "-j", "DROP"])
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "{} {} exited with {}",
+                program, action, status
+            )))
+        }
+    }
+}
+
+impl Enforcer for IptablesEnforcer {
+    fn block(&mut self, prefix: &Prefix) -> io::Result<()> {
+        Self::rule("-I", prefix)
+    }
+
+    fn unblock(&mut self, prefix: &Prefix) -> io::Result<()> {
+        Self::rule("-D", prefix)
+    }
+}
+
+// Keeps track of which sources and prefixes are blocked and until when, and
+// lifts blocks once they expire. Times are capture timestamps (see
+// `capture::Frame`), so replayed files expire blocks on their own clock.
+//
+// Every capture thread asks about every packet, so the blocked set sits
+// behind an `RwLock` (Go's `sync.RWMutex`) that lets them all read at once.
+// The enforcer has its own lock: it may fork `iptables`, which is far too
+// slow to do while holding up the lookups. Whoever holds the enforcer takes
+// the write lock only for the moment it takes to update the set.
+pub struct Blocklist {
+    enforcer: Mutex<Box<dyn Enforcer>>,
+    blocked: RwLock<Blocked>,
+}
+
+struct Blocked {
+    duration: Duration,
+    // Prefix -> the capture timestamp at which its block runs out.
+    until: HashMap<Prefix, Duration>,
+    // How many blocked prefixes have each length. Checking a source only has
+    // to try these lengths, which are rarely more than two or three.
+    lengths: BTreeMap<u8, usize>,
+}
+
+impl Blocked {
+    fn forget_length(&mut self, len: u8) {
+        if let Some(count) = self.lengths.get_mut(&len) {
+            *count -= 1;
+            if *count == 0 {
+                self.lengths.remove(&len);
+            }
+        }
+    }
+}
+
+impl Blocklist {
+    pub fn new(enforcer: Box<dyn Enforcer>, duration: Duration) -> Blocklist {
+        Blocklist {
+            enforcer: Mutex::new(enforcer),
+            blocked: RwLock::new(Blocked {
+                duration,
+                until: HashMap::new(),
+                lengths: BTreeMap::new(),
+            }),
+        }
+    }
+
+    // If a thread panicked while holding one of the locks, what it guards is
+    // still usable, so carry on with it.
+    fn enforcer(&self) -> MutexGuard<'_, Box<dyn Enforcer>> {
+        self.enforcer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Blocked> {
+        self.blocked.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Blocked> {
+        self.blocked.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether `source` is blocked, on its own or as part of a prefix.
+    pub fn is_blocked(&self, source: &Source) -> bool {
+        let address = Prefix::from(*source);
+        let blocked = self.read();
+        blocked
+            .lengths
+            .keys()
+            .any(|&len| blocked.until.contains_key(&address.truncate(len)))
+    }
+
+    /// Change how long future blocks last. Blocks already in place keep the
+    /// expiry time they were given.
+    pub fn set_duration(&self, duration: Duration) {
+        self.write().duration = duration;
+    }
+
+    /// Number of sources and prefixes currently blocked.
+    pub fn len(&self) -> usize {
+        self.read().until.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().until.is_empty()
+    }
+
+    /// Block `prefix` for the configured duration. Returns `Ok(false)` if it
+    /// already was blocked.
+    ///
+    /// The prefix is only remembered once the enforcer has blocked it, so
+    /// after an error it isn't reported as blocked, and the next packet over
+    /// the limit tries again.
+    pub fn block(&self, prefix: Prefix, now: Duration) -> io::Result<bool> {
+        if self.read().until.contains_key(&prefix) {
+            return Ok(false);
+        }
+        let mut enforcer = self.enforcer();
+        // Another thread may have blocked it while we waited for the
+        // enforcer.
+        if self.read().until.contains_key(&prefix) {
+            return Ok(false);
+        }
+        enforcer.block(&prefix)?;
+        let mut blocked = self.write();
+        // Saturating, so a huge `--block-duration` means "until the end of
+        // the run" instead of an overflow.
+        let until = now.saturating_add(blocked.duration);
+        blocked.until.insert(prefix, until);
+        *blocked.lengths.entry(prefix.prefix_len()).or_default() += 1;
+        Ok(true)
+    }
+
+    /// Lift every block whose time is up, returning the prefixes that were
+    /// unblocked along with the enforcer's result for each. This goes over
+    /// every block, so it's meant to be called now and then rather than for
+    /// every packet.
+    pub fn expire(&self, now: Duration) -> Vec<(Prefix, io::Result<()>)> {
+        let mut enforcer = self.enforcer();
+        // `Vec::new()` doesn't allocate, so the common case of nothing to
+        // expire stays cheap.
+        let mut expired = Vec::new();
+        {
+            let mut blocked = self.write();
+            // `retain` keeps the entries for which the closure returns
+            // `true`, like filtering a Go map in place.
+            blocked.until.retain(|prefix, until| {
+                if *until > now {
+                    return true;
+                }
+                expired.push(*prefix);
+                false
+            });
+            for prefix in &expired {
+                blocked.forget_length(prefix.prefix_len());
+            }
+        }
+        expired
+            .into_iter()
+            .map(|prefix| (prefix, enforcer.unblock(&prefix)))
+            .collect()
+    }
+
+    /// Lift every block now, whatever its expiry time. Failures are printed
+    /// rather than returned, since there's nothing left to retry them with.
+    pub fn clear(&self) {
+        let mut enforcer = self.enforcer();
+        let cleared: Vec<Prefix> = {
+            let mut blocked = self.write();
+            blocked.lengths.clear();
+            blocked.until.drain().map(|(prefix, _)| prefix).collect()
+        };
+        for prefix in cleared {
+            if let Err(e) = enforcer.unblock(&prefix) {
+                eprintln!("Failed to unblock {}: {}", prefix, e);
+            }
+        }
+    }
+}
+
+// `Drop` runs when the value goes out of scope, like a Go `defer`. Removing
+// our rules here means an error or a normal exit never leaves hosts blocked.
+impl Drop for Blocklist {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    // Fails every block when its flag is set.
+    struct Flaky(bool);
+
+    impl Enforcer for Flaky {
+        fn block(&mut self, _prefix: &Prefix) -> io::Result<()> {
+            match self.0 {
+                true => Err(io::Error::other("iptables failed")),
+                false => Ok(()),
+            }
+        }
+
+        fn unblock(&mut self, _prefix: &Prefix) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn source(last: u8) -> Source {
+        Source::Ipv4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    #[test]
+    fn remembers_a_block_only_once_the_enforcer_made_it() {
+        let blocklist = Blocklist::new(Box::new(Flaky(true)), Duration::from_secs(60));
+        let prefix = Prefix::from(source(1));
+
+        assert!(blocklist.block(prefix, Duration::ZERO).is_err());
+        assert!(!blocklist.is_blocked(&source(1)));
+        assert!(blocklist.is_empty());
+    }
+
+    #[test]
+    fn blocks_until_the_duration_runs_out() {
+        let blocklist = Blocklist::new(Box::new(Flaky(false)), Duration::from_secs(60));
+        let prefix = Prefix::from(source(1));
+
+        assert!(blocklist.block(prefix, Duration::ZERO).unwrap());
+        assert!(!blocklist.block(prefix, Duration::from_secs(1)).unwrap());
+        assert!(blocklist.is_blocked(&source(1)));
+        assert!(!blocklist.is_blocked(&source(2)));
+
+        assert!(blocklist.expire(Duration::from_secs(59)).is_empty());
+        let expired = blocklist.expire(Duration::from_secs(60));
+        assert_eq!(expired.len(), 1);
+        assert!(!blocklist.is_blocked(&source(1)));
+    }
+
+    #[test]
+    fn a_huge_duration_does_not_overflow() {
+        let blocklist = Blocklist::new(Box::new(Flaky(false)), Duration::MAX);
+        let prefix = Prefix::from(source(1));
+
+        assert!(blocklist.block(prefix, Duration::from_secs(1)).unwrap());
+        assert!(blocklist.expire(Duration::from_secs(1 << 40)).is_empty());
+        assert!(blocklist.is_blocked(&source(1)));
+    }
+}
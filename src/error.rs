@@ -0,0 +1,129 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// One error type for the whole crate, so functions can use `?` to bubble
+// failures up to `main` instead of calling `panic!` where they happen.
+// In Go, this would be a set of sentinel errors or `fmt.Errorf` wrapping.
+#[derive(Debug)]
+pub enum Error {
+    /// No interface matched the name or index given on the command line.
+    InterfaceNotFound(String),
+    /// The interface exists but is down or is a loopback device.
+    InterfaceUnsuitable(String),
+    /// `--all-interfaces` found nothing that is up and not a loopback device.
+    NoInterfaces,
+    /// `datalink::channel` returned something other than an Ethernet channel.
+    UnsupportedChannel(String),
+    /// A policy has responses, but the input can't send them: a capture
+    /// file, or a layer 3 channel.
+    CannotRespond,
+    /// `--bridge` didn't name two different interfaces.
+    Bridge,
+    /// The capture channel could not be opened (often missing privileges).
+    Channel {
+        interface: String,
+        source: io::Error,
+    },
+    /// A capture file could not be opened or is not a pcap/pcapng file.
+    File { path: PathBuf, source: io::Error },
+    /// Reading from an open channel or capture file failed. `input` is the
+    /// interface name or file path.
+    Receive { input: String, source: io::Error },
+    /// A capture thread could not be started.
+    Thread(io::Error),
+    /// A capture thread panicked. The string is its interface name or file
+    /// path.
+    WorkerFailed(String),
+    /// The SIGINT and SIGTERM handlers could not be installed.
+    Signal(io::Error),
+    /// The terminal could not be taken over for the dashboard.
+    Terminal(io::Error),
+    /// The metrics endpoint could not listen on its address.
+    Metrics { addr: SocketAddr, source: io::Error },
+    /// The event log could not be opened.
+    Events { target: String, source: io::Error },
+    /// The evidence directory could not be created, or its writer thread
+    /// could not be started.
+    Evidence { path: PathBuf, source: io::Error },
+    /// A config file could not be read, or has a mistake in it.
+    Config { path: PathBuf, message: String },
+    /// The command line flags combine in a way `Config::check` rejects.
+    Options(String),
+    /// An allowlist or denylist file could not be read, or has a line that
+    /// isn't an address or prefix.
+    List { path: PathBuf, message: String },
+}
+
+// `Display` is what `{}` uses, like implementing `Error() string` in Go.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InterfaceNotFound(name) => write!(f, "interface '{}' not found", name),
+            Error::InterfaceUnsuitable(name) => {
+                write!(f, "interface '{}' is down or is a loopback device", name)
+            }
+            Error::NoInterfaces => f.write_str("no interface is up and not a loopback device"),
+            Error::UnsupportedChannel(name) => {
+                write!(f, "unsupported channel type on interface '{}'", name)
+            }
+            Error::CannotRespond => {
+                f.write_str("policy responses need live interfaces on a layer 2 channel")
+            }
+            Error::Bridge => f.write_str("a bridge needs two different interfaces"),
+            Error::Channel { interface, source } => {
+                write!(f, "error opening channel on '{}': {}", interface, source)
+            }
+            Error::File { path, source } => {
+                write!(f, "error reading '{}': {}", path.display(), source)
+            }
+            Error::Receive { input, source } => {
+                write!(f, "error receiving packet from '{}': {}", input, source)
+            }
+            Error::Thread(e) => write!(f, "error starting capture thread: {}", e),
+            Error::WorkerFailed(input) => write!(f, "capture thread for '{}' panicked", input),
+            Error::Signal(e) => write!(f, "error setting up signal handlers: {}", e),
+            Error::Terminal(e) => write!(f, "error starting the dashboard: {}", e),
+            Error::Metrics { addr, source } => {
+                write!(f, "error serving metrics on {}: {}", addr, source)
+            }
+            Error::Events { target, source } => {
+                write!(f, "error opening event log '{}': {}", target, source)
+            }
+            Error::Evidence { path, source } => {
+                write!(
+                    f,
+                    "error setting up evidence capture in '{}': {}",
+                    path.display(),
+                    source
+                )
+            }
+            Error::Config { path, message } => {
+                write!(f, "error in config file '{}': {}", path.display(), message)
+            }
+            Error::Options(message) => write!(f, "invalid options: {}", message),
+            Error::List { path, message } => {
+                write!(f, "error in list file '{}': {}", path.display(), message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Channel { source, .. }
+            | Error::File { source, .. }
+            | Error::Receive { source, .. }
+            | Error::Metrics { source, .. }
+            | Error::Events { source, .. }
+            | Error::Evidence { source, .. } => Some(source),
+            Error::Thread(e) | Error::Signal(e) | Error::Terminal(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// `Result<T>` is shorthand for `Result<T, Error>` inside this crate.
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,566 @@
+// Structured event log. Every interesting thing that happens is written as
+// one JSON object per line ("JSON lines"), so the output can be piped into
+// `jq`, shipped to a log collector, or read by another program over a socket.
+//
+// A line looks like:
+//
+//   {"ts":1700000000.01,"event":"limit_exceeded","source":"10.0.0.1","unit":"packets","used":101,"limit":100}
+//   {"ts":1700000000.02,"event":"limit_exceeded","source":"10.0.0.9","prefix":"10.0.0.0/24","unit":"packets","used":1001,"limit":1000}
+//   {"ts":1700000000.03,"event":"limit_exceeded","source":"10.0.0.4","policy":"dns","key":{"destination":"10.0.0.53","destination_port":53},"unit":"packets","used":2001,"limit":2000}
+//
+// `ts` is the capture timestamp in seconds since the Unix epoch, so events
+// from a replayed file carry the time the traffic was recorded. Events from a
+// capture thread also carry the `interface` they were seen on.
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::capture::{Backoff, ErrorClass};
+use crate::dissect::{Summary, TcpFlags, Vlans};
+use crate::flow::Key;
+use crate::limiter::Unit;
+use crate::lists::Prefix;
+use crate::protocol::Protocol;
+use crate::respond::Response;
+use crate::source::Source;
+use crate::stats::Report;
+
+/// What happened to a packet, for `packet` debug events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketAction {
+    /// Counted and under every limit.
+    Allowed,
+    /// Counted, and took its source over a limit.
+    Exceeded,
+    /// Dropped without counting because its source is blocked.
+    Dropped,
+    /// Not counted because its source is on the allowlist.
+    Ignored,
+    /// Dropped because its source is on the denylist.
+    Denied,
+}
+
+// `#[serde(tag = "event")]` writes the variant name into an `"event"` field
+// next to the variant's own fields, e.g. `{"event":"rx_error","error":"..."}`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A live capture channel was opened.
+    InterfaceOpened { interface: String },
+    /// A capture file was opened for replay.
+    FileOpened { path: PathBuf },
+    /// The Prometheus endpoint is accepting scrapes.
+    MetricsListening { addr: SocketAddr },
+    /// A source went over a limit and is now blocked. For a prefix-level
+    /// limit, `prefix` is the range that went over and is blocked as a whole.
+    /// For a policy, `policy` names it and `key` is what went over; the
+    /// source is only blocked if the key includes it.
+    LimitExceeded {
+        source: Source,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prefix: Option<Prefix>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        policy: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<Key>,
+        unit: Unit,
+        used: u64,
+        limit: u64,
+    },
+    /// A block ran out. `source` is an address or a prefix.
+    LimitCleared { source: Prefix },
+    /// The enforcer failed to block or unblock an address or prefix.
+    EnforcerError { source: Prefix, error: String },
+    /// Receiving a frame failed. `class` says what happens next: a
+    /// `transient` error is retried after `retry_in_ms`, `interface_down`
+    /// waits for the interface to come back, and `fatal` ends the capture.
+    RxError {
+        error: String,
+        class: ErrorClass,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_in_ms: Option<u64>,
+    },
+    /// The interface was back up and its channel was opened again, after
+    /// `attempts` tries over `down_secs` seconds.
+    InterfaceReopened { attempts: u32, down_secs: f64 },
+    /// The interface is up again, but opening its channel failed; it's
+    /// tried again after `retry_in_ms`.
+    ReopenFailed { error: String, retry_in_ms: u64 },
+    /// The config file was reloaded. `ignored` lists changed settings that
+    /// only take effect after a restart.
+    ConfigReloaded {
+        path: PathBuf,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        ignored: Vec<&'static str>,
+    },
+    /// Reloading the config file failed; the previous config stays in use.
+    ConfigError { path: PathBuf, error: String },
+    /// A new evidence file was started.
+    EvidenceFile { path: PathBuf },
+    /// Writing evidence failed. Only the first of a run of errors is
+    /// reported.
+    EvidenceError { error: String },
+    /// Forwarding a frame to the bridge's other side, `to`, failed. Only
+    /// the first of a run of errors is reported.
+    ForwardError { to: String, error: String },
+    /// Sending a response to a packet over a policy's limit failed. Only
+    /// the first of a run of errors is reported.
+    ResponseError { response: Response, error: String },
+    /// Capture stopped, because of `signal`, because the dashboard was
+    /// quit, because every input ran out, because of an error, or because a
+    /// capture thread panicked; `reason` is `signal`, `quit`, `end_of_input`,
+    /// `error` or `worker_failed`. The rest sums up the whole run.
+    Stopped {
+        reason: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signal: Option<&'static str>,
+        #[serde(flatten)]
+        report: Report,
+    },
+    /// A packet from a source on the denylist, which is now blocked along
+    /// with the rest of the denylist entry `prefix`.
+    Denied { source: Source, prefix: Prefix },
+    /// A single packet, only logged when packet sampling is on. `headers`
+    /// is everything the dissector found in it.
+    Packet {
+        source: Source,
+        protocol: Protocol,
+        len: usize,
+        action: PacketAction,
+        headers: Summary,
+    },
+}
+
+// The line actually written: the timestamp and interface, followed by the
+// event's fields.
+#[derive(Serialize)]
+struct Line<'a> {
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interface: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Where events are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventTarget {
+    Stdout,
+    /// Appended to a file, created if missing.
+    File(PathBuf),
+    /// Sent to a listening Unix stream socket.
+    Unix(PathBuf),
+}
+
+impl FromStr for EventTarget {
+    type Err = String;
+
+    /// Parses `-` or `stdout`, `unix:PATH`, or a file path (optionally
+    /// written as `file:PATH`).
+    fn from_str(s: &str) -> Result<EventTarget, String> {
+        // `strip_prefix` returns `Some(rest)` if the prefix matched.
+        if s == "-" || s == "stdout" {
+            Ok(EventTarget::Stdout)
+        } else if let Some(path) = s.strip_prefix("unix:") {
+            Ok(EventTarget::Unix(path.into()))
+        } else if let Some(path) = s.strip_prefix("file:") {
+            Ok(EventTarget::File(path.into()))
+        } else if s.is_empty() {
+            Err("event target must not be empty".to_string())
+        } else {
+            Ok(EventTarget::File(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for EventTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventTarget::Stdout => f.write_str("stdout"),
+            EventTarget::File(path) => write!(f, "{}", path.display()),
+            EventTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// Cloning an `EventLog` gives another handle to the same output, so every
+// capture thread can hold one. Like Go's `slog.Logger.With`, `with_interface`
+// returns a handle that adds a field to everything it writes.
+#[derive(Clone)]
+pub struct EventLog {
+    sink: Arc<Mutex<Sink>>,
+    // `Arc<str>` is a shared, immutable string, cheaper to clone than a
+    // `String`.
+    interface: Option<Arc<str>>,
+}
+
+struct Sink {
+    // Any writer will do (Go: `io.Writer`). `Send` so the log can be used
+    // from other threads.
+    out: Box<dyn Write + Send>,
+    // Set after a write error, so a dead sink is reported once per run of
+    // errors instead of on every event.
+    failed: bool,
+}
+
+impl EventLog {
+    /// Write events to `out`.
+    pub fn new(out: Box<dyn Write + Send>) -> EventLog {
+        EventLog {
+            sink: Arc::new(Mutex::new(Sink { out, failed: false })),
+            interface: None,
+        }
+    }
+
+    pub fn open(target: &EventTarget) -> io::Result<EventLog> {
+        // `LineWriter` flushes after every newline, so each event reaches the
+        // file as soon as it's written. A socket sends each line by itself.
+        let out: Box<dyn Write + Send> = match target {
+            EventTarget::Stdout => Box::new(io::stdout()),
+            EventTarget::File(path) => {
+                let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+                Box::new(LineWriter::new(file))
+            }
+            EventTarget::Unix(path) => Box::new(SocketSink::connect(path)?),
+        };
+        Ok(EventLog::new(out))
+    }
+
+    /// A handle to the same log that tags every event with `interface`.
+    pub fn with_interface(&self, interface: &str) -> EventLog {
+        EventLog {
+            sink: Arc::clone(&self.sink),
+            interface: Some(interface.into()),
+        }
+    }
+
+    /// Write out anything still buffered, e.g. before exiting.
+    pub fn flush(&self) {
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = sink.out.flush()
+            && !sink.failed
+        {
+            eprintln!("error writing event log: {}", e);
+            sink.failed = true;
+        }
+    }
+
+    /// Write one event that happened at capture time `ts`.
+    pub fn emit(&self, ts: Duration, event: &Event) {
+        let line = Line {
+            ts: ts.as_secs_f64(),
+            interface: self.interface.as_deref(),
+            event,
+        };
+        // Serialize before taking the lock, so threads only wait on each other
+        // for the write itself. The lock keeps lines from interleaving.
+        let mut buf = match serde_json::to_vec(&line) {
+            Ok(buf) => buf,
+            Err(e) => return eprintln!("error encoding event: {}", e),
+        };
+        buf.push(b'\n');
+
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        match sink.out.write_all(&buf) {
+            Ok(()) => sink.failed = false,
+            Err(e) => {
+                if !sink.failed {
+                    eprintln!("error writing event log: {}", e);
+                }
+                sink.failed = true;
+            }
+        }
+    }
+}
+
+// Lines waiting for a socket's writer thread, at most.
+const SOCKET_QUEUE_LEN: usize = 4096;
+
+// How long one write to the socket may take before the reader is given up
+// on, and how long `flush` waits for the queue to empty.
+const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+const SOCKET_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The shortest and longest wait before connecting again after an error.
+const RECONNECT_FIRST: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+// What `emit` hands a socket's writer thread.
+enum Message {
+    Line(Vec<u8>),
+    // Answer once everything before this is written, or given up on.
+    Flush(SyncSender<()>),
+}
+
+// The write side of a Unix socket target. Lines are queued for a thread of
+// their own, which does the blocking writes, so a slow or stuck reader
+// never holds up `emit`, or the other threads waiting on its lock. If the
+// queue is full, the line is dropped.
+struct SocketSink {
+    sender: SyncSender<Message>,
+}
+
+impl SocketSink {
+    // Connect to the socket at `path` now, so a wrong path is an error at
+    // startup, and start the writer thread.
+    fn connect(path: &Path) -> io::Result<SocketSink> {
+        let stream = connect(path)?;
+        let (sender, receiver) = mpsc::sync_channel(SOCKET_QUEUE_LEN);
+        let writer = SocketWriter {
+            path: path.to_path_buf(),
+            stream: Some(stream),
+            backoff: Backoff::new(RECONNECT_FIRST, RECONNECT_MAX),
+            retry_at: Instant::now(),
+            failed: false,
+            lost: 0,
+        };
+        thread::Builder::new()
+            .name("events".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(SocketSink { sender })
+    }
+}
+
+impl Write for SocketSink {
+    // `emit` writes a whole line at a time, so each call queues one line.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.sender.try_send(Message::Line(buf.to_vec())) {
+            Ok(()) => Ok(buf.len()),
+            Err(TrySendError::Full(_)) => Err(io::Error::other(
+                "event socket isn't keeping up, dropping events",
+            )),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(io::Error::other("event socket writer stopped"))
+            }
+        }
+    }
+
+    // Wait for the writer thread to catch up, though not for ever.
+    fn flush(&mut self) -> io::Result<()> {
+        let (ack, done) = mpsc::sync_channel(1);
+        self.sender
+            .send(Message::Flush(ack))
+            .map_err(|_| io::Error::other("event socket writer stopped"))?;
+        done.recv_timeout(SOCKET_FLUSH_TIMEOUT).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out flushing the event socket",
+            )
+        })
+    }
+}
+
+// A socket's writer thread. When the connection breaks it connects again,
+// backing off while that fails; lines sent meanwhile are lost.
+struct SocketWriter {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    backoff: Backoff,
+    // When to next try connecting, after it failed.
+    retry_at: Instant,
+    // Set after an error, so it's reported once per run of errors.
+    failed: bool,
+    // Lines lost since the connection broke.
+    lost: u64,
+}
+
+impl SocketWriter {
+    fn run(mut self, receiver: Receiver<Message>) {
+        // `recv` fails once every `EventLog` handle is gone.
+        while let Ok(message) = receiver.recv() {
+            match message {
+                Message::Line(line) => self.write(&line),
+                Message::Flush(ack) => {
+                    let _ = ack.send(());
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, line: &[u8]) {
+        if let Some(stream) = &mut self.stream {
+            match stream.write_all(line) {
+                Ok(()) => return,
+                // Connect again right away: the reader may just have
+                // restarted.
+                Err(e) => {
+                    self.stream = None;
+                    self.error(&e);
+                }
+            }
+        }
+        if Instant::now() >= self.retry_at {
+            match connect(&self.path).and_then(|mut stream| stream.write_all(line).map(|()| stream))
+            {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.backoff.reset();
+                    if self.failed {
+                        eprintln!("event log reconnected, {} events lost", self.lost);
+                    }
+                    self.failed = false;
+                    self.lost = 0;
+                    return;
+                }
+                Err(e) => {
+                    self.retry_at = Instant::now() + self.backoff.next_delay();
+                    self.error(&e);
+                }
+            }
+        }
+        self.lost += 1;
+    }
+
+    fn error(&mut self, e: &io::Error) {
+        if !self.failed {
+            eprintln!("error writing event log: {}; reconnecting", e);
+        }
+        self.failed = true;
+    }
+}
+
+fn connect(path: &Path) -> io::Result<UnixStream> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+    Ok(stream)
+}
+
+// Addresses and protocols are written as their usual text form, e.g.
+// `"10.0.0.1"` and `"tcp"`, rather than as nested JSON objects.
+impl Serialize for Source {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for Prefix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for Unit {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// TCP flags as a list of names, e.g. `["syn","ack"]`.
+impl Serialize for TcpFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let set = TcpFlags::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag));
+        serializer.collect_seq(set.map(|(_, name)| name))
+    }
+}
+
+impl Serialize for Vlans {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.as_slice())
+    }
+}
+
+// For `#[serde(serialize_with = "display")]` on fields of types from other
+// crates, which can't be given a `Serialize` impl here, such as `MacAddr`.
+pub(crate) fn display<T: fmt::Display, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+impl Serialize for Response {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl Serialize for ErrorClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl Serialize for Protocol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+
+    fn listen(name: &str) -> (UnixListener, PathBuf) {
+        let name = format!("packet_processor-{}-events-{name}.sock", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&path);
+        (UnixListener::bind(&path).unwrap(), path)
+    }
+
+    // The `error` field of the next event `reader` gets.
+    fn next_error(reader: &mut BufReader<UnixStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        value["error"].as_str().unwrap().to_string()
+    }
+
+    fn accept(listener: &UnixListener) -> BufReader<UnixStream> {
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        BufReader::new(stream)
+    }
+
+    fn event(error: &str) -> Event {
+        Event::EvidenceError {
+            error: error.to_string(),
+        }
+    }
+
+    #[test]
+    fn reconnects_after_the_reader_hangs_up() {
+        let (listener, path) = listen("reconnect");
+        let log = EventLog::open(&EventTarget::Unix(path.clone())).unwrap();
+        let mut reader = accept(&listener);
+        log.emit(Duration::ZERO, &event("first"));
+        assert_eq!(next_error(&mut reader), "first");
+
+        drop(reader);
+        log.emit(Duration::ZERO, &event("second"));
+        let mut reader = accept(&listener);
+        assert_eq!(next_error(&mut reader), "second");
+        log.flush();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn does_not_wait_for_a_reader_that_is_stuck() {
+        let (listener, path) = listen("stuck");
+        let log = EventLog::open(&EventTarget::Unix(path.clone())).unwrap();
+        // Connected, but never read from, so the socket buffer fills up and
+        // the writer thread blocks.
+        let started = Instant::now();
+        for _ in 0..SOCKET_QUEUE_LEN * 4 {
+            log.emit(Duration::ZERO, &event(&"x".repeat(100)));
+        }
+        assert!(started.elapsed() < SOCKET_WRITE_TIMEOUT);
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,776 @@
+// Evidence capture: the frames of sources that go over a limit, saved to
+// pcapng files that can be opened in Wireshark.
+//
+// Each capture thread keeps its last few frames in a ring (a `Recorder`).
+// When a source trips a limit, its frames from the ring are written out,
+// ending with the one that tripped it, and so are its next frames up to a
+// per-offender budget, for as long as a block lasts. Every frame carries a
+// comment saying why it was saved; the one that tripped the limit has the
+// `limit_exceeded` event itself.
+//
+// The files are written by a thread of their own, fed through a bounded
+// channel. If it falls behind, frames are dropped (and counted) rather
+// than holding up the capture.
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::capture::{Backoff, Frame, now};
+use crate::config::EvidenceConfig;
+use crate::events::{Event, EventLog};
+use crate::lists::Prefix;
+use crate::metrics::Metrics;
+use crate::pcap::PcapngWriter;
+use crate::source::Source;
+
+// Frames waiting for the writer thread, at most.
+const QUEUE_LEN: usize = 8192;
+
+// The shortest and longest wait before trying a new file after an error.
+const RETRY_FIRST: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+// What the capture threads send the writer thread.
+enum Message {
+    Frame(Record),
+    // Write out everything before this, then stop.
+    Close,
+}
+
+// One frame on its way to a file.
+struct Record {
+    interface: Arc<str>,
+    timestamp: Duration,
+    len: usize,
+    data: Vec<u8>,
+    comment: String,
+}
+
+/// Shared by every capture thread: which offenders are being recorded, and
+/// the way to the writer thread.
+pub struct Evidence {
+    sender: SyncSender<Message>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    pre_trigger: usize,
+    post_trigger: u32,
+    // How long after tripping a limit an offender's frames are still saved.
+    timeout: Duration,
+    offenders: Mutex<Offenders>,
+    // Whether any offender is being recorded. Checked before taking the
+    // lock, so capture threads don't contend for it while nobody is.
+    recording: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+// Offenders still being recorded: how many more frames each gets, and the
+// capture time its recording ends, whichever runs out first. Like
+// `Blocklist`, it counts how many entries have each prefix length, so
+// looking a source up only tries those lengths.
+#[derive(Default)]
+struct Offenders {
+    remaining: HashMap<Prefix, (u32, Duration)>,
+    lengths: BTreeMap<u8, usize>,
+    // When offenders whose time ran out were last swept out.
+    swept: Duration,
+}
+
+// How often, in capture time, offenders whose time ran out are forgotten.
+// Until then they're only skipped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Offenders {
+    // The offender `source` belongs to, if any is still being recorded at
+    // `now`.
+    fn find(&self, source: Source, now: Duration) -> Option<Prefix> {
+        let address = Prefix::from(source);
+        self.lengths
+            .keys()
+            .map(|&len| address.truncate(len))
+            .find(|prefix| self.is_recording(prefix, now))
+    }
+
+    fn is_recording(&self, offender: &Prefix, now: Duration) -> bool {
+        self.remaining
+            .get(offender)
+            .is_some_and(|&(_, until)| now < until)
+    }
+
+    fn insert(&mut self, offender: Prefix, frames: u32, until: Duration) {
+        if self.remaining.insert(offender, (frames, until)).is_none() {
+            *self.lengths.entry(offender.prefix_len()).or_default() += 1;
+        }
+    }
+
+    // Use up one frame of `offender`'s budget, forgetting it once it's gone.
+    fn take(&mut self, offender: Prefix) {
+        let Some((remaining, _)) = self.remaining.get_mut(&offender) else {
+            return;
+        };
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.remaining.remove(&offender);
+            self.forget_length(offender.prefix_len());
+        }
+    }
+
+    // Forget the offenders whose time is up. Otherwise one that never sends
+    // again would stay in the map, and keep `recording` set, for good.
+    fn expire(&mut self, now: Duration) {
+        if now.saturating_sub(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept = now;
+        let mut expired = Vec::new();
+        self.remaining.retain(|offender, &mut (_, until)| {
+            if now < until {
+                return true;
+            }
+            expired.push(offender.prefix_len());
+            false
+        });
+        for len in expired {
+            self.forget_length(len);
+        }
+    }
+
+    fn forget_length(&mut self, len: u8) {
+        if let Some(count) = self.lengths.get_mut(&len) {
+            *count -= 1;
+            if *count == 0 {
+                self.lengths.remove(&len);
+            }
+        }
+    }
+}
+
+impl Evidence {
+    /// Start the writer thread, writing to `dir` (created if missing). New
+    /// files and write errors are reported to `events`. An offender's frames
+    /// are saved for `timeout` after it trips a limit, at most: usually the
+    /// block duration.
+    pub fn start(
+        config: &EvidenceConfig,
+        dir: &Path,
+        timeout: Duration,
+        events: EventLog,
+        metrics: Arc<Metrics>,
+    ) -> io::Result<Evidence> {
+        fs::create_dir_all(dir)?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let writer = Writer {
+            dir: dir.to_path_buf(),
+            max_file_size: config.max_file_mb * 1_000_000,
+            max_files: config.max_files,
+            file: None,
+            unflushed: 0,
+            files: VecDeque::new(),
+            sequence: 0,
+            failed: false,
+            backoff: Backoff::new(RETRY_FIRST, RETRY_MAX),
+            retry_at: None,
+            events,
+            metrics: Arc::clone(&metrics),
+        };
+        let writer = thread::Builder::new()
+            .name("evidence".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Evidence {
+            sender,
+            writer: Mutex::new(Some(writer)),
+            pre_trigger: config.pre_trigger,
+            post_trigger: config.post_trigger,
+            timeout,
+            offenders: Mutex::new(Offenders::default()),
+            recording: AtomicBool::new(false),
+            metrics,
+        })
+    }
+
+    /// Write out every frame saved so far and wait for the files to be
+    /// closed. Frames saved after this are dropped.
+    pub fn close(&self) {
+        // Unlike frames, this waits for room in the queue.
+        let _ = self.sender.send(Message::Close);
+        let writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+    }
+
+    /// A recorder for one capture thread, whose frames are saved as captured
+    /// on `interface`.
+    pub fn recorder(&self, interface: &str) -> Recorder<'_> {
+        Recorder {
+            evidence: self,
+            interface: interface.into(),
+            // One more than asked for, so the frame that trips a limit is
+            // always still there.
+            ring: VecDeque::with_capacity(self.pre_trigger + 1),
+        }
+    }
+
+    fn offenders(&self) -> MutexGuard<'_, Offenders> {
+        self.offenders
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn send(&self, record: Record) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Message::Frame(record)) {
+            self.metrics.evidence_dropped(1);
+        }
+    }
+}
+
+// A frame in the ring.
+struct Entry {
+    source: Source,
+    timestamp: Duration,
+    len: usize,
+    data: Vec<u8>,
+    // Already sent to the writer, as one of an offender's frames.
+    written: bool,
+}
+
+/// One capture thread's recent frames.
+pub struct Recorder<'a> {
+    evidence: &'a Evidence,
+    interface: Arc<str>,
+    ring: VecDeque<Entry>,
+}
+
+impl Recorder<'_> {
+    /// Remember a frame from `source`, and save it if `source` is an
+    /// offender being recorded.
+    pub fn frame(&mut self, frame: &Frame, source: Source) {
+        let mut written = false;
+        if self.evidence.recording.load(Ordering::Relaxed) {
+            let mut offenders = self.evidence.offenders();
+            offenders.expire(frame.timestamp);
+            if let Some(offender) = offenders.find(source, frame.timestamp) {
+                offenders.take(offender);
+                written = true;
+                self.evidence.send(Record {
+                    interface: Arc::clone(&self.interface),
+                    timestamp: frame.timestamp,
+                    len: frame.len,
+                    data: frame.data.to_vec(),
+                    comment: format!("after {} went over a limit", offender),
+                });
+            }
+            self.evidence
+                .recording
+                .store(!offenders.remaining.is_empty(), Ordering::Relaxed);
+        }
+
+        // Reuse the oldest entry's buffer once the ring is full, so this
+        // doesn't allocate per frame.
+        let mut data = if self.ring.len() > self.evidence.pre_trigger {
+            self.ring
+                .pop_front()
+                .map(|entry| entry.data)
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        data.clear();
+        data.extend_from_slice(frame.data);
+        self.ring.push_back(Entry {
+            source,
+            timestamp: frame.timestamp,
+            len: frame.len,
+            data,
+            written,
+        });
+    }
+
+    /// `offender` (a source, or a prefix for a prefix-level limit) just
+    /// went over a limit, as described by `reason`, with the frame last
+    /// passed to `frame`. Save its frames from the ring, and start saving
+    /// its next ones.
+    pub fn trigger(&mut self, offender: Prefix, reason: &Event) {
+        let now = self.ring.back().map_or_else(now, |entry| entry.timestamp);
+        let mut offenders = self.evidence.offenders();
+        // Still being recorded from an earlier trigger, e.g. by another
+        // policy: its frames are already on their way.
+        if offenders.is_recording(&offender, now) {
+            return;
+        }
+        if self.evidence.post_trigger > 0 {
+            let until = now.saturating_add(self.evidence.timeout);
+            offenders.insert(offender, self.evidence.post_trigger, until);
+            self.evidence.recording.store(true, Ordering::Relaxed);
+        }
+        drop(offenders);
+
+        let reason = serde_json::to_string(reason).unwrap_or_default();
+        let last = self.ring.len().saturating_sub(1);
+        for (i, entry) in self.ring.iter_mut().enumerate() {
+            if entry.written
+                || Prefix::from(entry.source).truncate(offender.prefix_len()) != offender
+            {
+                continue;
+            }
+            entry.written = true;
+            let comment = if i == last {
+                reason.clone()
+            } else {
+                format!("before {} went over a limit", offender)
+            };
+            self.evidence.send(Record {
+                interface: Arc::clone(&self.interface),
+                timestamp: entry.timestamp,
+                len: entry.len,
+                data: entry.data.clone(),
+                comment,
+            });
+        }
+    }
+}
+
+// The writer thread's state.
+struct Writer {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    file: Option<PcapngWriter<BufWriter<File>>>,
+    // Frames written to `file` since it was last flushed. `BufWriter` keeps
+    // write errors to itself until then, so they only count as saved once
+    // a flush works.
+    unflushed: u64,
+    // Files written so far, oldest first, for deleting the oldest.
+    files: VecDeque<PathBuf>,
+    // Tells apart files started within the same second.
+    sequence: u64,
+    // Set after a write error, so a full disk is reported once rather than
+    // for every frame.
+    failed: bool,
+    // After an error, no new file is started before `retry_at`, and the
+    // wait grows while errors go on, so a full disk doesn't get a new file
+    // for every batch.
+    backoff: Backoff,
+    retry_at: Option<Instant>,
+    events: EventLog,
+    metrics: Arc<Metrics>,
+}
+
+impl Writer {
+    // Write frames until told to close, or until the `Evidence` is gone.
+    // Each batch is flushed as soon as the queue is empty, so the files can
+    // be opened while they're still being written.
+    fn run(mut self, receiver: Receiver<Message>) {
+        let mut message = receiver.recv();
+        while let Ok(Message::Frame(record)) = message {
+            self.write(record);
+            message = match receiver.try_recv() {
+                Ok(next) => Ok(next),
+                Err(TryRecvError::Empty) => {
+                    self.flush();
+                    receiver.recv()
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+        }
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        match file.flush() {
+            Ok(()) => self.flushed(),
+            Err(e) => self.error(e),
+        }
+    }
+
+    // Everything written to the current file so far is in it.
+    fn flushed(&mut self) {
+        if self.unflushed > 0 {
+            self.metrics.evidence_frames(self.unflushed);
+            self.unflushed = 0;
+            self.failed = false;
+            self.backoff.reset();
+        }
+    }
+
+    fn write(&mut self, record: Record) {
+        // Still waiting to try again after an error.
+        if self.file.is_none() && self.retry_at.is_some_and(|at| Instant::now() < at) {
+            self.metrics.evidence_dropped(1);
+            return;
+        }
+        let result = self.file().and_then(|file| {
+            let comment = Some(record.comment.as_str());
+            file.write_packet(
+                &record.interface,
+                record.timestamp,
+                &record.data,
+                record.len,
+                comment,
+            )
+        });
+        match result {
+            Ok(()) => self.unflushed += 1,
+            Err(e) => {
+                self.metrics.evidence_dropped(1);
+                self.error(e);
+            }
+        }
+    }
+
+    // The file to write to: the current one, or a new one if there's none
+    // yet or it's full.
+    fn file(&mut self) -> io::Result<&mut PcapngWriter<BufWriter<File>>> {
+        let file = match self.file.take() {
+            Some(file) if file.written() < self.max_file_size => file,
+            // Dropping the `BufWriter` would flush it too, but ignore any
+            // error, so flush by hand first.
+            Some(mut full) => {
+                full.flush()?;
+                self.flushed();
+                self.create()?
+            }
+            None => self.create()?,
+        };
+        // `insert` stores the file and hands back a reference to it.
+        Ok(self.file.insert(file))
+    }
+
+    fn create(&mut self) -> io::Result<PcapngWriter<BufWriter<File>>> {
+        let path = self.dir.join(format!(
+            "evidence-{}-{}.pcapng",
+            now().as_secs(),
+            self.sequence
+        ));
+        self.sequence += 1;
+        let file = BufWriter::new(File::create(&path)?);
+        let writer = PcapngWriter::new(
+            file,
+            concat!("packet_processor ", env!("CARGO_PKG_VERSION")),
+        )?;
+        self.events
+            .emit(now(), &Event::EvidenceFile { path: path.clone() });
+
+        self.files.push_back(path);
+        while self.max_files > 0 && self.files.len() > self.max_files {
+            if let Some(oldest) = self.files.pop_front()
+                && let Err(e) = fs::remove_file(&oldest)
+            {
+                let error = format!("error deleting '{}': {}", oldest.display(), e);
+                self.events.emit(now(), &Event::EvidenceError { error });
+            }
+        }
+        Ok(writer)
+    }
+
+    fn error(&mut self, e: io::Error) {
+        // Whatever was still buffered is given up on along with the file.
+        self.metrics.evidence_dropped(self.unflushed);
+        self.unflushed = 0;
+        // Start a new file after a while, in case it was this one that broke.
+        self.file = None;
+        self.retry_at = Some(Instant::now() + self.backoff.next_delay());
+        if !self.failed {
+            self.events.emit(
+                now(),
+                &Event::EvidenceError {
+                    error: e.to_string(),
+                },
+            );
+        }
+        self.failed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limiter::Unit;
+    use crate::pcap::PcapReader;
+    use std::net::Ipv4Addr;
+
+    // A directory in the temp directory, removed with everything in it when
+    // dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let name = format!("packet_processor-{}-evidence-{name}", std::process::id());
+            let dir = std::env::temp_dir().join(name);
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        // The evidence files in it, oldest first, with the timestamps of
+        // the frames in each.
+        fn files(&self) -> Vec<(String, Vec<Duration>)> {
+            let mut names: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            // The sequence number tells apart files made in the same second.
+            names.sort_by_key(|name| {
+                let sequence = name.trim_end_matches(".pcapng").rsplit('-').next();
+                sequence.unwrap().parse::<u64>().unwrap()
+            });
+            names
+                .into_iter()
+                .map(|name| {
+                    let file = File::open(self.0.join(&name)).unwrap();
+                    let mut reader = PcapReader::new(file).unwrap();
+                    let mut times = Vec::new();
+                    while let Some(record) = reader.next_packet().unwrap() {
+                        times.push(record.timestamp);
+                    }
+                    (name, times)
+                })
+                .collect()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // The value of the counter `name` in the metrics output.
+    fn metric(metrics: &Metrics, name: &str) -> u64 {
+        let rendered = metrics.render();
+        let line = rendered
+            .lines()
+            .find(|line| {
+                line.split(' ')
+                    .next()
+                    .is_some_and(|key| key.ends_with(name))
+            })
+            .unwrap();
+        line.rsplit(' ').next().unwrap().parse().unwrap()
+    }
+
+    fn at(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    fn source(last: u8) -> Source {
+        Source::Ipv4(Ipv4Addr::new(10, 0, 0, last))
+    }
+
+    // `data`, captured `seconds` into the capture.
+    fn frame(data: &[u8; 60], seconds: u64) -> Frame<'_> {
+        Frame {
+            data,
+            len: data.len(),
+            timestamp: at(seconds),
+        }
+    }
+
+    fn exceeded(last: u8) -> Event {
+        Event::LimitExceeded {
+            source: source(last),
+            prefix: None,
+            policy: None,
+            key: None,
+            unit: Unit::Packets,
+            used: 101,
+            limit: 100,
+        }
+    }
+
+    fn start(dir: &TempDir, pre_trigger: usize, post_trigger: u32) -> (Evidence, Arc<Metrics>) {
+        let config = EvidenceConfig {
+            dir: Some(dir.0.clone()),
+            pre_trigger,
+            post_trigger,
+            ..EvidenceConfig::default()
+        };
+        let metrics = Arc::new(Metrics::new());
+        let events = EventLog::new(Box::new(io::sink()));
+        let evidence = Evidence::start(&config, &dir.0, at(10), events, Arc::clone(&metrics));
+        (evidence.unwrap(), metrics)
+    }
+
+    // A writer, driven by hand instead of from its thread.
+    fn writer(dir: &Path, max_file_size: u64, max_files: usize) -> (Writer, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::new());
+        let writer = Writer {
+            dir: dir.to_path_buf(),
+            max_file_size,
+            max_files,
+            file: None,
+            unflushed: 0,
+            files: VecDeque::new(),
+            sequence: 0,
+            failed: false,
+            backoff: Backoff::new(RETRY_FIRST, RETRY_MAX),
+            retry_at: None,
+            events: EventLog::new(Box::new(io::sink())),
+            metrics: Arc::clone(&metrics),
+        };
+        (writer, metrics)
+    }
+
+    fn record(seconds: u64) -> Record {
+        Record {
+            interface: "eth0".into(),
+            timestamp: at(seconds),
+            len: 100,
+            data: vec![0; 100],
+            comment: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn saves_an_offenders_frames_before_and_after_it_trips() {
+        let dir = TempDir::new("trigger");
+        let (evidence, metrics) = start(&dir, 3, 2);
+        let mut recorder = evidence.recorder("eth0");
+        let data = [0; 60];
+        // The ring keeps the tripping frame and the three before it, so the
+        // first is gone.
+        for (last, seconds) in [(1, 0), (1, 1), (1, 2), (2, 3), (1, 4)] {
+            recorder.frame(&frame(&data, seconds), source(last));
+        }
+        recorder.trigger(Prefix::from(source(1)), &exceeded(1));
+        // Two more frames are saved after the trigger, and no others.
+        for (last, seconds) in [(2, 5), (1, 6), (1, 7), (1, 8)] {
+            recorder.frame(&frame(&data, seconds), source(last));
+        }
+        evidence.close();
+
+        let files = dir.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].1, [at(1), at(2), at(4), at(6), at(7)]);
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 5);
+        assert_eq!(metric(&metrics, "evidence_dropped_total"), 0);
+    }
+
+    #[test]
+    fn stops_recording_an_offender_once_its_time_is_up() {
+        let dir = TempDir::new("timeout");
+        let (evidence, _) = start(&dir, 0, 1000);
+        let mut recorder = evidence.recorder("eth0");
+        let data = [0; 60];
+        recorder.frame(&frame(&data, 100), source(1));
+        recorder.trigger(Prefix::from(source(1)), &exceeded(1));
+        recorder.frame(&frame(&data, 105), source(1));
+        // Ten seconds after the trigger: no longer saved, and forgotten.
+        recorder.frame(&frame(&data, 110), source(1));
+        assert!(evidence.offenders().remaining.is_empty());
+        assert!(!evidence.recording.load(Ordering::Relaxed));
+        // Tripping again starts over.
+        recorder.trigger(Prefix::from(source(1)), &exceeded(1));
+        recorder.frame(&frame(&data, 111), source(1));
+        evidence.close();
+
+        assert_eq!(dir.files()[0].1, [at(100), at(105), at(110), at(111)]);
+    }
+
+    #[test]
+    fn forgets_offenders_that_go_quiet() {
+        let dir = TempDir::new("quiet");
+        let (evidence, _) = start(&dir, 0, 1000);
+        let mut recorder = evidence.recorder("eth0");
+        let data = [0; 60];
+        recorder.frame(&frame(&data, 100), source(1));
+        recorder.trigger(Prefix::from(source(1)), &exceeded(1));
+        // Only other sources send from here on.
+        recorder.frame(&frame(&data, 105), source(2));
+        assert!(evidence.recording.load(Ordering::Relaxed));
+        recorder.frame(&frame(&data, 111), source(2));
+        assert!(evidence.offenders().remaining.is_empty());
+        assert!(!evidence.recording.load(Ordering::Relaxed));
+        evidence.close();
+    }
+
+    #[test]
+    fn starts_a_new_file_at_the_size_cap_and_keeps_the_newest() {
+        let dir = TempDir::new("rotate");
+        // Room for a few 100-byte frames per file.
+        let (mut writer, metrics) = writer(&dir.0, 500, 2);
+        for seconds in 0..12 {
+            writer.write(record(seconds));
+        }
+        writer.flush();
+        drop(writer);
+
+        let files = dir.files();
+        assert_eq!(files.len(), 2);
+        // Each file goes over the cap with at most one frame, and the oldest
+        // ones were deleted.
+        for (name, _) in &files {
+            let size = fs::metadata(dir.0.join(name)).unwrap().len();
+            assert!(size < 500 + 200, "{}: {} bytes", name, size);
+        }
+        let times: Vec<Duration> = files.iter().flat_map(|(_, times)| times.clone()).collect();
+        assert_eq!(times.last(), Some(&at(11)));
+        assert!(times.len() < 12);
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 12);
+    }
+
+    #[test]
+    fn keeps_every_file_with_no_count_cap() {
+        let dir = TempDir::new("uncapped");
+        let (mut writer, _) = writer(&dir.0, 500, 0);
+        for seconds in 0..12 {
+            writer.write(record(seconds));
+        }
+        writer.flush();
+        drop(writer);
+
+        let files = dir.files();
+        assert!(files.len() > 2);
+        let frames: usize = files.iter().map(|(_, times)| times.len()).sum();
+        assert_eq!(frames, 12);
+    }
+
+    #[test]
+    fn counts_frames_only_once_flushed() {
+        let dir = TempDir::new("flush");
+        let (mut writer, metrics) = writer(&dir.0, 1_000_000, 0);
+        writer.write(record(1));
+        writer.write(record(2));
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 0);
+        writer.flush();
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 2);
+        // Nothing new to count.
+        writer.flush();
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 2);
+    }
+
+    #[test]
+    fn backs_off_after_an_error() {
+        let dir = TempDir::new("backoff");
+        let missing = dir.0.join("missing");
+        let (mut writer, metrics) = writer(&missing, 1_000_000, 0);
+        writer.write(record(1));
+        assert!(writer.failed);
+        assert_eq!(metric(&metrics, "evidence_dropped_total"), 1);
+        // Until the wait is over, frames are dropped without trying again.
+        fs::create_dir(&missing).unwrap();
+        writer.write(record(2));
+        assert!(writer.file.is_none());
+        assert_eq!(metric(&metrics, "evidence_dropped_total"), 2);
+
+        writer.retry_at = Some(Instant::now());
+        writer.write(record(3));
+        writer.flush();
+        assert!(!writer.failed);
+        assert_eq!(metric(&metrics, "evidence_frames_total"), 1);
+        assert_eq!(metric(&metrics, "evidence_dropped_total"), 2);
+        // A working flush resets the wait for the next error.
+        assert_eq!(writer.backoff.attempts(), 0);
+    }
+}
@@ -0,0 +1,1003 @@
+// Capture filters: a tcpdump-style expression such as
+//
+//   tcp port 80 or (udp and dst port 53) and not src net 10.0.0.0/8
+//
+// compiled to a classic BPF program. On a live interface the program is
+// attached to the capture socket, so the kernel drops frames that don't
+// match before they are ever copied to us. Capture files have no kernel to
+// run it, so `Filter::matches` runs the same program in a small interpreter,
+// which keeps the two behaving exactly alike.
+//
+// Supported primitives, combined with `and`/`&&`, `or`/`||`, `not`/`!` and
+// parentheses:
+//
+//   ip  ip6  arp  tcp  udp  icmp  icmp6  proto N
+//   [src|dst] host ADDR          IPv4 or IPv6
+//   [src|dst] net PREFIX         e.g. 10.0.0.0/8, 2001:db8::/32
+//   [tcp|udp] [src|dst] port N   TCP or UDP (IPv4 without fragments, IPv6
+//                                without extension headers)
+//   ether [src|dst] host MAC
+//   less N  greater N            frame length
+//
+// Like tcpdump, offsets assume an untagged Ethernet frame; on a live socket
+// the kernel has already taken any VLAN tag off.
+use std::fmt;
+use std::str::FromStr;
+
+use crate::lists::Prefix;
+use crate::source::Source;
+
+// Classic BPF opcodes, from <linux/filter.h>. An instruction's `code` is the
+// sum of a class, a size or operation, and an addressing mode.
+const LD: u16 = 0x00;
+const LDX: u16 = 0x01;
+const ALU: u16 = 0x04;
+const JMP: u16 = 0x05;
+const RET: u16 = 0x06;
+const W: u16 = 0x00;
+const H: u16 = 0x08;
+const B: u16 = 0x10;
+const ABS: u16 = 0x20;
+const IND: u16 = 0x40;
+const LEN: u16 = 0x80;
+const MSH: u16 = 0xa0;
+const AND: u16 = 0x50;
+const JA: u16 = 0x00;
+const JEQ: u16 = 0x10;
+const JGT: u16 = 0x20;
+const JGE: u16 = 0x30;
+const JSET: u16 = 0x40;
+
+// How many bytes of an accepted frame the kernel passes on: all of them.
+const ACCEPT: u32 = 262_144;
+
+/// One classic BPF instruction, laid out like the kernel's `sock_filter`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Instruction {
+    pub code: u16,
+    /// Instructions to skip when a conditional jump is taken.
+    pub jt: u8,
+    /// Instructions to skip when it isn't.
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// A compiled filter expression. The default, empty filter matches every
+/// frame.
+#[derive(Clone, Debug)]
+pub struct Filter {
+    // The expression as written, for messages and config comparisons.
+    text: String,
+    program: Vec<Instruction>,
+}
+
+impl Default for Filter {
+    fn default() -> Filter {
+        Filter {
+            text: String::new(),
+            program: vec![Instruction {
+                code: RET,
+                jt: 0,
+                jf: 0,
+                k: ACCEPT,
+            }],
+        }
+    }
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// The BPF program, ready for `SO_ATTACH_FILTER`.
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Whether a frame passes the filter. `len` is its length on the wire,
+    /// which `less` and `greater` compare against.
+    pub fn matches(&self, data: &[u8], len: usize) -> bool {
+        run(&self.program, data, len) != 0
+    }
+}
+
+// Filters are equal when they were written the same way.
+impl PartialEq for Filter {
+    fn eq(&self, other: &Filter) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Filter {}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let tokens = tokenize(s);
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let node = if tokens.is_empty() {
+            None
+        } else {
+            Some(parser.or()?)
+        };
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected '{}' in filter", token));
+        }
+        let program = compile(node.as_ref())?;
+        Ok(Filter {
+            text: s.trim().to_string(),
+            program,
+        })
+    }
+}
+
+// Split an expression into words, parentheses, `!`, `&&` and `||`.
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let operator = match c {
+            '(' | ')' | '!' => Some(c.to_string()),
+            '&' | '|' if chars.peek() == Some(&c) => {
+                chars.next();
+                Some(format!("{}{}", c, c))
+            }
+            _ => None,
+        };
+        if operator.is_some() || c.is_whitespace() {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            tokens.extend(operator);
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+// The expression tree. Every leaf is one comparison on the frame.
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Test(Test),
+}
+
+// Load a value, optionally mask it, and compare it with `value`.
+#[derive(Clone, Copy)]
+struct Test {
+    load: Load,
+    mask: Option<u32>,
+    op: Op,
+    value: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Load {
+    // `size` bytes at a fixed offset into the frame.
+    Abs { size: u16, offset: u32 },
+    // `size` bytes at `offset` past the end of a variable-length IPv4
+    // header.
+    Ipv4Payload { size: u16, offset: u32 },
+    // The frame's length.
+    Len,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Gt,
+    Ge,
+    // Any of the bits in `value` set.
+    Set,
+}
+
+// Builders for the tree, so the parser reads like the grammar.
+fn and(a: Node, b: Node) -> Node {
+    Node::And(Box::new(a), Box::new(b))
+}
+
+fn or(a: Node, b: Node) -> Node {
+    Node::Or(Box::new(a), Box::new(b))
+}
+
+fn not(a: Node) -> Node {
+    Node::Not(Box::new(a))
+}
+
+fn test(load: Load, op: Op, value: u32) -> Node {
+    Node::Test(Test {
+        load,
+        mask: None,
+        op,
+        value,
+    })
+}
+
+fn abs(size: u16, offset: u32) -> Load {
+    Load::Abs { size, offset }
+}
+
+// Offsets into an untagged Ethernet frame.
+const ETHERTYPE: u32 = 12;
+const IPV4_PROTOCOL: u32 = 23;
+const IPV4_FLAGS: u32 = 20;
+const IPV4_SOURCE: u32 = 26;
+const IPV4_DESTINATION: u32 = 30;
+const IPV6_NEXT_HEADER: u32 = 20;
+const IPV6_SOURCE: u32 = 22;
+const IPV6_DESTINATION: u32 = 38;
+const IPV6_PAYLOAD: u32 = 54;
+
+fn ethertype(value: u32) -> Node {
+    test(abs(H, ETHERTYPE), Op::Eq, value)
+}
+
+fn ipv4_protocol(protocol: u32) -> Node {
+    and(
+        ethertype(0x0800),
+        test(abs(B, IPV4_PROTOCOL), Op::Eq, protocol),
+    )
+}
+
+fn ipv6_protocol(protocol: u32) -> Node {
+    and(
+        ethertype(0x86dd),
+        test(abs(B, IPV6_NEXT_HEADER), Op::Eq, protocol),
+    )
+}
+
+fn protocol(protocol: u32) -> Node {
+    or(ipv4_protocol(protocol), ipv6_protocol(protocol))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Source,
+    Destination,
+    Either,
+}
+
+// `build(true)` tests the source side, `build(false)` the destination.
+fn directed(direction: Direction, build: impl Fn(bool) -> Node) -> Node {
+    match direction {
+        Direction::Source => build(true),
+        Direction::Destination => build(false),
+        Direction::Either => or(build(true), build(false)),
+    }
+}
+
+// Compare the address at `offset` with `prefix`, a word at a time.
+fn address(offset: u32, bits: &[u8], len: u8) -> Node {
+    let mut node: Option<Node> = None;
+    for (i, word) in bits.chunks(4).enumerate() {
+        let used = u32::from(len).saturating_sub(i as u32 * 32).min(32);
+        if used == 0 {
+            break;
+        }
+        let mask = if used == 32 {
+            u32::MAX
+        } else {
+            u32::MAX << (32 - used)
+        };
+        let value = u32::from_be_bytes([word[0], word[1], word[2], word[3]]) & mask;
+        let load = abs(W, offset + i as u32 * 4);
+        let leaf = Node::Test(Test {
+            load,
+            mask: (mask != u32::MAX).then_some(mask),
+            op: Op::Eq,
+            value,
+        });
+        // `Option::take` moves the value out, leaving `None` behind.
+        node = Some(match node.take() {
+            Some(node) => and(node, leaf),
+            None => leaf,
+        });
+    }
+    // A /0 matches every address.
+    node.unwrap_or_else(|| test(Load::Len, Op::Ge, 0))
+}
+
+fn host(direction: Direction, prefix: Prefix) -> Result<Node, String> {
+    let len = prefix.prefix_len();
+    match prefix.network() {
+        Source::Ipv4(addr) => Ok(and(
+            ethertype(0x0800),
+            directed(direction, |source| {
+                address(
+                    if source {
+                        IPV4_SOURCE
+                    } else {
+                        IPV4_DESTINATION
+                    },
+                    &addr.octets(),
+                    len,
+                )
+            }),
+        )),
+        Source::Ipv6(addr) => Ok(and(
+            ethertype(0x86dd),
+            directed(direction, |source| {
+                address(
+                    if source {
+                        IPV6_SOURCE
+                    } else {
+                        IPV6_DESTINATION
+                    },
+                    &addr.octets(),
+                    len,
+                )
+            }),
+        )),
+        Source::Mac(_) => Err(format!(
+            "'{}' is not an IP address (use `ether host` for MACs)",
+            prefix
+        )),
+    }
+}
+
+fn port(direction: Direction, port: u32, transport: Option<u32>) -> Node {
+    let protocols = |family: fn(u32) -> Node| match transport {
+        Some(protocol) => family(protocol),
+        None => or(family(6), family(17)),
+    };
+    // Later fragments have no ports, so skip any with a fragment offset.
+    let ipv4 = and(
+        protocols(ipv4_protocol),
+        and(
+            not(test(abs(H, IPV4_FLAGS), Op::Set, 0x1fff)),
+            directed(direction, |source| {
+                test(
+                    Load::Ipv4Payload {
+                        size: H,
+                        offset: if source { 0 } else { 2 },
+                    },
+                    Op::Eq,
+                    port,
+                )
+            }),
+        ),
+    );
+    let ipv6 = and(
+        protocols(ipv6_protocol),
+        directed(direction, |source| {
+            test(
+                abs(H, IPV6_PAYLOAD + if source { 0 } else { 2 }),
+                Op::Eq,
+                port,
+            )
+        }),
+    );
+    or(ipv4, ipv6)
+}
+
+fn ether_host(direction: Direction, mac: &str) -> Result<Node, String> {
+    let Ok(Source::Mac(mac)) = mac.parse::<Source>() else {
+        return Err(format!("'{}' is not a MAC address", mac));
+    };
+    let b = mac.octets();
+    Ok(directed(direction, |source| {
+        let offset = if source { 6 } else { 0 };
+        and(
+            test(
+                abs(W, offset),
+                Op::Eq,
+                u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            ),
+            test(
+                abs(H, offset + 4),
+                Op::Eq,
+                u32::from(u16::from_be_bytes([b[4], b[5]])),
+            ),
+        )
+    }))
+}
+
+// A recursive-descent parser: one method per precedence level, lowest
+// first, so `a or b and c` is `a or (b and c)`.
+struct Parser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+
+    fn next(&mut self) -> Result<&str, String> {
+        let token = self.tokens.get(self.pos).ok_or("filter ends too early")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    // Take the next token if it's one of `words`.
+    fn accept(&mut self, words: &[&str]) -> bool {
+        let found = self.peek().is_some_and(|token| words.contains(&token));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.accept(&["or", "||"]) {
+            node = or(node, self.and()?);
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.not()?;
+        while self.accept(&["and", "&&"]) {
+            node = and(node, self.not()?);
+        }
+        Ok(node)
+    }
+
+    fn not(&mut self) -> Result<Node, String> {
+        if self.accept(&["not", "!"]) {
+            return Ok(not(self.not()?));
+        }
+        if self.accept(&["("]) {
+            let node = self.or()?;
+            if !self.accept(&[")"]) {
+                return Err("missing ')' in filter".to_string());
+            }
+            return Ok(node);
+        }
+        self.primitive()
+    }
+
+    fn number(&mut self, max: u32) -> Result<u32, String> {
+        let token = self.next()?;
+        token.parse().ok().filter(|&n| n <= max).ok_or_else(|| {
+            format!(
+                "expected a number up to {} in filter, found '{}'",
+                max, token
+            )
+        })
+    }
+
+    fn direction(&mut self) -> Direction {
+        if self.accept(&["src"]) {
+            Direction::Source
+        } else if self.accept(&["dst"]) {
+            Direction::Destination
+        } else {
+            Direction::Either
+        }
+    }
+
+    fn primitive(&mut self) -> Result<Node, String> {
+        // `tcp port 80` is one primitive; a lone `tcp` is another.
+        if let Some(transport @ ("tcp" | "udp")) = self.peek()
+            && matches!(
+                self.tokens.get(self.pos + 1).map(String::as_str),
+                Some("port" | "src" | "dst")
+            )
+        {
+            let transport = if transport == "tcp" { 6 } else { 17 };
+            self.pos += 1;
+            let direction = self.direction();
+            if !self.accept(&["port"]) {
+                return Err("expected 'port' in filter".to_string());
+            }
+            return Ok(port(
+                direction,
+                self.number(u16::MAX.into())?,
+                Some(transport),
+            ));
+        }
+
+        let token = self.next()?.to_string();
+        let node = match token.as_str() {
+            "ip" => ethertype(0x0800),
+            "ip6" => ethertype(0x86dd),
+            "arp" => ethertype(0x0806),
+            "tcp" => protocol(6),
+            "udp" => protocol(17),
+            "icmp" => ipv4_protocol(1),
+            "icmp6" => ipv6_protocol(58),
+            "proto" => protocol(self.number(u8::MAX.into())?),
+            "less" => not(test(Load::Len, Op::Gt, self.number(u32::MAX)?)),
+            "greater" => test(Load::Len, Op::Ge, self.number(u32::MAX)?),
+            "ether" => {
+                let direction = self.direction();
+                if !self.accept(&["host"]) {
+                    return Err("expected 'host' after 'ether' in filter".to_string());
+                }
+                ether_host(direction, self.next()?)?
+            }
+            "src" | "dst" | "host" | "net" | "port" => {
+                self.pos -= 1;
+                let direction = self.direction();
+                match self.next()? {
+                    "host" => {
+                        let addr = self.next()?;
+                        let prefix: Prefix = addr.parse()?;
+                        if !prefix.is_address() {
+                            return Err(format!("'{}' is a network; use `net`", addr));
+                        }
+                        host(direction, prefix)?
+                    }
+                    "net" => host(direction, self.next()?.parse()?)?,
+                    "port" => port(direction, self.number(u16::MAX.into())?, None),
+                    other => {
+                        return Err(format!(
+                            "expected 'host', 'net' or 'port' in filter, found '{}'",
+                            other
+                        ));
+                    }
+                }
+            }
+            other => return Err(format!("unknown filter primitive '{}'", other)),
+        };
+        Ok(node)
+    }
+}
+
+// Code generation. Each node is compiled with the places to jump to when it
+// is true and when it is false, so `and`, `or` and `not` cost no
+// instructions of their own, only jump targets. Every jump goes forward,
+// as classic BPF requires.
+struct Codegen {
+    code: Vec<(Instruction, Option<(usize, usize)>)>,
+    // Where each label ended up, once placed.
+    labels: Vec<usize>,
+}
+
+impl Codegen {
+    fn label(&mut self) -> usize {
+        self.labels.push(usize::MAX);
+        self.labels.len() - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.labels[label] = self.code.len();
+    }
+
+    fn emit(&mut self, code: u16, k: u32) {
+        self.code.push((
+            Instruction {
+                code,
+                jt: 0,
+                jf: 0,
+                k,
+            },
+            None,
+        ));
+    }
+
+    fn compile(&mut self, node: &Node, yes: usize, no: usize) {
+        match node {
+            Node::And(a, b) => {
+                let next = self.label();
+                self.compile(a, next, no);
+                self.place(next);
+                self.compile(b, yes, no);
+            }
+            Node::Or(a, b) => {
+                let next = self.label();
+                self.compile(a, yes, next);
+                self.place(next);
+                self.compile(b, yes, no);
+            }
+            Node::Not(a) => self.compile(a, no, yes),
+            Node::Test(test) => {
+                match test.load {
+                    Load::Abs { size, offset } => self.emit(LD | size | ABS, offset),
+                    Load::Ipv4Payload { size, offset } => {
+                        // X = the IPv4 header length, 4 * the low nibble of
+                        // its first byte; then load relative to it.
+                        self.emit(LDX | B | MSH, 14);
+                        self.emit(LD | size | IND, 14 + offset);
+                    }
+                    Load::Len => self.emit(LD | W | LEN, 0),
+                }
+                if let Some(mask) = test.mask {
+                    self.emit(ALU | AND, mask);
+                }
+                let op = match test.op {
+                    Op::Eq => JEQ,
+                    Op::Gt => JGT,
+                    Op::Ge => JGE,
+                    Op::Set => JSET,
+                };
+                let jump = Instruction {
+                    code: JMP | op,
+                    jt: 0,
+                    jf: 0,
+                    k: test.value,
+                };
+                self.code.push((jump, Some((yes, no))));
+            }
+        }
+    }
+}
+
+fn compile(node: Option<&Node>) -> Result<Vec<Instruction>, String> {
+    let mut codegen = Codegen {
+        code: Vec::new(),
+        labels: Vec::new(),
+    };
+    let (accept, reject) = (codegen.label(), codegen.label());
+    // An empty filter accepts everything.
+    if let Some(node) = node {
+        codegen.compile(node, accept, reject);
+    }
+    codegen.place(accept);
+    codegen.emit(RET, ACCEPT);
+    codegen.place(reject);
+    codegen.emit(RET, 0);
+
+    // Turn jump labels into the relative offsets BPF uses, which only have
+    // 8 bits.
+    let Codegen { code, labels } = codegen;
+    code.into_iter()
+        .enumerate()
+        .map(|(i, (mut instruction, targets))| {
+            if let Some((yes, no)) = targets {
+                let offset = |label: usize| u8::try_from(labels[label] - i - 1);
+                instruction.jt = offset(yes).map_err(|_| "filter is too long".to_string())?;
+                instruction.jf = offset(no).map_err(|_| "filter is too long".to_string())?;
+            }
+            Ok(instruction)
+        })
+        .collect()
+}
+
+// Run a program the way the kernel would, returning how many bytes to
+// keep (0 means drop). Loads past the end of the frame drop it, as in the
+// kernel.
+fn run(program: &[Instruction], data: &[u8], len: usize) -> u32 {
+    let load = |offset: u32, size: u16| -> Option<u32> {
+        let start = offset as usize;
+        let bytes = data.get(
+            start
+                ..start
+                    + match size {
+                        W => 4,
+                        H => 2,
+                        _ => 1,
+                    },
+        )?;
+        // Fold big-endian bytes into one number, like `binary.BigEndian`.
+        Some(
+            bytes
+                .iter()
+                .fold(0, |acc, &byte| (acc << 8) | u32::from(byte)),
+        )
+    };
+    let (mut a, mut x) = (0u32, 0u32);
+    let mut pc = 0;
+    while let Some(&Instruction { code, jt, jf, k }) = program.get(pc) {
+        pc += 1;
+        let size = code & 0x18;
+        match code & 0x07 {
+            LD => {
+                a = match code & 0xe0 {
+                    ABS => match load(k, size) {
+                        Some(value) => value,
+                        None => return 0,
+                    },
+                    IND => match load(x.wrapping_add(k), size) {
+                        Some(value) => value,
+                        None => return 0,
+                    },
+                    LEN => len as u32,
+                    _ => return 0,
+                }
+            }
+            LDX if code & 0xe0 == MSH => match load(k, B) {
+                Some(value) => x = (value & 0x0f) * 4,
+                None => return 0,
+            },
+            ALU if code & 0xf0 == AND => a &= k,
+            JMP => {
+                let taken = match code & 0xf0 {
+                    JA => {
+                        pc += k as usize;
+                        continue;
+                    }
+                    JEQ => a == k,
+                    JGT => a > k,
+                    JGE => a >= k,
+                    JSET => a & k != 0,
+                    _ => return 0,
+                };
+                pc += usize::from(if taken { jt } else { jf });
+            }
+            RET => return k,
+            // Nothing above emits anything else.
+            _ => return 0,
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const DESTINATION_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+    fn ethernet(ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = [DESTINATION_MAC, SOURCE_MAC].concat();
+        frame.extend(ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    // An IPv4 packet carrying `payload` as `protocol`, with `options` and
+    // the flags and fragment offset field `fragment`.
+    fn ipv4(
+        source: [u8; 4],
+        destination: [u8; 4],
+        protocol: u8,
+        options: &[u8],
+        fragment: u16,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let header_len = 20 + options.len();
+        let mut packet = vec![0x40 | (header_len / 4) as u8, 0];
+        packet.extend(((header_len + payload.len()) as u16).to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(fragment.to_be_bytes());
+        packet.extend([64, protocol, 0, 0]);
+        packet.extend(source);
+        packet.extend(destination);
+        packet.extend(options);
+        packet.extend(payload);
+        packet
+    }
+
+    fn ipv6(source: &str, destination: &str, next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let address = |s: &str| s.parse::<std::net::Ipv6Addr>().unwrap().octets();
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend((payload.len() as u16).to_be_bytes());
+        packet.extend([next_header, 64]);
+        packet.extend(address(source));
+        packet.extend(address(destination));
+        packet.extend(payload);
+        packet
+    }
+
+    // The start of a TCP or UDP header: just the ports, padded out.
+    fn ports(source: u16, destination: u16) -> Vec<u8> {
+        let mut header = source.to_be_bytes().to_vec();
+        header.extend(destination.to_be_bytes());
+        header.extend([0; 16]);
+        header
+    }
+
+    fn tcp4(source: [u8; 4], destination: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let ip = ipv4(source, destination, 6, &[], 0, &ports(sport, dport));
+        ethernet(0x0800, &ip)
+    }
+
+    fn udp4(source: [u8; 4], destination: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let ip = ipv4(source, destination, 17, &[], 0, &ports(sport, dport));
+        ethernet(0x0800, &ip)
+    }
+
+    fn udp6(source: &str, destination: &str, sport: u16, dport: u16) -> Vec<u8> {
+        ethernet(0x86dd, &ipv6(source, destination, 17, &ports(sport, dport)))
+    }
+
+    fn arp() -> Vec<u8> {
+        let mut packet = vec![0, 1, 8, 0, 6, 4, 0, 1];
+        packet.extend(SOURCE_MAC);
+        packet.extend([192, 0, 2, 1, 0, 0, 0, 0, 0, 0, 192, 0, 2, 2]);
+        ethernet(0x0806, &packet)
+    }
+
+    fn compiled(expression: &str) -> Filter {
+        expression.parse().unwrap()
+    }
+
+    // Whether `expression` matches each frame, compared as a whole so a
+    // failure shows every verdict.
+    fn verdicts(expression: &str, frames: &[&[u8]]) -> Vec<bool> {
+        let filter = compiled(expression);
+        frames
+            .iter()
+            .map(|frame| filter.matches(frame, frame.len()))
+            .collect()
+    }
+
+    const A: [u8; 4] = [10, 0, 0, 1];
+    const B: [u8; 4] = [10, 0, 1, 2];
+    const C: [u8; 4] = [192, 0, 2, 1];
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = Filter::default();
+        assert!(filter.is_empty());
+        assert!(filter.matches(&arp(), 42));
+        assert!(filter.matches(&[], 0));
+        assert_eq!(filter, compiled(""));
+    }
+
+    #[test]
+    fn compiles_to_forward_jumps() {
+        let program = [
+            Instruction {
+                code: LD | H | ABS,
+                jt: 0,
+                jf: 0,
+                k: ETHERTYPE,
+            },
+            Instruction {
+                code: JMP | JEQ,
+                jt: 0,
+                jf: 1,
+                k: 0x0806,
+            },
+            Instruction {
+                code: RET,
+                jt: 0,
+                jf: 0,
+                k: ACCEPT,
+            },
+            Instruction {
+                code: RET,
+                jt: 0,
+                jf: 0,
+                k: 0,
+            },
+        ];
+        assert_eq!(compiled("arp").program(), program);
+        assert_eq!(run(&program, &arp(), 42), ACCEPT);
+        // Too short to load the EtherType.
+        assert_eq!(run(&program, &arp()[..12], 12), 0);
+    }
+
+    #[test]
+    fn matches_hosts_by_direction() {
+        let (ab, ba, bc) = (tcp4(A, B, 1, 2), tcp4(B, A, 1, 2), tcp4(B, C, 1, 2));
+        let frames: [&[u8]; 4] = [&ab, &ba, &bc, &arp()];
+        assert_eq!(
+            verdicts("host 10.0.0.1", &frames),
+            [true, true, false, false]
+        );
+        assert_eq!(
+            verdicts("src host 10.0.0.1", &frames),
+            [true, false, false, false]
+        );
+        assert_eq!(
+            verdicts("dst host 10.0.0.1", &frames),
+            [false, true, false, false]
+        );
+
+        let v6 = udp6("2001:db8::1", "2001:db8:1::2", 1, 2);
+        let frames: [&[u8]; 2] = [&v6, &ab];
+        assert_eq!(verdicts("host 2001:db8::1", &frames), [true, false]);
+        assert_eq!(verdicts("dst host 2001:db8::1", &frames), [false, false]);
+    }
+
+    #[test]
+    fn matches_networks() {
+        let (ab, bc) = (tcp4(A, B, 1, 2), tcp4(B, C, 1, 2));
+        let frames: [&[u8]; 2] = [&ab, &bc];
+        assert_eq!(verdicts("net 10.0.0.0/24", &frames), [true, false]);
+        assert_eq!(verdicts("src net 10.0.0.0/8", &frames), [true, true]);
+        assert_eq!(verdicts("dst net 10.0.0.0/8", &frames), [true, false]);
+        assert_eq!(verdicts("net 0.0.0.0/0", &frames), [true, true]);
+
+        let (inside, outside) = (
+            udp6("2001:db8:1::5", "2001:db8::1", 1, 2),
+            udp6("2001:db9::5", "2001:db8::1", 1, 2),
+        );
+        let frames: [&[u8]; 2] = [&inside, &outside];
+        assert_eq!(verdicts("src net 2001:db8::/32", &frames), [true, false]);
+        assert_eq!(verdicts("src net 2001:db8:1::/48", &frames), [true, false]);
+    }
+
+    #[test]
+    fn matches_ports() {
+        let options = [1, 1, 1, 1];
+        let with_options = ethernet(0x0800, &ipv4(A, B, 6, &options, 0, &ports(40000, 80)));
+        // The last fragment of a packet: what looks like ports is data.
+        let fragment = ethernet(0x0800, &ipv4(A, B, 6, &[], 185, &ports(40000, 80)));
+        let frames: [&[u8]; 6] = [
+            &tcp4(A, B, 40000, 80),
+            &udp4(A, B, 40000, 80),
+            &udp6("::1", "::2", 80, 40000),
+            &with_options,
+            &fragment,
+            &arp(),
+        ];
+        assert_eq!(
+            verdicts("port 80", &frames),
+            [true, true, true, true, false, false]
+        );
+        assert_eq!(
+            verdicts("tcp port 80", &frames),
+            [true, false, false, true, false, false]
+        );
+        assert_eq!(
+            verdicts("udp src port 80", &frames),
+            [false, false, true, false, false, false]
+        );
+        assert_eq!(
+            verdicts("dst port 80", &frames),
+            [true, true, false, true, false, false]
+        );
+    }
+
+    #[test]
+    fn matches_frame_length() {
+        let frame = tcp4(A, B, 1, 2);
+        let filter = compiled("less 100");
+        assert!(filter.matches(&frame, 100));
+        assert!(!filter.matches(&frame, 101));
+        // The wire length counts, not how much was captured.
+        assert!(!filter.matches(&frame[..40], 1514));
+        let filter = compiled("greater 100");
+        assert!(filter.matches(&frame, 100));
+        assert!(!filter.matches(&frame, 99));
+    }
+
+    #[test]
+    fn combines_with_and_or_not() {
+        let (tcp, udp, arp) = (tcp4(A, B, 1, 80), udp4(A, C, 1, 53), arp());
+        let frames: [&[u8]; 3] = [&tcp, &udp, &arp];
+        assert_eq!(verdicts("tcp or udp", &frames), [true, true, false]);
+        assert_eq!(verdicts("ip and not tcp", &frames), [false, true, false]);
+        assert_eq!(verdicts("!ip", &frames), [false, false, true]);
+        assert_eq!(
+            verdicts("tcp port 80 or (udp and dst port 53)", &frames),
+            [true, true, false]
+        );
+        // `and` binds tighter than `or`.
+        assert_eq!(
+            verdicts("arp or udp and dst net 10.0.0.0/8", &frames),
+            [false, false, true]
+        );
+        assert_eq!(
+            verdicts("(arp or udp) && ! dst net 10.0.0.0/8", &frames),
+            [false, true, true]
+        );
+        assert_eq!(
+            verdicts("ether src host 02:00:00:00:00:01 && not icmp", &frames),
+            [true, true, true]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for expression in [
+            "tcp port",
+            "port 65536",
+            "host 10.0.0.0/8",
+            "host 02:00:00:00:00:01",
+            "(tcp",
+            "tcp)",
+            "tcp and",
+            "ether host 10.0.0.1",
+            "frobnicate",
+        ] {
+            assert!(expression.parse::<Filter>().is_err(), "{expression}");
+        }
+    }
+}
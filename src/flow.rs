@@ -0,0 +1,586 @@
+// What policies count by, and which packets they count.
+//
+// A `KeySpec` picks the fields packets are grouped by: any combination of a
+// packet's source, destination, ports and IP protocol, e.g.
+// `destination+destination-port` to protect one service on one host, or
+// `5-tuple` to limit single connections.
+//
+// A `Match` narrows a policy down to the packets that matter for one kind of
+// flood, read from the dissected headers: `syn` for TCP SYNs without ACK,
+// `echo-request` for pings, `udp+dport=53` for DNS, `arp-request`, ...
+use pnet::packet::ethernet::EthernetPacket;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dissect::{ArpOperation, Network, Summary, TcpFlags, Transport};
+use crate::protocol::Protocol;
+use crate::source::Source;
+
+/// The addressing of one packet: who sent it to whom, and over what.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Flow {
+    /// The sender, as `Source::of` sees it.
+    pub source: Source,
+    /// The receiver: the destination IP address, or the destination MAC for
+    /// non-IP frames.
+    pub destination: Source,
+    /// The IP protocol number (6 for TCP, 17 for UDP, ...), for IP packets.
+    pub protocol: Option<u8>,
+    /// Ports, for TCP and UDP.
+    pub source_port: Option<u16>,
+    pub destination_port: Option<u16>,
+}
+
+impl Flow {
+    pub fn of(ethernet: &EthernetPacket) -> Flow {
+        Summary::of(ethernet).flow()
+    }
+}
+
+/// One part of a packet that a key can be built from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+    Source,
+    Destination,
+    SourcePort,
+    DestinationPort,
+    Protocol,
+}
+
+impl Field {
+    pub const ALL: [Field; 5] = [
+        Field::Source,
+        Field::Destination,
+        Field::SourcePort,
+        Field::DestinationPort,
+        Field::Protocol,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Source => "source",
+            Field::Destination => "destination",
+            Field::SourcePort => "source-port",
+            Field::DestinationPort => "destination-port",
+            Field::Protocol => "protocol",
+        }
+    }
+
+    // Each field is one bit in a `KeySpec`.
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Which fields a policy counts packets by. Written as field names joined by
+/// `+`, e.g. `source+destination-port`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct KeySpec {
+    // A bit set of `Field`s, like Go's `iota` flag constants.
+    fields: u8,
+}
+
+impl KeySpec {
+    /// Source and destination address and port, and the protocol.
+    pub const FIVE_TUPLE: KeySpec = KeySpec { fields: 0b1_1111 };
+
+    pub fn has(self, field: Field) -> bool {
+        self.fields & field.bit() != 0
+    }
+
+    /// The key for `flow`, or `None` if the packet lacks one of the fields,
+    /// e.g. an ICMP packet for a key with a port in it.
+    pub fn key(self, flow: &Flow) -> Option<Key> {
+        // `bool::then` gives `Some(value)` only when the field is wanted; `?`
+        // on a wanted but missing port or protocol gives up on the key.
+        let want = |field| self.has(field);
+        Some(Key {
+            source: want(Field::Source).then_some(flow.source),
+            destination: want(Field::Destination).then_some(flow.destination),
+            source_port: if want(Field::SourcePort) {
+                Some(flow.source_port?)
+            } else {
+                None
+            },
+            destination_port: if want(Field::DestinationPort) {
+                Some(flow.destination_port?)
+            } else {
+                None
+            },
+            protocol: if want(Field::Protocol) {
+                Some(flow.protocol?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl FromStr for KeySpec {
+    type Err = String;
+
+    /// Parses field names joined by `+`. `src`, `dst`, `sport`, `dport` and
+    /// `proto` are accepted as short names, and `5-tuple` means all five.
+    fn from_str(s: &str) -> Result<KeySpec, String> {
+        if s == "5-tuple" || s == "flow" {
+            return Ok(KeySpec::FIVE_TUPLE);
+        }
+        let mut fields = 0;
+        for name in s.split('+') {
+            let field = match name.trim() {
+                "src" => Field::Source,
+                "dst" => Field::Destination,
+                "sport" => Field::SourcePort,
+                "dport" => Field::DestinationPort,
+                "proto" => Field::Protocol,
+                name => Field::ALL
+                    .into_iter()
+                    .find(|field| field.name() == name)
+                    .ok_or_else(|| format!("unknown key field '{}'", name))?,
+            };
+            fields |= field.bit();
+        }
+        Ok(KeySpec { fields })
+    }
+}
+
+impl fmt::Display for KeySpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = Field::ALL
+            .into_iter()
+            .filter(|&field| self.has(field))
+            .map(Field::name)
+            .collect();
+        f.write_str(&names.join("+"))
+    }
+}
+
+/// The values of a packet's key fields. Fields the key doesn't use are
+/// `None`, and are left out when the key is written to the event log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct Key {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<Source>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<Source>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<u8>,
+}
+
+/// One thing a packet has to be for a policy to count it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Protocol(Protocol),
+    /// A TCP SYN without ACK: a connection attempt, as sent by SYN floods.
+    Syn,
+    /// TCP with all of `set` and none of `clear` set.
+    TcpFlags {
+        set: TcpFlags,
+        clear: TcpFlags,
+    },
+    /// An ICMP or ICMPv6 echo request (ping).
+    EchoRequest,
+    /// ICMP or ICMPv6 of this type.
+    IcmpType(u8),
+    ArpRequest,
+    ArpReply,
+    SourcePort(u16),
+    DestinationPort(u16),
+}
+
+impl Condition {
+    pub fn matches(self, summary: &Summary) -> bool {
+        let tcp_flags = match summary.transport {
+            Some(Transport::Tcp { flags, .. }) => Some(flags),
+            _ => None,
+        };
+        let icmp_type = match summary.transport {
+            Some(Transport::Icmp { icmp_type, .. } | Transport::Icmpv6 { icmp_type, .. }) => {
+                Some(icmp_type)
+            }
+            _ => None,
+        };
+        let arp = match summary.network {
+            Some(Network::Arp { operation, .. }) => Some(operation),
+            _ => None,
+        };
+        match self {
+            Condition::Protocol(protocol) => summary.protocol() == protocol,
+            Condition::Syn => tcp_flags.is_some_and(|flags| {
+                flags.contains(TcpFlags::SYN) && !flags.contains(TcpFlags::ACK)
+            }),
+            Condition::TcpFlags { set, clear } => {
+                tcp_flags.is_some_and(|flags| flags.contains(set) && flags.0 & clear.0 == 0)
+            }
+            // Type 8 for ICMP, 128 for ICMPv6.
+            Condition::EchoRequest => match summary.transport {
+                Some(Transport::Icmp { icmp_type, .. }) => icmp_type == 8,
+                Some(Transport::Icmpv6 { icmp_type, .. }) => icmp_type == 128,
+                _ => false,
+            },
+            Condition::IcmpType(wanted) => icmp_type == Some(wanted),
+            Condition::ArpRequest => arp == Some(ArpOperation::Request),
+            Condition::ArpReply => arp == Some(ArpOperation::Reply),
+            Condition::SourcePort(port) => {
+                summary.ports().is_some_and(|(source, _)| source == port)
+            }
+            Condition::DestinationPort(port) => summary
+                .ports()
+                .is_some_and(|(_, destination)| destination == port),
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        let condition = match s {
+            "syn" => Condition::Syn,
+            "echo-request" => Condition::EchoRequest,
+            "arp-request" => Condition::ArpRequest,
+            "arp-reply" => Condition::ArpReply,
+            _ => {
+                // Everything else is a protocol name, or `name=value`.
+                let Some((name, value)) = s.split_once('=') else {
+                    return Protocol::ALL
+                        .into_iter()
+                        .find(|protocol| protocol.name() == s)
+                        .map(Condition::Protocol)
+                        .ok_or_else(|| format!("unknown match '{}'", s));
+                };
+                let number = |max: u64| {
+                    value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|&n| n <= max)
+                        .ok_or_else(|| format!("invalid value in '{}'", s))
+                };
+                match name {
+                    "sport" | "source-port" => {
+                        Condition::SourcePort(number(u16::MAX.into())? as u16)
+                    }
+                    "dport" | "destination-port" => {
+                        Condition::DestinationPort(number(u16::MAX.into())? as u16)
+                    }
+                    "icmp-type" => Condition::IcmpType(number(u8::MAX.into())? as u8),
+                    "tcp-flags" => parse_tcp_flags(value)
+                        .ok_or_else(|| format!("invalid TCP flags in '{}'", s))?,
+                    _ => return Err(format!("unknown match '{}'", s)),
+                }
+            }
+        };
+        Ok(condition)
+    }
+}
+
+// Parse `syn,!ack`: flags that have to be set, and with `!`, clear.
+fn parse_tcp_flags(s: &str) -> Option<Condition> {
+    let (mut set, mut clear) = (TcpFlags::default(), TcpFlags::default());
+    for name in s.split(',') {
+        // `strip_prefix` gives `Some(rest)` if `name` starts with `!`.
+        let (flags, name) = match name.strip_prefix('!') {
+            Some(name) => (&mut clear, name),
+            None => (&mut set, name),
+        };
+        let (flag, _) = TcpFlags::NAMES
+            .iter()
+            .find(|(_, flag_name)| *flag_name == name)?;
+        flags.0 |= flag.0;
+    }
+    Some(Condition::TcpFlags { set, clear })
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Protocol(protocol) => f.write_str(protocol.name()),
+            Condition::Syn => f.write_str("syn"),
+            Condition::TcpFlags { set, clear } => {
+                let names = TcpFlags::NAMES.iter().filter_map(|&(flag, name)| {
+                    if set.contains(flag) {
+                        Some(name.to_string())
+                    } else if clear.contains(flag) {
+                        Some(format!("!{}", name))
+                    } else {
+                        None
+                    }
+                });
+                write!(f, "tcp-flags={}", names.collect::<Vec<_>>().join(","))
+            }
+            Condition::EchoRequest => f.write_str("echo-request"),
+            Condition::IcmpType(icmp_type) => write!(f, "icmp-type={}", icmp_type),
+            Condition::ArpRequest => f.write_str("arp-request"),
+            Condition::ArpReply => f.write_str("arp-reply"),
+            Condition::SourcePort(port) => write!(f, "sport={}", port),
+            Condition::DestinationPort(port) => write!(f, "dport={}", port),
+        }
+    }
+}
+
+/// The packets a policy counts: those meeting every condition. An empty
+/// match counts every packet.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Match {
+    conditions: Vec<Condition>,
+}
+
+impl Match {
+    pub fn matches(&self, summary: &Summary) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(summary))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+}
+
+impl FromStr for Match {
+    type Err = String;
+
+    /// Parses conditions joined by `+`, e.g. `udp+dport=53`. A condition is
+    /// a protocol (`tcp`, `udp`, `icmp`, `icmpv6`, `arp`), `syn`,
+    /// `tcp-flags=syn,!ack`, `echo-request`, `icmp-type=N`, `arp-request`,
+    /// `arp-reply`, `sport=N` or `dport=N`.
+    fn from_str(s: &str) -> Result<Match, String> {
+        let conditions = s
+            .split('+')
+            .map(|condition| condition.trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Match { conditions })
+    }
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let conditions: Vec<String> = self.conditions.iter().map(Condition::to_string).collect();
+        f.write_str(&conditions.join("+"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dissect::Vlans;
+    use pnet::datalink::MacAddr;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+
+    fn summary(network: Network, transport: Option<Transport>) -> Summary {
+        Summary {
+            source_mac: MacAddr(0x02, 0, 0, 0, 0, 0x01),
+            destination_mac: MacAddr(0x02, 0, 0, 0, 0, 0x02),
+            vlans: Vlans::default(),
+            ethertype: match network {
+                Network::Ipv4 { .. } => 0x0800,
+                Network::Ipv6 { .. } => 0x86dd,
+                Network::Arp { .. } => 0x0806,
+            },
+            network: Some(network),
+            transport,
+        }
+    }
+
+    fn ipv4(protocol: u8, transport: Transport) -> Summary {
+        let network = Network::Ipv4 {
+            source: SOURCE,
+            destination: DESTINATION,
+            ttl: 64,
+            protocol,
+            options: 0,
+            fragment: None,
+        };
+        summary(network, Some(transport))
+    }
+
+    fn tcp(destination_port: u16, flags: TcpFlags) -> Summary {
+        let transport = Transport::Tcp {
+            source_port: 40000,
+            destination_port,
+            flags,
+        };
+        ipv4(6, transport)
+    }
+
+    fn udp(destination_port: u16) -> Summary {
+        let transport = Transport::Udp {
+            source_port: 40000,
+            destination_port,
+        };
+        ipv4(17, transport)
+    }
+
+    fn icmp(icmp_type: u8) -> Summary {
+        ipv4(1, Transport::Icmp { icmp_type, code: 0 })
+    }
+
+    fn icmpv6(icmp_type: u8) -> Summary {
+        let network = Network::Ipv6 {
+            source: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1),
+            destination: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+            hop_limit: 64,
+            protocol: 58,
+            extension_headers: 0,
+            fragment: None,
+        };
+        summary(network, Some(Transport::Icmpv6 { icmp_type, code: 0 }))
+    }
+
+    fn arp(operation: ArpOperation) -> Summary {
+        let network = Network::Arp {
+            operation,
+            sender_mac: MacAddr(0x02, 0, 0, 0, 0, 0x01),
+            sender_ip: SOURCE,
+            target_ip: DESTINATION,
+        };
+        summary(network, None)
+    }
+
+    fn matches(s: &str, summary: &Summary) -> bool {
+        s.parse::<Match>().unwrap().matches(summary)
+    }
+
+    #[test]
+    fn parses_key_specs() {
+        let spec: KeySpec = "destination+destination-port".parse().unwrap();
+        assert_eq!(spec.to_string(), "destination+destination-port");
+        assert_eq!("dst + dport".parse(), Ok(spec));
+        assert_eq!("5-tuple".parse(), Ok(KeySpec::FIVE_TUPLE));
+        assert_eq!("proto+dport+sport+dst+src".parse(), Ok(KeySpec::FIVE_TUPLE));
+        for bad in ["", "source+", "source+colour", "5tuple", "src,dst"] {
+            assert!(bad.parse::<KeySpec>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn keys_keep_only_their_fields() {
+        let flow = tcp(80, TcpFlags::SYN).flow();
+        let spec: KeySpec = "destination+destination-port".parse().unwrap();
+        assert_eq!(
+            spec.key(&flow),
+            Some(Key {
+                source: None,
+                destination: Some(Source::Ipv4(DESTINATION)),
+                source_port: None,
+                destination_port: Some(80),
+                protocol: None,
+            })
+        );
+        assert_eq!(KeySpec::FIVE_TUPLE.key(&flow).unwrap().protocol, Some(6));
+        // Pings have no ports to key on.
+        assert_eq!(spec.key(&icmp(8).flow()), None);
+        assert!(
+            "source"
+                .parse::<KeySpec>()
+                .unwrap()
+                .key(&icmp(8).flow())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn parses_and_prints_matches() {
+        for s in [
+            "syn",
+            "echo-request",
+            "arp-request",
+            "arp-reply",
+            "udp+dport=53",
+            "tcp+sport=80",
+            "icmp-type=3",
+            "tcp-flags=syn,!ack",
+        ] {
+            assert_eq!(s.parse::<Match>().unwrap().to_string(), s);
+        }
+        assert_eq!(
+            "source-port=80".parse::<Match>().unwrap().to_string(),
+            "sport=80"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields_and_flags() {
+        for bad in [
+            "",
+            "colour",
+            "syn+",
+            "ttl=64",
+            "dport=65536",
+            "dport=http",
+            "sport=",
+            "icmp-type=256",
+            "tcp-flags=syn,bogus",
+            "tcp-flags=",
+            "tcp-flags=!",
+        ] {
+            assert!(bad.parse::<Match>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn syn_means_syn_without_ack() {
+        assert!(matches("syn", &tcp(80, TcpFlags::SYN)));
+        assert!(matches(
+            "syn",
+            &tcp(80, TcpFlags(TcpFlags::SYN.0 | TcpFlags::ECE.0))
+        ));
+        assert!(!matches(
+            "syn",
+            &tcp(80, TcpFlags(TcpFlags::SYN.0 | TcpFlags::ACK.0))
+        ));
+        assert!(!matches("syn", &tcp(80, TcpFlags::ACK)));
+        assert!(!matches("syn", &udp(80)));
+
+        assert!(matches("tcp-flags=syn,!ack", &tcp(80, TcpFlags::SYN)));
+        assert!(matches(
+            "tcp-flags=rst",
+            &tcp(80, TcpFlags(TcpFlags::RST.0 | TcpFlags::ACK.0))
+        ));
+        assert!(!matches(
+            "tcp-flags=fin,!ack",
+            &tcp(80, TcpFlags(TcpFlags::FIN.0 | TcpFlags::ACK.0))
+        ));
+    }
+
+    #[test]
+    fn echo_request_means_pings_over_either_ip() {
+        assert!(matches("echo-request", &icmp(8)));
+        assert!(matches("echo-request", &icmpv6(128)));
+        assert!(!matches("echo-request", &icmp(0)));
+        assert!(!matches("echo-request", &icmpv6(129)));
+        assert!(!matches("echo-request", &udp(7)));
+        assert!(matches("icmp-type=3", &icmp(3)));
+        assert!(matches("icmpv6+icmp-type=135", &icmpv6(135)));
+        assert!(!matches("icmp+icmp-type=135", &icmpv6(135)));
+    }
+
+    #[test]
+    fn matches_arp_by_operation() {
+        assert!(matches("arp-request", &arp(ArpOperation::Request)));
+        assert!(!matches("arp-request", &arp(ArpOperation::Reply)));
+        assert!(matches("arp-reply", &arp(ArpOperation::Reply)));
+        assert!(!matches("arp-reply", &arp(ArpOperation::Other(9))));
+        assert!(matches("arp", &arp(ArpOperation::Request)));
+        assert!(!matches("arp-request", &udp(53)));
+    }
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        assert!(matches("udp+dport=53", &udp(53)));
+        assert!(!matches("udp+dport=53", &udp(5353)));
+        assert!(!matches("udp+dport=53", &tcp(53, TcpFlags::SYN)));
+        assert!(matches("sport=40000+dport=80", &tcp(80, TcpFlags::ACK)));
+        assert!(!matches("dport=80", &icmp(8)));
+        // An empty match counts every packet.
+        assert!(Match::default().matches(&arp(ArpOperation::Request)));
+    }
+}
@@ -0,0 +1,291 @@
+// Hierarchical rate limits. Counting only single addresses lets an attacker
+// spread traffic over a /24 or an IPv6 /64 and keep every address under the
+// limit, so sources can also be counted together by the prefix they fall in,
+// with separate limits for each level:
+//
+//   level      10.0.0.7 is counted as    limit
+//   /32 /128   10.0.0.7                  100 packets per 10s
+//   /24 /64    10.0.0.0/24               1000 packets per 10s
+//
+// Every packet counts at every level, and a level that goes over its limit
+// reports the whole prefix, so the caller can block all of it.
+use std::time::Duration;
+
+use crate::algorithm::AlgorithmKind;
+use crate::limiter::{Limits, RateLimiter, Unit, Verdict};
+use crate::lists::Prefix;
+use crate::source::Source;
+
+/// The prefix lengths one level groups IPv4 and IPv6 sources by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Grouping {
+    pub ipv4: u8,
+    pub ipv6: u8,
+}
+
+impl Grouping {
+    /// Every address on its own.
+    pub const ADDRESS: Grouping = Grouping {
+        ipv4: 32,
+        ipv6: 128,
+    };
+
+    /// The prefix `source` is counted under at this level. MAC sources are
+    /// only counted at the address level, since MAC prefixes say nothing
+    /// about where traffic comes from.
+    pub fn group(&self, source: Source) -> Option<Prefix> {
+        let prefix = Prefix::from(source);
+        match source {
+            Source::Ipv4(_) => Some(prefix.truncate(self.ipv4)),
+            Source::Ipv6(_) => Some(prefix.truncate(self.ipv6)),
+            Source::Mac(_) => (*self == Grouping::ADDRESS).then_some(prefix),
+        }
+    }
+}
+
+/// The limits for one level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LevelLimits {
+    pub grouping: Grouping,
+    pub limits: Limits,
+}
+
+/// A level went over its limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Exceeded {
+    /// What went over: the source itself at the address level, otherwise
+    /// the prefix it's in.
+    pub prefix: Prefix,
+    pub unit: Unit,
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// A stack of rate limiters, one per level, all fed the same packets.
+pub struct HierarchicalLimiter {
+    levels: Vec<(Grouping, RateLimiter<Prefix>)>,
+}
+
+impl HierarchicalLimiter {
+    pub fn new(kind: AlgorithmKind, levels: &[LevelLimits]) -> HierarchicalLimiter {
+        let levels = levels
+            .iter()
+            .map(|level| (level.grouping, RateLimiter::new(kind, level.limits)))
+            .collect();
+        HierarchicalLimiter { levels }
+    }
+
+    /// Count one packet of `len` bytes from `source` at every level. If any
+    /// level went over, the widest one is reported, since blocking that
+    /// covers the narrower ones too.
+    pub fn record(&self, source: Source, now: Duration, len: usize) -> Option<Exceeded> {
+        let mut widest: Option<Exceeded> = None;
+        for (grouping, limiter) in &self.levels {
+            let Some(prefix) = grouping.group(source) else {
+                continue;
+            };
+            if let Verdict::Exceeded { unit, used, limit } = limiter.record(prefix, now, len)
+                && widest.is_none_or(|widest| prefix.prefix_len() < widest.prefix.prefix_len())
+            {
+                widest = Some(Exceeded {
+                    prefix,
+                    unit,
+                    used,
+                    limit,
+                });
+            }
+        }
+        widest
+    }
+
+    /// Apply new limits while running, keeping counters where possible (see
+    /// `RateLimiter::reconfigure`). Levels are matched up by grouping; adding
+    /// or removing a level needs a new limiter.
+    pub fn reconfigure(&self, kind: AlgorithmKind, levels: &[LevelLimits], now: Duration) {
+        for level in levels {
+            if let Some((_, limiter)) = self
+                .levels
+                .iter()
+                .find(|(grouping, _)| *grouping == level.grouping)
+            {
+                limiter.reconfigure(kind, level.limits, now);
+            }
+        }
+    }
+
+    /// Number of sources and prefixes tracked, over all levels.
+    pub fn len(&self) -> usize {
+        self.levels.iter().map(|(_, limiter)| limiter.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::Limit;
+    use crate::lists::{Lists, PrefixSet};
+
+    fn packets(amount: u64) -> Limits {
+        Limits {
+            packets: Some(Limit {
+                amount,
+                window: Duration::from_secs(10),
+            }),
+            bytes: None,
+        }
+    }
+
+    // `per_address` packets per address, and `per_24` per IPv4 /24 (and
+    // IPv6 /64).
+    fn limiter(per_address: u64, per_24: u64) -> HierarchicalLimiter {
+        HierarchicalLimiter::new(
+            AlgorithmKind::FixedWindow,
+            &[
+                LevelLimits {
+                    grouping: Grouping::ADDRESS,
+                    limits: packets(per_address),
+                },
+                LevelLimits {
+                    grouping: Grouping { ipv4: 24, ipv6: 64 },
+                    limits: packets(per_24),
+                },
+            ],
+        )
+    }
+
+    fn source(s: &str) -> Source {
+        s.parse().unwrap()
+    }
+
+    fn prefix(s: &str) -> Prefix {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn groups_sources_by_level() {
+        let per_24 = Grouping { ipv4: 24, ipv6: 64 };
+        assert_eq!(
+            per_24.group(source("10.0.0.7")),
+            Some(prefix("10.0.0.0/24"))
+        );
+        assert_eq!(
+            per_24.group(source("2001:db8::7")),
+            Some(prefix("2001:db8::/64"))
+        );
+        assert_eq!(per_24.group(source("02:00:00:00:00:07")), None);
+        assert_eq!(
+            Grouping::ADDRESS.group(source("02:00:00:00:00:07")),
+            Some(prefix("02:00:00:00:00:07"))
+        );
+    }
+
+    #[test]
+    fn many_quiet_addresses_trip_their_prefix() {
+        let limiter = limiter(5, 20);
+        let now = Duration::ZERO;
+        // 20 addresses, one packet each: nobody is over on their own, and
+        // the /24 is exactly at its limit.
+        for host in 1..=20 {
+            let source = source(&format!("10.0.0.{}", host));
+            assert_eq!(limiter.record(source, now, 60), None);
+        }
+        let exceeded = limiter.record(source("10.0.0.21"), now, 60).unwrap();
+        assert_eq!(exceeded.prefix, prefix("10.0.0.0/24"));
+        assert_eq!(
+            (exceeded.unit, exceeded.used, exceeded.limit),
+            (Unit::Packets, 21, 20)
+        );
+        // A neighbouring /24 has its own count.
+        assert_eq!(limiter.record(source("10.0.1.1"), now, 60), None);
+        // 22 addresses and 2 prefixes.
+        assert_eq!(limiter.len(), 24);
+    }
+
+    #[test]
+    fn reports_the_widest_level_over() {
+        let limiter = limiter(2, 3);
+        let now = Duration::ZERO;
+        let one = source("10.0.0.1");
+        assert_eq!(limiter.record(one, now, 60), None);
+        assert_eq!(limiter.record(one, now, 60), None);
+        // Only the address is over.
+        assert_eq!(
+            limiter.record(one, now, 60).map(|exceeded| exceeded.prefix),
+            Some(prefix("10.0.0.1"))
+        );
+        // Now both are, and the /24 wins.
+        assert_eq!(
+            limiter.record(one, now, 60).map(|exceeded| exceeded.prefix),
+            Some(prefix("10.0.0.0/24"))
+        );
+    }
+
+    #[test]
+    fn counts_mac_sources_only_by_address() {
+        let limiter = limiter(2, 1);
+        let mac = source("02:00:00:00:00:07");
+        assert_eq!(limiter.record(mac, Duration::ZERO, 60), None);
+        assert_eq!(limiter.record(mac, Duration::ZERO, 60), None);
+        assert_eq!(
+            limiter
+                .record(mac, Duration::ZERO, 60)
+                .map(|exceeded| exceeded.prefix),
+            Some(prefix("02:00:00:00:00:07"))
+        );
+    }
+
+    #[test]
+    fn a_tripped_prefix_with_allowed_hosts_blocks_one_source() {
+        let limiter = limiter(100, 2);
+        let mut allow = PrefixSet::new();
+        allow.insert(prefix("10.0.0.5"));
+        let lists = Lists {
+            allow,
+            deny: PrefixSet::new(),
+        };
+        let now = Duration::ZERO;
+        limiter.record(source("10.0.0.1"), now, 60);
+        limiter.record(source("10.0.0.2"), now, 60);
+        let exceeded = limiter.record(source("10.0.0.3"), now, 60).unwrap();
+        assert_eq!(exceeded.prefix, prefix("10.0.0.0/24"));
+        assert_eq!(
+            lists.block_target(exceeded.prefix, source("10.0.0.3")),
+            prefix("10.0.0.3")
+        );
+
+        // A /24 without allowed hosts is blocked whole.
+        limiter.record(source("10.0.9.1"), now, 60);
+        limiter.record(source("10.0.9.2"), now, 60);
+        let exceeded = limiter.record(source("10.0.9.3"), now, 60).unwrap();
+        assert_eq!(
+            lists.block_target(exceeded.prefix, source("10.0.9.3")),
+            prefix("10.0.9.0/24")
+        );
+    }
+
+    #[test]
+    fn reconfigure_matches_levels_by_grouping() {
+        let limiter = limiter(100, 1);
+        limiter.reconfigure(
+            AlgorithmKind::FixedWindow,
+            &[LevelLimits {
+                grouping: Grouping { ipv4: 24, ipv6: 64 },
+                limits: packets(3),
+            }],
+            Duration::ZERO,
+        );
+        for host in 1..=3 {
+            let source = source(&format!("10.0.0.{}", host));
+            assert_eq!(limiter.record(source, Duration::ZERO, 60), None);
+        }
+        assert!(
+            limiter
+                .record(source("10.0.0.4"), Duration::ZERO, 60)
+                .is_some()
+        );
+    }
+}
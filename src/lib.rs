@@ -0,0 +1,92 @@
+//! Per-source packet rate limiting on top of `pnet`.
+//!
+//! The pieces fit together like this:
+//!
+//! - a [`PacketSource`] hands out Ethernet frames, either from a live
+//!   interface ([`LiveCapture`], or [`PacketSocket`] with a [`Filter`]
+//!   running in the kernel) or a capture file ([`FileCapture`]), and
+//!   several can be read from their own threads at once, including several
+//!   threads sharing one interface through a [`FanoutMode`];
+//! - [`Summary::of`] dissects a frame's headers, through VLAN tags down to
+//!   TCP, UDP or ICMP, and [`Summary::source`] decides which address it is
+//!   counted against;
+//! - a [`RateLimiter`] tracks each source's packets and bytes with one of
+//!   several [`Algorithm`]s and says when one is over its [`Limits`]. It
+//!   can be shared between capture threads, and a [`HierarchicalLimiter`]
+//!   stacks several of them to also limit whole prefixes;
+//! - a [`PolicyLimiter`] limits packets by other [`Flow`] fields, such as
+//!   the destination, a port or the whole 5-tuple, optionally counting only
+//!   packets that [`Match`] a kind of flood, such as TCP SYNs;
+//! - [`Lists`] exempt some sources from limiting and always block others,
+//!   matched by address prefix;
+//! - a [`Blocklist`] keeps offenders blocked for a while through an
+//!   [`Enforcer`];
+//! - a [`Config`] describes all of the above, and can be loaded from a TOML
+//!   file and reloaded while running;
+//! - an [`EventLog`] reports what happened as JSON lines, and [`Metrics`]
+//!   counts it for a Prometheus scrape;
+//! - a [`Responder`] answers packets that go over a policy's limit with a
+//!   TCP reset, an ICMP error or an ARP correction, as the policy's
+//!   [`Response`]s say;
+//! - a [`Forwarder`] bridges two interfaces, sending on the frames the
+//!   limits let through, and counts each [`Direction`];
+//! - [`Evidence`] saves the frames of sources that went over a limit to
+//!   pcapng files;
+//! - [`Talkers`] count traffic per source, for the [`Report`] summing up a
+//!   run.
+//!
+//! The `packet_processor` binary is a thin command-line wrapper around these,
+//! and any of them can be used on their own, e.g. fed with synthetic frames.
+
+pub mod algorithm;
+pub mod bridge;
+pub mod capture;
+pub mod config;
+pub mod dissect;
+pub mod enforce;
+pub mod error;
+pub mod events;
+pub mod evidence;
+pub mod filter;
+pub mod flow;
+pub mod hierarchy;
+pub mod limiter;
+pub mod lists;
+pub mod metrics;
+pub mod pcap;
+pub mod policy;
+pub mod protocol;
+pub mod respond;
+// `AF_PACKET` sockets are Linux only; elsewhere captures use pnet's channel.
+#[cfg(target_os = "linux")]
+pub mod socket;
+pub mod source;
+pub mod stats;
+
+// Re-export the main types so users can write `packet_processor::RateLimiter`
+// instead of `packet_processor::limiter::RateLimiter`.
+pub use algorithm::{Algorithm, AlgorithmKind, Level, Limit};
+pub use bridge::{Direction, DirectionStats, Forwarder};
+pub use capture::{
+    Backoff, ChannelType, ErrorClass, FanoutMode, FileCapture, Frame, KernelStats, Layer3Capture,
+    LiveCapture, PacketSource,
+};
+pub use config::Config;
+pub use dissect::{Network, Summary, TcpFlags, Transport};
+pub use enforce::{Blocklist, Enforcer, EnforcerKind, IptablesEnforcer, LogEnforcer};
+pub use error::{Error, Result};
+pub use events::{Event, EventLog, EventTarget, PacketAction};
+pub use evidence::{Evidence, Recorder};
+pub use filter::Filter;
+pub use flow::{Condition, Field, Flow, Key, KeySpec, Match};
+pub use hierarchy::{Exceeded, Grouping, HierarchicalLimiter, LevelLimits};
+pub use limiter::{Limits, RateLimiter, Unit, Verdict};
+pub use lists::{Listing, Lists, Prefix, PrefixSet};
+pub use metrics::Metrics;
+pub use policy::{Policy, PolicyExceeded, PolicyLimiter};
+pub use protocol::Protocol;
+pub use respond::{Responder, Response};
+#[cfg(target_os = "linux")]
+pub use socket::{PacketSender, PacketSocket};
+pub use source::Source;
+pub use stats::{Limited, Report, Talker, Talkers, Traffic};
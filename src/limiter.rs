@@ -0,0 +1,447 @@
+use std::collections::hash_map::{self, HashMap, RandomState};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::algorithm::{AlgorithmKind, Level, Limit, State};
+use crate::source::Source;
+
+/// What a limit counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Unit {
+    Packets,
+    Bytes,
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unit::Packets => f.write_str("packets"),
+            Unit::Bytes => f.write_str("bytes"),
+        }
+    }
+}
+
+/// The packet and byte limits applied to every source. Either may be `None`,
+/// but a limiter with neither never limits anything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub packets: Option<Limit>,
+    pub bytes: Option<Limit>,
+}
+
+/// What the limiter decided about one packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The packet took the source over a limit. `used` is how much of that
+    /// limit the source has used, including this packet.
+    Exceeded {
+        unit: Unit,
+        used: u64,
+        limit: u64,
+    },
+}
+
+// A source's state for each configured limit.
+struct Entry {
+    packets: Option<State>,
+    bytes: Option<State>,
+}
+
+// One slice of the sources, with its own lock and its own sweep schedule.
+// `HashMap<K, Entry>` maps each key (usually a source address) to its
+// algorithm state.
+// Every shard has its own copy of the settings, so `reconfigure` can change
+// them under the same lock as the entries.
+struct Shard<K> {
+    kind: AlgorithmKind,
+    limits: Limits,
+    entries: HashMap<K, Entry>,
+    // Capture timestamp of the last sweep for idle sources.
+    last_sweep: Duration,
+}
+
+// Tracks every source separately with the chosen algorithm. Sources whose
+// state has gone back to "fresh" are forgotten on a periodic sweep, instead
+// of clearing every source at once when a global window ends.
+//
+// The sources are spread over many independently locked shards, so several
+// capture threads can share one limiter (through an `Arc`) and only contend
+// when two packets from sources in the same shard arrive at the same time.
+// That's the same trick as Go's sharded-map packages, and needs only `&self`.
+//
+// `K` is what gets counted. It's a type parameter (Go: `RateLimiter[K
+// comparable]`) so the same limiter can count single sources or whole
+// prefixes; `= Source` makes plain `RateLimiter` mean the per-source one.
+pub struct RateLimiter<K = Source> {
+    shards: Box<[Mutex<Shard<K>>]>,
+    // Picks a source's shard. `RandomState` is seeded per process, so an
+    // attacker spoofing source addresses can't aim them all at one shard
+    // (or one hash bucket).
+    hasher: RandomState,
+    // Total sources over all shards, kept up to date on insert and sweep so
+    // reading it doesn't have to lock every shard.
+    tracked: AtomicUsize,
+}
+
+// `K: Copy + Eq + Hash` says what a key has to support: being copied into the
+// map, compared, and hashed.
+impl<K: Copy + Eq + Hash> RateLimiter<K> {
+    pub fn new(kind: AlgorithmKind, limits: Limits) -> RateLimiter<K> {
+        // A few shards per CPU keeps the chance of two threads wanting the
+        // same shard low.
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        RateLimiter::with_shards(kind, limits, (cpus * 4).next_power_of_two())
+    }
+
+    /// Like `new`, with an explicit number of shards (at least one).
+    pub fn with_shards(kind: AlgorithmKind, limits: Limits, shards: usize) -> RateLimiter<K> {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let entries = HashMap::new();
+                Mutex::new(Shard {
+                    kind,
+                    limits,
+                    entries,
+                    last_sweep: Duration::ZERO,
+                })
+            })
+            .collect();
+        RateLimiter {
+            shards,
+            hasher: RandomState::new(),
+            tracked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count one packet of `len` bytes for `key`, seen at capture time
+    /// `now`. When both limits trip at once, the packet limit is reported.
+    pub fn record(&self, key: K, now: Duration, len: usize) -> Verdict {
+        let mut shard = self.shard(&key);
+
+        // Sweeping once per window keeps memory bounded by the sources seen
+        // recently without scanning the map on every packet.
+        if now.saturating_sub(shard.last_sweep) >= sweep_interval(&shard.limits) {
+            self.sweep(&mut shard, now);
+            shard.last_sweep = now;
+        }
+
+        // `entry()` is Go's `if _, ok := m[k]; !ok { m[k] = ... }`, but returns
+        // something we can update in place either way.
+        let (kind, limits) = (shard.kind, shard.limits);
+        let entry = match shard.entries.entry(key) {
+            hash_map::Entry::Occupied(occupied) => occupied.into_mut(),
+            hash_map::Entry::Vacant(vacant) => {
+                self.tracked.fetch_add(1, Ordering::Relaxed);
+                vacant.insert(Entry {
+                    packets: limits.packets.map(|limit| State::new(kind, &limit, now)),
+                    bytes: limits.bytes.map(|limit| State::new(kind, &limit, now)),
+                })
+            }
+        };
+
+        // Both states are always updated, so byte usage keeps accumulating
+        // while the packet limit is the one being reported.
+        let packets = check(&mut entry.packets, limits.packets, now, 1, Unit::Packets);
+        let bytes = check(&mut entry.bytes, limits.bytes, now, len as u64, Unit::Bytes);
+        packets.or(bytes).unwrap_or(Verdict::Allow)
+    }
+
+    /// Forget every source whose state has gone idle for all its limits.
+    pub fn expire(&self, now: Duration) {
+        for shard in self.shards.iter() {
+            self.sweep(&mut lock(shard), now);
+        }
+    }
+
+    /// Switch to a new algorithm or new limits while running.
+    ///
+    /// A source keeps its state for a limit as long as the algorithm and that
+    /// limit's window stay the same, so changing only the amount doesn't give
+    /// every source a fresh allowance. Anything else starts over.
+    pub fn reconfigure(&self, kind: AlgorithmKind, limits: Limits, now: Duration) {
+        for shard in self.shards.iter() {
+            let mut shard = lock(shard);
+            let same_kind = shard.kind == kind;
+            let old = shard.limits;
+            for entry in shard.entries.values_mut() {
+                rebuild(
+                    &mut entry.packets,
+                    old.packets,
+                    limits.packets,
+                    same_kind,
+                    kind,
+                    now,
+                );
+                rebuild(
+                    &mut entry.bytes,
+                    old.bytes,
+                    limits.bytes,
+                    same_kind,
+                    kind,
+                    now,
+                );
+            }
+            shard.kind = kind;
+            shard.limits = limits;
+        }
+    }
+
+    /// Number of sources currently tracked.
+    pub fn len(&self) -> usize {
+        self.tracked.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &K) -> MutexGuard<'_, Shard<K>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        lock(&self.shards[index])
+    }
+
+    fn sweep(&self, shard: &mut Shard<K>, now: Duration) {
+        let limits = shard.limits;
+        let before = shard.entries.len();
+        shard.entries.retain(|_, entry| {
+            let idle = |state: &Option<State>, limit: Option<Limit>| match (state, limit) {
+                (Some(state), Some(limit)) => state.is_idle(&limit, now),
+                _ => true,
+            };
+            !(idle(&entry.packets, limits.packets) && idle(&entry.bytes, limits.bytes))
+        });
+        self.tracked
+            .fetch_sub(before - shard.entries.len(), Ordering::Relaxed);
+    }
+}
+
+// The shorter of the two windows, so neither kind of state lingers for long
+// after it went idle.
+fn sweep_interval(limits: &Limits) -> Duration {
+    let windows = [limits.packets, limits.bytes];
+    windows
+        .iter()
+        .flatten()
+        .map(|limit| limit.window)
+        .min()
+        .unwrap_or(Duration::MAX)
+}
+
+// Bring one of a source's states in line with a new limit: keep it if it's
+// still compatible, start it fresh if not, and remove it if the limit is gone.
+fn rebuild(
+    state: &mut Option<State>,
+    old: Option<Limit>,
+    new: Option<Limit>,
+    same_kind: bool,
+    kind: AlgorithmKind,
+    now: Duration,
+) {
+    let compatible = match (old, new) {
+        (Some(old), Some(new)) => same_kind && old.window == new.window,
+        _ => false,
+    };
+    if !compatible {
+        *state = new.map(|limit| State::new(kind, &limit, now));
+    }
+}
+
+// A panic while a shard was locked can't leave it half-updated in a way that
+// matters for counting, so carry on with the data instead of panicking too.
+fn lock<K>(shard: &Mutex<Shard<K>>) -> MutexGuard<'_, Shard<K>> {
+    shard
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Record `cost` against one of a source's limits, returning a verdict only if
+// that limit was exceeded.
+fn check(
+    state: &mut Option<State>,
+    limit: Option<Limit>,
+    now: Duration,
+    cost: u64,
+    unit: Unit,
+) -> Option<Verdict> {
+    let (state, limit) = (state.as_mut()?, limit?);
+    let Level { used, exceeded } = state.record(&limit, now, cost);
+    exceeded.then_some(Verdict::Exceeded {
+        unit,
+        used,
+        limit: limit.amount,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const START: Duration = Duration::from_secs(1_000);
+
+    fn source(last: u8) -> Source {
+        Source::Ipv4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn limit(amount: u64, seconds: u64) -> Option<Limit> {
+        Some(Limit {
+            amount,
+            window: Duration::from_secs(seconds),
+        })
+    }
+
+    fn packets(amount: u64, seconds: u64) -> Limits {
+        Limits {
+            packets: limit(amount, seconds),
+            bytes: None,
+        }
+    }
+
+    fn exceeded(unit: Unit, used: u64, limit: u64) -> Verdict {
+        Verdict::Exceeded { unit, used, limit }
+    }
+
+    // Record `count` packets of `len` bytes and return the last verdict.
+    fn record_many(
+        limiter: &RateLimiter,
+        key: Source,
+        now: Duration,
+        count: usize,
+        len: usize,
+    ) -> Verdict {
+        (0..count)
+            .map(|_| limiter.record(key, now, len))
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_each_source_separately() {
+        let limiter = RateLimiter::new(AlgorithmKind::FixedWindow, packets(2, 10));
+        assert_eq!(
+            record_many(&limiter, source(1), START, 2, 60),
+            Verdict::Allow
+        );
+        assert_eq!(
+            record_many(&limiter, source(2), START, 2, 60),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.record(source(1), START, 60),
+            exceeded(Unit::Packets, 3, 2)
+        );
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn reports_packets_first_and_keeps_counting_bytes() {
+        let limits = Limits {
+            packets: limit(2, 10),
+            bytes: limit(1000, 10),
+        };
+        let limiter = RateLimiter::new(AlgorithmKind::FixedWindow, limits);
+        assert_eq!(
+            limiter.record(source(1), START, 1500),
+            exceeded(Unit::Bytes, 1500, 1000)
+        );
+        assert_eq!(
+            record_many(&limiter, source(1), START, 2, 100),
+            exceeded(Unit::Packets, 3, 2)
+        );
+        // Once the window is over both start again.
+        let later = START + Duration::from_secs(10);
+        assert_eq!(limiter.record(source(1), later, 100), Verdict::Allow);
+    }
+
+    #[test]
+    fn expire_forgets_idle_sources() {
+        let limiter = RateLimiter::new(AlgorithmKind::FixedWindow, packets(5, 10));
+        limiter.record(source(1), START, 60);
+        limiter.record(source(2), START + Duration::from_secs(5), 60);
+        assert!(!limiter.is_empty());
+
+        limiter.expire(START + Duration::from_secs(9));
+        assert_eq!(limiter.len(), 2);
+        limiter.expire(START + Duration::from_secs(10));
+        assert_eq!(limiter.len(), 1);
+        limiter.expire(START + Duration::from_secs(15));
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn record_sweeps_once_per_window() {
+        // One shard, so every source shares the sweep schedule.
+        let limiter = RateLimiter::with_shards(AlgorithmKind::FixedWindow, packets(5, 10), 1);
+        let at = |seconds| START + Duration::from_secs(seconds);
+        limiter.record(source(1), at(0), 60);
+        limiter.record(source(2), at(5), 60);
+
+        // Sweeps at 0 and 12: source 1 is idle by then, source 2 isn't.
+        limiter.record(source(3), at(12), 60);
+        assert_eq!(limiter.len(), 2);
+        // Source 2 is idle at 16, but the next sweep isn't due until 22.
+        limiter.record(source(3), at(16), 60);
+        assert_eq!(limiter.len(), 2);
+        limiter.record(source(3), at(22), 60);
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn reconfigure_keeps_state_only_for_the_same_kind_and_window() {
+        let limiter = RateLimiter::new(AlgorithmKind::FixedWindow, packets(3, 10));
+        let now = START + Duration::from_secs(1);
+        record_many(&limiter, source(1), START, 3, 60);
+
+        // A new amount keeps the count.
+        limiter.reconfigure(AlgorithmKind::FixedWindow, packets(4, 10), now);
+        assert_eq!(limiter.record(source(1), now, 60), Verdict::Allow);
+        assert_eq!(
+            limiter.record(source(1), now, 60),
+            exceeded(Unit::Packets, 5, 4)
+        );
+
+        // A new window starts over.
+        limiter.reconfigure(AlgorithmKind::FixedWindow, packets(4, 20), now);
+        assert_eq!(record_many(&limiter, source(1), now, 4, 60), Verdict::Allow);
+
+        // So does a new algorithm, even with the same limit.
+        limiter.reconfigure(AlgorithmKind::SlidingLog, packets(4, 20), now);
+        assert_eq!(record_many(&limiter, source(1), now, 4, 60), Verdict::Allow);
+
+        // A byte limit that wasn't there before starts fresh, and the
+        // dropped packet limit stops counting.
+        let bytes = Limits {
+            packets: None,
+            bytes: limit(100, 20),
+        };
+        limiter.reconfigure(AlgorithmKind::SlidingLog, bytes, now);
+        assert_eq!(limiter.record(source(1), now, 100), Verdict::Allow);
+        assert_eq!(
+            limiter.record(source(1), now, 1),
+            exceeded(Unit::Bytes, 101, 100)
+        );
+        assert_eq!(limiter.len(), 1);
+    }
+
+    #[test]
+    fn every_algorithm_limits_a_burst() {
+        for kind in AlgorithmKind::ALL {
+            let limiter = RateLimiter::new(kind, packets(10, 10));
+            assert_eq!(
+                record_many(&limiter, source(1), START, 10, 60),
+                Verdict::Allow,
+                "{kind}"
+            );
+            assert_eq!(
+                limiter.record(source(1), START, 60),
+                exceeded(Unit::Packets, 11, 10),
+                "{kind}"
+            );
+        }
+    }
+}
@@ -0,0 +1,551 @@
+// Allowlists and denylists of address prefixes: IPv4 and IPv6 CIDR ranges,
+// and MAC prefixes such as a vendor's OUI (`02:00:5e:00:00:00/24`).
+//
+// Each list is a set of binary tries, one per address family. Looking a
+// source up walks at most one node per address bit, however many prefixes
+// the list holds, so even lists of thousands of ranges cost the same few
+// dozen steps per packet.
+use pnet::datalink::MacAddr;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::config::ListConfig;
+use crate::error::{Error, Result};
+use crate::source::Source;
+
+/// An address prefix: an IP network in CIDR notation, or a MAC address with
+/// an optional prefix length. A plain address is a prefix of full length.
+/// Prefixes sort by family, then address, then length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Prefix {
+    family: Family,
+    // The address, left-aligned in 128 bits so every family can share one
+    // trie implementation.
+    bits: u128,
+    len: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Family {
+    Ipv4,
+    Ipv6,
+    Mac,
+}
+
+impl Family {
+    fn max_len(self) -> u8 {
+        match self {
+            Family::Ipv4 => 32,
+            Family::Ipv6 => 128,
+            Family::Mac => 48,
+        }
+    }
+}
+
+impl Prefix {
+    /// The first `len` bits of this prefix, e.g. the /24 an IPv4 address is
+    /// in. Lengths past the end of the address are clamped to its length.
+    pub fn truncate(self, len: u8) -> Prefix {
+        let len = len.min(self.len);
+        // Shifting a `u128` by 128 overflows, so /0 gets its mask separately.
+        let mask = if len == 0 {
+            0
+        } else {
+            u128::MAX << (128 - u32::from(len))
+        };
+        Prefix {
+            bits: self.bits & mask,
+            len,
+            ..self
+        }
+    }
+
+    /// The prefix length, e.g. 24 for `10.0.0.0/24`.
+    pub fn prefix_len(&self) -> u8 {
+        self.len
+    }
+
+    /// Whether this is a single address rather than a range.
+    pub fn is_address(&self) -> bool {
+        self.len == self.family.max_len()
+    }
+
+    /// The first address in the prefix, e.g. `10.0.0.0` for `10.0.0.0/24`.
+    pub fn network(&self) -> Source {
+        match self.family {
+            Family::Ipv4 => Source::Ipv4(((self.bits >> 96) as u32).into()),
+            Family::Ipv6 => Source::Ipv6(self.bits.into()),
+            Family::Mac => {
+                let b = (self.bits >> 80).to_be_bytes();
+                Source::Mac(MacAddr::new(b[10], b[11], b[12], b[13], b[14], b[15]))
+            }
+        }
+    }
+}
+
+/// The full-length prefix for a source, i.e. the source itself.
+impl From<Source> for Prefix {
+    fn from(source: Source) -> Prefix {
+        let (family, bits) = match source {
+            Source::Ipv4(addr) => (Family::Ipv4, u128::from(u32::from(addr)) << 96),
+            Source::Ipv6(addr) => (Family::Ipv6, u128::from(addr)),
+            Source::Mac(mac) => (Family::Mac, mac_bits(mac)),
+        };
+        Prefix {
+            family,
+            bits,
+            len: family.max_len(),
+        }
+    }
+}
+
+fn mac_bits(mac: MacAddr) -> u128 {
+    let MacAddr(a, b, c, d, e, f) = mac;
+    // `fold` shifts the six bytes into one integer, most significant first.
+    let value = [a, b, c, d, e, f]
+        .iter()
+        .fold(0u128, |acc, &byte| (acc << 8) | u128::from(byte));
+    value << 80
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    /// Parses `10.0.0.0/8`, `2001:db8::/32`, `02:00:5e:00:00:00/24`, or a
+    /// plain IP or MAC address. Bits past the prefix length are cleared, so
+    /// `10.1.2.3/8` is the same prefix as `10.0.0.0/8`.
+    fn from_str(s: &str) -> std::result::Result<Prefix, String> {
+        // `split_once` is Go's `strings.Cut`.
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let full = Prefix::from(addr.parse::<Source>()?);
+        let len = match len {
+            None => full.len,
+            Some(len) => len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= full.len)
+                .ok_or_else(|| format!("invalid prefix length in '{}'", s))?,
+        };
+        Ok(full.truncate(len))
+    }
+}
+
+// A single address is written without a length, like the source it is.
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_address() {
+            write!(f, "{}", self.network())
+        } else {
+            write!(f, "{}/{}", self.network(), self.len)
+        }
+    }
+}
+
+// A binary trie over address bits. Each node is one bit further into the
+// address, and a node marked `end` means some prefix finishes there, so any
+// address that passes through it is in the set.
+//
+// The nodes live in one `Vec` and point at their children by index, which
+// keeps the whole trie in a single allocation (no `Box` per node).
+#[derive(Clone, Debug, Default)]
+struct PrefixTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Node {
+    // Index of the child for a 0 bit and for a 1 bit. 0 means "no child",
+    // which is never ambiguous because the root (index 0) is nobody's child.
+    children: [u32; 2],
+    end: bool,
+}
+
+// Bit `i` of a left-aligned address, counting from the most significant.
+fn bit(bits: u128, i: u8) -> usize {
+    ((bits >> (127 - i)) & 1) as usize
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, bits: u128, len: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for i in 0..len {
+            let next = self.nodes[node].children[bit(bits, i)];
+            node = if next != 0 {
+                next as usize
+            } else {
+                self.nodes.push(Node::default());
+                let child = self.nodes.len() - 1;
+                self.nodes[node].children[bit(bits, i)] = child as u32;
+                child
+            };
+        }
+        self.nodes[node].end = true;
+    }
+
+    // The length of the shortest prefix in the trie that covers the first
+    // `len` bits of `bits`, if there is one.
+    fn covers(&self, bits: u128, len: u8) -> Option<u8> {
+        let mut node = self.nodes.first()?;
+        for i in 0..len {
+            if node.end {
+                return Some(i);
+            }
+            match node.children[bit(bits, i)] {
+                0 => return None,
+                next => node = &self.nodes[next as usize],
+            }
+        }
+        node.end.then_some(len)
+    }
+
+    // Whether any prefix in the trie overlaps the first `len` bits of
+    // `bits`, by covering them or by lying inside them.
+    fn overlaps(&self, bits: u128, len: u8) -> bool {
+        let Some(mut node) = self.nodes.first() else {
+            return false;
+        };
+        for i in 0..len {
+            if node.end {
+                return true;
+            }
+            match node.children[bit(bits, i)] {
+                0 => return false,
+                next => node = &self.nodes[next as usize],
+            }
+        }
+        // Nodes are only made on the way to a prefix's end, so there's at
+        // least one at or below this one.
+        true
+    }
+}
+
+/// A set of address prefixes that sources can be checked against.
+#[derive(Clone, Debug, Default)]
+pub struct PrefixSet {
+    ipv4: PrefixTrie,
+    ipv6: PrefixTrie,
+    mac: PrefixTrie,
+    len: usize,
+}
+
+impl PrefixSet {
+    pub fn new() -> PrefixSet {
+        PrefixSet::default()
+    }
+
+    pub fn insert(&mut self, prefix: Prefix) {
+        self.trie_mut(prefix.family).insert(prefix.bits, prefix.len);
+        self.len += 1;
+    }
+
+    /// Whether `source` falls inside any prefix in the set.
+    pub fn contains(&self, source: &Source) -> bool {
+        self.find(source).is_some()
+    }
+
+    /// The widest prefix in the set that `source` falls inside.
+    pub fn find(&self, source: &Source) -> Option<Prefix> {
+        let prefix = Prefix::from(*source);
+        let len = self.trie(prefix.family).covers(prefix.bits, prefix.len)?;
+        Some(prefix.truncate(len))
+    }
+
+    /// Whether any prefix in the set shares an address with `prefix`.
+    pub fn overlaps(&self, prefix: &Prefix) -> bool {
+        self.trie(prefix.family).overlaps(prefix.bits, prefix.len)
+    }
+
+    /// Add every prefix listed in a file: one per line, with blank lines and
+    /// everything after a `#` ignored.
+    pub fn load(&mut self, path: &Path) -> Result<()> {
+        let error = |message: String| Error::List {
+            path: path.to_path_buf(),
+            message,
+        };
+        let text = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+        // `enumerate` pairs each line with its index, for the error message.
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let prefix = line
+                .parse()
+                .map_err(|e| error(format!("line {}: {}", number + 1, e)))?;
+            self.insert(prefix);
+        }
+        Ok(())
+    }
+
+    /// Number of prefixes added.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn trie(&self, family: Family) -> &PrefixTrie {
+        match family {
+            Family::Ipv4 => &self.ipv4,
+            Family::Ipv6 => &self.ipv6,
+            Family::Mac => &self.mac,
+        }
+    }
+
+    fn trie_mut(&mut self, family: Family) -> &mut PrefixTrie {
+        match family {
+            Family::Ipv4 => &mut self.ipv4,
+            Family::Ipv6 => &mut self.ipv6,
+            Family::Mac => &mut self.mac,
+        }
+    }
+}
+
+/// Where a source stands with the lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Listing {
+    /// On the allowlist: never counted or blocked.
+    Allowed,
+    /// On the denylist (and not the allowlist) through this entry: always
+    /// blocked.
+    Denied(Prefix),
+    Unlisted,
+}
+
+/// The allowlist and the denylist. The allowlist wins when a source is on
+/// both, so a broad deny range can have exceptions carved out of it.
+#[derive(Clone, Debug, Default)]
+pub struct Lists {
+    pub allow: PrefixSet,
+    pub deny: PrefixSet,
+}
+
+impl Lists {
+    /// Build the lists from a config, reading any list files it names.
+    pub fn load(config: &ListConfig) -> Result<Lists> {
+        let mut lists = Lists::default();
+        let sets = [
+            (&mut lists.allow, &config.allow, &config.allow_files),
+            (&mut lists.deny, &config.deny, &config.deny_files),
+        ];
+        for (set, prefixes, files) in sets {
+            for &prefix in prefixes {
+                set.insert(prefix);
+            }
+            for path in files {
+                set.load(path)?;
+            }
+        }
+        Ok(lists)
+    }
+
+    pub fn check(&self, source: &Source) -> Listing {
+        if self.allow.contains(source) {
+            return Listing::Allowed;
+        }
+        match self.deny.find(source) {
+            Some(prefix) => Listing::Denied(prefix),
+            None => Listing::Unlisted,
+        }
+    }
+
+    /// What to block when `source` is denied through, or goes over a limit
+    /// on, `prefix`: the whole prefix, unless that would also block
+    /// allowlisted addresses inside it. Then only `source` is, and any other
+    /// source in the prefix is blocked on its own when it shows up.
+    pub fn block_target(&self, prefix: Prefix, source: Source) -> Prefix {
+        match self.allow.overlaps(&prefix) {
+            true => Prefix::from(source),
+            false => prefix,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Prefix {
+        s.parse().unwrap()
+    }
+
+    fn source(s: &str) -> Source {
+        s.parse().unwrap()
+    }
+
+    fn set(prefixes: &[&str]) -> PrefixSet {
+        let mut set = PrefixSet::new();
+        for s in prefixes {
+            set.insert(prefix(s));
+        }
+        set
+    }
+
+    #[test]
+    fn parses_and_prints_prefixes() {
+        assert_eq!(prefix("10.1.2.3/8"), prefix("10.0.0.0/8"));
+        assert_eq!(prefix("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(prefix("10.1.2.3/32").to_string(), "10.1.2.3");
+        assert!(prefix("10.1.2.3").is_address());
+        assert_eq!(prefix("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(
+            prefix("02:00:5e:12:34:56/24").to_string(),
+            "02:00:5e:00:00:00/24"
+        );
+        assert_eq!(prefix("0.0.0.0/0").prefix_len(), 0);
+    }
+
+    #[test]
+    fn rejects_bad_prefixes() {
+        for bad in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "02:00:5e:00:00:00/49",
+            "02:00:5e:zz:00:00/24",
+            "02:00:5e:00:00",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "example.com",
+        ] {
+            assert!(bad.parse::<Prefix>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn finds_the_widest_covering_prefix() {
+        let set = set(&["10.0.0.0/8", "10.1.0.0/16", "192.0.2.7/32"]);
+        assert_eq!(set.find(&source("10.1.2.3")), Some(prefix("10.0.0.0/8")));
+        assert_eq!(set.find(&source("192.0.2.7")), Some(prefix("192.0.2.7")));
+        assert_eq!(set.find(&source("192.0.2.8")), None);
+        assert_eq!(set.find(&source("11.0.0.1")), None);
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn zero_length_prefixes_cover_their_whole_family() {
+        let set = set(&["0.0.0.0/0"]);
+        assert_eq!(set.find(&source("203.0.113.9")), Some(prefix("0.0.0.0/0")));
+        assert!(!set.contains(&source("2001:db8::1")));
+        assert!(!set.contains(&source("02:00:00:00:00:01")));
+
+        let set = self::set(&["::/0"]);
+        assert!(set.contains(&source("2001:db8::1")));
+        assert!(!set.contains(&source("203.0.113.9")));
+    }
+
+    #[test]
+    fn full_length_prefixes_cover_one_address() {
+        let set = set(&["192.0.2.1/32", "2001:db8::1/128"]);
+        assert!(set.contains(&source("192.0.2.1")));
+        assert!(!set.contains(&source("192.0.2.0")));
+        assert!(!set.contains(&source("192.0.2.2")));
+        assert!(set.contains(&source("2001:db8::1")));
+        assert!(!set.contains(&source("2001:db8::2")));
+    }
+
+    #[test]
+    fn keeps_families_apart() {
+        // `a00::/8` and `10.0.0.0/8` start with the same eight bits, and
+        // IPv4 addresses are left-aligned like IPv6 ones, so a shared trie
+        // would mix them up.
+        let set = set(&["10.0.0.0/8", "2001:db8::/32"]);
+        assert!(set.contains(&source("10.9.9.9")));
+        assert!(!set.contains(&source("a00::1")));
+        assert!(set.contains(&source("2001:db8:ffff::1")));
+        assert!(!set.contains(&source("2001:db9::1")));
+
+        let set = self::set(&["a00::/8"]);
+        assert!(set.contains(&source("a00::1")));
+        assert!(!set.contains(&source("10.9.9.9")));
+    }
+
+    #[test]
+    fn matches_mac_prefixes() {
+        let set = set(&["02:00:5e:00:00:00/24", "0a:00:00:00:00:01"]);
+        assert_eq!(
+            set.find(&source("02:00:5e:ab:cd:ef")),
+            Some(prefix("02:00:5e:00:00:00/24"))
+        );
+        assert!(!set.contains(&source("02:00:5f:00:00:00")));
+        assert!(set.contains(&source("0a:00:00:00:00:01")));
+        assert!(!set.contains(&source("0a:00:00:00:00:02")));
+    }
+
+    #[test]
+    fn overlaps_prefixes_inside_and_around() {
+        let set = set(&["10.1.0.0/16", "192.0.2.7"]);
+        // Covered by an entry.
+        assert!(set.overlaps(&prefix("10.1.2.0/24")));
+        // Holding an entry.
+        assert!(set.overlaps(&prefix("10.0.0.0/8")));
+        assert!(set.overlaps(&prefix("192.0.2.0/24")));
+        assert!(set.overlaps(&prefix("0.0.0.0/0")));
+        assert!(!set.overlaps(&prefix("10.2.0.0/16")));
+        assert!(!set.overlaps(&prefix("::/0")));
+        assert!(!PrefixSet::new().overlaps(&prefix("0.0.0.0/0")));
+    }
+
+    #[test]
+    fn the_allowlist_wins_over_the_denylist() {
+        let lists = Lists {
+            allow: set(&["10.0.0.5"]),
+            deny: set(&["10.0.0.0/24", "2001:db8::/32"]),
+        };
+        assert_eq!(lists.check(&source("10.0.0.5")), Listing::Allowed);
+        assert_eq!(
+            lists.check(&source("10.0.0.6")),
+            Listing::Denied(prefix("10.0.0.0/24"))
+        );
+        assert_eq!(
+            lists.check(&source("2001:db8::9")),
+            Listing::Denied(prefix("2001:db8::/32"))
+        );
+        assert_eq!(lists.check(&source("10.0.1.1")), Listing::Unlisted);
+    }
+
+    #[test]
+    fn blocks_one_source_when_the_range_holds_an_allowed_host() {
+        let lists = Lists {
+            allow: set(&["10.0.0.5"]),
+            deny: set(&["10.0.0.0/24", "10.0.1.0/24"]),
+        };
+        assert_eq!(
+            lists.block_target(prefix("10.0.0.0/24"), source("10.0.0.6")),
+            prefix("10.0.0.6")
+        );
+        assert_eq!(
+            lists.block_target(prefix("10.0.1.0/24"), source("10.0.1.6")),
+            prefix("10.0.1.0/24")
+        );
+    }
+
+    #[test]
+    fn loads_list_files() {
+        let path =
+            std::env::temp_dir().join(format!("packet_processor-{}-lists.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# header\n10.0.0.0/8  # a comment\n\n02:00:5e:00:00:00/24\n",
+        )
+        .unwrap();
+        let mut set = PrefixSet::new();
+        set.load(&path).unwrap();
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&source("10.2.3.4")));
+
+        fs::write(&path, "10.0.0.0/8\n10.0.0.0/33\n").unwrap();
+        let error = PrefixSet::new().load(&path).unwrap_err().to_string();
+        let _ = fs::remove_file(&path);
+        assert!(error.contains("line 2"), "{}", error);
+    }
+}
@@ -1,140 +1,1278 @@
 // Rust uses `use` to import modules.
 // `pnet::datalink` is a module from the `pnet` crate (library).
 // `self` means we import the `datalink` module itself.
-use pnet::datalink;
-// In this case we import the `Packet` trait from `pnet::packet`.
-// Traits in Rust are like Go interfaces.
-use pnet::packet::Packet;
+use pnet::datalink::{self, NetworkInterface};
 use pnet::packet::ethernet::EthernetPacket;
+// `Duration` is like Go's `time.Duration`.
 use std::collections::HashMap;
-// `Instant` is like Go's `time.Now()`, and `Duration` is like Go's
-// `time.Duration`.
-use std::time::{Instant, Duration};
-// `Arc` is for letting multiple owners share data safely.
-// `Mutex` is like Go's `sync.Mutex` for locking shared data.
-use std::sync::{Arc, Mutex};
-
-fn main() {
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+
+// Everything except argument parsing lives in the library half of this crate
+// (`src/lib.rs`), which the binary imports by the package name.
+use packet_processor::config::{
+    BlockConfig, CaptureConfig, EvidenceConfig, LimitConfig, ListConfig, OutputConfig,
+};
+use packet_processor::{
+    Backoff, Blocklist, ChannelType, Config, Error, ErrorClass, Event, EventLog, EventTarget,
+    Evidence, Exceeded, FileCapture, Filter, Forwarder, Frame, HierarchicalLimiter, Layer3Capture,
+    Limited, Listing, Lists, LiveCapture, Metrics, PacketAction, PacketSource, PolicyLimiter,
+    Prefix, Report, Responder, Response, Result, Summary, Talkers, capture, config, metrics,
+};
+// Our own capture socket only exists on Linux (see `open_interface`).
+#[cfg(target_os = "linux")]
+use packet_processor::PacketSocket;
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+// `mod` pulls in another file of this crate: `mod cli;` loads `src/cli.rs`.
+// It's roughly a Go package, except it lives inside the same binary.
+mod cli;
+mod tui;
+
+use cli::{Cli, Command, RunArgs};
+use tui::Recent;
+
+fn main() -> ExitCode {
+    // Parse `std::env::args()` into our `Cli` struct. On bad input clap prints
+    // the usage and exits for us, like Go's `flag.Parse()`.
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::ListInterfaces => {
+            list_interfaces();
+            Ok(())
+        }
+        Command::Run(args) => load_config(&args, cli.verbose)
+            .and_then(|config| run(config, args.input.config.clone(), args.tui)),
+    };
+
+    // Returning an `ExitCode` instead of panicking gives a clean message and a
+    // non-zero status, like `fmt.Fprintln(os.Stderr, err); os.Exit(1)` in Go.
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn list_interfaces() {
     // Get all network interfaces.
-    // `let` declares a variable, immutable by default (unlike Go, unless `const`).
     // `interfaces` is a `Vec<NetworkInterface>` (Rust's dynamic array, like Go's
     // slice `[]net.Interfaces`).
     let interfaces = datalink::interfaces();
 
-    // Print all interfaces for debugging.
     println!("Available interfaces:");
-    for (i, iface) in interfaces.iter().enumerate() {
+    for iface in &interfaces {
         println!(
             "[{}] {}: up={}, loopback={}",
-            i, iface.name, iface.is_up(), iface.is_loopback()
+            iface.index,
+            iface.name,
+            iface.is_up(),
+            iface.is_loopback()
         );
     }
+}
+
+// Interfaces we can sensibly capture on: up, and not the loopback device.
+fn is_capturable(iface: &NetworkInterface) -> bool {
+    iface.is_up() && !iface.is_loopback()
+}
 
-    // Select an interface by name
-    // `into_iter()` converts the `Vec` into an iterator, like a Go
-    // `for _, iface := range interfaces`.
-    // `find` is a method on iterators that returns an `Option` (like Go's
-    // value, ok idiom but more explicit).
+// Find the interfaces the user asked for, by name, by index, or all of them.
+// Go's equivalent:
+// `for _, iface := range ifaces { if iface.Name == name { ... } }`.
+fn select_interfaces(input: &CaptureConfig) -> Result<Vec<NetworkInterface>> {
+    let all = datalink::interfaces();
+    if input.all_interfaces {
+        // `filter` keeps the elements the closure returns `true` for, and
+        // `collect` gathers them into a new `Vec`.
+        let found: Vec<_> = all.into_iter().filter(is_capturable).collect();
+        if found.is_empty() {
+            return Err(Error::NoInterfaces);
+        }
+        return Ok(found);
+    }
+
+    // `iter()` borrows each interface, and `find` returns an `Option` (like
+    // Go's value, ok idiom but more explicit).
     // `|iface|` is a closure (anonymous function), like Go's `func(iface)`.
-    // `&iface` borrows `iface` (Rust's way to avoid moving ownership).
-    // Go's equivalent:
-    // `for _, iface := range ifaces { if iface.Flags&net.FlagUp != 0 && ... }'.
-    let interface = interfaces
-        .into_iter()
-        .find(|iface| {
-            iface.name == "en0" && iface.is_up() && !iface.is_loopback()
-        })
-        // `expect` unwraps the `Option`, panicking with a message if `None`.
-        // In Go, we'd panic manually: `if iface == nil { panic("no interface") }`.
-        .expect(&format!("Interface '{}' not found or not suitable", "en0"));
-    
-    // `println!` is a macro, like Go's `fmt.Println`.
-    // `{}` is a placeholder, filled by `interface.name`.
-    println!("Using interface: {}", interface.name);
-
-    // Open a packet capture channel on the interface.
+    let by_name = input
+        .interfaces
+        .iter()
+        .chain(&input.bridge)
+        .map(|name| (all.iter().find(|iface| &iface.name == name), name.clone()));
+    let by_index = input.indexes.iter().map(|&index| {
+        (
+            all.iter().find(|iface| iface.index == index),
+            format!("#{}", index),
+        )
+    });
+
+    let mut selected: Vec<NetworkInterface> = Vec::new();
+    for (found, wanted) in by_name.chain(by_index) {
+        // `ok_or` turns `None` into an error we can return with `?`.
+        let interface = found.ok_or(Error::InterfaceNotFound(wanted))?;
+        if !is_capturable(interface) {
+            return Err(Error::InterfaceUnsuitable(interface.name.clone()));
+        }
+        // Naming an interface twice shouldn't capture everything twice.
+        if !selected.iter().any(|iface| iface.name == interface.name) {
+            selected.push(interface.clone());
+        }
+    }
+    Ok(selected)
+}
+
+// Open a live capture channel on one interface. At layer 2 on Linux it's our
+// own socket, since pnet's channel has no way to attach a filter or to ask
+// the kernel about drops. The filter expects Ethernet headers, so a layer 3
+// channel leaves it to the capture thread instead. So does a bridge, which
+// still has to forward the frames the filter doesn't match; its sockets
+// also skip the frames the other side forwards out of this interface.
+// Other systems always get pnet's channel, with the filter in the capture
+// thread.
+//
+// With `respond`, a layer 2 channel also comes with a `Responder` sending on
+// the same socket.
+fn open_interface(
+    interface: &NetworkInterface,
+    settings: &CaptureConfig,
+    channel: datalink::Config,
+    respond: bool,
+) -> Result<(Box<dyn PacketSource>, Option<Responder>)> {
+    // `#[cfg]` leaves this block out of the build elsewhere, like a Go
+    // `//go:build linux` file.
+    #[cfg(target_os = "linux")]
+    if settings.channel == ChannelType::Layer2 {
+        let error = |e| Error::Channel {
+            interface: interface.name.clone(),
+            source: e,
+        };
+        let bridge = !settings.bridge.is_empty();
+        let filter = if bridge {
+            &Filter::default()
+        } else {
+            &settings.filter
+        };
+        let socket = PacketSocket::open(interface, &channel, filter, bridge).map_err(error)?;
+        let responder = match respond {
+            true => {
+                let sender = socket.sender(settings.write_buffer_size).map_err(error)?;
+                Some(Responder::new(Box::new(sender), interface))
+            }
+            false => None,
+        };
+        return Ok((Box::new(socket), responder));
+    }
+
     // `&interface` passes a reference (borrow), not the value itself.
-    // `Default::default()` gives default config options, like Go's zero values.
     // `datalink::channel` returns a `Result`, Rust's way of handling errors (like Go's `value,
     // err`).
     // `match` is like Go's `switch`, but more powerful, it pattern-matches on the `Result`.
-    let (_tx, mut rx) = match datalink::channel(&interface, Default::default()) {
+    let (tx, rx) = match datalink::channel(interface, channel) {
         // `Ok` is the success case of `Result`, like `err == nil` in Go.
         // `datalink:Channel::Ethernet` is an enum variant, containing a
         // sender (`tx`) and receiver (`rx`).
         // In Go, this is like `handle, err := pcap.OpenLive(...)`.
         Ok(datalink::Channel::Ethernet(tx, rx)) => (tx, rx),
         // `_` is a wildcard, like Go's `_` for unused variables.
-        // `panic!` crashes the program, like Go's `panic()`.
-        Ok(_) => panic!("Unsupported channel type"),
+        Ok(_) => return Err(Error::UnsupportedChannel(interface.name.clone())),
         // `Err(e)` is the error case, `e` is the error value.
-        // `{}` in `panic!` formats the error, like Go's `panic(fmt.Sprintf("Error: %v", e))`.
-        Err(e) => panic!("Error opening channel: {}", e),
+        Err(e) => {
+            return Err(Error::Channel {
+                interface: interface.name.clone(),
+                source: e,
+            });
+        }
+    };
+    let input = Box::new(LiveCapture::new(rx));
+    match settings.channel {
+        ChannelType::Layer2 => {
+            let responder = respond.then(|| Responder::new(tx, interface));
+            Ok((input, responder))
+        }
+        // At layer 3 the sender sends bare IP packets with no way to say
+        // which MAC address they're for, so it can't answer anyone.
+        ChannelType::Layer3(ethertype) => {
+            Ok((Box::new(Layer3Capture::new(input, ethertype)), None))
+        }
+    }
+}
+
+// A transient receive error is retried this many times in a row, with
+// growing pauses in between, before the capture gives up on it.
+const MAX_RETRIES: u32 = 10;
+
+// How to open a live interface's channel again after the interface went
+// down. The interface is looked up by name again, since one that was
+// deleted and created again comes back with a new index.
+struct Reopen {
+    interface: String,
+    index: usize,
+    settings: Arc<CaptureConfig>,
+    respond: bool,
+}
+
+impl Reopen {
+    fn open(&self) -> Result<(Box<dyn PacketSource>, Option<Responder>)> {
+        let all = datalink::interfaces();
+        let interface = all
+            .iter()
+            .find(|iface| iface.name == self.interface)
+            .ok_or_else(|| Error::InterfaceNotFound(self.interface.clone()))?;
+        if !is_capturable(interface) {
+            return Err(Error::InterfaceUnsuitable(self.interface.clone()));
+        }
+        open_interface(
+            interface,
+            &self.settings,
+            self.settings.datalink(self.index),
+            self.respond,
+        )
+    }
+
+    // Wait for the interface to come back up and open it. An interface can
+    // stay down for hours, so this only gives up when we're stopping
+    // (`None`); it just checks less and less often, up to every few seconds.
+    fn wait(
+        &self,
+        events: &EventLog,
+        stopping: &AtomicBool,
+    ) -> Option<(Box<dyn PacketSource>, Option<Responder>)> {
+        let down = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(5));
+        while !stopping.load(Ordering::Relaxed) {
+            let error = match self.open() {
+                Ok(opened) => {
+                    let (attempts, down_secs) =
+                        (backoff.attempts() + 1, down.elapsed().as_secs_f64());
+                    events.emit(
+                        capture::now(),
+                        &Event::InterfaceReopened {
+                            attempts,
+                            down_secs,
+                        },
+                    );
+                    return Some(opened);
+                }
+                // Not back yet: nothing worth reporting.
+                Err(Error::InterfaceNotFound(_) | Error::InterfaceUnsuitable(_)) => None,
+                Err(e) => Some(e.to_string()),
+            };
+            let delay = backoff.next_delay();
+            if let Some(error) = error {
+                events.emit(
+                    capture::now(),
+                    &Event::ReopenFailed {
+                        error,
+                        retry_in_ms: delay.as_millis() as u64,
+                    },
+                );
+            }
+            thread::sleep(delay);
+        }
+        None
+    }
+}
+
+// One input to capture from, and the name its events are tagged with.
+struct Capture {
+    name: String,
+    input: Box<dyn PacketSource>,
+    // Whether events from this input get an `interface` field.
+    is_interface: bool,
+    // Which interface (or file) this is, counting from 0. Fanout threads on
+    // one interface share it, and so share limiters with
+    // `per_interface_limits`.
+    index: usize,
+    // A filter to run on every frame, for inputs that can't run it in the
+    // kernel.
+    filter: Option<Filter>,
+    // How to open a live interface again if it goes down; `None` for a file.
+    reopen: Option<Reopen>,
+    // Sends the policies' responses, if any policy has some.
+    responder: Option<Responder>,
+    // Sends frames on to the other side, for a bridge.
+    forward: Option<Forwarder>,
+}
+
+// Open whichever inputs were asked for. Everything is
+// opened before any capturing starts, so a bad interface fails the run
+// straight away instead of leaving the others running.
+// `Box<dyn PacketSource>` lets both kinds of input go through the same loop.
+// With `respond`, every capture thread gets a `Responder`, which needs a
+// layer 2 channel to send on. For a bridge, each one also gets a
+// `Forwarder` to the other interface, counted in `metrics`.
+fn open_inputs(
+    input: &CaptureConfig,
+    respond: bool,
+    metrics: &Metrics,
+    events: &EventLog,
+) -> Result<Vec<Capture>> {
+    if respond && (input.read.is_some() || input.channel != ChannelType::Layer2) {
+        return Err(Error::CannotRespond);
+    }
+    if let Some(path) = &input.read {
+        let capture = FileCapture::open(path).map_err(|e| Error::File {
+            path: path.clone(),
+            source: e,
+        })?;
+        events.emit(capture::now(), &Event::FileOpened { path: path.clone() });
+        let name = path.display().to_string();
+        let filter = (!input.filter.is_empty()).then(|| input.filter.clone());
+        let capture = Capture {
+            name,
+            input: Box::new(capture),
+            is_interface: false,
+            index: 0,
+            filter,
+            reopen: None,
+            responder: None,
+            forward: None,
+        };
+        return Ok(vec![capture]);
+    }
+
+    // Only our own socket on Linux can run the filter in the kernel.
+    let in_thread = !cfg!(target_os = "linux")
+        || input.channel != ChannelType::Layer2
+        || !input.bridge.is_empty();
+    let filter = (in_thread && !input.filter.is_empty()).then(|| input.filter.clone());
+    let settings = Arc::new(input.clone());
+    let interfaces = select_interfaces(input)?;
+    // Naming the same interface twice leaves only one.
+    if !input.bridge.is_empty() && interfaces.len() != 2 {
+        return Err(Error::Bridge);
+    }
+    let mut captures = Vec::new();
+    for (index, interface) in interfaces.iter().enumerate() {
+        // One socket per fanout thread, all in the interface's group.
+        let channel = input.datalink(index);
+        // A bridge's other side is the other of its two interfaces.
+        let other = (!input.bridge.is_empty()).then(|| &interfaces[1 - index]);
+        for _ in 0..input.fanout {
+            let (opened, responder) = open_interface(interface, input, channel, respond)?;
+            let forward =
+                match other {
+                    Some(other) => {
+                        let direction = metrics.bridge(&interface.name, &other.name);
+                        let forwarder = Forwarder::open(direction, input.write_buffer_size)
+                            .map_err(|e| Error::Channel {
+                                interface: other.name.clone(),
+                                source: e,
+                            })?;
+                        Some(forwarder)
+                    }
+                    None => None,
+                };
+            let settings = Arc::clone(&settings);
+            captures.push(Capture {
+                name: interface.name.clone(),
+                input: opened,
+                is_interface: true,
+                index,
+                filter: filter.clone(),
+                reopen: Some(Reopen {
+                    interface: interface.name.clone(),
+                    index,
+                    settings,
+                    respond,
+                }),
+                responder,
+                forward,
+            });
+        }
+        events.emit(
+            capture::now(),
+            &Event::InterfaceOpened {
+                interface: interface.name.clone(),
+            },
+        );
+    }
+    Ok(captures)
+}
+
+// Build the run's configuration, from a config file if one was given and from
+// the flags otherwise. Either way it goes through the same checks, since clap
+// can't express them all.
+fn load_config(args: &RunArgs, verbose: u8) -> Result<Config> {
+    if let Some(path) = &args.input.config {
+        return Config::load(path);
+    }
+
+    let input = &args.input;
+    let config = Config {
+        capture: CaptureConfig {
+            interfaces: input.interface.clone(),
+            indexes: input.index.clone(),
+            all_interfaces: input.all_interfaces,
+            bridge: input.bridge.clone(),
+            read: input.read.clone(),
+            per_interface_limits: args.per_interface_limits,
+            filter: args.filter.clone().unwrap_or_default(),
+            read_buffer_size: args.read_buffer_size,
+            write_buffer_size: args.write_buffer_size,
+            read_timeout_ms: args.read_timeout,
+            promiscuous: !args.no_promiscuous,
+            channel: args.channel,
+            fanout: args.fanout,
+            fanout_mode: args.fanout_mode.into(),
+            fanout_group: args.fanout_group,
+        },
+        limit: LimitConfig {
+            window: args.window,
+            threshold: args.threshold,
+            pps: args.pps,
+            bps: args.bps,
+            algorithm: args.algorithm.into(),
+            aggregate: args.aggregate.clone(),
+        },
+        policies: args.limit_by.clone(),
+        block: BlockConfig {
+            enforcer: args.enforcer.into(),
+            duration: args.block_duration,
+        },
+        lists: ListConfig {
+            allow: args.allow.clone(),
+            deny: args.deny.clone(),
+            allow_files: args.allow_file.clone(),
+            deny_files: args.deny_file.clone(),
+        },
+        output: OutputConfig {
+            events: args.events.clone(),
+            metrics: args.metrics,
+            // One `packet` event per `sample` packets; `-v` on its own logs
+            // them all.
+            packet_sample: args
+                .packet_sample
+                .unwrap_or(if verbose > 0 { 1 } else { 0 }),
+        },
+        evidence: EvidenceConfig {
+            dir: args.evidence_dir.clone(),
+            pre_trigger: args.evidence_pre_trigger,
+            post_trigger: args.evidence_post_trigger,
+            max_file_mb: args.evidence_max_file_mb,
+            max_files: args.evidence_max_files,
+        },
+    };
+    config.check().map_err(Error::Options)?;
+    Ok(config)
+}
+
+// The counters for one input, or for all of them: per source and prefix,
+// and per policy.
+struct Limiters {
+    sources: HierarchicalLimiter,
+    policies: PolicyLimiter,
+}
+
+impl Limiters {
+    fn new(config: &Config) -> Limiters {
+        Limiters {
+            sources: HierarchicalLimiter::new(config.limit.algorithm, &config.limit.levels()),
+            policies: PolicyLimiter::new(config.limit.algorithm, &config.policies()),
+        }
+    }
+
+    fn reconfigure(&self, config: &Config, now: Duration) {
+        self.sources
+            .reconfigure(config.limit.algorithm, &config.limit.levels(), now);
+        self.policies
+            .reconfigure(config.limit.algorithm, &config.policies(), now);
+    }
+
+    fn len(&self) -> usize {
+        self.sources.len() + self.policies.len()
+    }
+}
+
+// State shared by every capture thread.
+struct Shared {
+    // One set of limiters for all inputs, or one per input with
+    // `per_interface_limits`.
+    limiters: Vec<Limiters>,
+    // A blocked source is blocked host-wide, so there's only one blocklist.
+    // It does its own locking, so capture threads can share it.
+    blocklist: Blocklist,
+    // `RwLock` is Go's `sync.RWMutex`: every capture thread reads the lists
+    // at once, and only a reload has to wait for exclusive access.
+    lists: RwLock<Lists>,
+    // One `packet` event per this many packets.
+    sample: AtomicU64,
+    metrics: Arc<Metrics>,
+    // Saves offenders' frames, if an evidence directory was given.
+    evidence: Option<Evidence>,
+    // Each capture thread's own count of its sources. Only its thread and
+    // the final report ever lock one, so they're practically never
+    // contended.
+    talkers: Vec<Mutex<Talkers>>,
+    // How often each source or prefix went over a limit. Only touched when
+    // one does, which is rare enough for one lock.
+    limited: Mutex<HashMap<Prefix, u64>>,
+    // Set by SIGINT or SIGTERM. Capture threads check it between frames.
+    stopping: Arc<AtomicBool>,
+}
+
+impl Shared {
+    // If another capture thread panicked while holding one of these locks,
+    // what it guards is still usable, so carry on with it.
+    fn talkers(&self, i: usize) -> MutexGuard<'_, Talkers> {
+        self.talkers[i]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn limited(&self) -> MutexGuard<'_, HashMap<Prefix, u64>> {
+        self.limited.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stopping(&self) -> bool {
+        self.stopping.load(Ordering::Relaxed)
+    }
+
+    fn lists(&self) -> RwLockReadGuard<'_, Lists> {
+        self.lists.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Apply the settings that can change while running. Capture threads
+    // pick each of them up with their next packet.
+    fn reconfigure(&self, config: &Config, lists: Lists, now: Duration) {
+        for limiters in &self.limiters {
+            limiters.reconfigure(config, now);
+        }
+        self.blocklist
+            .set_duration(Duration::from_secs(config.block.duration));
+        *self.lists.write().unwrap_or_else(PoisonError::into_inner) = lists;
+        self.sample
+            .store(config.output.packet_sample, Ordering::Relaxed);
+    }
+}
+
+fn run(config: Config, config_path: Option<PathBuf>, tui: bool) -> Result<()> {
+    let started = Instant::now();
+    // The dashboard needs stdout to itself, so events bound for it go to the
+    // dashboard's events pane instead.
+    let recent = Recent::new();
+    let events = match config.output.events {
+        EventTarget::Stdout if tui => Ok(EventLog::new(Box::new(recent.clone()))),
+        ref target => EventLog::open(target),
+    };
+    let events = events.map_err(|e| Error::Events {
+        target: config.output.events.to_string(),
+        source: e,
+    })?;
+    let lists = Lists::load(&config.lists)?;
+    // `Arc` lets the metrics server thread read the counters the capture
+    // threads update, like sharing a pointer between goroutines.
+    let metrics = Arc::new(Metrics::new());
+    let respond = config
+        .policies
+        .iter()
+        .any(|policy| !policy.respond.is_empty());
+    let captures = open_inputs(&config.capture, respond, &metrics, &events)?;
+
+    let blocklist = Blocklist::new(
+        config.block.enforcer.build(),
+        Duration::from_secs(config.block.duration),
+    );
+
+    // Counts packets per source, per prefix for any aggregate levels, and
+    // per key for any policies, and tells us when one goes over the limit.
+    // The limiters are sharded, so capture threads can share them.
+    let limiter_count = match captures.last() {
+        Some(last) if config.capture.per_interface_limits => last.index + 1,
+        _ => 1,
+    };
+    let limiters = (0..limiter_count).map(|_| Limiters::new(&config)).collect();
+
+    if let Some(addr) = config.output.metrics {
+        metrics::serve(addr, Arc::clone(&metrics))
+            .map_err(|e| Error::Metrics { addr, source: e })?;
+        events.emit(capture::now(), &Event::MetricsListening { addr });
+    }
+
+    let evidence = match &config.evidence.dir {
+        Some(dir) => Some(
+            Evidence::start(
+                &config.evidence,
+                dir,
+                Duration::from_secs(config.block.duration),
+                events.clone(),
+                Arc::clone(&metrics),
+            )
+            .map_err(|e| Error::Evidence {
+                path: dir.clone(),
+                source: e,
+            })?,
+        ),
+        None => None,
+    };
+
+    // Only layer 2 channels on live interfaces can tell how many frames the
+    // kernel dropped.
+    let kernel_stats =
+        config.capture.read.is_none() && config.capture.channel == ChannelType::Layer2;
+
+    let shared = Arc::new(Shared {
+        limiters,
+        blocklist,
+        lists: RwLock::new(lists),
+        sample: AtomicU64::new(config.output.packet_sample),
+        metrics,
+        evidence,
+        talkers: captures
+            .iter()
+            .map(|_| Mutex::new(Talkers::new(MAX_TALKERS)))
+            .collect(),
+        limited: Mutex::new(HashMap::new()),
+        stopping: Arc::new(AtomicBool::new(false)),
+    });
+    let signal = on_stop_signal(&shared.stopping).map_err(Error::Signal)?;
+
+    if let Some(path) = config_path {
+        watch_config(path, config, Arc::clone(&shared), events.clone())?;
+    }
+
+    // Started before any capture thread, so there's nothing to undo if the
+    // terminal can't be taken over.
+    let dashboard = match tui {
+        true => Some(tui::start(Arc::clone(&shared), recent).map_err(Error::Terminal)?),
+        false => None,
+    };
+
+    // Each capture thread sends its result down this channel when its input
+    // ends or fails, or it panics (see `Done`), like a Go `chan error`
+    // shared by several goroutines.
+    // From here on, errors go through the shutdown below rather than `?`, so
+    // the threads already started are stopped and the blocks lifted.
+    let mut result = Ok(());
+    let mut reason = "end_of_input";
+    let (done_tx, done_rx) = mpsc::channel();
+    for (talkers, capture) in captures.into_iter().enumerate() {
+        let worker = Worker {
+            events: if capture.is_interface {
+                events.with_interface(&capture.name)
+            } else {
+                events.clone()
+            },
+            limiter: capture.index % shared.limiters.len(),
+            talkers,
+            shared: Arc::clone(&shared),
+        };
+        let done = Done {
+            input: capture.name.clone(),
+            tx: Some(done_tx.clone()),
+        };
+        let spawned = thread::Builder::new()
+            .name(format!("capture {}", capture.name))
+            .spawn(move || done.send(worker.capture(capture)));
+        if let Err(e) = spawned {
+            (result, reason) = (Err(Error::Thread(e)), "error");
+            break;
+        }
+    }
+    // Drop our own sender, so the loop below ends once every thread is done.
+    drop(done_tx);
+
+    // A live interface never runs out, so this normally only ends on a
+    // signal, when the dashboard is quit, or when a capture fails. Either
+    // way, tell the other threads to stop, and give them until their next
+    // read timeout to notice. One that still hasn't (say, with a long
+    // `--read-timeout`) is left behind, and goes when the process exits.
+    while result.is_ok() {
+        match done_rx.recv_timeout(STOP_POLL) {
+            Ok(Ok(())) => {}
+            Ok(Err(e @ Error::WorkerFailed(_))) => {
+                (result, reason) = (Err(e), "worker_failed");
+            }
+            Ok(Err(e)) => {
+                (result, reason) = (Err(e), "error");
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) if shared.stopping() => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+    // Threads told to stop end just like inputs that ran out, and may all
+    // have done so before the loop above saw the flag.
+    if result.is_ok() && shared.stopping() {
+        reason = if signal.load(Ordering::Relaxed) != 0 {
+            "signal"
+        } else {
+            "quit"
+        };
+    }
+    shared.stopping.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + STOP_GRACE;
+    while done_rx
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .is_ok()
+    {}
+    // The dashboard gives the terminal back as it goes, in time for the
+    // summary below.
+    if let Some(dashboard) = dashboard {
+        let _ = dashboard.join();
+    }
+
+    // The threads left behind still hold the shared state, so the
+    // `Blocklist` is never dropped: lift the blocks by hand. Then make sure
+    // every saved frame and event is written before summing up.
+    shared.blocklist.clear();
+    if let Some(evidence) = &shared.evidence {
+        evidence.close();
+    }
+    let report = report(&shared, started, kernel_stats);
+    let signal = match signal.load(Ordering::Relaxed) {
+        0 => None,
+        signal => signal_hook::low_level::signal_name(signal as i32),
+    };
+    events.emit(
+        capture::now(),
+        &Event::Stopped {
+            reason,
+            signal,
+            report: report.clone(),
+        },
+    );
+    events.flush();
+    let _ = report.print(&mut io::stderr());
+    result
+}
+
+// How often the main thread checks for a signal while capture runs.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+// How long capture threads get to finish after being told to stop: well
+// over the default read timeout.
+const STOP_GRACE: Duration = Duration::from_secs(1);
+
+// Sources counted per capture thread, at most (see `Talkers`).
+const MAX_TALKERS: usize = 100_000;
+
+// Sources listed in the final report, by traffic and by how often they went
+// over a limit.
+const REPORT_TOP: usize = 10;
+
+// Stop on the first SIGINT or SIGTERM, and set `stopping`. A second one
+// exits straight away, for when stopping cleanly hangs. Returns the number
+// of the signal that came, or 0 while none has.
+fn on_stop_signal(stopping: &Arc<AtomicBool>) -> io::Result<Arc<AtomicUsize>> {
+    let signal = Arc::new(AtomicUsize::new(0));
+    for number in [SIGINT, SIGTERM] {
+        // Registered first, so it sees the flag before this same signal
+        // sets it.
+        signal_hook::flag::register_conditional_shutdown(number, 1, Arc::clone(stopping))?;
+        signal_hook::flag::register(number, Arc::clone(stopping))?;
+        signal_hook::flag::register_usize(number, Arc::clone(&signal), number as usize)?;
+    }
+    Ok(signal)
+}
+
+// Sum up the run from every capture thread's counts.
+fn report(shared: &Shared, started: Instant, kernel_stats: bool) -> Report {
+    let mut talkers = Talkers::new(usize::MAX);
+    for i in 0..shared.talkers.len() {
+        talkers.merge(&shared.talkers(i));
+    }
+    let limited = shared.limited();
+    let mut most_limited: Vec<Limited> = limited
+        .iter()
+        .map(|(&source, &times)| Limited { source, times })
+        .collect();
+    most_limited.sort_by(|a, b| b.times.cmp(&a.times).then(a.source.cmp(&b.source)));
+    most_limited.truncate(REPORT_TOP);
+
+    let (packets, bytes) = shared.metrics.packets();
+    Report {
+        duration_secs: started.elapsed().as_secs_f64(),
+        packets,
+        bytes,
+        sources: talkers.len(),
+        top_talkers: talkers.top(REPORT_TOP),
+        limited: most_limited,
+        limited_total: limited.len(),
+        kernel: kernel_stats.then(|| shared.metrics.kernel()),
+        bridge: shared.metrics.bridge_stats(),
+    }
+}
+
+// Reload the config file whenever it changes or we get SIGHUP. A file with a
+// mistake in it is reported and otherwise ignored, so a typo can't take the
+// running capture down.
+fn watch_config(
+    path: PathBuf,
+    mut current: Config,
+    shared: Arc<Shared>,
+    events: EventLog,
+) -> Result<()> {
+    let watched = path.clone();
+    let reload = move || {
+        let now = capture::now();
+        // List files are re-read too, so SIGHUP also picks up edits to them.
+        let loaded = Config::load(&path).and_then(|new| Ok((Lists::load(&new.lists)?, new)));
+        let (lists, new) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                events.emit(
+                    now,
+                    &Event::ConfigError {
+                        path: path.clone(),
+                        error: e.to_string(),
+                    },
+                );
+                return;
+            }
+        };
+        let ignored = current.needs_restart(&new);
+        shared.reconfigure(&new, lists, now);
+        // Remember what's actually running, for the next reload to compare
+        // against.
+        current = current.reloaded(new, &ignored);
+        events.emit(
+            now,
+            &Event::ConfigReloaded {
+                path: path.clone(),
+                ignored,
+            },
+        );
     };
-    // `mut tx` and `mut rx` mean they’re mutable; Rust vars are immutable unless `mut` is added.
-    // `tx` and `rx` are like Go channels, but here they’re for sending/receiving raw packets.
-    
-    // Create a thread-safe `HashMap` to track packet counts.
-    // `Arc::new` wraps the `Mutex` in an atomic reference counter.
-    // `Mutex::new` creates a mutex guarding the `HashMap`.
-    // `HashMap<String, u32>` maps strings (source addresses) to 32-bit unsigned ints (counts).
-    let packet_counts: Arc<Mutex<HashMap<String, u32>>> = Arc::new(Mutex::new(HashMap::new()));
-
-    // Track the last reset time for rate limiting.
-    // `Instant::now()` is like Go’s `time.Now()`.
-    // `Arc<Mutex<>>` again for thread safety; Rust requires this for shared mutable state.
-    let last_reset = Arc::new(Mutex::new(Instant::now()));
-
-    // Clone the `Arc`s for use in the loop.
-    // `Arc::clone` increases the reference count, like copying a pointer in Go.
-    // In Go, you’d just use the same `packetCounts` variable in a goroutine with a mutex.
-    let counts_clone = Arc::clone(&packet_counts);
-    let time_clone = Arc::clone(&last_reset);
-
-    // Infinite loop.
-    loop {
-        // `rx.next()` gets the next packet, returning a `Result<&[u8], Error>`.
-        // `&[u8]` is a slice of bytes (like Go’s `[]byte`).
-        // `match` again for error handling.
-        match rx.next() {
-            // `Ok(packet)` is the success case, `packet` is the raw bytes.
-            Ok(packet) => {
-                // Try to parse the packet as an Ethernet frame.
-                // `EthernetPacket::new` takes a `&[u8]` and returns an `Option<EthernetPacket>`.
-                // `if let` is a shorthand for matching on `Option`—like Go’s `if val, ok := ...; ok`.
-                // In Go, you’d use `gopacket.NewPacket` and check layers.
-                if let Some(ethernet) = EthernetPacket::new(packet) {
-                    // TODO: parse srcIP from the IP header, instead of MAC address
-                    let source = ethernet.get_source().to_string();
-
-                    // Lock the shared state (like Go's mutex.Lock())
-                    let mut counts = counts_clone.lock().unwrap(); // unwrap is like Go's panic on
-                                                                   // error
-                    let mut last_reset_time = time_clone.lock().unwrap();
-
-                    // Reset counts every 10 seconds (rate limiting window)
-                    if last_reset_time.elapsed() >= Duration::from_secs(10) {
-                        counts.clear();
-                        *last_reset_time = Instant::now();
+    config::watch(watched.clone(), Duration::from_secs(1), reload).map_err(|e| Error::Config {
+        path: watched,
+        message: e.to_string(),
+    })?;
+    Ok(())
+}
+
+// Hands a capture thread's result to `run`. A thread that panics never gets
+// to call `send`, but the guard is still dropped as its stack unwinds, and
+// reports the panic instead, so a crash can't pass for an input that ran
+// out. It's the `defer` with a `recover()` a Go goroutine would use.
+struct Done {
+    // The interface name or file path the thread reads.
+    input: String,
+    // Taken once a result is sent.
+    tx: Option<mpsc::Sender<Result<()>>>,
+}
+
+impl Done {
+    fn send(mut self, result: Result<()>) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(result);
+        }
+    }
+}
+
+impl Drop for Done {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(Err(Error::WorkerFailed(std::mem::take(&mut self.input))));
+        }
+    }
+}
+
+// Everything one capture thread needs.
+struct Worker {
+    shared: Arc<Shared>,
+    // Index of this thread's limiter in `shared.limiters`.
+    limiter: usize,
+    // Index of this thread's counts in `shared.talkers`.
+    talkers: usize,
+    events: EventLog,
+}
+
+impl Worker {
+    // Taking `self` by value means the worker, and its handle on the shared
+    // state, is dropped as soon as the capture ends.
+    fn capture(self, capture: Capture) -> Result<()> {
+        let Capture {
+            name,
+            mut input,
+            filter,
+            reopen,
+            mut responder,
+            mut forward,
+            ..
+        } = capture;
+        let Worker {
+            shared,
+            limiter,
+            talkers,
+            events,
+        } = self;
+        let limiters = &shared.limiters[limiter];
+        let metrics = &shared.metrics;
+        let blocklist = &shared.blocklist;
+        // When this thread last lifted expired blocks, in capture time.
+        let mut expired = Duration::ZERO;
+        let mut seen: u64 = 0;
+        let mut recorder = shared
+            .evidence
+            .as_ref()
+            .map(|evidence| evidence.recorder(&name));
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_secs(1));
+        let mut polled = Instant::now();
+        // Whether the last response failed, so a run of errors is only
+        // reported once.
+        let mut responses_failing = false;
+        let mut forward_failing = false;
+
+        // Loop until the input runs out, which a live interface never does,
+        // or until we're stopping.
+        // `input.next_frame()` returns a `Result<Option<Frame>>`: errors go
+        // back to `run`, and the loop stops at the first `None`.
+        while !shared.stopping() {
+            if polled.elapsed() >= KERNEL_STATS_INTERVAL {
+                poll_kernel_stats(&mut *input, metrics);
+                polled = Instant::now();
+            }
+            let frame = match input.next_frame() {
+                // A frame that arrives after we're told to stop could still
+                // block its source, after the blocks were lifted.
+                Ok(Some(_)) if shared.stopping() => break,
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                // Nothing arrived within the read timeout; just wait again.
+                Err(e) if ErrorClass::of(&e) == ErrorClass::Timeout => continue,
+                // Only a live interface is worth retrying or reopening; a
+                // file that fails to read stays broken.
+                Err(e) => {
+                    metrics.receive_error();
+                    let (class, error) = (ErrorClass::of(&e), e.to_string());
+                    match (class, &reopen) {
+                        (ErrorClass::Transient, Some(_)) if backoff.attempts() < MAX_RETRIES => {
+                            let delay = backoff.next_delay();
+                            let retry_in_ms = Some(delay.as_millis() as u64);
+                            events.emit(
+                                capture::now(),
+                                &Event::RxError {
+                                    error,
+                                    class,
+                                    retry_in_ms,
+                                },
+                            );
+                            thread::sleep(delay);
+                        }
+                        (ErrorClass::InterfaceDown, Some(reopen)) => {
+                            events.emit(
+                                capture::now(),
+                                &Event::RxError {
+                                    error,
+                                    class,
+                                    retry_in_ms: None,
+                                },
+                            );
+                            poll_kernel_stats(&mut *input, metrics);
+                            let Some(reopened) = reopen.wait(&events, &shared.stopping) else {
+                                break;
+                            };
+                            (input, responder) = reopened;
+                            metrics.interface_reopened();
+                        }
+                        _ => {
+                            let class = ErrorClass::Fatal;
+                            events.emit(
+                                capture::now(),
+                                &Event::RxError {
+                                    error,
+                                    class,
+                                    retry_in_ms: None,
+                                },
+                            );
+                            return Err(Error::Receive {
+                                input: name,
+                                source: e,
+                            });
+                        }
                     }
+                    continue;
+                }
+            };
+            backoff.reset();
+            // Frames from a file haven't been through the kernel filter.
+            if let Some(filter) = &filter
+                && !filter.matches(frame.data, frame.len)
+            {
+                // A bridge passes on what it doesn't look at.
+                if let Some(forwarder) = &mut forward {
+                    forward_frame(forwarder, &frame, &events, &mut forward_failing);
+                }
+                continue;
+            }
+            let started = Instant::now();
+            // Try to parse the packet as an Ethernet frame.
+            // `EthernetPacket::new` takes a `&[u8]` and returns an `Option<EthernetPacket>`.
+            // `let ... else` skips frames too short to parse, like Go's
+            // `if !ok { continue }`.
+            // In Go, you’d use `gopacket.NewPacket` and check layers.
+            let Some(ethernet) = EthernetPacket::new(frame.data) else {
+                // A bridge holds them back: with no source they can't be
+                // rate limited, and no real sender makes them.
+                if let Some(forwarder) = &forward {
+                    forwarder.drop_frame(&frame);
+                }
+                continue;
+            };
+
+            // Parse every header once. The source is the IP source, or the
+            // MAC for non-IP frames.
+            let summary = Summary::of(&ethernet);
+            let source = summary.source();
+            let protocol = summary.protocol();
+            metrics.packet(protocol, frame.len);
+            shared.talkers(talkers).record(source, protocol, frame.len);
+            if let Some(recorder) = &mut recorder {
+                recorder.frame(&frame, source);
+            }
 
-                    // Increment packet count for this source
-                    let count = counts.entry(source.clone()).or_insert(0);
-                    *count += 1;
+            // Allowlisted sources are never counted or blocked, and take
+            // precedence over the denylist.
+            let listing = shared.lists().check(&source);
 
-                    // Rate limiting logic: block if > 100 packets in 10s
-                    if *count > 100 {
-                        println!("Rate limiting exceeded for {}: {} packets", source, count);
-                        // TODO: drop packets
-                    } else {
-                        println!("Packet from {}: total {}", source, count);
+            // Lift blocks that have run out. That means going over every
+            // block, so it's done once a second of capture time, like the
+            // kernel stats above, rather than for every packet.
+            let now = frame.timestamp;
+            if now.saturating_sub(expired) >= EXPIRE_INTERVAL {
+                for (source, result) in blocklist.expire(now) {
+                    events.emit(now, &Event::LimitCleared { source });
+                    if let Err(e) = result {
+                        events.emit(
+                            now,
+                            &Event::EnforcerError {
+                                source,
+                                error: e.to_string(),
+                            },
+                        );
                     }
                 }
+                expired = now;
             }
-            Err(e) => {
-                eprintln!("Error receiving packet: {}", e);
-                break;
+
+            // Drop anything from a denied or blocked source without counting
+            // it; otherwise apply the rate limit, blocking sources that go
+            // over. Two threads can see the same source go over at once, but
+            // only one of them gets `Ok(true)` from `block`. Whoever went
+            // over is saved as evidence below.
+            let mut offenders = Vec::new();
+            let mut responses: Vec<Response> = Vec::new();
+            let action = match listing {
+                Listing::Allowed => PacketAction::Ignored,
+                // The whole denylist entry is blocked, so a denied range
+                // becomes one firewall rule instead of one per address,
+                // unless the allowlist carves exceptions out of it.
+                Listing::Denied(prefix) => {
+                    let target = shared.lists().block_target(prefix, source);
+                    match blocklist.block(target, now) {
+                        Ok(true) => events.emit(now, &Event::Denied { source, prefix }),
+                        Ok(false) => {}
+                        Err(e) => events.emit(
+                            now,
+                            &Event::EnforcerError {
+                                source: target,
+                                error: e.to_string(),
+                            },
+                        ),
+                    }
+                    PacketAction::Denied
+                }
+                Listing::Unlisted if blocklist.is_blocked(&source) => PacketAction::Dropped,
+                Listing::Unlisted => {
+                    let mut action = PacketAction::Allowed;
+                    // Whatever went over is blocked: the source itself, or
+                    // the whole prefix for an aggregate level. A prefix with
+                    // allowlisted addresses in it is blocked a source at a
+                    // time instead, each as it sends while the prefix is
+                    // over.
+                    if let Some(Exceeded {
+                        prefix,
+                        unit,
+                        used,
+                        limit,
+                    }) = limiters.sources.record(source, now, frame.len)
+                    {
+                        metrics.limit_exceeded();
+                        let target = shared.lists().block_target(prefix, source);
+                        if let Err(e) = blocklist.block(target, now) {
+                            events.emit(
+                                now,
+                                &Event::EnforcerError {
+                                    source: target,
+                                    error: e.to_string(),
+                                },
+                            );
+                        }
+                        let level = (!prefix.is_address()).then_some(prefix);
+                        let event = Event::LimitExceeded {
+                            source,
+                            prefix: level,
+                            policy: None,
+                            key: None,
+                            unit,
+                            used,
+                            limit,
+                        };
+                        events.emit(now, &event);
+                        offenders.push((prefix, event));
+                        action = PacketAction::Exceeded;
+                    }
+                    // Policies only block the source when their key includes
+                    // it. A key like `destination` is shared by every sender,
+                    // so going over it is reported (once per window) but
+                    // blocks nobody.
+                    for exceeded in limiters.policies.record(&summary, now, frame.len) {
+                        metrics.limit_exceeded();
+                        action = PacketAction::Exceeded;
+                        // Every packet over the limit is answered, repeats
+                        // too, but with each kind of response only once.
+                        for &response in exceeded.responses {
+                            if !responses.contains(&response) {
+                                responses.push(response);
+                            }
+                        }
+                        if exceeded.repeat {
+                            continue;
+                        }
+                        if exceeded.blockable
+                            && let Err(e) = blocklist.block(Prefix::from(source), now)
+                        {
+                            events.emit(
+                                now,
+                                &Event::EnforcerError {
+                                    source: source.into(),
+                                    error: e.to_string(),
+                                },
+                            );
+                        }
+                        let event = Event::LimitExceeded {
+                            source,
+                            prefix: None,
+                            policy: Some(exceeded.policy.to_string()),
+                            key: Some(exceeded.key),
+                            unit: exceeded.unit,
+                            used: exceeded.used,
+                            limit: exceeded.limit,
+                        };
+                        events.emit(now, &event);
+                        offenders.push((Prefix::from(source), event));
+                    }
+                    action
+                }
+            };
+            let limited = blocklist.len();
+            // A bridge only passes on frames that didn't go over a limit,
+            // from sources that aren't blocked.
+            if let Some(forwarder) = &mut forward {
+                match action {
+                    PacketAction::Allowed | PacketAction::Ignored => {
+                        forward_frame(forwarder, &frame, &events, &mut forward_failing);
+                    }
+                    _ => forwarder.drop_frame(&frame),
+                }
+            }
+            if !offenders.is_empty() {
+                let mut counts = shared.limited();
+                for (offender, _) in &offenders {
+                    *counts.entry(*offender).or_default() += 1;
+                }
+            }
+            if let Some(recorder) = &mut recorder {
+                for (offender, reason) in &offenders {
+                    recorder.trigger(*offender, reason);
+                }
             }
+            if let Some(responder) = &mut responder {
+                for &response in &responses {
+                    match responder.respond(response, frame.data, &summary) {
+                        Ok(true) => {
+                            metrics.response(response);
+                            responses_failing = false;
+                        }
+                        // Didn't apply to this packet, or too many already.
+                        Ok(false) => {}
+                        Err(e) => {
+                            metrics.response_error();
+                            if !responses_failing {
+                                events.emit(
+                                    now,
+                                    &Event::ResponseError {
+                                        response,
+                                        error: e.to_string(),
+                                    },
+                                );
+                            }
+                            responses_failing = true;
+                        }
+                    }
+                }
+            }
+
+            // True for every `sample`th packet, like `seen%sample == 0` in Go.
+            let sample = shared.sample.load(Ordering::Relaxed);
+            if sample > 0 && seen.is_multiple_of(sample) {
+                events.emit(
+                    now,
+                    &Event::Packet {
+                        source,
+                        protocol,
+                        len: frame.len,
+                        action,
+                        headers: summary,
+                    },
+                );
+            }
+            seen += 1;
+
+            let tracked = shared.limiters.iter().map(Limiters::len).sum();
+            metrics.set_sources(tracked, limited);
+            metrics.observe_latency(started.elapsed());
         }
+
+        poll_kernel_stats(&mut *input, metrics);
+        Ok(())
+    }
+}
+
+// Send `frame` over the bridge. Only the first of a run of errors is
+// reported, since a side that's down fails every frame.
+fn forward_frame(forwarder: &mut Forwarder, frame: &Frame, events: &EventLog, failing: &mut bool) {
+    match forwarder.forward(frame) {
+        Ok(()) => *failing = false,
+        Err(e) => {
+            if !*failing {
+                let to = forwarder.direction().to().to_string();
+                events.emit(
+                    frame.timestamp,
+                    &Event::ForwardError {
+                        to,
+                        error: e.to_string(),
+                    },
+                );
+            }
+            *failing = true;
+        }
+    }
+}
+
+// How often a capture thread adds its socket's kernel counters to the
+// metrics.
+const KERNEL_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often, in capture time, each capture thread lifts the blocks that
+// have run out. Blocks can last up to this much longer than asked.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn poll_kernel_stats(input: &mut dyn PacketSource, metrics: &Metrics) {
+    if let Some(stats) = input.kernel_stats() {
+        metrics.kernel_stats(stats);
     }
 }
@@ -0,0 +1,561 @@
+// Prometheus metrics. The capture loop updates plain atomic counters, and a
+// small HTTP server thread renders them in the Prometheus text format when
+// scraped, so exporting never takes a lock on the packet path.
+//
+// Text format reference:
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::bridge::{Direction, DirectionStats};
+use crate::capture::KernelStats;
+use crate::protocol::Protocol;
+use crate::respond::Response;
+
+// Upper bounds of the latency histogram buckets, in seconds. Processing a
+// packet normally takes a few microseconds; the top buckets catch stalls such
+// as an enforcer shelling out to iptables.
+const LATENCY_BUCKETS: [f64; 10] = [
+    1e-6, 2.5e-6, 5e-6, 1e-5, 2.5e-5, 5e-5, 1e-4, 1e-3, 1e-2, 1e-1,
+];
+
+// `AtomicU64` is like Go's `atomic.Uint64`. `Relaxed` ordering is enough
+// because each counter is independent; a scrape may see one counter a packet
+// ahead of another, which Prometheus doesn't care about.
+#[derive(Default)]
+pub struct Metrics {
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    protocols: [AtomicU64; Protocol::ALL.len()],
+    tracked_sources: AtomicU64,
+    limited_sources: AtomicU64,
+    limit_exceeded: AtomicU64,
+    receive_errors: AtomicU64,
+    interface_reopens: AtomicU64,
+    evidence_frames: AtomicU64,
+    evidence_dropped: AtomicU64,
+    kernel_packets: AtomicU64,
+    kernel_drops: AtomicU64,
+    responses: [AtomicU64; Response::ALL.len()],
+    response_errors: AtomicU64,
+    // Each direction of a bridge, if there is one. Only locked to add a
+    // direction and to render; forwarding updates the counters directly.
+    bridge: Mutex<Vec<Arc<Direction>>>,
+    latency: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count one received frame of `len` bytes.
+    pub fn packet(&self, protocol: Protocol, len: usize) {
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        // The enum's discriminant doubles as the array index.
+        self.protocols[protocol as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count one packet that took its source over a limit.
+    pub fn limit_exceeded(&self) {
+        self.limit_exceeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn receive_error(&self) {
+        self.receive_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count one channel opened again after its interface went down.
+    pub fn interface_reopened(&self) {
+        self.interface_reopens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count frames written to an evidence file.
+    pub fn evidence_frames(&self, frames: u64) {
+        self.evidence_frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Count frames that should have been saved as evidence but weren't.
+    pub fn evidence_dropped(&self, frames: u64) {
+        self.evidence_dropped.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    /// Add what a capture socket's kernel counters went up by.
+    pub fn kernel_stats(&self, stats: KernelStats) {
+        self.kernel_packets
+            .fetch_add(stats.packets, Ordering::Relaxed);
+        self.kernel_drops.fetch_add(stats.drops, Ordering::Relaxed);
+    }
+
+    /// Count one response sent.
+    pub fn response(&self, response: Response) {
+        self.responses[response as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn response_error(&self) {
+        self.response_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters for bridging frames from the interface `from` to `to`,
+    /// registered the first time they're asked for.
+    pub fn bridge(&self, from: &str, to: &str) -> Arc<Direction> {
+        let mut directions = self.bridge.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(direction) = directions
+            .iter()
+            .find(|direction| direction.from() == from && direction.to() == to)
+        {
+            return Arc::clone(direction);
+        }
+        let direction = Arc::new(Direction::new(from, to));
+        directions.push(Arc::clone(&direction));
+        direction
+    }
+
+    /// Every bridge direction's counts so far.
+    pub fn bridge_stats(&self) -> Vec<DirectionStats> {
+        let directions = self.bridge.lock().unwrap_or_else(PoisonError::into_inner);
+        directions
+            .iter()
+            .map(|direction| direction.stats())
+            .collect()
+    }
+
+    /// Frames and bytes received so far.
+    pub fn packets(&self) -> (u64, u64) {
+        (
+            self.packets.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The kernel's counts so far, over every capture socket.
+    pub fn kernel(&self) -> KernelStats {
+        KernelStats {
+            packets: self.kernel_packets.load(Ordering::Relaxed),
+            drops: self.kernel_drops.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record the current size of the limiter and the blocklist.
+    pub fn set_sources(&self, tracked: usize, limited: usize) {
+        self.tracked_sources
+            .store(tracked as u64, Ordering::Relaxed);
+        self.limited_sources
+            .store(limited as u64, Ordering::Relaxed);
+    }
+
+    /// Record how long one packet took to process.
+    pub fn observe_latency(&self, elapsed: Duration) {
+        self.latency.observe(elapsed);
+    }
+
+    /// Render every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        counter(
+            &mut out,
+            "packets_total",
+            "Frames received.",
+            load(&self.packets),
+        );
+        counter(
+            &mut out,
+            "bytes_total",
+            "Bytes received, as seen on the wire.",
+            load(&self.bytes),
+        );
+
+        header(
+            &mut out,
+            "protocol_packets_total",
+            "counter",
+            "Frames received, by protocol.",
+        );
+        for protocol in Protocol::ALL {
+            let value = load(&self.protocols[protocol as usize]);
+            let _ = writeln!(
+                out,
+                "packet_processor_protocol_packets_total{{protocol=\"{}\"}} {}",
+                protocol.name(),
+                value
+            );
+        }
+
+        gauge(
+            &mut out,
+            "tracked_sources",
+            "Sources, prefixes and policy keys with rate limiting state.",
+            load(&self.tracked_sources),
+        );
+        gauge(
+            &mut out,
+            "limited_sources",
+            "Sources and prefixes currently blocked.",
+            load(&self.limited_sources),
+        );
+        counter(
+            &mut out,
+            "limit_exceeded_total",
+            "Packets that took a source or policy key over a limit.",
+            load(&self.limit_exceeded),
+        );
+        counter(
+            &mut out,
+            "receive_errors_total",
+            "Errors returned while receiving frames.",
+            load(&self.receive_errors),
+        );
+        counter(
+            &mut out,
+            "interface_reopens_total",
+            "Capture channels opened again after their interface went down.",
+            load(&self.interface_reopens),
+        );
+        counter(
+            &mut out,
+            "evidence_frames_total",
+            "Frames written to evidence files.",
+            load(&self.evidence_frames),
+        );
+        counter(
+            &mut out,
+            "kernel_packets_total",
+            "Frames the kernel received for the capture sockets, including dropped ones.",
+            load(&self.kernel_packets),
+        );
+        counter(
+            &mut out,
+            "kernel_drops_total",
+            "Frames the kernel dropped because capture didn't keep up.",
+            load(&self.kernel_drops),
+        );
+        counter(
+            &mut out,
+            "evidence_dropped_total",
+            "Frames not saved as evidence because the writer fell behind or failed.",
+            load(&self.evidence_dropped),
+        );
+
+        header(
+            &mut out,
+            "responses_total",
+            "counter",
+            "Responses sent to packets over a policy's limit, by kind.",
+        );
+        for response in Response::ALL {
+            let value = load(&self.responses[response as usize]);
+            let _ = writeln!(
+                out,
+                "packet_processor_responses_total{{response=\"{}\"}} {}",
+                response.name(),
+                value
+            );
+        }
+        counter(
+            &mut out,
+            "response_errors_total",
+            "Responses that failed to send.",
+            load(&self.response_errors),
+        );
+
+        let bridge = self.bridge_stats();
+        if !bridge.is_empty() {
+            header(
+                &mut out,
+                "bridge_frames_total",
+                "counter",
+                "Frames read on one side of the bridge, by what happened to them.",
+            );
+            for direction in &bridge {
+                for (action, value) in [
+                    ("forwarded", direction.forwarded),
+                    ("dropped", direction.dropped),
+                    ("error", direction.errors),
+                ] {
+                    let _ = writeln!(
+                        out,
+                        "packet_processor_bridge_frames_total{{from=\"{}\",to=\"{}\",action=\"{}\"}} {}",
+                        direction.from, direction.to, action, value
+                    );
+                }
+            }
+            header(
+                &mut out,
+                "bridge_bytes_total",
+                "counter",
+                "Bytes read on one side of the bridge, forwarded or dropped.",
+            );
+            for direction in &bridge {
+                for (action, value) in [
+                    ("forwarded", direction.forwarded_bytes),
+                    ("dropped", direction.dropped_bytes),
+                ] {
+                    let _ = writeln!(
+                        out,
+                        "packet_processor_bridge_bytes_total{{from=\"{}\",to=\"{}\",action=\"{}\"}} {}",
+                        direction.from, direction.to, action, value
+                    );
+                }
+            }
+        }
+
+        self.latency.render(
+            &mut out,
+            "processing_seconds",
+            "Time spent processing one frame.",
+        );
+        out
+    }
+}
+
+// A Prometheus histogram with fixed buckets. Each observation lands in one
+// bucket here; the cumulative `le` counts are only summed up when rendering.
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    // All observations, which is also the `+Inf` bucket.
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, "histogram", help);
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "packet_processor_{}_bucket{{le=\"{}\"}} {}",
+                name, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(
+            out,
+            "packet_processor_{}_bucket{{le=\"+Inf\"}} {}",
+            name, count
+        );
+        let _ = writeln!(out, "packet_processor_{}_sum {}", name, sum);
+        let _ = writeln!(out, "packet_processor_{}_count {}", name, count);
+    }
+}
+
+// `let _ =` discards the `fmt::Result`: writing to a `String` can't fail.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP packet_processor_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE packet_processor_{} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "packet_processor_{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, "gauge", help);
+    let _ = writeln!(out, "packet_processor_{} {}", name, value);
+}
+
+/// Serve `GET /metrics` on `addr` from a background thread.
+///
+/// Binding happens before this returns, so a taken port is reported to the
+/// caller rather than lost in the thread.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    Ok(listen(listener, metrics))
+}
+
+// Answer scrapes on an already bound listener.
+fn listen(listener: TcpListener, metrics: Arc<Metrics>) -> JoinHandle<()> {
+    // `thread::spawn` is like `go func() { ... }()`; `move` hands the
+    // listener and the `Arc` over to the new thread.
+    thread::spawn(move || {
+        // Scrapes are rare and tiny, so one at a time is plenty. `flatten`
+        // skips connections that failed to be accepted, and a broken
+        // connection only affects that scrape.
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream, &metrics);
+        }
+    })
+}
+
+// The most of a request that's read: plenty for a scraper's request line
+// and headers.
+const MAX_REQUEST: usize = 8192;
+
+// How long a client gets to send its request, and to take the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Don't let a client that never sends a request, sends it a byte at a
+    // time, or never reads the response hold the server up.
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let deadline = Instant::now() + CLIENT_TIMEOUT;
+
+    // Only the request line matters, e.g. "GET /metrics HTTP/1.1". The
+    // headers are read and ignored so closing the socket doesn't reset it.
+    // `take` stops after `MAX_REQUEST` bytes, like Go's `io.LimitReader`,
+    // so they're read into a fixed buffer rather than one that grows.
+    let mut request = [0; MAX_REQUEST];
+    let mut len = 0;
+    let mut reader = (&stream).take(MAX_REQUEST as u64);
+    let mut ended = false;
+    while !ended {
+        if Instant::now() >= deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        match reader.read(&mut request[len..])? {
+            0 => break,
+            n => len += n,
+        }
+        ended = request[..len].windows(4).any(|end| end == b"\r\n\r\n");
+    }
+    let request = String::from_utf8_lossy(&request[..len]);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, content_type, body) = match (method, path) {
+        // The buffer filled up before the headers ended.
+        _ if !ended && len == MAX_REQUEST => (
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            "request too large\n".to_string(),
+        ),
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send one request for `path` and return the whole response.
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrape_serves_counters_and_histogram() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.packet(Protocol::Tcp, 60);
+        metrics.packet(Protocol::Tcp, 1514);
+        metrics.packet(Protocol::Arp, 42);
+        metrics.limit_exceeded();
+        metrics.set_sources(7, 2);
+        metrics.response(Response::TcpReset);
+        metrics.kernel_stats(KernelStats {
+            packets: 10,
+            drops: 1,
+        });
+        metrics.observe_latency(Duration::from_micros(3));
+        metrics.observe_latency(Duration::from_millis(2));
+        // Slower than the top bucket, so only counted in `+Inf`.
+        metrics.observe_latency(Duration::from_secs(1));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listen(listener, Arc::clone(&metrics));
+
+        let response = get(addr, "/metrics");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        let lines: Vec<&str> = body.lines().collect();
+        for expected in [
+            "# TYPE packet_processor_packets_total counter",
+            "packet_processor_packets_total 3",
+            "packet_processor_bytes_total 1616",
+            "packet_processor_protocol_packets_total{protocol=\"tcp\"} 2",
+            "packet_processor_protocol_packets_total{protocol=\"arp\"} 1",
+            "packet_processor_protocol_packets_total{protocol=\"udp\"} 0",
+            "# TYPE packet_processor_tracked_sources gauge",
+            "packet_processor_tracked_sources 7",
+            "packet_processor_limited_sources 2",
+            "packet_processor_limit_exceeded_total 1",
+            "packet_processor_kernel_packets_total 10",
+            "packet_processor_kernel_drops_total 1",
+            "packet_processor_responses_total{response=\"tcp-reset\"} 1",
+            "packet_processor_responses_total{response=\"arp-correction\"} 0",
+            "# TYPE packet_processor_processing_seconds histogram",
+            "packet_processor_processing_seconds_bucket{le=\"0.0000025\"} 0",
+            "packet_processor_processing_seconds_bucket{le=\"0.000005\"} 1",
+            "packet_processor_processing_seconds_bucket{le=\"0.001\"} 1",
+            "packet_processor_processing_seconds_bucket{le=\"0.01\"} 2",
+            "packet_processor_processing_seconds_bucket{le=\"0.1\"} 2",
+            "packet_processor_processing_seconds_bucket{le=\"+Inf\"} 3",
+            "packet_processor_processing_seconds_sum 1.002003",
+            "packet_processor_processing_seconds_count 3",
+        ] {
+            assert!(lines.contains(&expected), "missing {expected:?} in\n{body}");
+        }
+        // No bridge, so no bridge metrics.
+        assert!(!body.contains("bridge"));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn turns_away_requests_that_are_too_large() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listen(listener, Arc::new(Metrics::new()));
+
+        // Headers that never end, as long as the server reads.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = b"GET /metrics HTTP/1.1\r\nX: ".to_vec();
+        request.resize(MAX_REQUEST, b'x');
+        stream.write_all(&request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+
+        // A request cut off before its headers end is still answered, and
+        // the server goes on to the next one.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(addr, "/metrics").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,801 @@
+// Reader for capture files written by tcpdump, Wireshark and friends.
+// Both the classic pcap format and the newer pcapng format are supported, in
+// either byte order; the format is detected from the first four bytes.
+// There's also a writer, for pcapng only.
+//
+// Format references:
+// - pcap:   https://www.ietf.org/archive/id/draft-ietf-opsawg-pcap-04.html
+// - pcapng: https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+/// Link type for Ethernet frames, the only one the rest of the program parses.
+pub const LINKTYPE_ETHERNET: u16 = 1;
+
+// Anything bigger than this is treated as a corrupt file rather than a reason
+// to allocate gigabytes.
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+// pcapng block types.
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const BLOCK_PACKET: u32 = 0x0000_0002;
+const BLOCK_SIMPLE_PACKET: u32 = 0x0000_0003;
+const BLOCK_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+// pcapng option codes. Codes are only unique within a block type.
+const OPT_END_OF_OPTIONS: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_TSRESOL: u16 = 9;
+
+/// One packet read from a capture file.
+pub struct Record<'a> {
+    /// Capture time, as an offset from the Unix epoch.
+    pub timestamp: Duration,
+    /// Link type of the interface the packet was captured on.
+    pub linktype: u16,
+    /// The captured bytes (may be shorter than the packet on the wire).
+    pub data: &'a [u8],
+    /// Length of the packet on the wire, before any snaplen truncation.
+    pub original_len: usize,
+}
+
+// What we learned from an Interface Description Block.
+struct Interface {
+    linktype: u16,
+    // Timestamp units per second, e.g. 1_000_000 for microseconds.
+    units_per_sec: u64,
+}
+
+// Timestamp, link type, offset and length of the data in the read buffer,
+// and original length of a packet, before it is handed out as a `Record`.
+type Located = (Duration, u16, usize, usize, usize);
+
+enum Format {
+    Pcap {
+        big_endian: bool,
+        units_per_sec: u64,
+        linktype: u16,
+    },
+    PcapNg {
+        big_endian: bool,
+        interfaces: Vec<Interface>,
+    },
+}
+
+// Generic over `R: Read` so it works on a `File`, a `BufReader`, or an
+// in-memory `&[u8]`, the way Go code would accept an `io.Reader`.
+pub struct PcapReader<R> {
+    reader: R,
+    format: Format,
+    // Reused between records so reading doesn't allocate per packet.
+    buf: Vec<u8>,
+    // Simple Packet Blocks carry no timestamp, so they inherit the last one.
+    last_timestamp: Duration,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Read the file header and work out which format and byte order it uses.
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        let format = match magic {
+            [0xd4, 0xc3, 0xb2, 0xa1] => pcap_header(&mut reader, false, 1_000_000)?,
+            [0xa1, 0xb2, 0xc3, 0xd4] => pcap_header(&mut reader, true, 1_000_000)?,
+            [0x4d, 0x3c, 0xb2, 0xa1] => pcap_header(&mut reader, false, 1_000_000_000)?,
+            [0xa1, 0xb2, 0x3c, 0x4d] => pcap_header(&mut reader, true, 1_000_000_000)?,
+            [0x0a, 0x0d, 0x0d, 0x0a] => {
+                let big_endian = section_header(&mut reader)?;
+                Format::PcapNg {
+                    big_endian,
+                    interfaces: Vec::new(),
+                }
+            }
+            _ => return Err(invalid("not a pcap or pcapng file")),
+        };
+
+        Ok(PcapReader {
+            reader,
+            format,
+            buf: Vec::new(),
+            last_timestamp: Duration::ZERO,
+        })
+    }
+
+    /// Read the next packet, or `None` at the end of the file.
+    pub fn next_packet(&mut self) -> io::Result<Option<Record<'_>>> {
+        let (timestamp, linktype, start, len, original_len) = match self.format {
+            Format::Pcap { .. } => match self.next_pcap()? {
+                Some(found) => found,
+                None => return Ok(None),
+            },
+            Format::PcapNg { .. } => match self.next_pcapng()? {
+                Some(found) => found,
+                None => return Ok(None),
+            },
+        };
+        self.last_timestamp = timestamp;
+        let data = &self.buf[start..start + len];
+        Ok(Some(Record {
+            timestamp,
+            linktype,
+            data,
+            original_len,
+        }))
+    }
+
+    // The helpers below return where the packet sits in `self.buf` rather than
+    // a slice, which keeps the borrow checker happy inside their loops.
+    fn next_pcap(&mut self) -> io::Result<Option<Located>> {
+        let Format::Pcap {
+            big_endian,
+            units_per_sec,
+            linktype,
+        } = self.format
+        else {
+            unreachable!()
+        };
+
+        let mut header = [0u8; 16];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let seconds = u32_at(&header, 0, big_endian) as u64;
+        let fraction = u32_at(&header, 4, big_endian) as u64;
+        let captured = u32_at(&header, 8, big_endian) as usize;
+        let original = u32_at(&header, 12, big_endian) as usize;
+        if captured > MAX_RECORD_LEN {
+            return Err(invalid("packet record too large"));
+        }
+
+        self.buf.resize(captured, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        let timestamp =
+            Duration::from_secs(seconds) + fraction_to_duration(fraction, units_per_sec);
+        Ok(Some((
+            timestamp,
+            linktype,
+            0,
+            captured,
+            original.max(captured),
+        )))
+    }
+
+    fn next_pcapng(&mut self) -> io::Result<Option<Located>> {
+        loop {
+            let Format::PcapNg {
+                big_endian,
+                ref mut interfaces,
+            } = self.format
+            else {
+                unreachable!()
+            };
+
+            let mut header = [0u8; 8];
+            if !read_or_eof(&mut self.reader, &mut header)? {
+                return Ok(None);
+            }
+            let block_type = u32_at(&header, 0, big_endian);
+
+            // A new section may start mid-file (e.g. concatenated captures),
+            // possibly with a different byte order and its own interfaces.
+            if block_type == BLOCK_SECTION_HEADER {
+                let big_endian = section_header_after_type(&mut self.reader, &header[4..8])?;
+                self.format = Format::PcapNg {
+                    big_endian,
+                    interfaces: Vec::new(),
+                };
+                continue;
+            }
+
+            // Block length covers the 8 header bytes, the body, and a trailing
+            // copy of the length.
+            let total = u32_at(&header, 4, big_endian) as usize;
+            if total < 12 || !total.is_multiple_of(4) || total > MAX_RECORD_LEN {
+                return Err(invalid("bad pcapng block length"));
+            }
+            let body_len = total - 12;
+            self.buf.resize(body_len + 4, 0);
+            self.reader.read_exact(&mut self.buf)?;
+            let body = &self.buf[..body_len];
+
+            match block_type {
+                BLOCK_INTERFACE_DESCRIPTION => {
+                    if body.len() < 8 {
+                        return Err(invalid("truncated interface description block"));
+                    }
+                    let linktype = u16_at(body, 0, big_endian);
+                    let units_per_sec = tsresol(&body[8..], big_endian)?;
+                    interfaces.push(Interface {
+                        linktype,
+                        units_per_sec,
+                    });
+                }
+                BLOCK_ENHANCED_PACKET | BLOCK_PACKET => {
+                    if body.len() < 20 {
+                        return Err(invalid("truncated packet block"));
+                    }
+                    // The obsolete Packet Block has a 16-bit interface id
+                    // followed by a 16-bit drop counter.
+                    let interface_id = if block_type == BLOCK_PACKET {
+                        u16_at(body, 0, big_endian) as usize
+                    } else {
+                        u32_at(body, 0, big_endian) as usize
+                    };
+                    let high = u32_at(body, 4, big_endian) as u64;
+                    let low = u32_at(body, 8, big_endian) as u64;
+                    let captured = u32_at(body, 12, big_endian) as usize;
+                    let original = u32_at(body, 16, big_endian) as usize;
+                    if 20 + captured > body.len() {
+                        return Err(invalid("packet data overruns its block"));
+                    }
+                    let interface = interfaces
+                        .get(interface_id)
+                        .ok_or_else(|| invalid("packet refers to an unknown interface"))?;
+                    let units = (high << 32) | low;
+                    let timestamp = Duration::from_secs(units / interface.units_per_sec)
+                        + fraction_to_duration(
+                            units % interface.units_per_sec,
+                            interface.units_per_sec,
+                        );
+                    return Ok(Some((
+                        timestamp,
+                        interface.linktype,
+                        20,
+                        captured,
+                        original.max(captured),
+                    )));
+                }
+                BLOCK_SIMPLE_PACKET => {
+                    if body.len() < 4 {
+                        return Err(invalid("truncated simple packet block"));
+                    }
+                    let interface = interfaces
+                        .first()
+                        .ok_or_else(|| invalid("packet refers to an unknown interface"))?;
+                    let original = u32_at(body, 0, big_endian) as usize;
+                    let captured = original.min(body.len() - 4);
+                    return Ok(Some((
+                        self.last_timestamp,
+                        interface.linktype,
+                        4,
+                        captured,
+                        original,
+                    )));
+                }
+                // Name resolution, statistics, custom blocks and so on are not
+                // needed for rate limiting.
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Writes a pcapng file: one section, with an interface described the first
+/// time a packet from it is written. Timestamps have nanosecond resolution,
+/// and everything is little-endian.
+pub struct PcapngWriter<W> {
+    writer: W,
+    // Interface names, by their id in the file.
+    interfaces: Vec<String>,
+    written: u64,
+    // Reused for each block's body.
+    block: Vec<u8>,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start a file, naming `application` as the program that wrote it.
+    pub fn new(writer: W, application: &str) -> io::Result<PcapngWriter<W>> {
+        let mut pcapng = PcapngWriter {
+            writer,
+            interfaces: Vec::new(),
+            written: 0,
+            block: Vec::new(),
+        };
+        pcapng
+            .block
+            .extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+        // Version 1.0, and a section length of -1: not given.
+        pcapng.block.extend_from_slice(&1u16.to_le_bytes());
+        pcapng.block.extend_from_slice(&0u16.to_le_bytes());
+        pcapng.block.extend_from_slice(&(-1i64).to_le_bytes());
+        option(&mut pcapng.block, OPT_SHB_USERAPPL, application.as_bytes());
+        option(&mut pcapng.block, OPT_END_OF_OPTIONS, &[]);
+        pcapng.write_block(BLOCK_SECTION_HEADER)?;
+        Ok(pcapng)
+    }
+
+    /// Write one Ethernet frame captured on `interface`. `data` may be
+    /// shorter than the frame was on the wire (`original_len`).
+    pub fn write_packet(
+        &mut self,
+        interface: &str,
+        timestamp: Duration,
+        data: &[u8],
+        original_len: usize,
+        comment: Option<&str>,
+    ) -> io::Result<()> {
+        let id = self.interface(interface)?;
+        let nanos = timestamp.as_nanos() as u64;
+        for value in [
+            id,
+            (nanos >> 32) as u32,
+            nanos as u32,
+            data.len() as u32,
+            original_len as u32,
+        ] {
+            self.block.extend_from_slice(&value.to_le_bytes());
+        }
+        self.block.extend_from_slice(data);
+        pad(&mut self.block);
+        if let Some(comment) = comment {
+            option(&mut self.block, OPT_COMMENT, comment.as_bytes());
+            option(&mut self.block, OPT_END_OF_OPTIONS, &[]);
+        }
+        self.write_block(BLOCK_ENHANCED_PACKET)
+    }
+
+    /// Bytes written so far, including headers.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // The id of `name`, writing an Interface Description Block for it first
+    // if it's new to this file.
+    fn interface(&mut self, name: &str) -> io::Result<u32> {
+        if let Some(id) = self.interfaces.iter().position(|known| known == name) {
+            return Ok(id as u32);
+        }
+        self.block
+            .extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        self.block.extend_from_slice(&0u16.to_le_bytes());
+        // A snap length of 0 means no limit.
+        self.block.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut self.block, OPT_IF_NAME, name.as_bytes());
+        // 10^-9 seconds.
+        option(&mut self.block, OPT_IF_TSRESOL, &[9]);
+        option(&mut self.block, OPT_END_OF_OPTIONS, &[]);
+        self.write_block(BLOCK_INTERFACE_DESCRIPTION)?;
+        self.interfaces.push(name.to_string());
+        Ok(self.interfaces.len() as u32 - 1)
+    }
+
+    // Write `self.block` as the body of a block of type `block_type`: the
+    // total length goes both before and after it, so readers can skip
+    // blocks in either direction.
+    fn write_block(&mut self, block_type: u32) -> io::Result<()> {
+        let total = (self.block.len() + 12) as u32;
+        let result = (|| {
+            self.writer.write_all(&block_type.to_le_bytes())?;
+            self.writer.write_all(&total.to_le_bytes())?;
+            self.writer.write_all(&self.block)?;
+            self.writer.write_all(&total.to_le_bytes())
+        })();
+        self.block.clear();
+        self.written += u64::from(total);
+        result
+    }
+}
+
+// Append an option: code, length, and the value padded to 4 bytes.
+fn option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad(buf);
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    buf.resize(buf.len().next_multiple_of(4), 0);
+}
+
+// Read the remaining 20 bytes of a classic pcap global header.
+fn pcap_header<R: Read>(
+    reader: &mut R,
+    big_endian: bool,
+    units_per_sec: u64,
+) -> io::Result<Format> {
+    let mut header = [0u8; 20];
+    reader.read_exact(&mut header)?;
+    // The upper bits of the link type field may carry FCS information.
+    let linktype = (u32_at(&header, 16, big_endian) & 0xffff) as u16;
+    Ok(Format::Pcap {
+        big_endian,
+        units_per_sec,
+        linktype,
+    })
+}
+
+// Read the rest of a Section Header Block whose type has already been
+// consumed, returning whether the section is big-endian.
+fn section_header<R: Read>(reader: &mut R) -> io::Result<bool> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length)?;
+    section_header_after_type(reader, &length)
+}
+
+fn section_header_after_type<R: Read>(reader: &mut R, length: &[u8]) -> io::Result<bool> {
+    // The byte-order magic tells us how to read everything else, including
+    // the block length we already have.
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    let big_endian = match magic {
+        [0x4d, 0x3c, 0x2b, 0x1a] => false,
+        [0x1a, 0x2b, 0x3c, 0x4d] => true,
+        _ => return Err(invalid("bad pcapng byte-order magic")),
+    };
+
+    let total = u32_at(length, 0, big_endian) as usize;
+    if total < 28 || !total.is_multiple_of(4) || total > MAX_RECORD_LEN {
+        return Err(invalid("bad pcapng section header length"));
+    }
+    // Skip version, section length, options and the trailing length.
+    let skipped = io::copy(&mut reader.take((total - 12) as u64), &mut io::sink())?;
+    if skipped != (total - 12) as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(big_endian)
+}
+
+// Look for `if_tsresol` in an Interface Description Block's options.
+// The default resolution is microseconds.
+fn tsresol(mut options: &[u8], big_endian: bool) -> io::Result<u64> {
+    while options.len() >= 4 {
+        let code = u16_at(options, 0, big_endian);
+        let len = u16_at(options, 2, big_endian) as usize;
+        if code == OPT_END_OF_OPTIONS {
+            break;
+        }
+        let padded = (len + 3) & !3;
+        if options.len() < 4 + padded {
+            return Err(invalid("truncated pcapng option"));
+        }
+        if code == OPT_IF_TSRESOL && len >= 1 {
+            // High bit set: a power of two, otherwise a power of ten.
+            let value = options[4];
+            let exponent = u32::from(value & 0x7f);
+            let units = if value & 0x80 != 0 {
+                2u64.checked_pow(exponent)
+            } else {
+                10u64.checked_pow(exponent)
+            };
+            return units.ok_or_else(|| invalid("unsupported timestamp resolution"));
+        }
+        options = &options[4 + padded..];
+    }
+    Ok(1_000_000)
+}
+
+fn fraction_to_duration(fraction: u64, units_per_sec: u64) -> Duration {
+    // `u128` avoids overflow for nanosecond (and finer) resolutions.
+    Duration::from_nanos((fraction as u128 * 1_000_000_000 / units_per_sec as u128) as u64)
+}
+
+// Like `read_exact`, but a clean end of file before the first byte is `false`
+// instead of an error.
+fn read_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn u16_at(buf: &[u8], offset: usize, big_endian: bool) -> u16 {
+    let bytes = [buf[offset], buf[offset + 1]];
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize, big_endian: bool) -> u32 {
+    let bytes = [
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ];
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One packet to put in a test file: its timestamp in the file's units,
+    // the captured bytes, and its length on the wire.
+    struct Packet<'a> {
+        interface: u32,
+        units: u64,
+        data: &'a [u8],
+        original: u32,
+    }
+
+    // Numbers in the file's byte order.
+    struct Order {
+        big_endian: bool,
+    }
+
+    impl Order {
+        fn u16(&self, value: u16) -> [u8; 2] {
+            match self.big_endian {
+                true => value.to_be_bytes(),
+                false => value.to_le_bytes(),
+            }
+        }
+
+        fn u32(&self, value: u32) -> [u8; 4] {
+            match self.big_endian {
+                true => value.to_be_bytes(),
+                false => value.to_le_bytes(),
+            }
+        }
+    }
+
+    // A classic pcap file with `units_per_sec` of 10^6 or 10^9.
+    fn pcap(big_endian: bool, units_per_sec: u64, packets: &[Packet]) -> Vec<u8> {
+        let order = Order { big_endian };
+        let magic = match units_per_sec {
+            1_000_000 => 0xa1b2_c3d4,
+            _ => 0xa1b2_3c4d,
+        };
+        let mut file = Vec::new();
+        file.extend(order.u32(magic));
+        file.extend(order.u16(2));
+        file.extend(order.u16(4));
+        // Time zone, accuracy, snap length and link type.
+        for value in [0, 0, 65535, u32::from(LINKTYPE_ETHERNET)] {
+            file.extend(order.u32(value));
+        }
+        for packet in packets {
+            let seconds = (packet.units / units_per_sec) as u32;
+            let fraction = (packet.units % units_per_sec) as u32;
+            for value in [seconds, fraction, packet.data.len() as u32, packet.original] {
+                file.extend(order.u32(value));
+            }
+            file.extend_from_slice(packet.data);
+        }
+        file
+    }
+
+    // A pcapng section with an interface per `if_tsresol` value given
+    // (`None` leaves the option out), then `packets`.
+    fn pcapng(big_endian: bool, tsresols: &[Option<u8>], packets: &[Packet]) -> Vec<u8> {
+        let order = Order { big_endian };
+        let mut file = Vec::new();
+        let mut block = |block_type: u32, mut body: Vec<u8>| {
+            body.resize(body.len().next_multiple_of(4), 0);
+            let total = order.u32(body.len() as u32 + 12);
+            file.extend(order.u32(block_type));
+            file.extend(total);
+            file.extend(body);
+            file.extend(total);
+        };
+
+        let mut body = Vec::new();
+        body.extend(order.u32(0x1A2B_3C4D));
+        body.extend(order.u16(1));
+        body.extend(order.u16(0));
+        body.extend((-1i64).to_le_bytes());
+        block(BLOCK_SECTION_HEADER, body);
+
+        for tsresol in tsresols {
+            let mut body = Vec::new();
+            body.extend(order.u16(LINKTYPE_ETHERNET));
+            body.extend(order.u16(0));
+            body.extend(order.u32(0));
+            if let Some(tsresol) = tsresol {
+                body.extend(order.u16(OPT_IF_TSRESOL));
+                body.extend(order.u16(1));
+                body.extend([*tsresol, 0, 0, 0]);
+            }
+            body.extend(order.u16(OPT_END_OF_OPTIONS));
+            body.extend(order.u16(0));
+            block(BLOCK_INTERFACE_DESCRIPTION, body);
+        }
+
+        for packet in packets {
+            let mut body = Vec::new();
+            let (high, low) = ((packet.units >> 32) as u32, packet.units as u32);
+            for value in [
+                packet.interface,
+                high,
+                low,
+                packet.data.len() as u32,
+                packet.original,
+            ] {
+                body.extend(order.u32(value));
+            }
+            body.extend_from_slice(packet.data);
+            block(BLOCK_ENHANCED_PACKET, body);
+        }
+        file
+    }
+
+    // Read every packet as (timestamp, data, original length), checking
+    // they're all Ethernet.
+    fn read_all(file: &[u8]) -> Vec<(Duration, Vec<u8>, usize)> {
+        let mut reader = PcapReader::new(file).unwrap();
+        let mut packets = Vec::new();
+        while let Some(record) = reader.next_packet().unwrap() {
+            assert_eq!(record.linktype, LINKTYPE_ETHERNET);
+            packets.push((record.timestamp, record.data.to_vec(), record.original_len));
+        }
+        packets
+    }
+
+    #[test]
+    fn reads_pcap_in_either_byte_order() {
+        let packets = [
+            Packet {
+                interface: 0,
+                units: 1_700_000_000_250_000,
+                data: &[1, 2, 3, 4],
+                original: 4,
+            },
+            // Cut short by the snap length.
+            Packet {
+                interface: 0,
+                units: 1_700_000_001_000_001,
+                data: &[5, 6],
+                original: 60,
+            },
+        ];
+        let expected = vec![
+            (
+                Duration::new(1_700_000_000, 250_000_000),
+                vec![1, 2, 3, 4],
+                4,
+            ),
+            (Duration::new(1_700_000_001, 1_000), vec![5, 6], 60),
+        ];
+        assert_eq!(read_all(&pcap(false, 1_000_000, &packets)), expected);
+        assert_eq!(read_all(&pcap(true, 1_000_000, &packets)), expected);
+    }
+
+    #[test]
+    fn reads_nanosecond_pcap() {
+        let packets = [Packet {
+            interface: 0,
+            units: 1_700_000_000_123_456_789,
+            data: &[1],
+            original: 1,
+        }];
+        for big_endian in [false, true] {
+            let read = read_all(&pcap(big_endian, 1_000_000_000, &packets));
+            assert_eq!(read[0].0, Duration::new(1_700_000_000, 123_456_789));
+        }
+    }
+
+    #[test]
+    fn reads_pcapng_timestamps_by_if_tsresol() {
+        // Nanoseconds, the default microseconds, and 2^-10 seconds.
+        let tsresols = [Some(9), None, Some(0x80 | 10)];
+        let packets = [
+            Packet {
+                interface: 0,
+                units: 1_700_000_000_123_456_789,
+                data: &[1, 2, 3],
+                original: 3,
+            },
+            Packet {
+                interface: 1,
+                units: 1_700_000_000_500_000,
+                data: &[4],
+                original: 1514,
+            },
+            Packet {
+                interface: 2,
+                units: 5 * 1024 + 512,
+                data: &[5, 6, 7, 8, 9],
+                original: 5,
+            },
+        ];
+        let expected = vec![
+            (Duration::new(1_700_000_000, 123_456_789), vec![1, 2, 3], 3),
+            (Duration::new(1_700_000_000, 500_000_000), vec![4], 1514),
+            (Duration::new(5, 500_000_000), vec![5, 6, 7, 8, 9], 5),
+        ];
+        assert_eq!(read_all(&pcapng(false, &tsresols, &packets)), expected);
+        assert_eq!(read_all(&pcapng(true, &tsresols, &packets)), expected);
+    }
+
+    #[test]
+    fn reads_a_new_section_in_another_byte_order() {
+        let packet = |units| Packet {
+            interface: 0,
+            units,
+            data: &[1, 2],
+            original: 2,
+        };
+        let mut file = pcapng(false, &[Some(9)], &[packet(1_000_000_000)]);
+        file.extend(pcapng(true, &[None], &[packet(2_000_000)]));
+        let read = read_all(&file);
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].0, Duration::from_secs(1));
+        assert_eq!(read[1].0, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(PcapReader::new(&b"GIF89a.."[..]).is_err());
+        let packet = Packet {
+            interface: 1,
+            units: 0,
+            data: &[],
+            original: 0,
+        };
+        // A packet on an interface that was never described.
+        let file = pcapng(false, &[None], &[packet]);
+        let mut reader = PcapReader::new(&file[..]).unwrap();
+        assert!(reader.next_packet().is_err());
+    }
+
+    // The arguments to one `PcapngWriter::write_packet` call.
+    type Written<'a> = (&'a str, Duration, &'a [u8], usize, Option<&'a str>);
+
+    #[test]
+    fn writer_output_reads_back_unchanged() {
+        let long = [0xab; 61];
+        let packets: [Written; 3] = [
+            (
+                "eth0",
+                Duration::new(1_700_000_000, 123_456_789),
+                &[1, 2, 3, 4, 5],
+                5,
+                Some("first"),
+            ),
+            (
+                "eth1",
+                Duration::new(1_700_000_001, 1),
+                &[6, 7, 8],
+                60,
+                None,
+            ),
+            (
+                "eth0",
+                Duration::new(1_700_000_002, 0),
+                &long,
+                61,
+                Some("a comment that needs padding"),
+            ),
+        ];
+        let mut writer = PcapngWriter::new(Vec::new(), "test").unwrap();
+        for (interface, timestamp, data, len, comment) in packets {
+            writer
+                .write_packet(interface, timestamp, data, len, comment)
+                .unwrap();
+        }
+        writer.flush().unwrap();
+        assert_eq!(writer.written(), writer.writer.len() as u64);
+
+        let expected: Vec<_> = packets
+            .iter()
+            .map(|&(_, timestamp, data, len, _)| (timestamp, data.to_vec(), len))
+            .collect();
+        assert_eq!(read_all(&writer.writer), expected);
+    }
+}
@@ -0,0 +1,413 @@
+// Rate limit policies keyed on more than the source address. Where the
+// per-source limits catch one sender flooding everyone, a policy counts
+// packets by whatever combination of fields it names (see `flow::KeySpec`):
+//
+//   key                              protects
+//   destination                      a victim host, from all senders together
+//   destination+destination-port     one service on one host
+//   source+destination-port          a service, from each sender separately
+//   5-tuple                          against single runaway connections
+//
+// A policy can also count only some packets (see `flow::Match`), e.g. TCP
+// SYNs without ACK per source, to catch a SYN flood that hides among a
+// source's ordinary traffic.
+//
+// Every policy has its own limiter, fed the same packets.
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::algorithm::AlgorithmKind;
+use crate::dissect::Summary;
+use crate::flow::{Field, Key, KeySpec, Match};
+use crate::limiter::{Limits, RateLimiter, Unit, Verdict};
+use crate::respond::Response;
+
+/// One policy: what it counts by, and how much each key may send.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    /// Names the policy in events, and matches it up on reload.
+    pub name: String,
+    pub key: KeySpec,
+    /// Which packets count; all of them if empty.
+    pub matches: Match,
+    pub limits: Limits,
+    /// What to send back to packets over the limit.
+    pub responses: Vec<Response>,
+}
+
+/// A packet took one key of a policy over its limit. The policy's name is
+/// borrowed from the limiter (`'a`), so nothing is copied until it's needed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicyExceeded<'a> {
+    pub policy: &'a str,
+    pub key: Key,
+    pub unit: Unit,
+    pub used: u64,
+    pub limit: u64,
+    /// Whether the key includes the source address, so the sender can be
+    /// blocked without also blocking everyone else talking to the same
+    /// destination.
+    pub blockable: bool,
+    /// Whether this key was already reported within the last window. Keys
+    /// that can't be blocked keep going over with every packet, so callers
+    /// should only report the first time.
+    pub repeat: bool,
+    /// The policy's responses, for this packet and every repeat.
+    pub responses: &'a [Response],
+}
+
+// One policy's counters.
+struct Entry {
+    name: String,
+    key: KeySpec,
+    matches: Match,
+    responses: Vec<Response>,
+    limiter: RateLimiter<Key>,
+    // When each key that can't be blocked was last reported, and how long
+    // to stay quiet about it afterwards: the policy's window.
+    reported: Mutex<Reported>,
+}
+
+struct Reported {
+    window: Duration,
+    until: HashMap<Key, Duration>,
+}
+
+impl Entry {
+    // Whether `key` was reported recently; if not, remember that it is now.
+    fn repeat(&self, key: Key, now: Duration) -> bool {
+        let mut reported = self.reported.lock().unwrap_or_else(PoisonError::into_inner);
+        if reported.until.get(&key).is_some_and(|&until| now < until) {
+            return true;
+        }
+        // Reports are rare, so this is a good time to forget old ones.
+        reported.until.retain(|_, until| now < *until);
+        let until = now.saturating_add(reported.window);
+        reported.until.insert(key, until);
+        false
+    }
+}
+
+// The longer of the two windows, so a key is reported at most once per
+// window whichever limit it went over.
+fn window(limits: Limits) -> Duration {
+    let windows = [limits.packets, limits.bytes];
+    windows
+        .iter()
+        .flatten()
+        .map(|limit| limit.window)
+        .max()
+        .unwrap_or_default()
+}
+
+/// A limiter per policy.
+pub struct PolicyLimiter {
+    policies: Vec<Entry>,
+}
+
+impl PolicyLimiter {
+    pub fn new(kind: AlgorithmKind, policies: &[Policy]) -> PolicyLimiter {
+        let policies = policies
+            .iter()
+            .map(|policy| Entry {
+                name: policy.name.clone(),
+                key: policy.key,
+                matches: policy.matches.clone(),
+                responses: policy.responses.clone(),
+                limiter: RateLimiter::new(kind, policy.limits),
+                reported: Mutex::new(Reported {
+                    window: window(policy.limits),
+                    until: HashMap::new(),
+                }),
+            })
+            .collect();
+        PolicyLimiter { policies }
+    }
+
+    /// Count one packet of `len` bytes against every policy that matches it,
+    /// and return the ones it took over their limit. Packets without a field
+    /// a policy needs (e.g. ICMP for a key with a port) are skipped by that
+    /// policy.
+    pub fn record(&self, summary: &Summary, now: Duration, len: usize) -> Vec<PolicyExceeded<'_>> {
+        // An empty `Vec` doesn't allocate, so the common case of nothing
+        // going over costs nothing extra.
+        let mut exceeded = Vec::new();
+        let flow = summary.flow();
+        for entry in &self.policies {
+            if !entry.matches.matches(summary) {
+                continue;
+            }
+            let Some(key) = entry.key.key(&flow) else {
+                continue;
+            };
+            if let Verdict::Exceeded { unit, used, limit } = entry.limiter.record(key, now, len) {
+                let blockable = entry.key.has(Field::Source);
+                exceeded.push(PolicyExceeded {
+                    policy: &entry.name,
+                    key,
+                    unit,
+                    used,
+                    limit,
+                    blockable,
+                    repeat: !blockable && entry.repeat(key, now),
+                    responses: &entry.responses,
+                });
+            }
+        }
+        exceeded
+    }
+
+    /// Apply new limits while running, keeping counters where possible (see
+    /// `RateLimiter::reconfigure`). Policies are matched up by name, key and
+    /// match; adding or removing one needs a new limiter, and so does
+    /// changing its responses.
+    pub fn reconfigure(&self, kind: AlgorithmKind, policies: &[Policy], now: Duration) {
+        for policy in policies {
+            let found = self.policies.iter().find(|entry| {
+                entry.name == policy.name
+                    && entry.key == policy.key
+                    && entry.matches == policy.matches
+            });
+            if let Some(entry) = found {
+                entry.limiter.reconfigure(kind, policy.limits, now);
+                entry
+                    .reported
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .window = window(policy.limits);
+            }
+        }
+    }
+
+    /// Number of keys tracked, over all policies.
+    pub fn len(&self) -> usize {
+        self.policies.iter().map(|entry| entry.limiter.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::algorithm::Limit;
+    use crate::dissect::{Network, TcpFlags, Transport, Vlans};
+    use crate::source::Source;
+    use pnet::datalink::MacAddr;
+    use std::net::Ipv4Addr;
+
+    fn at(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    // A policy allowing `packets` per `seconds` for each key.
+    fn policy(name: &str, key: &str, matches: &str, packets: u64, seconds: u64) -> Policy {
+        Policy {
+            name: name.to_string(),
+            key: key.parse().unwrap(),
+            matches: match matches {
+                "" => Match::default(),
+                matches => matches.parse().unwrap(),
+            },
+            limits: Limits {
+                packets: Some(Limit {
+                    amount: packets,
+                    window: at(seconds),
+                }),
+                bytes: None,
+            },
+            responses: Vec::new(),
+        }
+    }
+
+    fn new_limiter(policies: &[Policy]) -> PolicyLimiter {
+        PolicyLimiter::new(AlgorithmKind::FixedWindow, policies)
+    }
+
+    // A TCP packet from 10.0.0.`from` to 10.0.0.`to` port 80 with `flags`.
+    fn tcp(from: u8, to: u8, flags: TcpFlags) -> Summary {
+        Summary {
+            source_mac: MacAddr(0x02, 0, 0, 0, 0, from),
+            destination_mac: MacAddr(0x02, 0, 0, 0, 0, to),
+            vlans: Vlans::default(),
+            ethertype: 0x0800,
+            network: Some(Network::Ipv4 {
+                source: Ipv4Addr::new(10, 0, 0, from),
+                destination: Ipv4Addr::new(10, 0, 0, to),
+                ttl: 64,
+                protocol: 6,
+                options: 0,
+                fragment: None,
+            }),
+            transport: Some(Transport::Tcp {
+                source_port: 40000,
+                destination_port: 80,
+                flags,
+            }),
+        }
+    }
+
+    fn ack(from: u8, to: u8) -> Summary {
+        tcp(from, to, TcpFlags::ACK)
+    }
+
+    // Record `summary` at `now`, returning `(policy, blockable, repeat)` for
+    // every policy it took over.
+    fn record(
+        limiter: &PolicyLimiter,
+        summary: &Summary,
+        now: Duration,
+    ) -> Vec<(String, bool, bool)> {
+        limiter
+            .record(summary, now, 60)
+            .into_iter()
+            .map(|exceeded| {
+                (
+                    exceeded.policy.to_string(),
+                    exceeded.blockable,
+                    exceeded.repeat,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counts_each_key_separately() {
+        let limiter = new_limiter(&[policy("victim", "destination", "", 2, 10)]);
+        // Two senders together take one destination over.
+        assert!(record(&limiter, &ack(1, 100), at(0)).is_empty());
+        assert!(record(&limiter, &ack(2, 100), at(0)).is_empty());
+        assert!(record(&limiter, &ack(1, 200), at(0)).is_empty());
+        assert_eq!(record(&limiter, &ack(3, 100), at(0)).len(), 1);
+        assert_eq!(limiter.len(), 2);
+
+        let exceeded = limiter.record(&ack(3, 100), at(1), 60);
+        assert_eq!(
+            exceeded[0].key.destination,
+            Some(Source::Ipv4(Ipv4Addr::new(10, 0, 0, 100)))
+        );
+        assert_eq!(exceeded[0].key.source, None);
+        assert_eq!(
+            (exceeded[0].unit, exceeded[0].used, exceeded[0].limit),
+            (Unit::Packets, 4, 2)
+        );
+    }
+
+    #[test]
+    fn only_counts_matching_packets() {
+        let limiter = new_limiter(&[policy("syn-flood", "source", "syn", 1, 10)]);
+        for _ in 0..5 {
+            assert!(record(&limiter, &ack(1, 100), at(0)).is_empty());
+        }
+        assert!(record(&limiter, &tcp(1, 100, TcpFlags::SYN), at(0)).is_empty());
+        assert_eq!(
+            record(&limiter, &tcp(1, 100, TcpFlags::SYN), at(0)).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn skips_packets_without_a_key_field() {
+        let limiter = new_limiter(&[policy("web", "destination+destination-port", "", 1, 10)]);
+        let mut ping = ack(1, 100);
+        ping.transport = Some(Transport::Icmp {
+            icmp_type: 8,
+            code: 0,
+        });
+        for _ in 0..5 {
+            assert!(record(&limiter, &ping, at(0)).is_empty());
+        }
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn keys_with_the_source_are_blockable_and_never_repeats() {
+        let limiter = new_limiter(&[policy("per-sender", "source+destination-port", "", 1, 10)]);
+        record(&limiter, &ack(1, 100), at(0));
+        for _ in 0..3 {
+            assert_eq!(
+                record(&limiter, &ack(1, 100), at(0)),
+                [("per-sender".to_string(), true, false)]
+            );
+        }
+    }
+
+    #[test]
+    fn reports_other_keys_once_per_window() {
+        let limiter = new_limiter(&[policy("victim", "destination", "", 1, 10)]);
+        record(&limiter, &ack(1, 100), at(0));
+        assert_eq!(
+            record(&limiter, &ack(1, 100), at(0)),
+            [("victim".to_string(), false, false)]
+        );
+        assert_eq!(
+            record(&limiter, &ack(2, 100), at(9)),
+            [("victim".to_string(), false, true)]
+        );
+        // A new window: the key goes over again and is reported afresh.
+        record(&limiter, &ack(1, 100), at(10));
+        assert_eq!(
+            record(&limiter, &ack(1, 100), at(10)),
+            [("victim".to_string(), false, false)]
+        );
+    }
+
+    #[test]
+    fn a_huge_window_does_not_overflow() {
+        let limiter = new_limiter(&[policy("victim", "destination", "", 1, u64::MAX)]);
+        record(&limiter, &ack(1, 100), at(1));
+        assert_eq!(
+            record(&limiter, &ack(1, 100), at(1)),
+            [("victim".to_string(), false, false)]
+        );
+        assert_eq!(
+            record(&limiter, &ack(1, 100), at(1 << 40)),
+            [("victim".to_string(), false, true)]
+        );
+    }
+
+    #[test]
+    fn reconfigure_matches_policies_by_name_key_and_match() {
+        let limiter = new_limiter(&[
+            policy("victim", "destination", "", 1, 10),
+            policy("syn-flood", "source", "syn", 1, 10),
+        ]);
+        limiter.reconfigure(
+            AlgorithmKind::FixedWindow,
+            &[
+                // Same name, key and match: takes the new limit.
+                policy("victim", "destination", "", 3, 10),
+                // A different match makes it another policy, ignored.
+                policy("syn-flood", "source", "echo-request", 3, 10),
+            ],
+            at(0),
+        );
+        for _ in 0..3 {
+            assert!(record(&limiter, &ack(1, 100), at(0)).is_empty());
+        }
+        assert_eq!(record(&limiter, &ack(1, 100), at(0)).len(), 1);
+
+        let syn = tcp(2, 100, TcpFlags::SYN);
+        let over = |limiter: &PolicyLimiter| {
+            limiter
+                .record(&syn, at(0), 60)
+                .iter()
+                .any(|exceeded| exceeded.policy == "syn-flood")
+        };
+        assert!(!over(&limiter));
+        assert!(over(&limiter));
+
+        // Nor does a policy with the same name but another key.
+        let limiter = new_limiter(&[policy("victim", "destination", "", 1, 10)]);
+        limiter.reconfigure(
+            AlgorithmKind::FixedWindow,
+            &[policy("victim", "source", "", 3, 10)],
+            at(0),
+        );
+        assert!(record(&limiter, &ack(1, 100), at(0)).is_empty());
+        assert_eq!(record(&limiter, &ack(1, 100), at(0)).len(), 1);
+    }
+}
@@ -0,0 +1,59 @@
+use pnet::packet::ethernet::EthernetPacket;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+use crate::dissect::Summary;
+
+/// A coarse protocol classification of a frame, used for per-protocol
+/// statistics.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+    Icmpv6,
+    /// IPv4 or IPv6 carrying anything else.
+    OtherIp,
+    Arp,
+    /// Everything that is neither IP nor ARP.
+    Other,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 7] = [
+        Protocol::Tcp,
+        Protocol::Udp,
+        Protocol::Icmp,
+        Protocol::Icmpv6,
+        Protocol::OtherIp,
+        Protocol::Arp,
+        Protocol::Other,
+    ];
+
+    /// Classify a frame; see `Summary::protocol`.
+    pub fn of(ethernet: &EthernetPacket) -> Protocol {
+        Summary::of(ethernet).protocol()
+    }
+
+    pub(crate) fn from_next_header(next: IpNextHeaderProtocol) -> Protocol {
+        match next {
+            IpNextHeaderProtocols::Tcp => Protocol::Tcp,
+            IpNextHeaderProtocols::Udp => Protocol::Udp,
+            IpNextHeaderProtocols::Icmp => Protocol::Icmp,
+            IpNextHeaderProtocols::Icmpv6 => Protocol::Icmpv6,
+            _ => Protocol::OtherIp,
+        }
+    }
+
+    /// Lower-case name, as used in metric labels.
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+            Protocol::Icmpv6 => "icmpv6",
+            Protocol::OtherIp => "other_ip",
+            Protocol::Arp => "arp",
+            Protocol::Other => "other",
+        }
+    }
+}
@@ -0,0 +1,921 @@
+// Active responses to packets that go over a policy's limit. Blocking only
+// stops traffic at this host; a response tells the other end to stop:
+//
+//   response          sent to                         answers
+//   tcp-reset         the sender, as the receiver     TCP: tears the connection down
+//   icmp-prohibited   the sender, as the receiver     IP: "administratively prohibited"
+//   arp-correction    everyone on the link            ARP claiming one of our addresses
+//
+// Replies go back out of the interface the packet came in on, with the
+// Ethernet addresses swapped and any VLAN tags kept, so they take the same
+// path back. Each is built in place in the sender's buffer with pnet's
+// mutable packet views, the writing counterparts of the views `dissect`
+// reads with.
+//
+// A response is never sent about a broadcast or multicast packet, and never
+// in reply to a TCP reset or an ICMP error, so two hosts doing this can't
+// keep answering each other.
+use pnet::datalink::{DataLinkSender, MacAddr, NetworkInterface};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::{self, IcmpCode, IcmpTypes, MutableIcmpPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Code, Icmpv6Types, MutableIcmpv6Packet};
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpPacket};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::dissect::{Network, Summary, TcpFlags, Transport};
+
+/// One kind of response, enabled per policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Response {
+    /// A TCP reset to the sender, as if from the receiver.
+    TcpReset,
+    /// An ICMP or ICMPv6 "administratively prohibited" error to the sender,
+    /// as if from the receiver.
+    IcmpProhibited,
+    /// A gratuitous ARP reply with the interface's own MAC address, for ARP
+    /// packets claiming one of its IPv4 addresses for another MAC.
+    ArpCorrection,
+}
+
+impl Response {
+    pub const ALL: [Response; 3] = [
+        Response::TcpReset,
+        Response::IcmpProhibited,
+        Response::ArpCorrection,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Response::TcpReset => "tcp-reset",
+            Response::IcmpProhibited => "icmp-prohibited",
+            Response::ArpCorrection => "arp-correction",
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Response {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Response, String> {
+        Response::ALL
+            .into_iter()
+            .find(|response| response.name() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown response '{}', expected tcp-reset, icmp-prohibited or arp-correction",
+                    s
+                )
+            })
+    }
+}
+
+// Responses sent per second by one responder, at most. A flood is many
+// packets over the limit, and answering every one would turn this host into
+// a flood of its own.
+const MAX_PER_SECOND: u32 = 1000;
+
+// The smallest Ethernet frame, not counting the checksum. Shorter replies
+// are padded with zeros, since not every driver does it.
+const MIN_FRAME: usize = 60;
+
+// TTL or hop limit of the replies.
+const HOP_LIMIT: u8 = 64;
+
+// An ICMPv6 error may quote as much of the packet as fits in the minimum
+// IPv6 MTU, 1280 bytes, after its own 40-byte IPv6 and 8-byte ICMPv6
+// headers.
+const MAX_ICMPV6_QUOTE: usize = 1280 - 40 - 8;
+
+/// Sends responses on one interface.
+pub struct Responder {
+    // `Box<dyn DataLinkSender>` is the `tx` half of a pnet channel, or
+    // anything else that sends whole Ethernet frames.
+    sender: Box<dyn DataLinkSender>,
+    mac: Option<MacAddr>,
+    ipv4: Vec<Ipv4Addr>,
+    // When the current second started, and how many responses it's had.
+    second: Instant,
+    sent: u32,
+}
+
+impl Responder {
+    /// Respond through `sender`, which sends on `interface`. The interface's
+    /// MAC and IPv4 addresses are what `arp-correction` defends.
+    pub fn new(sender: Box<dyn DataLinkSender>, interface: &NetworkInterface) -> Responder {
+        let ipv4 = interface
+            .ips
+            .iter()
+            .filter_map(|network| match network.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        let mac = interface.mac.filter(|mac| !mac.is_zero());
+        Responder {
+            sender,
+            mac,
+            ipv4,
+            second: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Answer `frame`, which `summary` describes, with `response`. Returns
+    /// whether anything was sent: a response that doesn't apply to the frame
+    /// (a TCP reset for UDP, say) is skipped, and so is anything over the
+    /// responder's limit of responses per second.
+    pub fn respond(
+        &mut self,
+        response: Response,
+        frame: &[u8],
+        summary: &Summary,
+    ) -> io::Result<bool> {
+        let reply = match response {
+            Response::TcpReset => tcp_reset(frame, summary),
+            Response::IcmpProhibited => icmp_prohibited(frame, summary),
+            Response::ArpCorrection => self.arp_correction(frame, summary),
+        };
+        let Some(reply) = reply else {
+            return Ok(false);
+        };
+        if self.second.elapsed() >= Duration::from_secs(1) {
+            (self.second, self.sent) = (Instant::now(), 0);
+        }
+        if self.sent >= MAX_PER_SECOND {
+            return Ok(false);
+        }
+        self.sent += 1;
+        // `None` means the frame doesn't fit the sender's buffer.
+        match self
+            .sender
+            .build_and_send(1, reply.len(), &mut |buf| reply.write(buf))
+        {
+            Some(result) => result.map(|()| true),
+            None => Err(io::Error::other("response is larger than the write buffer")),
+        }
+    }
+
+    // A gratuitous ARP reply, broadcast, putting right an ARP packet that
+    // gave one of our addresses another MAC.
+    fn arp_correction<'a>(&self, frame: &'a [u8], summary: &Summary) -> Option<Reply<'a>> {
+        let Some(Network::Arp {
+            sender_mac,
+            sender_ip,
+            ..
+        }) = summary.network
+        else {
+            return None;
+        };
+        let mac = self.mac?;
+        if sender_mac == mac || !self.ipv4.contains(&sender_ip) {
+            return None;
+        }
+        Some(Reply {
+            link: frame.get(..link_len(summary))?,
+            source_mac: mac,
+            destination_mac: MacAddr::broadcast(),
+            body: Body::Arp { mac, ip: sender_ip },
+        })
+    }
+}
+
+// A TCP reset for the connection a segment belongs to. The sender only
+// accepts one with a sequence number it expects: the segment's
+// acknowledgement number if it had one, and otherwise the reset
+// acknowledges the segment instead.
+fn tcp_reset<'a>(frame: &'a [u8], summary: &Summary) -> Option<Reply<'a>> {
+    let Some(Transport::Tcp { flags, .. }) = summary.transport else {
+        return None;
+    };
+    if flags.contains(TcpFlags::RST) {
+        return None;
+    }
+    let datagram = Datagram::of(frame, summary)?;
+    let segment = TcpPacket::new(datagram.payload?)?;
+    let (sequence, acknowledgement, reset) = if flags.contains(TcpFlags::ACK) {
+        (segment.get_acknowledgement(), 0, TcpFlags::RST)
+    } else {
+        // SYN and FIN each take up one sequence number, like a byte of data.
+        let header_len = usize::from(segment.get_data_offset()) * 4;
+        let data_len = datagram.payload_len.saturating_sub(header_len) as u32;
+        let len = data_len
+            + u32::from(flags.contains(TcpFlags::SYN))
+            + u32::from(flags.contains(TcpFlags::FIN));
+        (
+            0,
+            segment.get_sequence().wrapping_add(len),
+            TcpFlags(TcpFlags::RST.0 | TcpFlags::ACK.0),
+        )
+    };
+    Some(Reply {
+        link: datagram.link,
+        source_mac: summary.destination_mac,
+        destination_mac: summary.source_mac,
+        body: Body::Reset {
+            addresses: datagram.reply,
+            source_port: segment.get_destination(),
+            destination_port: segment.get_source(),
+            sequence,
+            acknowledgement,
+            flags: reset,
+        },
+    })
+}
+
+// An "administratively prohibited" error quoting the packet: ICMP type 3
+// code 13 for IPv4, with the IP header and the first 8 bytes after it, or
+// ICMPv6 type 1 code 1 for IPv6, with as much as fits.
+fn icmp_prohibited<'a>(frame: &'a [u8], summary: &Summary) -> Option<Reply<'a>> {
+    match summary.transport {
+        // Types 3, 4, 5, 11 and 12 are the ICMP errors; every ICMPv6 type
+        // below 128 is one.
+        Some(Transport::Icmp {
+            icmp_type: 3 | 4 | 5 | 11 | 12,
+            ..
+        }) => return None,
+        Some(Transport::Icmpv6 { icmp_type, .. }) if icmp_type < 128 => return None,
+        _ => {}
+    }
+    // Only the first fragment of a packet is worth an error.
+    let fragment = match summary.network {
+        Some(Network::Ipv4 { fragment, .. } | Network::Ipv6 { fragment, .. }) => fragment,
+        _ => None,
+    };
+    if fragment.is_some_and(|fragment| fragment.offset != 0) {
+        return None;
+    }
+    let datagram = Datagram::of(frame, summary)?;
+    let quote = match datagram.reply {
+        Addresses::V4(..) => datagram.header_len + 8,
+        Addresses::V6(..) => MAX_ICMPV6_QUOTE,
+    };
+    let packet = datagram.packet;
+    Some(Reply {
+        link: datagram.link,
+        source_mac: summary.destination_mac,
+        destination_mac: summary.source_mac,
+        body: Body::Prohibited {
+            addresses: datagram.reply,
+            quote: &packet[..quote.min(packet.len())],
+        },
+    })
+}
+
+// The bytes in front of the network header: the Ethernet header and any
+// VLAN tags.
+fn link_len(summary: &Summary) -> usize {
+    EthernetPacket::minimum_packet_size() + 4 * summary.vlans.as_slice().len()
+}
+
+// An IP packet that can be answered, split up for building the answer.
+struct Datagram<'a> {
+    link: &'a [u8],
+    // The whole packet as far as it was captured, without any Ethernet
+    // padding after it.
+    packet: &'a [u8],
+    header_len: usize,
+    // What follows the IP header, and its length according to the header,
+    // which may be more than was captured. `None` for IPv6 with extension
+    // headers, which aren't worth walking again.
+    payload: Option<&'a [u8]>,
+    payload_len: usize,
+    // The answer's addresses: the packet's, swapped.
+    reply: Addresses,
+}
+
+impl<'a> Datagram<'a> {
+    fn of(frame: &'a [u8], summary: &Summary) -> Option<Datagram<'a>> {
+        if !summary.source_mac.is_unicast() || !summary.destination_mac.is_unicast() {
+            return None;
+        }
+        let (link, packet) = frame.split_at_checked(link_len(summary))?;
+        match summary.network? {
+            Network::Ipv4 {
+                source,
+                destination,
+                ..
+            } => {
+                let unicast =
+                    |ip: Ipv4Addr| !(ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast());
+                if !unicast(source) || !unicast(destination) {
+                    return None;
+                }
+                let ipv4 = Ipv4Packet::new(packet)?;
+                let header_len = usize::from(ipv4.get_header_length()) * 4;
+                let total_len = usize::from(ipv4.get_total_length());
+                let packet = &packet[..total_len.min(packet.len())];
+                Some(Datagram {
+                    link,
+                    packet,
+                    header_len,
+                    payload: packet.get(header_len..),
+                    payload_len: total_len.saturating_sub(header_len),
+                    reply: Addresses::V4(destination, source),
+                })
+            }
+            Network::Ipv6 {
+                source,
+                destination,
+                extension_headers,
+                ..
+            } => {
+                if source.is_unspecified() || source.is_multicast() || destination.is_multicast() {
+                    return None;
+                }
+                let ipv6 = Ipv6Packet::new(packet)?;
+                let header_len = Ipv6Packet::minimum_packet_size();
+                let payload_len = usize::from(ipv6.get_payload_length());
+                let packet = &packet[..(header_len + payload_len).min(packet.len())];
+                Some(Datagram {
+                    link,
+                    packet,
+                    header_len,
+                    payload: (extension_headers == 0)
+                        .then(|| &packet[header_len.min(packet.len())..]),
+                    payload_len,
+                    reply: Addresses::V6(destination, source),
+                })
+            }
+            Network::Arp { .. } => None,
+        }
+    }
+}
+
+// Source and destination of an IP header, in that order.
+#[derive(Clone, Copy)]
+enum Addresses {
+    V4(Ipv4Addr, Ipv4Addr),
+    V6(Ipv6Addr, Ipv6Addr),
+}
+
+impl Addresses {
+    fn header_len(self) -> usize {
+        match self {
+            Addresses::V4(..) => Ipv4Packet::minimum_packet_size(),
+            Addresses::V6(..) => Ipv6Packet::minimum_packet_size(),
+        }
+    }
+
+    // Write an IP header for `payload_len` bytes of `protocol` into the
+    // start of `buf`.
+    fn write(self, buf: &mut [u8], protocol: IpNextHeaderProtocol, payload_len: usize) {
+        match self {
+            Addresses::V4(source, destination) => {
+                let Some(mut ip) = MutableIpv4Packet::new(buf) else {
+                    return;
+                };
+                ip.set_version(4);
+                ip.set_header_length(5);
+                ip.set_total_length((Ipv4Packet::minimum_packet_size() + payload_len) as u16);
+                ip.set_flags(Ipv4Flags::DontFragment);
+                ip.set_ttl(HOP_LIMIT);
+                ip.set_next_level_protocol(protocol);
+                ip.set_source(source);
+                ip.set_destination(destination);
+                ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+            }
+            Addresses::V6(source, destination) => {
+                let Some(mut ip) = MutableIpv6Packet::new(buf) else {
+                    return;
+                };
+                ip.set_version(6);
+                ip.set_payload_length(payload_len as u16);
+                ip.set_next_header(protocol);
+                ip.set_hop_limit(HOP_LIMIT);
+                ip.set_source(source);
+                ip.set_destination(destination);
+            }
+        }
+    }
+}
+
+// A response, worked out from the frame it answers and ready to be written
+// into the send buffer.
+struct Reply<'a> {
+    // The answered frame's Ethernet header and VLAN tags, copied so the
+    // reply carries the same tags and EtherType.
+    link: &'a [u8],
+    source_mac: MacAddr,
+    destination_mac: MacAddr,
+    body: Body<'a>,
+}
+
+enum Body<'a> {
+    Reset {
+        addresses: Addresses,
+        source_port: u16,
+        destination_port: u16,
+        sequence: u32,
+        acknowledgement: u32,
+        flags: TcpFlags,
+    },
+    Prohibited {
+        addresses: Addresses,
+        quote: &'a [u8],
+    },
+    Arp {
+        mac: MacAddr,
+        ip: Ipv4Addr,
+    },
+}
+
+// Both ICMP headers are 8 bytes: type, code, checksum and 4 unused bytes
+// before the quoted packet.
+const ICMP_HEADER_LEN: usize = 8;
+
+impl Reply<'_> {
+    // The frame's length, padding included.
+    fn len(&self) -> usize {
+        let body = match &self.body {
+            Body::Reset { addresses, .. } => {
+                addresses.header_len() + TcpPacket::minimum_packet_size()
+            }
+            Body::Prohibited { addresses, quote } => {
+                addresses.header_len() + ICMP_HEADER_LEN + quote.len()
+            }
+            Body::Arp { .. } => MutableArpPacket::minimum_packet_size(),
+        };
+        (self.link.len() + body).max(MIN_FRAME)
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        // The buffer still holds whatever was sent last; every field not set
+        // below, and the padding, has to be zero.
+        buf.fill(0);
+        buf[..self.link.len()].copy_from_slice(self.link);
+        if let Some(mut ethernet) = MutableEthernetPacket::new(buf) {
+            ethernet.set_source(self.source_mac);
+            ethernet.set_destination(self.destination_mac);
+        }
+        let buf = &mut buf[self.link.len()..];
+
+        match self.body {
+            Body::Reset {
+                addresses,
+                source_port,
+                destination_port,
+                sequence,
+                acknowledgement,
+                flags,
+            } => {
+                let (header, rest) = buf.split_at_mut(addresses.header_len());
+                let segment_len = TcpPacket::minimum_packet_size();
+                addresses.write(header, IpNextHeaderProtocols::Tcp, segment_len);
+                let Some(mut segment) = MutableTcpPacket::new(&mut rest[..segment_len]) else {
+                    return;
+                };
+                segment.set_source(source_port);
+                segment.set_destination(destination_port);
+                segment.set_sequence(sequence);
+                segment.set_acknowledgement(acknowledgement);
+                segment.set_data_offset(5);
+                segment.set_flags(flags.0);
+                let checksum = match addresses {
+                    Addresses::V4(source, destination) => {
+                        tcp::ipv4_checksum(&segment.to_immutable(), &source, &destination)
+                    }
+                    Addresses::V6(source, destination) => {
+                        tcp::ipv6_checksum(&segment.to_immutable(), &source, &destination)
+                    }
+                };
+                segment.set_checksum(checksum);
+            }
+            Body::Prohibited { addresses, quote } => {
+                let (header, rest) = buf.split_at_mut(addresses.header_len());
+                let message_len = ICMP_HEADER_LEN + quote.len();
+                let message = &mut rest[..message_len];
+                message[ICMP_HEADER_LEN..].copy_from_slice(quote);
+                match addresses {
+                    Addresses::V4(..) => {
+                        addresses.write(header, IpNextHeaderProtocols::Icmp, message_len);
+                        let Some(mut icmp) = MutableIcmpPacket::new(message) else {
+                            return;
+                        };
+                        icmp.set_icmp_type(IcmpTypes::DestinationUnreachable);
+                        icmp.set_icmp_code(IcmpCode(13));
+                        icmp.set_checksum(icmp::checksum(&icmp.to_immutable()));
+                    }
+                    Addresses::V6(source, destination) => {
+                        addresses.write(header, IpNextHeaderProtocols::Icmpv6, message_len);
+                        let Some(mut icmp) = MutableIcmpv6Packet::new(message) else {
+                            return;
+                        };
+                        icmp.set_icmpv6_type(Icmpv6Types::DestinationUnreachable);
+                        icmp.set_icmpv6_code(Icmpv6Code(1));
+                        icmp.set_checksum(icmpv6::checksum(
+                            &icmp.to_immutable(),
+                            &source,
+                            &destination,
+                        ));
+                    }
+                }
+            }
+            Body::Arp { mac, ip } => {
+                let Some(mut arp) = MutableArpPacket::new(buf) else {
+                    return;
+                };
+                arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+                arp.set_protocol_type(EtherTypes::Ipv4);
+                arp.set_hw_addr_len(6);
+                arp.set_proto_addr_len(4);
+                arp.set_operation(ArpOperations::Reply);
+                arp.set_sender_hw_addr(mac);
+                arp.set_sender_proto_addr(ip);
+                arp.set_target_hw_addr(MacAddr::broadcast());
+                arp.set_target_proto_addr(ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::Packet;
+    use std::sync::{Arc, Mutex};
+
+    // The remote host, and this one.
+    const THEIR_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x01);
+    const OUR_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 0x02);
+    const THEIR_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const OUR_IP: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+    const THEIR_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const OUR_IPV6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    // Keeps every frame sent, for the test to look at.
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl DataLinkSender for Recorder {
+        fn build_and_send(
+            &mut self,
+            num_packets: usize,
+            packet_size: usize,
+            func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<io::Result<()>> {
+            for _ in 0..num_packets {
+                // Left over from an earlier frame, as in a real send buffer.
+                let mut buf = vec![0xaa; packet_size];
+                func(&mut buf);
+                self.0.lock().unwrap().push(buf);
+            }
+            Some(Ok(()))
+        }
+
+        fn send_to(
+            &mut self,
+            packet: &[u8],
+            _: Option<NetworkInterface>,
+        ) -> Option<io::Result<()>> {
+            self.0.lock().unwrap().push(packet.to_vec());
+            Some(Ok(()))
+        }
+    }
+
+    struct Test {
+        responder: Responder,
+        sent: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Test {
+        fn new(mac: Option<MacAddr>) -> Test {
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            let responder = Responder {
+                sender: Box::new(Recorder(Arc::clone(&sent))),
+                mac,
+                ipv4: vec![OUR_IP],
+                second: Instant::now(),
+                sent: 0,
+            };
+            Test { responder, sent }
+        }
+
+        // The reply to `frame`, if there is one.
+        fn answer(&mut self, response: Response, frame: &[u8]) -> Option<Vec<u8>> {
+            let summary = Summary::of(&EthernetPacket::new(frame).unwrap());
+            if !self.responder.respond(response, frame, &summary).unwrap() {
+                return None;
+            }
+            self.sent.lock().unwrap().pop()
+        }
+    }
+
+    fn answer(response: Response, frame: &[u8]) -> Option<Vec<u8>> {
+        Test::new(Some(OUR_MAC)).answer(response, frame)
+    }
+
+    fn ethernet(destination: MacAddr, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        for mac in [destination, THEIR_MAC] {
+            frame.extend(mac.octets());
+        }
+        frame.extend(ethertype.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn vlan(id: u16, ethertype: u16, payload: &[u8]) -> Vec<u8> {
+        let mut tag = id.to_be_bytes().to_vec();
+        tag.extend(ethertype.to_be_bytes());
+        tag.extend(payload);
+        tag
+    }
+
+    // An IPv4 packet from them to `destination`, with the flags and
+    // fragment offset field `fragment`.
+    fn ipv4(destination: Ipv4Addr, protocol: u8, fragment: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x45, 0];
+        packet.extend((20 + payload.len() as u16).to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(fragment.to_be_bytes());
+        packet.extend([64, protocol, 0, 0]);
+        packet.extend(THEIR_IP.octets());
+        packet.extend(destination.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    fn ipv6(next_header: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend((payload.len() as u16).to_be_bytes());
+        packet.extend([next_header, 64]);
+        packet.extend(THEIR_IPV6.octets());
+        packet.extend(OUR_IPV6.octets());
+        packet.extend(payload);
+        packet
+    }
+
+    fn tcp(sequence: u32, acknowledgement: u32, flags: TcpFlags, data: &[u8]) -> Vec<u8> {
+        let mut segment = 40000u16.to_be_bytes().to_vec();
+        segment.extend(80u16.to_be_bytes());
+        segment.extend(sequence.to_be_bytes());
+        segment.extend(acknowledgement.to_be_bytes());
+        segment.extend([0x50, flags.0, 0xff, 0xff, 0, 0, 0, 0]);
+        segment.extend(data);
+        segment
+    }
+
+    fn udp(data: &[u8]) -> Vec<u8> {
+        let mut datagram = 40000u16.to_be_bytes().to_vec();
+        datagram.extend(53u16.to_be_bytes());
+        datagram.extend((8 + data.len() as u16).to_be_bytes());
+        datagram.extend([0, 0]);
+        datagram.extend(data);
+        datagram
+    }
+
+    fn icmp(icmp_type: u8) -> Vec<u8> {
+        vec![icmp_type, 0, 0, 0, 0, 0, 0, 1]
+    }
+
+    fn arp(sender_mac: MacAddr, sender_ip: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0, 1, 8, 0, 6, 4, 0, 2];
+        packet.extend(sender_mac.octets());
+        packet.extend(sender_ip.octets());
+        packet.extend([0; 6]);
+        packet.extend(sender_ip.octets());
+        ethernet(MacAddr::broadcast(), 0x0806, &packet)
+    }
+
+    // Whether `parts` add up to a valid internet checksum: the one's
+    // complement sum of the 16-bit words, the checksum included, is all
+    // ones.
+    fn checksum_ok(parts: &[&[u8]]) -> bool {
+        let bytes = parts.concat();
+        let mut sum: u32 = bytes
+            .chunks(2)
+            .map(|word| u32::from(word[0]) << 8 | u32::from(*word.get(1).unwrap_or(&0)))
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum == 0xffff
+    }
+
+    fn ipv4_pseudo_header(ip: &Ipv4Packet) -> Vec<u8> {
+        let mut header = [ip.get_source().octets(), ip.get_destination().octets()].concat();
+        header.extend([0, ip.get_next_level_protocol().0]);
+        header.extend((ip.payload().len() as u16).to_be_bytes());
+        header
+    }
+
+    fn ipv6_pseudo_header(ip: &Ipv6Packet) -> Vec<u8> {
+        let mut header = [ip.get_source().octets(), ip.get_destination().octets()].concat();
+        header.extend((ip.payload().len() as u32).to_be_bytes());
+        header.extend([0, 0, 0, ip.get_next_header().0]);
+        header
+    }
+
+    // Check a reply's Ethernet header goes back to them from us, and return
+    // what follows it.
+    fn returned(reply: &[u8]) -> &[u8] {
+        let ethernet = EthernetPacket::new(reply).unwrap();
+        assert_eq!(ethernet.get_source(), OUR_MAC);
+        assert_eq!(ethernet.get_destination(), THEIR_MAC);
+        &reply[EthernetPacket::minimum_packet_size()..]
+    }
+
+    // Check a reply's IPv4 header goes back to them from us, and return it.
+    fn returned_ipv4(packet: &[u8], protocol: IpNextHeaderProtocol) -> Ipv4Packet<'_> {
+        let ip = Ipv4Packet::new(packet).unwrap();
+        assert_eq!((ip.get_source(), ip.get_destination()), (OUR_IP, THEIR_IP));
+        assert_eq!((ip.get_ttl(), ip.get_next_level_protocol()), (64, protocol));
+        assert!(checksum_ok(&[&packet[..20]]));
+        ip
+    }
+
+    #[test]
+    fn resets_an_acknowledged_segment_with_its_ack_number() {
+        let segment = tcp(
+            1000,
+            5000,
+            TcpFlags(TcpFlags::ACK.0 | TcpFlags::PSH.0),
+            b"hello",
+        );
+        let ip = ipv4(OUR_IP, 6, 0, &segment);
+        let frame = ethernet(OUR_MAC, 0x8100, &vlan(5, 0x0800, &ip));
+        let reply = answer(Response::TcpReset, &frame).unwrap();
+
+        // The tag is kept, and the 58 bytes padded to the minimum.
+        let tagged = returned(&reply);
+        assert_eq!(tagged[..4], frame[14..18]);
+        assert_eq!(reply.len(), 60);
+        assert_eq!(reply[58..], [0, 0]);
+
+        let ip = returned_ipv4(&tagged[4..], IpNextHeaderProtocols::Tcp);
+        assert_eq!(ip.get_total_length(), 40);
+        let reset = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!((reset.get_source(), reset.get_destination()), (80, 40000));
+        assert_eq!(
+            (reset.get_sequence(), reset.get_acknowledgement()),
+            (5000, 0)
+        );
+        assert_eq!(reset.get_flags(), TcpFlags::RST.0);
+        assert!(checksum_ok(&[&ipv4_pseudo_header(&ip), ip.payload()]));
+    }
+
+    #[test]
+    fn resets_a_segment_without_ack_by_acknowledging_it() {
+        // A SYN takes up one sequence number.
+        let frame = ethernet(OUR_MAC, 0x86dd, &ipv6(6, &tcp(7, 0, TcpFlags::SYN, &[])));
+        let reply = answer(Response::TcpReset, &frame).unwrap();
+        let ip = Ipv6Packet::new(returned(&reply)).unwrap();
+        assert_eq!(
+            (ip.get_source(), ip.get_destination()),
+            (OUR_IPV6, THEIR_IPV6)
+        );
+        let reset = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!((reset.get_sequence(), reset.get_acknowledgement()), (0, 8));
+        assert_eq!(reset.get_flags(), TcpFlags::RST.0 | TcpFlags::ACK.0);
+        assert!(checksum_ok(&[&ipv6_pseudo_header(&ip), ip.payload()]));
+
+        // So does a FIN, and so does every byte of data, captured or not.
+        let segment = tcp(u32::MAX - 10, 0, TcpFlags::FIN, &[0; 100]);
+        let mut frame = ethernet(OUR_MAC, 0x0800, &ipv4(OUR_IP, 6, 0, &segment));
+        frame.truncate(54);
+        let reply = answer(Response::TcpReset, &frame).unwrap();
+        let ip = returned_ipv4(returned(&reply), IpNextHeaderProtocols::Tcp);
+        let reset = TcpPacket::new(ip.payload()).unwrap();
+        assert_eq!(reset.get_acknowledgement(), 90);
+    }
+
+    #[test]
+    fn prohibits_ipv4_quoting_the_header_and_8_bytes() {
+        let ip = ipv4(OUR_IP, 17, 0, &udp(&[0x55; 20]));
+        let inner = vlan(20, 0x0800, &ip);
+        let frame = ethernet(OUR_MAC, 0x88a8, &vlan(10, 0x8100, &inner));
+        let reply = answer(Response::IcmpProhibited, &frame).unwrap();
+
+        let tagged = returned(&reply);
+        assert_eq!(tagged[..8], frame[14..22]);
+        let ip_reply = returned_ipv4(&tagged[8..], IpNextHeaderProtocols::Icmp);
+        let message = ip_reply.payload();
+        assert_eq!(message[..2], [3, 13]);
+        assert_eq!(message[8..], ip[..28]);
+        assert!(checksum_ok(&[message]));
+    }
+
+    #[test]
+    fn prohibits_ipv6_quoting_up_to_the_minimum_mtu() {
+        let frame = ethernet(OUR_MAC, 0x86dd, &ipv6(17, &udp(&[0x55; 1400])));
+        let reply = answer(Response::IcmpProhibited, &frame).unwrap();
+        let ip = Ipv6Packet::new(returned(&reply)).unwrap();
+        assert_eq!(
+            (ip.get_source(), ip.get_destination()),
+            (OUR_IPV6, THEIR_IPV6)
+        );
+        assert_eq!(ip.get_next_header(), IpNextHeaderProtocols::Icmpv6);
+        assert_eq!(ip.get_payload_length(), 1240);
+        let message = ip.payload();
+        assert_eq!(message[..2], [1, 1]);
+        assert_eq!(message[8..], frame[14..14 + 1232]);
+        assert!(checksum_ok(&[&ipv6_pseudo_header(&ip), message]));
+
+        // Bytes after the end of the packet aren't part of it.
+        let packet = ipv6(17, &udp(&[]));
+        let mut frame = ethernet(OUR_MAC, 0x86dd, &packet);
+        frame.extend([0; 4]);
+        let reply = answer(Response::IcmpProhibited, &frame).unwrap();
+        let ip = Ipv6Packet::new(returned(&reply)).unwrap();
+        assert_eq!(ip.payload()[8..], packet);
+    }
+
+    #[test]
+    fn corrects_arp_claiming_our_address() {
+        let mut test = Test::new(Some(OUR_MAC));
+        let reply = test
+            .answer(Response::ArpCorrection, &arp(THEIR_MAC, OUR_IP))
+            .unwrap();
+        let ethernet = EthernetPacket::new(&reply).unwrap();
+        assert_eq!(ethernet.get_source(), OUR_MAC);
+        assert_eq!(ethernet.get_destination(), MacAddr::broadcast());
+        assert_eq!(ethernet.get_ethertype(), EtherTypes::Arp);
+        assert_eq!(reply.len(), 60);
+
+        let arp_reply = pnet::packet::arp::ArpPacket::new(ethernet.payload()).unwrap();
+        assert_eq!(arp_reply.get_operation(), ArpOperations::Reply);
+        assert_eq!(arp_reply.get_sender_hw_addr(), OUR_MAC);
+        assert_eq!(arp_reply.get_sender_proto_addr(), OUR_IP);
+        assert_eq!(arp_reply.get_target_hw_addr(), MacAddr::broadcast());
+        assert_eq!(arp_reply.get_target_proto_addr(), OUR_IP);
+
+        // Someone else's address, our own announcement, and an interface
+        // with no MAC to defend it with.
+        assert!(
+            test.answer(Response::ArpCorrection, &arp(THEIR_MAC, THEIR_IP))
+                .is_none()
+        );
+        assert!(
+            test.answer(Response::ArpCorrection, &arp(OUR_MAC, OUR_IP))
+                .is_none()
+        );
+        let frame = arp(THEIR_MAC, OUR_IP);
+        assert!(
+            Test::new(None)
+                .answer(Response::ArpCorrection, &frame)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn never_answers_broadcasts_resets_or_errors() {
+        let syn = ipv4(OUR_IP, 6, 0, &tcp(1, 0, TcpFlags::SYN, &[]));
+        let broadcast = ethernet(MacAddr::broadcast(), 0x0800, &syn);
+        assert!(answer(Response::TcpReset, &broadcast).is_none());
+        assert!(answer(Response::IcmpProhibited, &broadcast).is_none());
+        let multicast = ipv4(
+            Ipv4Addr::new(224, 0, 0, 1),
+            6,
+            0,
+            &tcp(1, 0, TcpFlags::SYN, &[]),
+        );
+        let multicast = ethernet(OUR_MAC, 0x0800, &multicast);
+        assert!(answer(Response::TcpReset, &multicast).is_none());
+
+        let reset = ipv4(OUR_IP, 6, 0, &tcp(1, 0, TcpFlags::RST, &[]));
+        assert!(answer(Response::TcpReset, &ethernet(OUR_MAC, 0x0800, &reset)).is_none());
+
+        let unreachable = ethernet(OUR_MAC, 0x0800, &ipv4(OUR_IP, 1, 0, &icmp(3)));
+        assert!(answer(Response::IcmpProhibited, &unreachable).is_none());
+        let echo = ethernet(OUR_MAC, 0x0800, &ipv4(OUR_IP, 1, 0, &icmp(8)));
+        assert!(answer(Response::IcmpProhibited, &echo).is_some());
+        let unreachable = ethernet(OUR_MAC, 0x86dd, &ipv6(58, &icmp(1)));
+        assert!(answer(Response::IcmpProhibited, &unreachable).is_none());
+        let echo = ethernet(OUR_MAC, 0x86dd, &ipv6(58, &icmp(128)));
+        assert!(answer(Response::IcmpProhibited, &echo).is_some());
+
+        // The last fragment of a packet, 1480 bytes in.
+        let fragment = ipv4(OUR_IP, 17, 185, &[0; 16]);
+        let fragment = ethernet(OUR_MAC, 0x0800, &fragment);
+        assert!(answer(Response::IcmpProhibited, &fragment).is_none());
+
+        // And nothing that doesn't apply.
+        let datagram = ethernet(OUR_MAC, 0x0800, &ipv4(OUR_IP, 17, 0, &udp(&[])));
+        assert!(answer(Response::TcpReset, &datagram).is_none());
+        assert!(answer(Response::IcmpProhibited, &arp(THEIR_MAC, OUR_IP)).is_none());
+    }
+
+    #[test]
+    fn limits_responses_per_second() {
+        let mut test = Test::new(Some(OUR_MAC));
+        let syn = ipv4(OUR_IP, 6, 0, &tcp(1, 0, TcpFlags::SYN, &[]));
+        let frame = ethernet(OUR_MAC, 0x0800, &syn);
+        for _ in 0..MAX_PER_SECOND {
+            assert!(test.answer(Response::TcpReset, &frame).is_some());
+        }
+        assert!(test.answer(Response::TcpReset, &frame).is_none());
+    }
+}
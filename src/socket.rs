@@ -0,0 +1,293 @@
+// A Linux `AF_PACKET` capture socket of our own. pnet's datalink channel
+// keeps its socket to itself, so there's no way to attach a BPF filter to
+// it or ask the kernel how many frames it dropped; a layer 2 live capture
+// opens one of these instead. It reads the same raw Ethernet frames as
+// pnet's channel. A `PacketSender` sends like the channel's `tx` half,
+// through one of these or through a send-only socket of its own.
+use pnet::datalink::{self, DataLinkSender, FanoutType, NetworkInterface};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::capture::{Frame, KernelStats, PacketSource, now};
+use crate::filter::Filter;
+
+// `ETH_P_ALL`: every protocol. The kernel wants it in network byte order.
+const ETH_P_ALL: u16 = 0x0003;
+
+// Not in every version of `libc` yet; from `<linux/if_packet.h>`.
+const PACKET_IGNORE_OUTGOING: libc::c_int = 23;
+// The same for a whole fanout group, which ignores the option above.
+const PACKET_FANOUT_FLAG_IGNORE_OUTGOING: libc::c_uint = 0x4000;
+
+/// A raw packet socket on one interface, with an optional filter run by the
+/// kernel.
+pub struct PacketSocket {
+    // `OwnedFd` closes the socket when dropped, like a Go `*os.File` with a
+    // finalizer, but deterministic.
+    fd: OwnedFd,
+    buf: Vec<u8>,
+}
+
+impl PacketSocket {
+    /// Open a socket on `interface` that only receives frames matching
+    /// `filter`, or every frame if it's empty. `config` supplies the buffer
+    /// size, read timeout, promiscuous mode and fanout group, as for a pnet
+    /// channel. The channel type is ignored: the filter is compiled for whole
+    /// Ethernet frames.
+    ///
+    /// With `incoming_only`, frames this host sends out of the interface
+    /// aren't received, only those arriving on it. Frames sent through this
+    /// socket itself are never received either way.
+    pub fn open(
+        interface: &NetworkInterface,
+        config: &datalink::Config,
+        filter: &Filter,
+        incoming_only: bool,
+    ) -> io::Result<PacketSocket> {
+        // Protocol 0 receives nothing until `bind` below, so no unfiltered
+        // frame can sneak in before the filter is attached.
+        let fd = packet_socket()?;
+
+        // The kernel reads the program through this pointer during the call;
+        // `Instruction` has the same layout as its `sock_filter`. Without a
+        // filter there's nothing to attach, and no program to run per frame.
+        if !filter.is_empty() {
+            let program = filter.program();
+            let fprog = libc::sock_fprog {
+                len: u16::try_from(program.len())
+                    .map_err(|_| io::Error::other("filter is too long"))?,
+                filter: program.as_ptr() as *mut libc::sock_filter,
+            };
+            setsockopt(&fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &fprog)?;
+        }
+
+        if config.promiscuous {
+            // Fields not set here are zero, like a Go struct literal.
+            let mreq = libc::packet_mreq {
+                mr_ifindex: interface.index as i32,
+                mr_type: libc::PACKET_MR_PROMISC as u16,
+                ..unsafe { mem::zeroed() }
+            };
+            setsockopt(&fd, libc::SOL_PACKET, libc::PACKET_ADD_MEMBERSHIP, &mreq)?;
+        }
+        if let Some(timeout) = config.read_timeout {
+            let tv = libc::timeval {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+            };
+            setsockopt(&fd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, &tv)?;
+        }
+
+        if incoming_only {
+            setsockopt(
+                &fd,
+                libc::SOL_PACKET,
+                PACKET_IGNORE_OUTGOING,
+                &(1 as libc::c_int),
+            )?;
+        }
+
+        bind(&fd, interface, ETH_P_ALL)?;
+
+        // Joining a fanout group only works once the socket is bound.
+        if let Some(fanout) = config.linux_fanout {
+            let mode = match fanout.fanout_type {
+                FanoutType::HASH => libc::PACKET_FANOUT_HASH,
+                FanoutType::LB => libc::PACKET_FANOUT_LB,
+                FanoutType::CPU => libc::PACKET_FANOUT_CPU,
+                FanoutType::ROLLOVER => libc::PACKET_FANOUT_ROLLOVER,
+                FanoutType::RND => libc::PACKET_FANOUT_RND,
+                FanoutType::QM => libc::PACKET_FANOUT_QM,
+                FanoutType::CBPF => libc::PACKET_FANOUT_CBPF,
+                FanoutType::EBPF => libc::PACKET_FANOUT_EBPF,
+            };
+            let mut flags = 0;
+            if fanout.defrag {
+                flags |= libc::PACKET_FANOUT_FLAG_DEFRAG;
+            }
+            if fanout.rollover {
+                flags |= libc::PACKET_FANOUT_FLAG_ROLLOVER;
+            }
+            if incoming_only {
+                flags |= PACKET_FANOUT_FLAG_IGNORE_OUTGOING;
+            }
+            // The group id goes in the low 16 bits, the mode and flags above.
+            let arg: libc::c_uint = fanout.group_id as libc::c_uint | (mode | flags) << 16;
+            setsockopt(&fd, libc::SOL_PACKET, libc::PACKET_FANOUT, &arg)?;
+        }
+
+        Ok(PacketSocket {
+            fd,
+            buf: vec![0; config.read_buffer_size],
+        })
+    }
+
+    /// A sender for the same socket, building frames in a buffer of
+    /// `write_buffer_size` bytes. What it sends goes out on the socket's
+    /// interface, and isn't received back by this socket or any other in
+    /// its fanout group.
+    pub fn sender(&self, write_buffer_size: usize) -> io::Result<PacketSender> {
+        // `try_clone` is `dup(2)`: a second handle on the same socket.
+        Ok(PacketSender {
+            fd: self.fd.try_clone()?,
+            buf: vec![0; write_buffer_size],
+        })
+    }
+}
+
+/// Sends whole Ethernet frames through a `PacketSocket`.
+pub struct PacketSender {
+    fd: OwnedFd,
+    buf: Vec<u8>,
+}
+
+impl PacketSender {
+    /// A socket of its own that only sends, out of `interface`.
+    pub fn open(
+        interface: &NetworkInterface,
+        write_buffer_size: usize,
+    ) -> io::Result<PacketSender> {
+        // Bound with protocol 0, it never receives anything.
+        let fd = packet_socket()?;
+        bind(&fd, interface, 0)?;
+        Ok(PacketSender {
+            fd,
+            buf: vec![0; write_buffer_size],
+        })
+    }
+}
+
+impl DataLinkSender for PacketSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        let buf = self.buf.get_mut(..packet_size)?;
+        for _ in 0..num_packets {
+            func(buf);
+            if let Err(e) = send(&self.fd, buf) {
+                return Some(Err(e));
+            }
+        }
+        Some(Ok(()))
+    }
+
+    fn send_to(&mut self, packet: &[u8], _dst: Option<NetworkInterface>) -> Option<io::Result<()>> {
+        Some(send(&self.fd, packet))
+    }
+}
+
+// Send one frame on the interface the socket is bound to. `MSG_DONTWAIT`
+// turns a full send queue into an error rather than a capture thread stuck
+// waiting for room.
+fn send(fd: &OwnedFd, frame: &[u8]) -> io::Result<()> {
+    let result = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            frame.as_ptr().cast(),
+            frame.len(),
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl PacketSource for PacketSocket {
+    fn next_frame(&mut self) -> io::Result<Option<Frame<'_>>> {
+        // `MSG_TRUNC` makes `recv` return the frame's full length even when
+        // it didn't fit in the buffer, so byte limits see the real size.
+        let len = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                self.buf.as_mut_ptr().cast(),
+                self.buf.len(),
+                libc::MSG_TRUNC,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = len as usize;
+        let data = &self.buf[..len.min(self.buf.len())];
+        Ok(Some(Frame {
+            data,
+            len,
+            timestamp: now(),
+        }))
+    }
+
+    // `PACKET_STATISTICS` hands out the counts since it was last asked, and
+    // starts them again from zero.
+    fn kernel_stats(&mut self) -> Option<KernelStats> {
+        let mut stats: libc::tpacket_stats = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tpacket_stats>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                libc::PACKET_STATISTICS,
+                &mut stats as *mut libc::tpacket_stats as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (result == 0).then(|| KernelStats {
+            packets: stats.tp_packets.into(),
+            drops: stats.tp_drops.into(),
+        })
+    }
+}
+
+// A new raw packet socket, receiving nothing until it's bound.
+fn packet_socket() -> io::Result<OwnedFd> {
+    // `unsafe` marks calls into C that the compiler can't check, like cgo.
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// Bind a packet socket to `interface`, receiving frames of `protocol` (in
+// host byte order) on it.
+fn bind(fd: &OwnedFd, interface: &NetworkInterface, protocol: u16) -> io::Result<()> {
+    let addr = libc::sockaddr_ll {
+        sll_family: libc::AF_PACKET as u16,
+        sll_protocol: protocol.to_be(),
+        sll_ifindex: interface.index as i32,
+        ..unsafe { mem::zeroed() }
+    };
+    let result = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// `setsockopt` for an option whose value is the C struct `T`.
+fn setsockopt<T>(fd: &OwnedFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use pnet::datalink::MacAddr;
+use pnet::packet::ethernet::EthernetPacket;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::dissect::Summary;
+
+// The thing we count packets against. Behind a router every frame carries the
+// router's MAC, so we prefer the IP source address and only fall back to the
+// MAC for non-IP traffic such as ARP.
+// Every variant is a fixed-size value (4, 16 or 6 bytes), so a `Source` is
+// `Copy`, never allocates, and is cheap to hash and compare as a map key.
+// Deriving `Hash` and `Eq` lets this be used as a `HashMap` key, like a Go
+// struct of comparable fields can be a map key. `Ord` sorts IPv4 before
+// IPv6 before MACs, and each by address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Source {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Mac(MacAddr),
+}
+
+impl Source {
+    /// Work out which source a frame should be counted against. IP packets
+    /// are found behind VLAN tags too; a frame whose IP header is too short
+    /// to parse is counted against its MAC. To also look at the rest of the
+    /// frame, dissect it once with `Summary::of` and use `Summary::source`.
+    pub fn of(ethernet: &EthernetPacket) -> Source {
+        Summary::of(ethernet).source()
+    }
+
+    /// The IP address, for IP sources.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match *self {
+            Source::Ipv4(addr) => Some(IpAddr::V4(addr)),
+            Source::Ipv6(addr) => Some(IpAddr::V6(addr)),
+            Source::Mac(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Ipv4(addr) => write!(f, "{}", addr),
+            Source::Ipv6(addr) => write!(f, "{}", addr),
+            Source::Mac(mac) => write!(f, "{}", mac),
+        }
+    }
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// Parses an IPv4 or IPv6 address, or a MAC address like
+    /// `02:00:00:00:00:01`.
+    fn from_str(s: &str) -> Result<Source, String> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(match ip {
+                IpAddr::V4(addr) => Source::Ipv4(addr),
+                IpAddr::V6(addr) => Source::Ipv6(addr),
+            });
+        }
+        s.parse::<MacAddr>()
+            .map(Source::Mac)
+            .map_err(|_| format!("'{}' is not an IP or MAC address", s))
+    }
+}
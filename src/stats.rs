@@ -0,0 +1,230 @@
+// Totals for the whole run, reported once capture stops: how much traffic
+// there was, who sent the most of it, who went over a limit, and what the
+// kernel had to drop.
+//
+// Each capture thread counts its own sources in a `Talkers`, so counting
+// never waits on another thread; the counts are only added up at the end.
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::bridge::DirectionStats;
+use crate::capture::KernelStats;
+use crate::lists::Prefix;
+use crate::protocol::Protocol;
+use crate::source::Source;
+
+/// Packets and bytes from one source.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Traffic {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets by protocol, indexed like `Protocol::ALL`. Left out of the
+    /// report, which only lists totals.
+    #[serde(skip)]
+    pub protocols: [u64; Protocol::ALL.len()],
+}
+
+impl Traffic {
+    /// What was added since `earlier`, a copy of the same counts taken
+    /// before.
+    pub fn since(&self, earlier: &Traffic) -> Traffic {
+        Traffic {
+            packets: self.packets.saturating_sub(earlier.packets),
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+            protocols: std::array::from_fn(|i| {
+                self.protocols[i].saturating_sub(earlier.protocols[i])
+            }),
+        }
+    }
+
+    /// Add `other`'s counts to these.
+    pub fn add(&mut self, other: &Traffic) {
+        self.packets += other.packets;
+        self.bytes += other.bytes;
+        for (total, count) in self.protocols.iter_mut().zip(other.protocols) {
+            *total += count;
+        }
+    }
+}
+
+/// Traffic per source, for finding the ones that sent the most.
+///
+/// A flood from spoofed addresses could otherwise grow this without end, so
+/// it holds at most `capacity` sources. Once full, the half that sent the
+/// fewest packets is forgotten: the top talkers stay exact, only the long
+/// tail of small ones is undercounted.
+#[derive(Clone, Debug)]
+pub struct Talkers {
+    counts: HashMap<Source, Traffic>,
+    capacity: usize,
+}
+
+impl Talkers {
+    pub fn new(capacity: usize) -> Talkers {
+        Talkers {
+            counts: HashMap::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Count one `protocol` packet of `len` bytes from `source`.
+    pub fn record(&mut self, source: Source, protocol: Protocol, len: usize) {
+        if self.counts.len() >= self.capacity && !self.counts.contains_key(&source) {
+            self.prune();
+        }
+        let traffic = self.counts.entry(source).or_default();
+        traffic.packets += 1;
+        traffic.bytes += len as u64;
+        traffic.protocols[protocol as usize] += 1;
+    }
+
+    /// Add another thread's counts to these.
+    pub fn merge(&mut self, other: &Talkers) {
+        for (&source, traffic) in &other.counts {
+            self.counts.entry(source).or_default().add(traffic);
+        }
+    }
+
+    /// Every source counted, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (Source, &Traffic)> {
+        self.counts
+            .iter()
+            .map(|(&source, traffic)| (source, traffic))
+    }
+
+    /// The `n` sources that sent the most packets, most first.
+    pub fn top(&self, n: usize) -> Vec<Talker> {
+        let mut all: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(&source, &traffic)| Talker { source, traffic })
+            .collect();
+        // Ties are broken by address, so the order is the same every run.
+        all.sort_by(|a, b| {
+            b.traffic
+                .packets
+                .cmp(&a.traffic.packets)
+                .then(a.source.cmp(&b.source))
+        });
+        all.truncate(n);
+        all
+    }
+
+    /// Number of sources counted.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    // Forget the half of the sources with the fewest packets.
+    fn prune(&mut self) {
+        let mut packets: Vec<u64> = self
+            .counts
+            .values()
+            .map(|traffic| traffic.packets)
+            .collect();
+        let middle = packets.len() / 2;
+        // Finds the median without sorting everything, like a partial sort.
+        let (_, &mut median, _) = packets.select_nth_unstable(middle);
+        self.counts.retain(|_, traffic| traffic.packets > median);
+    }
+}
+
+/// One source and what it sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Talker {
+    pub source: Source,
+    #[serde(flatten)]
+    pub traffic: Traffic,
+}
+
+/// A source or prefix that went over a limit, and how often.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Limited {
+    pub source: Prefix,
+    pub times: u64,
+}
+
+/// What a whole run saw.
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub duration_secs: f64,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Distinct sources seen, up to the `Talkers` capacity per thread.
+    pub sources: usize,
+    pub top_talkers: Vec<Talker>,
+    /// Sources and prefixes that went over a limit during the run, whether
+    /// or not they're still blocked. The most frequent first, up to the
+    /// same number as `top_talkers`.
+    pub limited: Vec<Limited>,
+    pub limited_total: usize,
+    /// `None` when no input could report it: a capture file, or a layer 3
+    /// channel.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<KernelStats>,
+    /// What each direction of a bridge forwarded and dropped; empty when
+    /// not bridging.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub bridge: Vec<DirectionStats>,
+}
+
+impl Report {
+    /// Write the report for a person to read.
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} packets, {} bytes from {} sources in {:.1}s",
+            self.packets, self.bytes, self.sources, self.duration_secs
+        )?;
+        if let Some(kernel) = self.kernel {
+            writeln!(
+                out,
+                "{} packets received by the kernel, {} dropped",
+                kernel.packets, kernel.drops
+            )?;
+        }
+        for direction in &self.bridge {
+            writeln!(
+                out,
+                "{} -> {}: {} packets ({} bytes) forwarded, {} ({} bytes) dropped, {} failed",
+                direction.from,
+                direction.to,
+                direction.forwarded,
+                direction.forwarded_bytes,
+                direction.dropped,
+                direction.dropped_bytes,
+                direction.errors
+            )?;
+        }
+        if !self.top_talkers.is_empty() {
+            writeln!(out, "top talkers:")?;
+            for talker in &self.top_talkers {
+                let Traffic { packets, bytes, .. } = talker.traffic;
+                // `to_string` first, since addresses ignore the padding.
+                let source = talker.source.to_string();
+                writeln!(
+                    out,
+                    "  {:<39} {:>12} packets {:>14} bytes",
+                    source, packets, bytes
+                )?;
+            }
+        }
+        if !self.limited.is_empty() {
+            writeln!(out, "went over a limit ({} in all):", self.limited_total)?;
+            for limited in &self.limited {
+                writeln!(
+                    out,
+                    "  {:<39} {:>12} times",
+                    limited.source.to_string(),
+                    limited.times
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,666 @@
+// `run --tui`: a live view of the loudest sources, redrawn every second, for
+// when the JSON event stream scrolls by too fast to read. It takes over the
+// terminal, so events meant for stdout are shown in a pane at the bottom
+// instead.
+//
+// The rates are worked out here, not counted on the packet path: every
+// second the dashboard adds up each capture thread's `Talkers` and compares
+// them with the previous second's totals.
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// ratatui re-exports the crossterm version it draws with, so the two can't
+// disagree.
+use ratatui::crossterm::event::{
+    self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+
+use packet_processor::{Listing, Prefix, Protocol, Source, Talkers, Traffic};
+
+use crate::Shared;
+
+// How often the rates are worked out again.
+const REFRESH: Duration = Duration::from_secs(1);
+
+// How long to wait for a key before checking whether we're stopping, e.g.
+// on SIGTERM.
+const KEY_POLL: Duration = Duration::from_millis(100);
+
+// Event lines kept for the events pane.
+const RECENT_LINES: usize = 100;
+
+/// The last few event lines, for the events pane. The event log writes here
+/// instead of to stdout while the dashboard has the terminal.
+#[derive(Clone, Default)]
+pub struct Recent {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl Recent {
+    pub fn new() -> Recent {
+        Recent::default()
+    }
+}
+
+// Implementing `Write` (Go: `io.Writer`) is all it takes to be an event
+// log's output. `EventLog` hands over each event as one line in one call.
+impl Write for Recent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut lines = self.lines.lock().unwrap_or_else(PoisonError::into_inner);
+        for line in String::from_utf8_lossy(buf).lines() {
+            if lines.len() == RECENT_LINES {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Take over the terminal and draw the dashboard from a thread of its own,
+/// until we're stopping. `q` or Ctrl-C stops the run, just like SIGINT does
+/// without the dashboard.
+pub fn start(shared: Arc<Shared>, recent: Recent) -> io::Result<JoinHandle<()>> {
+    if !io::stdout().is_terminal() {
+        return Err(io::Error::other("stdout is not a terminal"));
+    }
+    // Raw mode and the alternate screen, given back by `ratatui::restore`.
+    // ratatui also restores the terminal if anything panics.
+    let terminal = ratatui::try_init()?;
+    let mut dashboard = Dashboard::new(shared, recent);
+    thread::Builder::new()
+        .name("dashboard".to_string())
+        .spawn(move || {
+            let result = dashboard.run(terminal);
+            ratatui::restore();
+            // Without the dashboard there's nothing to watch the run with, and
+            // Ctrl-C may not even reach us, so stop.
+            if let Err(e) = result {
+                eprintln!("error drawing dashboard: {}", e);
+                dashboard.shared.stopping.store(true, Ordering::Relaxed);
+            }
+        })
+        .inspect_err(|_| ratatui::restore())
+}
+
+/// What the table is sorted by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Sort {
+    #[default]
+    Pps,
+    Bps,
+    Packets,
+    Bytes,
+    Source,
+}
+
+impl Sort {
+    const ALL: [Sort; 5] = [
+        Sort::Pps,
+        Sort::Bps,
+        Sort::Packets,
+        Sort::Bytes,
+        Sort::Source,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Sort::Pps => "pps",
+            Sort::Bps => "bit/s",
+            Sort::Packets => "packets",
+            Sort::Bytes => "bytes",
+            Sort::Source => "source",
+        }
+    }
+
+    fn next(self) -> Sort {
+        let i = Sort::ALL.iter().position(|&sort| sort == self).unwrap_or(0);
+        Sort::ALL[(i + 1) % Sort::ALL.len()]
+    }
+}
+
+// One source, as of the last refresh.
+struct Talker {
+    source: Source,
+    // Its text form, for the filter and the table, made once per refresh.
+    name: String,
+    // What it sent over the last second, and in all.
+    recent: Traffic,
+    total: Traffic,
+    pps: f64,
+    bps: f64,
+}
+
+// The sources in the table, apart from the terminal: their rates since
+// the last refresh, and the order and filter to show them in.
+#[derive(Default)]
+struct Sources {
+    // Every source's totals at the last refresh, to work out rates from.
+    last: HashMap<Source, Traffic>,
+    talkers: Vec<Talker>,
+    // Rates over every source.
+    traffic: Traffic,
+    pps: f64,
+    bps: f64,
+    sort: Sort,
+    // Smallest first instead of largest first.
+    reverse: bool,
+    filter: String,
+}
+
+impl Sources {
+    // Work out each source's rates from `all`, every capture thread's
+    // counts added up, `secs` seconds after the last refresh.
+    fn refresh(&mut self, all: &Talkers, secs: f64) {
+        let none = Traffic::default();
+        self.talkers = all
+            .iter()
+            .map(|(source, &total)| {
+                let recent = total.since(self.last.get(&source).unwrap_or(&none));
+                Talker {
+                    source,
+                    name: source.to_string(),
+                    recent,
+                    total,
+                    pps: recent.packets as f64 / secs,
+                    bps: recent.bytes as f64 * 8.0 / secs,
+                }
+            })
+            .collect();
+        self.last = all
+            .iter()
+            .map(|(source, &traffic)| (source, traffic))
+            .collect();
+
+        self.traffic = Traffic::default();
+        for talker in &self.talkers {
+            self.traffic.add(&talker.recent);
+        }
+        self.pps = self.traffic.packets as f64 / secs;
+        self.bps = self.traffic.bytes as f64 * 8.0 / secs;
+    }
+
+    // The sources to show: those matching the filter, in order. The filter
+    // is a prefix such as `10.0.0.0/8`, or else part of the address.
+    fn view(&self) -> Vec<&Talker> {
+        let prefix = self.filter.parse::<Prefix>().ok();
+        let mut view: Vec<&Talker> = self
+            .talkers
+            .iter()
+            .filter(|talker| match prefix {
+                Some(prefix) => Prefix::from(talker.source).truncate(prefix.prefix_len()) == prefix,
+                None => talker.name.contains(&self.filter),
+            })
+            .collect();
+        // Largest first; the address breaks ties, so rows don't jump about.
+        view.sort_by(|a, b| {
+            let order = match self.sort {
+                Sort::Pps => b.pps.total_cmp(&a.pps),
+                Sort::Bps => b.bps.total_cmp(&a.bps),
+                Sort::Packets => b.total.packets.cmp(&a.total.packets),
+                Sort::Bytes => b.total.bytes.cmp(&a.total.bytes),
+                Sort::Source => a.source.cmp(&b.source),
+            };
+            order.then(a.source.cmp(&b.source))
+        });
+        if self.reverse {
+            view.reverse();
+        }
+        view
+    }
+}
+
+struct Dashboard {
+    shared: Arc<Shared>,
+    recent: Recent,
+    refreshed: Instant,
+    sources: Sources,
+    // Keys go to the filter instead of being commands.
+    editing: bool,
+    paused: bool,
+    // The first row shown, for scrolling.
+    offset: usize,
+}
+
+impl Dashboard {
+    fn new(shared: Arc<Shared>, recent: Recent) -> Dashboard {
+        Dashboard {
+            shared,
+            recent,
+            refreshed: Instant::now(),
+            sources: Sources::default(),
+            editing: false,
+            paused: false,
+            offset: 0,
+        }
+    }
+
+    fn run(&mut self, mut terminal: DefaultTerminal) -> io::Result<()> {
+        let mut next = Instant::now();
+        let mut redraw = true;
+        while !self.shared.stopping() {
+            if Instant::now() >= next {
+                if !self.paused {
+                    self.refresh();
+                }
+                next = Instant::now() + REFRESH;
+                // New events may have come in even while paused.
+                redraw = true;
+            }
+            if redraw {
+                terminal.draw(|frame| self.draw(frame))?;
+                redraw = false;
+            }
+            let wait = next.saturating_duration_since(Instant::now()).min(KEY_POLL);
+            if event::poll(wait)? {
+                // Anything, including a resize, calls for a redraw.
+                if let TermEvent::Key(key) = event::read()?
+                    && key.kind == KeyEventKind::Press
+                {
+                    self.key(key);
+                }
+                redraw = true;
+            }
+        }
+        Ok(())
+    }
+
+    // Add up every capture thread's counts, and work out each source's
+    // rates since the last refresh.
+    fn refresh(&mut self) {
+        let mut all = Talkers::new(usize::MAX);
+        for i in 0..self.shared.talkers.len() {
+            all.merge(&self.shared.talkers(i));
+        }
+        let now = Instant::now();
+        let secs = now.duration_since(self.refreshed).as_secs_f64().max(0.001);
+        self.refreshed = now;
+        self.sources.refresh(&all, secs);
+    }
+
+    fn key(&mut self, key: KeyEvent) {
+        // Raw mode turns Ctrl-C into a key press instead of SIGINT.
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            self.shared.stopping.store(true, Ordering::Relaxed);
+            return;
+        }
+        if self.editing {
+            match key.code {
+                KeyCode::Enter => self.editing = false,
+                KeyCode::Esc => {
+                    self.sources.filter.clear();
+                    self.editing = false;
+                }
+                KeyCode::Backspace => {
+                    self.sources.filter.pop();
+                }
+                KeyCode::Char(c) => self.sources.filter.push(c),
+                _ => {}
+            }
+            self.offset = 0;
+            return;
+        }
+        match key.code {
+            KeyCode::Char('q') => self.shared.stopping.store(true, Ordering::Relaxed),
+            KeyCode::Char('s') | KeyCode::Tab => self.sources.sort = self.sources.sort.next(),
+            KeyCode::Char('r') => self.sources.reverse = !self.sources.reverse,
+            KeyCode::Char('/') => self.editing = true,
+            KeyCode::Esc => self.sources.filter.clear(),
+            KeyCode::Char(' ') => self.paused = !self.paused,
+            KeyCode::Down | KeyCode::Char('j') => self.offset += 1,
+            KeyCode::Up | KeyCode::Char('k') => self.offset = self.offset.saturating_sub(1),
+            KeyCode::PageDown => self.offset += 20,
+            KeyCode::PageUp => self.offset = self.offset.saturating_sub(20),
+            KeyCode::Home | KeyCode::Char('g') => self.offset = 0,
+            _ => {}
+        }
+    }
+
+    // Whether `source` is blocked, listed, or has been over a limit before.
+    fn status(&self, source: Source) -> String {
+        match self.shared.lists().check(&source) {
+            Listing::Allowed => return "allowed".to_string(),
+            Listing::Denied(_) => return "denied".to_string(),
+            Listing::Unlisted => {}
+        }
+        if self.shared.blocklist.is_blocked(&source) {
+            return "blocked".to_string();
+        }
+        match self.shared.limited().get(&Prefix::from(source)) {
+            Some(times) => format!("over {}x", times),
+            None => String::new(),
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [summary, table, events, footer] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Min(5),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        // Totals.
+        let blocked = self.shared.blocklist.len();
+        let drops = self.shared.metrics.kernel().drops;
+        let title = if self.paused {
+            " packet_processor (paused) "
+        } else {
+            " packet_processor "
+        };
+        let lines = vec![
+            Line::from(format!(
+                "{} pps   {}   {} sources   {} blocked   {} kernel drops",
+                si(self.sources.pps, ""),
+                si(self.sources.bps, "bit/s"),
+                self.sources.talkers.len(),
+                blocked,
+                drops
+            )),
+            Line::from(match self.sources.traffic.packets {
+                0 => "nothing in the last second".to_string(),
+                _ => mix(&self.sources.traffic, Protocol::ALL.len()),
+            }),
+        ];
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            summary,
+        );
+
+        // Sources. Only the rows that fit are looked up in the blocklist.
+        let view = self.sources.view();
+        let height = table.height.saturating_sub(3) as usize;
+        let offset = self.offset.min(view.len().saturating_sub(1));
+        let rows: Vec<Row> = view
+            .iter()
+            .skip(offset)
+            .take(height)
+            .map(|talker| {
+                Row::new(vec![
+                    talker.name.clone(),
+                    si(talker.pps, ""),
+                    si(talker.bps, "bit/s"),
+                    talker.total.packets.to_string(),
+                    talker.total.bytes.to_string(),
+                    // Last second's mix, or all of it for a source gone quiet.
+                    mix(
+                        if talker.recent.packets > 0 {
+                            &talker.recent
+                        } else {
+                            &talker.total
+                        },
+                        2,
+                    ),
+                    self.status(talker.source),
+                ])
+            })
+            .collect();
+        let header = Row::new([
+            "source",
+            "pps",
+            "bit/s",
+            "packets",
+            "bytes",
+            "protocols",
+            "status",
+        ])
+        .bold();
+        let widths = [
+            Constraint::Min(17),
+            Constraint::Length(8),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(14),
+            Constraint::Length(20),
+            Constraint::Length(10),
+        ];
+        let mut title = format!(" {} sources by {}", view.len(), self.sources.sort.name());
+        // Scrolling past the end stops at the last row.
+        self.offset = offset;
+        if self.sources.reverse {
+            title.push_str(", smallest first");
+        }
+        if !self.sources.filter.is_empty() {
+            title.push_str(&format!(", matching '{}'", self.sources.filter));
+        }
+        title.push(' ');
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(header)
+                .block(Block::bordered().title(title)),
+            table,
+        );
+
+        // The newest events that fit.
+        let lines = self
+            .recent
+            .lines
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let fit = events.height.saturating_sub(2) as usize;
+        let shown: Vec<Line> = lines
+            .iter()
+            .skip(lines.len().saturating_sub(fit))
+            .map(|line| Line::from(line.clone()))
+            .collect();
+        drop(lines);
+        frame.render_widget(
+            Paragraph::new(shown).block(Block::bordered().title(" events ")),
+            events,
+        );
+
+        let help = if self.editing {
+            Line::from(format!(
+                "filter: {}_   (enter to keep, esc to clear)",
+                self.sources.filter
+            ))
+        } else {
+            Line::from(
+                "q quit   s sort   r reverse   / filter   esc clear filter   space pause   ↑↓ scroll",
+            )
+        };
+        frame.render_widget(Paragraph::new(help).style(Style::new().reversed()), footer);
+    }
+}
+
+// A number with a k, M or G suffix, e.g. `12.3k` or `98.1 Mbit/s`.
+fn si(value: f64, unit: &str) -> String {
+    let space = if unit.is_empty() { "" } else { " " };
+    for (scale, suffix) in [(1e9, "G"), (1e6, "M"), (1e3, "k")] {
+        if value >= scale {
+            return format!("{:.1}{}{}{}", value / scale, space, suffix, unit);
+        }
+    }
+    format!("{:.0}{}{}", value, space, unit)
+}
+
+// The `most` biggest protocols in `traffic` and their share of its packets,
+// e.g. `tcp 80% udp 20%`.
+fn mix(traffic: &Traffic, most: usize) -> String {
+    let total = traffic.protocols.iter().sum::<u64>().max(1);
+    let mut shares: Vec<(Protocol, u64)> = Protocol::ALL
+        .into_iter()
+        .zip(traffic.protocols)
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    shares.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    let shares = shares
+        .iter()
+        .take(most)
+        .map(|(protocol, count)| format!("{} {}%", protocol.name(), count * 100 / total));
+    shares.collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(s: &str) -> Source {
+        s.parse().unwrap()
+    }
+
+    // Counts `packets` packets of `len` bytes from `from` into `talkers`.
+    fn send(talkers: &mut Talkers, from: &str, protocol: Protocol, packets: usize, len: usize) {
+        for _ in 0..packets {
+            talkers.record(source(from), protocol, len);
+        }
+    }
+
+    fn names(sources: &Sources) -> Vec<&str> {
+        sources
+            .view()
+            .iter()
+            .map(|talker| talker.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn adds_up_every_thread_and_works_out_rates() {
+        let (mut first, mut second) = (Talkers::new(100), Talkers::new(100));
+        send(&mut first, "10.0.0.1", Protocol::Tcp, 4, 100);
+        send(&mut second, "10.0.0.1", Protocol::Udp, 2, 50);
+        send(&mut second, "10.0.0.2", Protocol::Udp, 1, 1000);
+        let mut all = Talkers::new(usize::MAX);
+        all.merge(&first);
+        all.merge(&second);
+
+        let mut sources = Sources::default();
+        sources.refresh(&all, 2.0);
+        let view = sources.view();
+        assert_eq!(view[0].source, source("10.0.0.1"));
+        assert_eq!((view[0].total.packets, view[0].total.bytes), (6, 500));
+        assert_eq!((view[0].pps, view[0].bps), (3.0, 2000.0));
+        assert_eq!(view[0].total.protocols[Protocol::Tcp as usize], 4);
+        assert_eq!((sources.traffic.packets, sources.traffic.bytes), (7, 1500));
+        assert_eq!((sources.pps, sources.bps), (3.5, 6000.0));
+    }
+
+    #[test]
+    fn rates_count_only_what_came_since_the_last_refresh() {
+        let mut all = Talkers::new(100);
+        send(&mut all, "10.0.0.1", Protocol::Tcp, 10, 100);
+        send(&mut all, "10.0.0.2", Protocol::Tcp, 1, 100);
+        let mut sources = Sources::default();
+        sources.refresh(&all, 1.0);
+        assert_eq!(names(&sources), ["10.0.0.1", "10.0.0.2"]);
+
+        // The first source went quiet, so it's down to no rate at all.
+        send(&mut all, "10.0.0.2", Protocol::Udp, 3, 100);
+        sources.refresh(&all, 1.0);
+        assert_eq!(names(&sources), ["10.0.0.2", "10.0.0.1"]);
+        let view = sources.view();
+        assert_eq!(
+            (view[0].pps, view[0].recent.packets, view[0].total.packets),
+            (3.0, 3, 4)
+        );
+        assert_eq!((view[1].pps, view[1].total.packets), (0.0, 10));
+        assert_eq!(sources.traffic.protocols[Protocol::Udp as usize], 3);
+        assert_eq!(sources.traffic.protocols[Protocol::Tcp as usize], 0);
+    }
+
+    #[test]
+    fn sorts_by_each_column_with_the_address_breaking_ties() {
+        let mut all = Talkers::new(100);
+        // Most packets, fewest bytes.
+        send(&mut all, "10.0.0.3", Protocol::Tcp, 5, 60);
+        // Fewest packets, most bytes.
+        send(&mut all, "10.0.0.1", Protocol::Tcp, 1, 1500);
+        // Ties with the first on packets.
+        send(&mut all, "10.0.0.2", Protocol::Tcp, 5, 100);
+        let mut sources = Sources::default();
+        sources.refresh(&all, 1.0);
+
+        let order = |sources: &mut Sources, sort: Sort| {
+            sources.sort = sort;
+            names(sources).join(" ")
+        };
+        assert_eq!(order(&mut sources, Sort::Pps), "10.0.0.2 10.0.0.3 10.0.0.1");
+        assert_eq!(
+            order(&mut sources, Sort::Packets),
+            "10.0.0.2 10.0.0.3 10.0.0.1"
+        );
+        assert_eq!(order(&mut sources, Sort::Bps), "10.0.0.1 10.0.0.2 10.0.0.3");
+        assert_eq!(
+            order(&mut sources, Sort::Bytes),
+            "10.0.0.1 10.0.0.2 10.0.0.3"
+        );
+        assert_eq!(
+            order(&mut sources, Sort::Source),
+            "10.0.0.1 10.0.0.2 10.0.0.3"
+        );
+        sources.reverse = true;
+        assert_eq!(
+            order(&mut sources, Sort::Packets),
+            "10.0.0.1 10.0.0.3 10.0.0.2"
+        );
+    }
+
+    #[test]
+    fn cycles_through_every_sort() {
+        let mut sort = Sort::default();
+        for expected in [
+            Sort::Bps,
+            Sort::Packets,
+            Sort::Bytes,
+            Sort::Source,
+            Sort::Pps,
+        ] {
+            sort = sort.next();
+            assert_eq!(sort, expected);
+        }
+    }
+
+    #[test]
+    fn filters_by_prefix_or_part_of_the_address() {
+        let mut all = Talkers::new(100);
+        for from in ["10.0.0.1", "10.0.1.1", "192.168.0.10", "2001:db8::1"] {
+            send(&mut all, from, Protocol::Other, 1, 60);
+        }
+        let mut sources = Sources {
+            sort: Sort::Source,
+            ..Sources::default()
+        };
+        sources.refresh(&all, 1.0);
+
+        sources.filter = "10.0.0.0/24".to_string();
+        assert_eq!(names(&sources), ["10.0.0.1"]);
+        sources.filter = "10.0.0.0/8".to_string();
+        assert_eq!(names(&sources), ["10.0.0.1", "10.0.1.1"]);
+        // Not a prefix, so it's part of an address.
+        sources.filter = ".1".to_string();
+        assert_eq!(names(&sources), ["10.0.0.1", "10.0.1.1", "192.168.0.10"]);
+        sources.filter = "db8".to_string();
+        assert_eq!(names(&sources), ["2001:db8::1"]);
+        sources.filter.clear();
+        assert_eq!(names(&sources).len(), 4);
+    }
+
+    #[test]
+    fn shows_the_biggest_protocols_and_numbers_with_suffixes() {
+        let mut traffic = Traffic::default();
+        traffic.protocols[Protocol::Tcp as usize] = 6;
+        traffic.protocols[Protocol::Udp as usize] = 3;
+        traffic.protocols[Protocol::Icmp as usize] = 1;
+        assert_eq!(mix(&traffic, 2), "tcp 60% udp 30%");
+        assert_eq!(mix(&Traffic::default(), 2), "");
+
+        assert_eq!(si(999.0, ""), "999");
+        assert_eq!(si(12_345.0, ""), "12.3k");
+        assert_eq!(si(98_100_000.0, "bit/s"), "98.1 Mbit/s");
+        assert_eq!(si(0.0, "bit/s"), "0 bit/s");
+    }
+}